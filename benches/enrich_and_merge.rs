@@ -0,0 +1,115 @@
+//! Throughput benchmark for merging trade logs with block bodies.
+//!
+//! Run with `cargo bench --features testing`.
+
+use alloy::primitives::{Address, B256};
+use criterion::{
+    criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion,
+    Throughput,
+};
+use proptest::strategy::{Strategy, ValueTree};
+use proptest::test_runner::TestRunner;
+use rain_drops::compose::{
+    enrich_and_merge, BlockMetadata, TradeEvent, TradeFill, TradeLog,
+    TxMetadata,
+};
+use rain_drops::testing::{arb_enrich_and_merge_input, ArbSizes};
+use std::collections::BTreeMap;
+
+fn bench_enrich_and_merge(c: &mut Criterion) {
+    let mut group = c.benchmark_group("enrich_and_merge");
+    let mut runner = TestRunner::deterministic();
+
+    for (max_trades_per_event, max_extra_txs_per_block) in
+        [(10, 10), (1_000, 100), (1_000, 1_000)]
+    {
+        let sizes = ArbSizes { max_trades_per_event, max_extra_txs_per_block };
+        let input = arb_enrich_and_merge_input(sizes)
+            .new_tree(&mut runner)
+            .expect("failed to generate benchmark input")
+            .current();
+
+        group.throughput(Throughput::Elements(input.trade_count() as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!(
+                "{max_trades_per_event}x{max_extra_txs_per_block}"
+            )),
+            &input,
+            |b, input| {
+                b.iter_batched(
+                    || input.clone(),
+                    |input| input.enrich_and_merge(),
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+
+    group.finish();
+}
+
+/// A block with the given number of TakeOrderV2 trades, each in its own
+/// transaction, among the given number of transactions.
+fn dense_block(
+    trade_count: u64,
+    tx_count: u64,
+) -> (BTreeMap<u64, Vec<TradeLog>>, BTreeMap<u64, BlockMetadata>) {
+    let tx_hash = |index: u64| B256::left_padding_from(&index.to_be_bytes());
+    let trades = (0..trade_count)
+        .map(|index| TradeLog {
+            log_index: index,
+            block_number: 1,
+            block_hash: B256::ZERO,
+            // The trades' transactions are spread over the block.
+            tx_hash: tx_hash(index * tx_count / trade_count),
+            event: TradeEvent::TakeOrderV2,
+            contract_address: Address::ZERO,
+            fill: TradeFill::default(),
+            event_json: None,
+        })
+        .collect();
+    let transactions = (0..tx_count)
+        .map(|index| TxMetadata {
+            origin: Address::ZERO,
+            hash: tx_hash(index),
+            tx_type: "eip1559".to_string(),
+        })
+        .collect();
+
+    (
+        BTreeMap::from([(1, trades)]),
+        BTreeMap::from([(1, BlockMetadata { timestamp: 0, transactions })]),
+    )
+}
+
+fn bench_enrich_and_merge_dense_block(c: &mut Criterion) {
+    let mut group = c.benchmark_group("enrich_and_merge_dense_block");
+
+    for trade_count in [1, 2, 4, 8, 16, 64, 256] {
+        let (trades, blocks) = dense_block(trade_count, 1_000);
+
+        group.throughput(Throughput::Elements(trade_count));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{trade_count}x1000")),
+            &(trades, blocks),
+            |b, (trades, blocks)| {
+                b.iter_batched(
+                    || (trades.clone(), blocks.clone()),
+                    |(trades, blocks)| {
+                        enrich_and_merge(BTreeMap::new(), trades, blocks)
+                    },
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_enrich_and_merge,
+    bench_enrich_and_merge_dense_block
+);
+criterion_main!(benches);
@@ -0,0 +1,108 @@
+//! The orderbook ABI the contract bindings are generated from, also parsed at
+//! runtime so that events can be selected by name with
+//! `--abi-event-allowlist`, including events the bindings aren't used for.
+
+use alloy::dyn_abi::{DecodedEvent, DynSolValue};
+use alloy::json_abi::{Event, EventParam, JsonAbi};
+use itertools::Itertools;
+use std::sync::OnceLock;
+
+use crate::logs::TradeEvent;
+
+/// The ABI file the bindings are generated from.
+const ORDERBOOK_ABI: &str = include_str!("../abi/orderbookv4.json");
+
+/// The parsed orderbook ABI.
+fn orderbook_abi() -> &'static JsonAbi {
+    static ABI: OnceLock<JsonAbi> = OnceLock::new();
+
+    // The bindings are generated from the same file at compile time, so it
+    // is known to parse.
+    ABI.get_or_init(|| {
+        serde_json::from_str(ORDERBOOK_ABI).expect("the orderbook ABI parses")
+    })
+}
+
+/// The event of the orderbook ABI with the given name, the first one if it
+/// is overloaded.
+pub(crate) fn event(name: &str) -> Option<&'static Event> {
+    orderbook_abi().events.get(name)?.first()
+}
+
+/// Normalize an event name for matching, ignoring case and underscores, so
+/// that e.g. `clear_v2` and `clearv2` both name ClearV2.
+pub(crate) fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(|&char| char != '_')
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// The trade event for the event of the orderbook ABI with the given name,
+/// matched ignoring case and underscores. ClearV2 and TakeOrderV2 are decoded
+/// through the bindings into fills, any other event is collected generically.
+pub(crate) fn trade_event(name: &str) -> Result<TradeEvent, String> {
+    let normalized = normalize(name);
+    let Some(name) = orderbook_abi()
+        .events
+        .keys()
+        .find(|event_name| normalize(event_name) == normalized)
+    else {
+        return Err(format!(
+            "{name} isn't an event of the orderbook ABI, which has {}",
+            orderbook_abi().events.keys().join(", ")
+        ));
+    };
+
+    match name.as_str() {
+        "ClearV2" => Ok(TradeEvent::ClearV2),
+        "TakeOrderV2" => Ok(TradeEvent::TakeOrderV2),
+        _ => Ok(TradeEvent::Abi(name.clone())),
+    }
+}
+
+/// Pair each parameter of the given event with its decoded value, in the
+/// order of the parameters.
+pub(crate) fn named_values<'a>(
+    event: &'a Event,
+    decoded: &'a DecodedEvent,
+) -> impl Iterator<Item = (&'a EventParam, &'a DynSolValue)> {
+    let mut indexed = decoded.indexed.iter();
+    let mut body = decoded.body.iter();
+
+    event.inputs.iter().filter_map(move |param| {
+        let value = match param.indexed {
+            true => indexed.next(),
+            false => body.next(),
+        }?;
+        Some((param, value))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::sol_types::SolEvent;
+
+    use super::*;
+    use crate::IOrderBookV4;
+
+    #[test]
+    fn test_abi_events_match_bindings() {
+        assert_eq!(
+            event("ClearV2").unwrap().selector(),
+            IOrderBookV4::ClearV2::SIGNATURE_HASH
+        );
+        assert_eq!(
+            event("AddOrderV2").unwrap().selector(),
+            IOrderBookV4::AddOrderV2::SIGNATURE_HASH
+        );
+
+        assert_eq!(trade_event("TakeOrderV2"), Ok(TradeEvent::TakeOrderV2));
+        assert_eq!(
+            trade_event("Deposit"),
+            Ok(TradeEvent::Abi("Deposit".to_string()))
+        );
+        assert!(trade_event("FailedTrade").is_err());
+        assert_eq!(trade_event("clear_v2"), Ok(TradeEvent::ClearV2));
+    }
+}
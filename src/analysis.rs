@@ -0,0 +1,353 @@
+//! Local analysis of saved trades that doesn't touch the blockchain.
+
+use alloy::primitives::{Address, BlockNumber, FixedBytes, U256};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use crate::logs::{ClearSide, TradeEvent};
+use crate::{
+    env, output, read_trades_csv, stream_trades_file, units, Error, Trade,
+};
+
+/// Count how many blocks contain each number of trades, e.g. `{1: 40, 2: 3}`
+/// means 40 blocks have a single trade and 3 blocks have two. Blocks without
+/// trades aren't counted.
+pub fn trades_per_block_histogram(trades: &[Trade]) -> BTreeMap<usize, usize> {
+    let mut trades_per_block = BTreeMap::<BlockNumber, usize>::new();
+    for trade in trades {
+        *trades_per_block.entry(trade.block_number).or_default() += 1;
+    }
+
+    let mut histogram = BTreeMap::new();
+    for trade_count in trades_per_block.into_values() {
+        *histogram.entry(trade_count).or_default() += 1;
+    }
+
+    histogram
+}
+
+/// Print the histogram of trades per block of the saved trades, either as a
+/// table or as a JSON object.
+pub async fn print_histogram(env: &env::Env, json: bool) -> Result<(), Error> {
+    let trades = read_trades_csv(env).await?;
+    let histogram = trades_per_block_histogram(&trades);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&histogram)?);
+    } else {
+        println!("trades_per_block,blocks");
+        for (trade_count, block_count) in histogram {
+            println!("{trade_count},{block_count}");
+        }
+    }
+
+    Ok(())
+}
+
+/// The summed raw amounts of trades per token, on the input and output side
+/// of the filled orders.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct TokenVolume {
+    #[serde(serialize_with = "units::decimal_per_token::serialize")]
+    pub input: BTreeMap<Address, U256>,
+    #[serde(serialize_with = "units::decimal_per_token::serialize")]
+    pub output: BTreeMap<Address, U256>,
+}
+
+impl TokenVolume {
+    fn add(&mut self, trade: &Trade) {
+        let sides = [
+            (&mut self.input, trade.input_token, trade.input_amount),
+            (&mut self.output, trade.output_token, trade.output_amount),
+        ];
+        for (volume, token, amount) in sides {
+            if let (Some(token), Some(amount)) = (token, amount) {
+                let total = volume.entry(token).or_default();
+                *total = total.saturating_add(amount);
+            }
+        }
+    }
+
+    /// All tokens traded on either side.
+    fn tokens(&self) -> BTreeSet<Address> {
+        self.input.keys().chain(self.output.keys()).copied().collect()
+    }
+}
+
+/// The trade volume per token, in total and per event type.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct VolumeReport {
+    pub total: TokenVolume,
+    pub by_event: BTreeMap<TradeEvent, TokenVolume>,
+}
+
+/// Sum the input and output amounts of the given trades per token, in total
+/// and per event type. Sides without a decoded token or amount aren't
+/// counted, and totals saturate rather than overflow.
+pub fn aggregate_volume(trades: &[Trade]) -> VolumeReport {
+    let mut report = VolumeReport::default();
+    for trade in trades {
+        report.total.add(trade);
+        report.by_event.entry(trade.event.clone()).or_default().add(trade);
+    }
+
+    report
+}
+
+/// Print the trade volume per token of the saved trades, in total and per
+/// event type, either as a table or as a JSON object.
+pub async fn print_volume(env: &env::Env, json: bool) -> Result<(), Error> {
+    let trades = read_trades_csv(env).await?;
+    let report = aggregate_volume(&trades);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    println!("event,token,input_volume,output_volume");
+    let volumes = std::iter::once(("total".to_string(), &report.total)).chain(
+        report
+            .by_event
+            .iter()
+            .map(|(event, volume)| (event.name().to_string(), volume)),
+    );
+    for (event, volume) in volumes {
+        for token in volume.tokens() {
+            let amount = |side: &BTreeMap<Address, U256>| {
+                side.get(&token).copied().unwrap_or_default()
+            };
+            println!(
+                "{event},{token},{},{}",
+                amount(&volume.input),
+                amount(&volume.output)
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// The key rows of two CSV files are matched by: the transaction hash, log
+/// index, event and side, which tell apart the rows of split ClearV2 events.
+type DiffKey = (FixedBytes<32>, u64, TradeEvent, Option<ClearSide>);
+
+fn diff_key(trade: &Trade) -> DiffKey {
+    (trade.tx_hash, trade.log_index, trade.event.clone(), trade.side)
+}
+
+/// Which of the two compared CSV files a row is only found in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffSide {
+    Left,
+    Right,
+}
+
+/// How many rows of two compared CSV files are only found in one of them.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct DiffSummary {
+    pub only_left: usize,
+    pub only_right: usize,
+    pub common: usize,
+}
+
+impl DiffSummary {
+    /// Whether both files have the same rows.
+    pub fn is_same(&self) -> bool {
+        self.only_left == 0 && self.only_right == 0
+    }
+}
+
+/// Compare the trades of the CSV files at the given paths, matching rows by
+/// transaction hash, log index, event and side, and hand each row only found
+/// in one of them to `differing`, left rows first, in file order. Only the
+/// keys of the rows are kept in memory, at the cost of reading the right
+/// file twice.
+pub fn diff_trades_files(
+    left_path: &str,
+    right_path: &str,
+    mut differing: impl FnMut(DiffSide, &Trade) -> Result<(), Error>,
+) -> Result<DiffSummary, Error> {
+    let mut right_keys = HashSet::new();
+    for trade in stream_trades_file(right_path)? {
+        right_keys.insert(diff_key(&trade?));
+    }
+
+    let mut summary = DiffSummary::default();
+    let mut left_keys = HashSet::new();
+    for trade in stream_trades_file(left_path)? {
+        let trade = trade?;
+        let key = diff_key(&trade);
+        if right_keys.contains(&key) {
+            summary.common += 1;
+        } else {
+            summary.only_left += 1;
+            differing(DiffSide::Left, &trade)?;
+        }
+        left_keys.insert(key);
+    }
+
+    for trade in stream_trades_file(right_path)? {
+        let trade = trade?;
+        if !left_keys.contains(&diff_key(&trade)) {
+            summary.only_right += 1;
+            differing(DiffSide::Right, &trade)?;
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Compare the saved trades with those of the CSV file at the given path and
+/// print how many rows only one of them has, optionally preceded by those
+/// rows as JSON, marked `<` if only saved and `>` if only in the other file.
+pub async fn print_diff(
+    env: &env::Env,
+    other_path: &str,
+    rows: bool,
+) -> Result<DiffSummary, Error> {
+    let csv_path = env.output_path();
+    if output::is_stdout(&csv_path) {
+        return Err(Error::Config(
+            "Can't diff trades written to stdout".to_string(),
+        ));
+    }
+
+    let summary = diff_trades_files(&csv_path, other_path, |side, trade| {
+        if rows {
+            let marker = match side {
+                DiffSide::Left => '<',
+                DiffSide::Right => '>',
+            };
+            println!("{marker} {}", serde_json::to_string(trade)?);
+        }
+        Ok(())
+    })?;
+
+    println!("Only in {csv_path}: {}", summary.only_left);
+    println!("Only in {other_path}: {}", summary.only_right);
+    println!("In both: {}", summary.common);
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::{Address, FixedBytes};
+
+    use super::*;
+
+    fn trade_in_block(block_number: BlockNumber) -> Trade {
+        Trade {
+            timestamp: 0,
+            tx_origin: Address::ZERO,
+            tx_hash: FixedBytes::ZERO,
+            event: TradeEvent::TakeOrderV2,
+            contract_address: Address::ZERO,
+            tx_type: "eip1559".to_string(),
+            input_token: None,
+            output_token: None,
+            input_amount: None,
+            output_amount: None,
+            input_symbol: None,
+            output_symbol: None,
+            input_amount_adjusted: None,
+            output_amount_adjusted: None,
+            block_number,
+            log_index: 0,
+            order_owner: None,
+            side: None,
+            event_json: None,
+            block_hash: None,
+            log_index_in_tx: None,
+            first_seen: None,
+            counterparty_owner: None,
+        }
+    }
+
+    #[test]
+    fn test_trades_per_block_histogram() {
+        let trades = [1, 1, 2, 3, 3, 3, 4, 5, 5]
+            .into_iter()
+            .map(trade_in_block)
+            .collect::<Vec<_>>();
+
+        let histogram = trades_per_block_histogram(&trades);
+
+        assert_eq!(histogram, BTreeMap::from([(1, 2), (2, 2), (3, 1)]));
+    }
+
+    #[test]
+    fn test_aggregate_volume_per_token_and_event() {
+        let [weth, usdc] = [1, 2].map(Address::repeat_byte);
+        let trade = |event, input_amount: u64, output_amount| Trade {
+            event,
+            input_token: Some(weth),
+            output_token: Some(usdc),
+            input_amount: Some(U256::from(input_amount)),
+            output_amount,
+            ..trade_in_block(1)
+        };
+        let trades = [
+            trade(TradeEvent::ClearV2, 1, Some(U256::from(2_000))),
+            trade(TradeEvent::TakeOrderV2, 2, Some(U256::from(4_000))),
+            trade(TradeEvent::TakeOrderV2, 3, None),
+        ];
+
+        let report = aggregate_volume(&trades);
+
+        assert_eq!(report.total.input, BTreeMap::from([(weth, U256::from(6))]));
+        assert_eq!(
+            report.total.output,
+            BTreeMap::from([(usdc, U256::from(6_000))])
+        );
+        assert_eq!(
+            report.by_event[&TradeEvent::TakeOrderV2].input,
+            BTreeMap::from([(weth, U256::from(5))])
+        );
+        assert_eq!(
+            report.by_event[&TradeEvent::ClearV2].output,
+            BTreeMap::from([(usdc, U256::from(2_000))])
+        );
+
+        let json = serde_json::to_value(&report).unwrap();
+        let usdc = serde_json::to_value(usdc).unwrap();
+        assert_eq!(json["total"]["output"][usdc.as_str().unwrap()], "6000");
+    }
+
+    #[test]
+    fn test_diff_trades_files_matches_rows_by_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name, blocks: &[BlockNumber]| {
+            let path = dir.path().join(name);
+            let mut csv_writer = csv::Writer::from_path(&path).unwrap();
+            for &block_number in blocks {
+                let mut trade = trade_in_block(block_number);
+                trade.log_index = block_number;
+                csv_writer.serialize(trade).unwrap();
+            }
+            path.to_str().unwrap().to_string()
+        };
+        let left_path = write("left.csv", &[1, 2, 3, 4]);
+        let right_path = write("right.csv", &[2, 3, 5]);
+
+        let mut differing = vec![];
+        let summary =
+            diff_trades_files(&left_path, &right_path, |side, trade| {
+                differing.push((side, trade.block_number));
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(
+            summary,
+            DiffSummary { only_left: 2, only_right: 1, common: 2 }
+        );
+        assert_eq!(
+            differing,
+            [(DiffSide::Left, 1), (DiffSide::Left, 4), (DiffSide::Right, 5)]
+        );
+        assert!(diff_trades_files(&left_path, &left_path, |_, _| Ok(()))
+            .unwrap()
+            .is_same());
+    }
+}
@@ -0,0 +1,121 @@
+//! A module for packaging the CSV file and its metadata into a single
+//! `.tar.zst` archive for distribution, with a manifest describing the
+//! dataset so that recipients can verify it.
+
+use alloy::primitives::BlockNumber;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::path::Path;
+
+use crate::Error;
+
+/// The name of the manifest inside the archive.
+pub(crate) const MANIFEST_NAME: &str = "manifest.json";
+
+/// A description of the archived dataset.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Manifest {
+    pub(crate) chain_id: u64,
+    pub(crate) contract_addresses: Vec<String>,
+    /// The name of the CSV file inside the archive.
+    pub(crate) csv_file: String,
+    /// The hex-encoded SHA-256 checksum of the CSV file as archived, i.e.
+    /// compressed if the CSV file is.
+    pub(crate) sha256: String,
+    pub(crate) row_count: usize,
+    /// The block of the first saved trade.
+    pub(crate) first_block: Option<BlockNumber>,
+    /// The last block whose trades are all saved, or the block of the last
+    /// saved trade if that isn't recorded.
+    pub(crate) last_block: Option<BlockNumber>,
+}
+
+/// Compute the hex-encoded SHA-256 checksum of the file at the given path.
+pub(crate) fn sha256(path: &str) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+
+    Ok(alloy::hex::encode(hasher.finalize()))
+}
+
+/// Write a zstd-compressed tarball at `archive_path` with the given files,
+/// stored under their file names, followed by the manifest. The archive is
+/// written to a temporary file first so that an existing archive is only
+/// replaced by a complete one.
+pub(crate) fn write(
+    archive_path: &str,
+    files: &[&str],
+    manifest: &Manifest,
+) -> Result<(), Error> {
+    let tmp_path = format!("{archive_path}.tmp");
+    let encoder = zstd::Encoder::new(File::create(&tmp_path)?, 0)?;
+    let mut builder = tar::Builder::new(encoder);
+
+    for path in files {
+        let name = Path::new(path).file_name().ok_or_else(|| {
+            Error::Config(format!("Can't archive {path} without a file name"))
+        })?;
+        builder.append_path_with_name(path, name)?;
+    }
+
+    let manifest = serde_json::to_vec_pretty(manifest)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, MANIFEST_NAME, manifest.as_slice())?;
+
+    builder.into_inner()?.finish()?;
+    std::fs::rename(&tmp_path, archive_path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    #[test]
+    fn test_archive_contains_files_and_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv_path = dir.path().join("trades.csv");
+        let csv_path = csv_path.to_str().unwrap();
+        std::fs::write(csv_path, "timestamp\n").unwrap();
+        let archive_path = dir.path().join("trades.tar.zst");
+        let archive_path = archive_path.to_str().unwrap();
+
+        let manifest = Manifest {
+            chain_id: 42161,
+            contract_addresses: vec![],
+            csv_file: "trades.csv".to_string(),
+            sha256: sha256(csv_path).unwrap(),
+            row_count: 0,
+            first_block: None,
+            last_block: None,
+        };
+        write(archive_path, &[csv_path], &manifest).unwrap();
+
+        let decoder =
+            zstd::Decoder::new(File::open(archive_path).unwrap()).unwrap();
+        let mut archive = tar::Archive::new(decoder);
+        let mut contents = std::collections::BTreeMap::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().to_str().unwrap().to_string();
+            let mut content = String::new();
+            entry.read_to_string(&mut content).unwrap();
+            contents.insert(name, content);
+        }
+
+        assert_eq!(contents["trades.csv"], "timestamp\n");
+        let archived: Manifest =
+            serde_json::from_str(&contents[MANIFEST_NAME]).unwrap();
+        assert_eq!(archived, manifest);
+        assert_eq!(
+            archived.sha256,
+            "2cd8ec3de6a07e1fd39676100db57ba62372e820c19812fee55899f65746e192"
+        );
+    }
+}
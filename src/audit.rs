@@ -0,0 +1,221 @@
+//! Bookkeeping for the `--audit` mode, which verifies that the block ranges
+//! queried during a run exactly cover the requested range, and the
+//! `--verify-sorted` check of the CSV file's ordering.
+
+use alloy::primitives::BlockNumber;
+use itertools::Itertools;
+
+use crate::{Error, Trade};
+
+/// The set of inclusive block ranges queried during a run, mirroring the
+/// inclusive `fromBlock`/`toBlock` semantics of `eth_getLogs`.
+#[derive(Debug, Default)]
+pub(crate) struct BlockCoverage {
+    ranges: Vec<(BlockNumber, BlockNumber)>,
+}
+
+impl BlockCoverage {
+    /// Record that the blocks from `start_block` to `end_block` (inclusive)
+    /// were queried.
+    pub(crate) fn record(
+        &mut self,
+        start_block: BlockNumber,
+        end_block: BlockNumber,
+    ) {
+        self.ranges.push((start_block, end_block));
+    }
+
+    /// Verify that the recorded ranges cover every block from `start_block`
+    /// to `latest_block` (inclusive) exactly once, without gaps, overlaps or
+    /// blocks past the head.
+    pub(crate) fn verify(
+        &self,
+        start_block: BlockNumber,
+        latest_block: BlockNumber,
+    ) -> Result<(), Error> {
+        if start_block > latest_block {
+            return ensure(self.ranges.is_empty(), || {
+                format!(
+                    "Queried {:?} although there were no blocks to scan",
+                    self.ranges
+                )
+            });
+        }
+
+        let mut next_block = start_block;
+
+        for &(range_start, range_end) in
+            self.ranges.iter().sorted_by_key(|(range_start, _)| *range_start)
+        {
+            ensure(range_start <= range_end, || {
+                format!("Queried an empty range [{range_start}, {range_end}]")
+            })?;
+            ensure(range_start >= next_block, || {
+                format!(
+                    "Range [{range_start}, {range_end}] overlaps with blocks \
+                    before {next_block}"
+                )
+            })?;
+            ensure(range_start == next_block, || {
+                format!(
+                    "Blocks [{next_block}, {}] were never queried",
+                    range_start - 1
+                )
+            })?;
+            next_block = range_end + 1;
+        }
+
+        ensure(next_block > latest_block, || {
+            format!(
+                "Blocks [{next_block}, {latest_block}] at the tail were never \
+                queried"
+            )
+        })?;
+        ensure(next_block == latest_block + 1, || {
+            format!(
+                "Queried blocks [{}, {}] past the latest block",
+                latest_block + 1,
+                next_block - 1
+            )
+        })
+    }
+}
+
+/// Verify that the given stream of saved trades is strictly ordered by
+/// `(block_number, log_index, event, side)`, failing at the first row that is
+/// out of order or duplicated. The event only differs between rows with the
+/// same log index when failed trades, whose log index is their transaction's
+/// index in the block, are mixed in, and the side only between the rows of a
+/// split ClearV2 event. Returns the number of trades checked.
+pub(crate) fn verify_sorted(
+    trades: impl IntoIterator<Item = Result<Trade, Error>>,
+) -> Result<usize, Error> {
+    let mut previous_key = None;
+    let mut count = 0;
+
+    for trade in trades {
+        let trade = trade?;
+        let key =
+            (trade.block_number, trade.log_index, trade.event, trade.side);
+
+        if let Some(previous_key) = previous_key {
+            ensure(key > previous_key, || {
+                format!(
+                    "Trade #{count} at block {} with log index {} doesn't \
+                    come after the previous trade at block {} with log index \
+                    {}",
+                    key.0, key.1, previous_key.0, previous_key.1
+                )
+            })?;
+        }
+
+        previous_key = Some(key);
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Fail the audit with the given message unless the condition holds.
+fn ensure(
+    condition: bool,
+    message: impl FnOnce() -> String,
+) -> Result<(), Error> {
+    if condition {
+        Ok(())
+    } else {
+        Err(Error::Integrity(message()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logs::ClearSide;
+
+    fn coverage(ranges: &[(BlockNumber, BlockNumber)]) -> BlockCoverage {
+        BlockCoverage { ranges: ranges.to_vec() }
+    }
+
+    #[test]
+    fn test_verify_exact_cover() {
+        let coverage = coverage(&[(20, 29), (10, 19), (30, 35)]);
+        assert!(coverage.verify(10, 35).is_ok());
+    }
+
+    #[test]
+    fn test_verify_gap() {
+        let coverage = coverage(&[(10, 19), (25, 35)]);
+        assert!(coverage.verify(10, 35).is_err());
+    }
+
+    #[test]
+    fn test_verify_overlap() {
+        let coverage = coverage(&[(10, 20), (20, 35)]);
+        assert!(coverage.verify(10, 35).is_err());
+    }
+
+    #[test]
+    fn test_verify_uncovered_tail() {
+        let coverage = coverage(&[(10, 19), (20, 29)]);
+        assert!(coverage.verify(10, 35).is_err());
+    }
+
+    #[test]
+    fn test_verify_overshoot() {
+        let coverage = coverage(&[(10, 19), (20, 40)]);
+        assert!(coverage.verify(10, 35).is_err());
+    }
+
+    #[test]
+    fn test_verify_nothing_to_scan() {
+        assert!(coverage(&[]).verify(36, 35).is_ok());
+        assert!(coverage(&[(36, 40)]).verify(36, 35).is_err());
+    }
+
+    fn trade(
+        block_number: BlockNumber,
+        log_index: u64,
+    ) -> Result<Trade, Error> {
+        let mut trade = crate::testing::trade();
+        trade.block_number = block_number;
+        trade.log_index = log_index;
+        Ok(trade)
+    }
+
+    #[test]
+    fn test_verify_sorted() {
+        let trades = [trade(1, 0), trade(1, 3), trade(2, 1), trade(5, 0)];
+        assert_eq!(verify_sorted(trades).unwrap(), 4);
+        assert_eq!(verify_sorted([]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_verify_sorted_out_of_order() {
+        let trades = [trade(1, 0), trade(2, 1), trade(2, 0)];
+        assert!(verify_sorted(trades).is_err());
+
+        let trades = [trade(2, 0), trade(1, 5)];
+        assert!(verify_sorted(trades).is_err());
+    }
+
+    #[test]
+    fn test_verify_sorted_duplicate() {
+        let trades = [trade(1, 0), trade(1, 0)];
+        assert!(verify_sorted(trades).is_err());
+    }
+
+    #[test]
+    fn test_verify_sorted_split_clear() {
+        let side = |side| -> Result<Trade, Error> {
+            let mut trade = trade(1, 0)?;
+            trade.side = Some(side);
+            Ok(trade)
+        };
+
+        assert!(verify_sorted([side(ClearSide::Alice), side(ClearSide::Bob)])
+            .is_ok());
+        assert!(verify_sorted([side(ClearSide::Bob), side(ClearSide::Alice)])
+            .is_err());
+    }
+}
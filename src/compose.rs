@@ -1,17 +1,93 @@
 //! Purely-functional composition of trade logs into a single vector of trades.
 //! Isolated into a single module for easier testing.
+//!
+//! [`enrich_and_merge`] and its input types are public so that downstream
+//! crates can test their own logic against the same merge semantics.
 
+use alloy::primitives::map::B256Map;
 use alloy::primitives::BlockNumber;
+use alloy::primitives::{Address, FixedBytes};
 use itertools::Itertools;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use tracing::*;
 
-use crate::logs::TradeLog;
-use crate::onchain::BlockMetadata;
+use crate::env::TimestampUnit;
 use crate::Trade;
 
+pub use crate::logs::{ClearSide, TradeEvent, TradeFill, TradeLog};
+pub use crate::onchain::{BlockMetadata, FailedTx, TxMetadata};
+
+/// The number of trades from which a block's transactions are indexed by hash
+/// to look up those of its trades. Below it, scanning the block for each
+/// trade's transaction is faster, as `cargo bench` shows.
+const INDEXED_BLOCK_TRADES: usize = 16;
+
+/// Merge trade logs collected from another contract into the given map,
+/// keeping them grouped by block. Ordering within each block is restored by
+/// [`enrich_and_merge`], which sorts by the block-wide log index.
+pub(crate) fn merge_by_block(
+    trades: &mut BTreeMap<BlockNumber, Vec<TradeLog>>,
+    other_trades: BTreeMap<BlockNumber, Vec<TradeLog>>,
+) {
+    for (block_number, block_trades) in other_trades {
+        trades.entry(block_number).or_default().extend(block_trades);
+    }
+}
+
+/// The `(block_number, log_index)` pairs shared by more than one of the given
+/// trade logs, which a real chain never emits but a buggy provider may, e.g.
+/// when merging the logs of a proxy with those of its implementation. The
+/// fills of a split ClearV2 event share its log, so they count as one as
+/// long as there's one per side.
+pub(crate) fn duplicate_log_indices(
+    these_trades: &BTreeMap<BlockNumber, Vec<TradeLog>>,
+    other_trades: &BTreeMap<BlockNumber, Vec<TradeLog>>,
+) -> BTreeSet<(BlockNumber, u64)> {
+    let mut sides: HashMap<(BlockNumber, u64), Vec<Option<ClearSide>>> =
+        HashMap::new();
+    for trade in these_trades.values().chain(other_trades.values()).flatten() {
+        sides
+            .entry((trade.block_number, trade.log_index))
+            .or_default()
+            .push(trade.fill.side);
+    }
+
+    sides
+        .into_iter()
+        .filter(|(_, sides)| {
+            sides.len() > 1
+                && (sides.contains(&None) || !sides.iter().all_unique())
+        })
+        .map(|(key, _)| key)
+        .collect()
+}
+
+/// Remove the trade logs at the given `(block_number, log_index)` pairs,
+/// dropping blocks left without any.
+pub(crate) fn reject_log_indices(
+    trades: &mut BTreeMap<BlockNumber, Vec<TradeLog>>,
+    rejected: &BTreeSet<(BlockNumber, u64)>,
+) {
+    trades.retain(|_, block_trades| {
+        block_trades.retain(|trade| {
+            !rejected.contains(&(trade.block_number, trade.log_index))
+        });
+        !block_trades.is_empty()
+    });
+}
+
 /// Enrich trade logs with block metadata and merge them into a single vector of trades.
-pub(crate) fn enrich_and_merge(
+///
+/// Each trade also gets the position of its log among the trade logs of its
+/// transaction, counting both events of all contracts.
+///
+/// Trades are ordered by `(block_number, log_index, event, side)`, with
+/// ClearV2 before TakeOrderV2 for equal log indices and Alice before Bob for
+/// split ClearV2 events. Log indices are unique within a
+/// block on a real chain, so the event only matters for synthetic data, but
+/// it makes the order a deterministic function of the input regardless of
+/// the order trades were inserted in.
+pub fn enrich_and_merge(
     mut these_trades: BTreeMap<BlockNumber, Vec<TradeLog>>,
     mut other_trades: BTreeMap<BlockNumber, Vec<TradeLog>>,
     block_bodies: BTreeMap<BlockNumber, BlockMetadata>,
@@ -32,48 +108,79 @@ pub(crate) fn enrich_and_merge(
 
     let clearv2_trades_count: usize =
         these_trades.values().map(|trades| trades.len()).sum();
-    debug!("Blocks [{start_block}, {end_block}] emitted {clearv2_trades_count} ClearV2 events");
+    debug!(
+        "Blocks [{start_block}, {end_block}] emitted {clearv2_trades_count} \
+        ClearV2 events"
+    );
 
     let takeorderv2_trades_count: usize =
         other_trades.values().map(|trades| trades.len()).sum();
     debug!(
-        "Blocks [{start_block}, {end_block}] emitted {takeorderv2_trades_count} TakeOrderV2 events"
+        "Blocks [{start_block}, {end_block}] emitted \
+        {takeorderv2_trades_count} TakeOrderV2 and other events"
     );
 
     let trades = blocks_with_trades
         .into_iter()
+        .dedup()
         .flat_map(|block_number| {
             let clearv2_trade =
                 these_trades.remove(&block_number).unwrap_or_default();
             let takeorderv2_trade =
                 other_trades.remove(&block_number).unwrap_or_default();
 
+            let block = block_bodies.get(&block_number).unwrap();
+
+            // Index the transactions of a block with many trades by hash once
+            // so that each trade's origin is a constant-time lookup rather
+            // than a scan of the block.
+            let trade_count = clearv2_trade.len() + takeorderv2_trade.len();
+            let transactions_by_hash: Option<B256Map<&TxMetadata>> =
+                (trade_count >= INDEXED_BLOCK_TRADES).then(|| {
+                    block.transactions.iter().map(|tx| (tx.hash, tx)).collect()
+                });
+            // The last log index seen in each transaction and its position.
+            let mut tx_positions: B256Map<(u64, u64)> = B256Map::default();
+
             clearv2_trade
                 .into_iter()
                 .chain(takeorderv2_trade)
-                .sorted_by_key(|trade| trade.log_index)
-        })
-        .map(|trade| {
-            let BlockMetadata { timestamp, transactions } =
-                block_bodies.get(&trade.block_number).unwrap().to_owned();
-
-            let tx_origin = transactions
-                .into_iter()
-                .find_map(|tx| {
-                    if tx.hash == trade.tx_hash {
-                        Some(tx.origin)
-                    } else {
-                        None
+                .sorted_by(|a, b| {
+                    (a.log_index, &a.event, a.fill.side).cmp(&(
+                        b.log_index,
+                        &b.event,
+                        b.fill.side,
+                    ))
+                })
+                .map(move |trade| {
+                    let tx = match &transactions_by_hash {
+                        Some(transactions_by_hash) => {
+                            transactions_by_hash.get(&trade.tx_hash).copied()
+                        }
+                        None => block
+                            .transactions
+                            .iter()
+                            .find(|tx| tx.hash == trade.tx_hash),
                     }
+                    .unwrap();
+                    // Trades are in log order, and the trades of a split
+                    // ClearV2 event share its log.
+                    let log_index_in_tx = match tx_positions.get(&trade.tx_hash)
+                    {
+                        Some(&(log_index, position))
+                            if log_index == trade.log_index =>
+                        {
+                            position
+                        }
+                        Some(&(_, position)) => position + 1,
+                        None => 0,
+                    };
+                    tx_positions.insert(
+                        trade.tx_hash,
+                        (trade.log_index, log_index_in_tx),
+                    );
+                    enrich(trade, block.timestamp, tx, log_index_in_tx)
                 })
-                .unwrap();
-
-            Trade {
-                timestamp,
-                tx_origin,
-                event: trade.event,
-                tx_hash: trade.tx_hash,
-            }
         })
         .collect_vec();
 
@@ -86,25 +193,179 @@ pub(crate) fn enrich_and_merge(
     trades
 }
 
+/// Turn reverted transactions into rows tagged [`TradeEvent::FailedTrade`],
+/// enriched with the metadata of their blocks and transactions like trades.
+/// Reverted transactions emit no logs, so their rows hold the transaction's
+/// index in its block as the log index, and have no tokens or amounts.
+/// Transactions missing from the given block bodies are skipped.
+pub(crate) fn enrich_failed_txs(
+    failed_txs: Vec<FailedTx>,
+    block_bodies: &BTreeMap<BlockNumber, BlockMetadata>,
+) -> Vec<Trade> {
+    failed_txs
+        .into_iter()
+        .filter_map(|failed_tx| {
+            let block = block_bodies.get(&failed_tx.block_number);
+            let Some((block, tx)) = block.and_then(|block| {
+                let tx = block
+                    .transactions
+                    .iter()
+                    .find(|tx| tx.hash == failed_tx.tx_hash)?;
+                Some((block, tx))
+            }) else {
+                error!(
+                    "Failed transaction {} is missing from block {}",
+                    failed_tx.tx_hash, failed_tx.block_number
+                );
+                return None;
+            };
+
+            Some(Trade {
+                timestamp: block.timestamp,
+                tx_origin: tx.origin,
+                tx_hash: failed_tx.tx_hash,
+                event: TradeEvent::FailedTrade,
+                contract_address: failed_tx.contract_address,
+                tx_type: tx.tx_type.clone(),
+                input_token: None,
+                output_token: None,
+                input_amount: None,
+                output_amount: None,
+                input_symbol: None,
+                output_symbol: None,
+                input_amount_adjusted: None,
+                output_amount_adjusted: None,
+                block_number: failed_tx.block_number,
+                log_index: failed_tx.tx_index,
+                order_owner: None,
+                side: None,
+                event_json: None,
+                block_hash: Some(failed_tx.block_hash),
+                log_index_in_tx: None,
+                first_seen: None,
+                counterparty_owner: None,
+            })
+        })
+        .collect()
+}
+
+/// Stand-in block bodies for blocks whose headers were fetched without their
+/// transactions: each block has the given timestamp and lists the given
+/// transactions with the zero address as origin and an empty type, so that
+/// trades can be enriched as usual and re-enriched later.
+pub(crate) fn header_only_blocks(
+    timestamps: BTreeMap<BlockNumber, u64>,
+    tx_hashes: impl IntoIterator<Item = (BlockNumber, FixedBytes<32>)>,
+) -> BTreeMap<BlockNumber, BlockMetadata> {
+    let mut blocks: BTreeMap<BlockNumber, BlockMetadata> = timestamps
+        .into_iter()
+        .map(|(block_number, timestamp)| {
+            (block_number, BlockMetadata { timestamp, transactions: vec![] })
+        })
+        .collect();
+
+    for (block_number, hash) in tx_hashes.into_iter().unique() {
+        if let Some(block) = blocks.get_mut(&block_number) {
+            block.transactions.push(TxMetadata {
+                origin: Address::ZERO,
+                hash,
+                tx_type: String::new(),
+            });
+        }
+    }
+
+    blocks
+}
+
+/// Combine a trade log with the metadata of its block and transaction.
+fn enrich(
+    trade: TradeLog,
+    timestamp: u64,
+    tx: &TxMetadata,
+    log_index_in_tx: u64,
+) -> Trade {
+    Trade {
+        timestamp,
+        tx_origin: tx.origin,
+        event: trade.event,
+        tx_hash: trade.tx_hash,
+        contract_address: trade.contract_address,
+        tx_type: tx.tx_type.clone(),
+        input_token: trade.fill.input_token,
+        output_token: trade.fill.output_token,
+        input_amount: trade.fill.input_amount,
+        output_amount: trade.fill.output_amount,
+        input_symbol: None,
+        output_symbol: None,
+        input_amount_adjusted: None,
+        output_amount_adjusted: None,
+        block_number: trade.block_number,
+        log_index: trade.log_index,
+        order_owner: trade.fill.owner,
+        side: trade.fill.side,
+        event_json: trade.event_json,
+        block_hash: Some(trade.block_hash),
+        log_index_in_tx: Some(log_index_in_tx),
+        first_seen: None,
+        counterparty_owner: trade.fill.counterparty_owner,
+    }
+}
+
+/// Refresh the block and transaction metadata of saved trades from freshly
+/// fetched block bodies, converting timestamps to the given unit. Trades
+/// whose block or transaction is missing, e.g. after a reorg, are kept as
+/// they are.
+pub(crate) fn reenrich(
+    trades: Vec<Trade>,
+    block_bodies: &BTreeMap<BlockNumber, BlockMetadata>,
+    timestamp_unit: TimestampUnit,
+) -> Vec<Trade> {
+    trades
+        .into_iter()
+        .map(|mut trade| {
+            let tx = block_bodies.get(&trade.block_number).and_then(|block| {
+                block
+                    .transactions
+                    .iter()
+                    .find(|tx| tx.hash == trade.tx_hash)
+                    .map(|tx| (block.timestamp, tx))
+            });
+
+            match tx {
+                Some((timestamp, tx)) => {
+                    trade.timestamp = timestamp_unit.convert(timestamp);
+                    trade.tx_origin = tx.origin;
+                    trade.tx_type = tx.tx_type.clone();
+                }
+                None => warn!(
+                    "Transaction {} of a trade wasn't found in block {}, \
+                    keeping its saved metadata",
+                    trade.tx_hash, trade.block_number
+                ),
+            }
+
+            trade
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
+    use alloy::primitives::Address;
     use std::collections::BTreeSet;
 
-    use alloy::{
-        hex::FromHex,
-        primitives::{Address, FixedBytes, TxHash},
-    };
     use proptest::prelude::*;
 
     use super::*;
-    use crate::{onchain::TxMetadata, TradeEvent};
+    use crate::testing::{arb_enrich_and_merge_args, ArbSizes};
 
     const DEBUG_TEST: bool = false;
 
     proptest! {
         #[test]
         fn test_enrich_and_merge(
-            (clearv2_trades, takeorderv2_trades, block_bodies) in arb_enrich_and_merge_args()
+            (clearv2_trades, takeorderv2_trades, block_bodies) in
+                arb_enrich_and_merge_args(ArbSizes::default())
         ) {
             let clearv2_count =
                 clearv2_trades.values().map(|trades| trades.len()).sum::<usize>();
@@ -177,180 +438,258 @@ mod tests {
         }
     }
 
-    fn arb_enrich_and_merge_args() -> impl Strategy<
-        Value = (
-            BTreeMap<BlockNumber, Vec<TradeLog>>,
-            BTreeMap<BlockNumber, Vec<TradeLog>>,
-            BTreeMap<BlockNumber, BlockMetadata>,
-        ),
-    > {
-        arb_trade_logs_and_hashes().prop_flat_map(
-            |(clearv2_trades, takeorderv2_trades, block_num_to_tx_hashes)| {
-                let block_metadata_strategy =
-                    arb_blocks(block_num_to_tx_hashes);
-
-                (
-                    Just(clearv2_trades),
-                    Just(takeorderv2_trades),
-                    block_metadata_strategy,
-                )
-                    .prop_map(
-                        |(clearv2, takeorderv2, blocks)| {
-                            (clearv2, takeorderv2, blocks)
-                        },
-                    )
-            },
-        )
-    }
-
-    fn arb_blocks(
-        block_num_to_tx_hashes: BTreeMap<BlockNumber, Vec<TxHash>>,
-    ) -> impl Strategy<Value = BTreeMap<BlockNumber, BlockMetadata>> {
-        let block_strategies: Vec<_> = block_num_to_tx_hashes
-            .into_iter()
-            .map(|(block_number, tx_hashes)| {
-                (Just(block_number), arb_block_metadata(tx_hashes))
-            })
-            .collect();
-
-        prop_oneof![block_strategies]
-            .prop_map(|blocks| blocks.into_iter().collect())
-    }
-
-    prop_compose! {
-        fn arb_trade_logs_and_hashes()(
-            clearv2_logs in arb_trade_logs(TradeEvent::ClearV2),
-            takeorderv2_logs in arb_trade_logs(TradeEvent::TakeOrderV2)
-        ) -> (
-            BTreeMap<BlockNumber, Vec<TradeLog>>,
-            BTreeMap<BlockNumber, Vec<TradeLog>>,
-            BTreeMap<BlockNumber, Vec<TxHash>>,
+    proptest! {
+        #[test]
+        fn test_enrich_and_merge_matches_linear_lookup(
+            (clearv2_trades, takeorderv2_trades, block_bodies) in
+                arb_enrich_and_merge_args(ArbSizes::default())
         ) {
-            let mut block_num_to_tx_hashes =
-                BTreeMap::<BlockNumber, Vec<(u64, TxHash)>>::new();
-
-            // First collect all takeorderv2 trades
-            for log in takeorderv2_logs.clone() {
-                block_num_to_tx_hashes
-                    .entry(log.block_number)
-                    .and_modify(|hashes| hashes.push((log.log_index, log.tx_hash)))
-                    .or_insert(vec![(log.log_index, log.tx_hash)]);
-            }
+            let trades = enrich_and_merge(
+                clearv2_trades,
+                takeorderv2_trades,
+                block_bodies.clone(),
+            );
 
-            // Then add clearv2 trades, skipping any that have same block and log index as takeorderv2
-            for log in clearv2_logs.clone() {
-                let is_duplicate = block_num_to_tx_hashes
-                    .get(&log.block_number)
-                    .map(|hashes| hashes.iter().any(|(idx, _)| *idx == log.log_index))
-                    .unwrap_or(false);
-
-                if !is_duplicate {
-                    block_num_to_tx_hashes
-                        .entry(log.block_number)
-                        .and_modify(|hashes| hashes.push((log.log_index, log.tx_hash)))
-                        .or_insert(vec![(log.log_index, log.tx_hash)]);
-                }
+            for trade in trades {
+                let (timestamp, tx) = block_bodies
+                    .values()
+                    .find_map(|block| {
+                        block
+                            .transactions
+                            .iter()
+                            .find(|tx| tx.hash == trade.tx_hash)
+                            .map(|tx| (block.timestamp, tx))
+                    })
+                    .unwrap();
+
+                prop_assert_eq!(trade.timestamp, timestamp);
+                prop_assert_eq!(trade.tx_origin, tx.origin);
+                prop_assert_eq!(&trade.tx_type, &tx.tx_type);
             }
+        }
+    }
 
-            let clearv2_trades: BTreeMap<BlockNumber, Vec<TradeLog>> = clearv2_logs
-                .into_iter()
-                .map(|log| (log.block_number, vec![log]))
-                .collect();
-
-            let takeorderv2_trades: BTreeMap<BlockNumber, Vec<TradeLog>> =
-                takeorderv2_logs
-                    .into_iter()
-                    .map(|log| (log.block_number, vec![log]))
-                    .collect();
+    proptest! {
+        #[test]
+        fn test_reenrich_restores_block_metadata(
+            (clearv2_trades, takeorderv2_trades, block_bodies) in
+                arb_enrich_and_merge_args(ArbSizes::default())
+        ) {
+            let trades = enrich_and_merge(
+                clearv2_trades,
+                takeorderv2_trades,
+                block_bodies.clone(),
+            );
 
-            let block_num_to_tx_hashes = block_num_to_tx_hashes
-                .into_iter()
-                .map(|(block_number, hashes_with_indices)| {
-                    let sorted_hashes = hashes_with_indices
-                        .into_iter()
-                        .sorted_by_key(|(index, _)| *index)
-                        .map(|(_, hash)| hash)
-                        .collect_vec();
-                    (block_number, sorted_hashes)
+            let stale_trades = trades
+                .iter()
+                .cloned()
+                .map(|mut trade| {
+                    trade.timestamp = 0;
+                    trade.tx_origin = Address::ZERO;
+                    trade.tx_type = String::new();
+                    trade
                 })
-                .collect::<BTreeMap<_, _>>();
-
-            (clearv2_trades, takeorderv2_trades, block_num_to_tx_hashes)
-        }
-    }
+                .collect();
 
-    prop_compose! {
-        fn arb_block_metadata(required_tx_hashes: Vec<TxHash>)(
-            timestamp in 0u64..10000,
-            transactions in arb_transactions(required_tx_hashes)
-        ) -> BlockMetadata {
-            BlockMetadata { timestamp, transactions }
+            let reenriched =
+                reenrich(stale_trades, &block_bodies, TimestampUnit::Seconds);
+            prop_assert_eq!(reenriched, trades);
         }
     }
 
-    fn arb_transactions(
-        required_tx_hashes: Vec<TxHash>,
-    ) -> impl Strategy<Value = Vec<TxMetadata>> {
-        // First generate the required transactions using arb_tx_metadata_from_hash
-        let required_txs = required_tx_hashes
-            .into_iter()
-            .map(arb_tx_metadata_from_hash)
-            .collect::<Vec<_>>();
-
-        // Then generate some arbitrary transactions
-        let arbitrary_txs = prop::collection::vec(arb_tx_metadata(), 0..10);
-
-        // Combine them using prop_flat_map
-        (required_txs, arbitrary_txs).prop_flat_map(|(required, arbitrary)| {
-            // Combine the required and arbitrary transactions
-            let mut all_txs = required;
-            all_txs.extend(arbitrary);
-            Just(all_txs)
-        })
-    }
+    proptest! {
+        #[test]
+        fn test_enrich_and_merge_order_is_independent_of_insertion_order(
+            (clearv2_trades, takeorderv2_trades, block_bodies) in
+                arb_enrich_and_merge_args(ArbSizes::default())
+        ) {
+            let trades = enrich_and_merge(
+                clearv2_trades.clone(),
+                takeorderv2_trades.clone(),
+                block_bodies.clone(),
+            );
 
-    fn arb_trade_logs(
-        event: TradeEvent,
-    ) -> impl Strategy<Value = Vec<TradeLog>> {
-        prop::collection::vec(arb_trade_log(event), 1..11)
-    }
+            let reversed = |trades: BTreeMap<BlockNumber, Vec<TradeLog>>| {
+                trades
+                    .into_iter()
+                    .map(|(block_number, mut block_trades)| {
+                        block_trades.reverse();
+                        (block_number, block_trades)
+                    })
+                    .collect()
+            };
+            let reinserted_trades = enrich_and_merge(
+                reversed(takeorderv2_trades),
+                reversed(clearv2_trades),
+                block_bodies,
+            );
 
-    prop_compose! {
-        fn arb_trade_log(event: TradeEvent)(
-            log_index in 0u64..1000,
-            block_number in 0u64..1000,
-            tx_hash in arb_tx_hash(),
-        ) -> TradeLog {
-            TradeLog { log_index, block_number, tx_hash, event: event.clone() }
+            prop_assert_eq!(&trades, &reinserted_trades);
+            let sorted = trades.windows(2).all(|pair| {
+                (pair[0].block_number, pair[0].log_index, &pair[0].event)
+                    <= (pair[1].block_number, pair[1].log_index, &pair[1].event)
+            });
+            prop_assert!(sorted, "trades out of order");
         }
     }
 
-    prop_compose! {
-        fn arb_tx_metadata_from_hash(hash: TxHash)(
-            origin in arb_address()
-        ) -> TxMetadata {
-            TxMetadata { hash, origin }
+    proptest! {
+        #[test]
+        fn test_injected_duplicate_log_index_is_detected(
+            (clearv2_trades, mut takeorderv2_trades, _) in
+                arb_enrich_and_merge_args(ArbSizes::default()),
+            pick in any::<prop::sample::Index>(),
+        ) {
+            let logs = clearv2_trades.values().flatten().collect_vec();
+            prop_assume!(!logs.is_empty());
+            let duplicate = logs[pick.index(logs.len())].clone();
+            let key = (duplicate.block_number, duplicate.log_index);
+            takeorderv2_trades
+                .entry(duplicate.block_number)
+                .or_default()
+                .push(TradeLog { event: TradeEvent::TakeOrderV2, ..duplicate });
+
+            let duplicates =
+                duplicate_log_indices(&clearv2_trades, &takeorderv2_trades);
+            prop_assert!(duplicates.contains(&key));
+
+            let mut clearv2_trades = clearv2_trades;
+            reject_log_indices(&mut clearv2_trades, &duplicates);
+            reject_log_indices(&mut takeorderv2_trades, &duplicates);
+            prop_assert!(
+                duplicate_log_indices(&clearv2_trades, &takeorderv2_trades)
+                    .is_empty()
+            );
         }
     }
 
-    prop_compose! {
-        fn arb_tx_metadata()(
-            hash in arb_tx_hash(),
-            origin in arb_address()
-        ) -> TxMetadata {
-            TxMetadata { hash, origin }
-        }
+    #[test]
+    fn test_split_clear_sides_share_log_index() {
+        let trade_log = |log_index, side| TradeLog {
+            log_index,
+            block_number: 1,
+            block_hash: FixedBytes::ZERO,
+            tx_hash: FixedBytes::ZERO,
+            event: TradeEvent::ClearV2,
+            contract_address: Address::ZERO,
+            fill: TradeFill { side, ..TradeFill::default() },
+            event_json: None,
+        };
+        let clearv2_trades = BTreeMap::from([(
+            1,
+            vec![
+                trade_log(2, Some(ClearSide::Alice)),
+                trade_log(2, Some(ClearSide::Bob)),
+                trade_log(4, Some(ClearSide::Alice)),
+                trade_log(4, Some(ClearSide::Alice)),
+            ],
+        )]);
+
+        assert_eq!(
+            duplicate_log_indices(&clearv2_trades, &BTreeMap::new()),
+            BTreeSet::from([(1, 4)])
+        );
     }
 
-    prop_compose! {
-        fn arb_tx_hash()(hash in "0x[a-f0-9]{64}") -> FixedBytes<32> {
-            FixedBytes::from_hex(hash).unwrap()
-        }
+    #[test]
+    fn test_log_index_in_tx_counts_logs_per_transaction() {
+        let [tx_a, tx_b] = [1, 2].map(FixedBytes::<32>::repeat_byte);
+        let trade_log = |log_index, tx_hash, event, side| TradeLog {
+            log_index,
+            block_number: 1,
+            block_hash: FixedBytes::ZERO,
+            tx_hash,
+            event,
+            contract_address: Address::ZERO,
+            fill: TradeFill { side, ..TradeFill::default() },
+            event_json: None,
+        };
+        let clearv2_trades = BTreeMap::from([(
+            1,
+            vec![
+                trade_log(2, tx_a, TradeEvent::ClearV2, Some(ClearSide::Alice)),
+                trade_log(2, tx_a, TradeEvent::ClearV2, Some(ClearSide::Bob)),
+            ],
+        )]);
+        let takeorderv2_trades = BTreeMap::from([(
+            1,
+            vec![
+                trade_log(5, tx_a, TradeEvent::TakeOrderV2, None),
+                trade_log(3, tx_b, TradeEvent::TakeOrderV2, None),
+                trade_log(7, tx_b, TradeEvent::TakeOrderV2, None),
+            ],
+        )]);
+        let tx = |hash| TxMetadata {
+            origin: Address::ZERO,
+            hash,
+            tx_type: "eip1559".to_string(),
+        };
+        let block_bodies = BTreeMap::from([(
+            1,
+            BlockMetadata {
+                timestamp: 0,
+                transactions: vec![tx(tx_a), tx(tx_b)],
+            },
+        )]);
+
+        let trades =
+            enrich_and_merge(clearv2_trades, takeorderv2_trades, block_bodies);
+
+        assert_eq!(
+            trades
+                .iter()
+                .map(|trade| (trade.log_index, trade.log_index_in_tx))
+                .collect_vec(),
+            vec![
+                (2, Some(0)),
+                (2, Some(0)),
+                (3, Some(0)),
+                (5, Some(1)),
+                (7, Some(1))
+            ]
+        );
     }
+    #[test]
+    fn test_origins_of_trades_in_indexed_block() {
+        let trade_count = INDEXED_BLOCK_TRADES as u8 * 2;
+        let takeorderv2_trades = BTreeMap::from([(
+            1,
+            (0..trade_count)
+                .map(|index| TradeLog {
+                    log_index: index.into(),
+                    block_number: 1,
+                    block_hash: FixedBytes::ZERO,
+                    tx_hash: FixedBytes::repeat_byte(index),
+                    event: TradeEvent::TakeOrderV2,
+                    contract_address: Address::ZERO,
+                    fill: TradeFill::default(),
+                    event_json: None,
+                })
+                .collect(),
+        )]);
+        // The block's transactions are in reverse order of its trades.
+        let block_bodies = BTreeMap::from([(
+            1,
+            BlockMetadata {
+                timestamp: 0,
+                transactions: (0..trade_count)
+                    .rev()
+                    .map(|index| TxMetadata {
+                        origin: Address::repeat_byte(index),
+                        hash: FixedBytes::repeat_byte(index),
+                        tx_type: "eip1559".to_string(),
+                    })
+                    .collect(),
+            },
+        )]);
 
-    prop_compose! {
-        fn arb_address()(address in "0x[a-f0-9]{40}") -> Address {
-            Address::from_hex(address).unwrap()
-        }
+        let trades =
+            enrich_and_merge(BTreeMap::new(), takeorderv2_trades, block_bodies);
+
+        assert_eq!(
+            trades.iter().map(|trade| trade.tx_origin).collect_vec(),
+            (0..trade_count).map(Address::repeat_byte).collect_vec()
+        );
     }
 }
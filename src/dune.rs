@@ -0,0 +1,152 @@
+//! The Dune dialect of the CSV file, laid out for uploading to Dune: ISO-8601
+//! UTC timestamps, lowercase hex addresses and hashes, and the block and
+//! transaction columns first. The column names are those of the raw dialect,
+//! so that the rows read back into [`Trade`] by name.
+
+use alloy::primitives::{Address, BlockNumber, B256, U256};
+use chrono::{DateTime, SecondsFormat};
+
+use crate::logs::{ClearSide, TradeEvent};
+use crate::{units, Trade};
+
+/// The header row of the Dune dialect, matching the field order of
+/// [`DuneTrade`].
+pub(crate) const DUNE_HEADERS: [&str; 23] = [
+    "timestamp",
+    "block_number",
+    "block_hash",
+    "tx_hash",
+    "log_index",
+    "log_index_in_tx",
+    "tx_origin",
+    "tx_type",
+    "contract_address",
+    "event",
+    "order_owner",
+    "counterparty_owner",
+    "side",
+    "input_token",
+    "input_symbol",
+    "input_amount",
+    "input_amount_adjusted",
+    "output_token",
+    "output_symbol",
+    "output_amount",
+    "output_amount_adjusted",
+    "event_json",
+    "first_seen",
+];
+
+/// A trade as written in the Dune dialect.
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct DuneTrade<'a> {
+    timestamp: String,
+    block_number: BlockNumber,
+    block_hash: Option<String>,
+    tx_hash: String,
+    log_index: u64,
+    log_index_in_tx: Option<u64>,
+    tx_origin: String,
+    tx_type: &'a str,
+    contract_address: String,
+    event: &'a TradeEvent,
+    order_owner: Option<String>,
+    counterparty_owner: Option<String>,
+    side: Option<ClearSide>,
+    input_token: Option<String>,
+    input_symbol: Option<&'a str>,
+    #[serde(with = "units::decimal")]
+    input_amount: Option<U256>,
+    input_amount_adjusted: Option<&'a str>,
+    output_token: Option<String>,
+    output_symbol: Option<&'a str>,
+    #[serde(with = "units::decimal")]
+    output_amount: Option<U256>,
+    output_amount_adjusted: Option<&'a str>,
+    event_json: Option<&'a str>,
+    first_seen: Option<String>,
+}
+
+impl<'a> From<&'a Trade> for DuneTrade<'a> {
+    fn from(trade: &'a Trade) -> Self {
+        Self {
+            timestamp: iso_8601(trade.timestamp),
+            block_number: trade.block_number,
+            block_hash: trade.block_hash.as_ref().map(hash),
+            tx_hash: hash(&trade.tx_hash),
+            log_index: trade.log_index,
+            log_index_in_tx: trade.log_index_in_tx,
+            tx_origin: address(&trade.tx_origin),
+            tx_type: &trade.tx_type,
+            contract_address: address(&trade.contract_address),
+            event: &trade.event,
+            order_owner: trade.order_owner.as_ref().map(address),
+            counterparty_owner: trade.counterparty_owner.as_ref().map(address),
+            side: trade.side,
+            input_token: trade.input_token.as_ref().map(address),
+            input_symbol: trade.input_symbol.as_deref(),
+            input_amount: trade.input_amount,
+            input_amount_adjusted: trade.input_amount_adjusted.as_deref(),
+            output_token: trade.output_token.as_ref().map(address),
+            output_symbol: trade.output_symbol.as_deref(),
+            output_amount: trade.output_amount,
+            output_amount_adjusted: trade.output_amount_adjusted.as_deref(),
+            event_json: trade.event_json.as_deref(),
+            first_seen: trade.first_seen.map(iso_8601),
+        }
+    }
+}
+
+/// Format a timestamp in seconds as an ISO-8601 UTC date and time, e.g.
+/// `2023-11-14T22:13:20Z`. Timestamps beyond chrono's range, which no block
+/// has, are written as the integer.
+fn iso_8601(seconds: u64) -> String {
+    i64::try_from(seconds)
+        .ok()
+        .and_then(|seconds| DateTime::from_timestamp(seconds, 0))
+        .map_or_else(
+            || seconds.to_string(),
+            |datetime| datetime.to_rfc3339_opts(SecondsFormat::Secs, true),
+        )
+}
+
+fn address(address: &Address) -> String {
+    format!("{address:#x}")
+}
+
+fn hash(hash: &B256) -> String {
+    format!("{hash:#x}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::trade;
+
+    #[test]
+    fn test_dune_trade_formats_timestamp_and_hex() {
+        let mut trade = trade();
+        trade.timestamp = 1_700_000_000;
+        trade.tx_origin = Address::repeat_byte(0xab);
+        trade.block_hash = Some(B256::repeat_byte(0xcd));
+
+        let dune_trade = DuneTrade::from(&trade);
+
+        assert_eq!(dune_trade.timestamp, "2023-11-14T22:13:20Z");
+        assert_eq!(dune_trade.tx_origin, format!("0x{}", "ab".repeat(20)));
+        assert_eq!(
+            dune_trade.block_hash,
+            Some(format!("0x{}", "cd".repeat(32)))
+        );
+    }
+
+    #[test]
+    fn test_dune_headers_are_raw_columns() {
+        let mut headers = DUNE_HEADERS.to_vec();
+        let mut raw_headers = crate::CSV_HEADERS.to_vec();
+        headers.sort_unstable();
+        raw_headers.sort_unstable();
+
+        assert_eq!(headers, raw_headers);
+    }
+}
@@ -0,0 +1,140 @@
+//! The enrichment files stored next to the CSV file, so that the RPC calls
+//! of enriching trades aren't repeated after a crash: the metadata of every
+//! token resolved with `--resolve-tokens`, reused by all later runs, and the
+//! blocks fetched so far by `reenrich`, which an interrupted run resumes
+//! after.
+
+use alloy::primitives::{Address, BlockNumber};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+
+use crate::onchain::BlockMetadata;
+use crate::tokens::TokenMetadata;
+use crate::{output, Error};
+
+/// The path of the token file of the CSV file at the given path.
+pub(crate) fn tokens_path(csv_path: &str) -> String {
+    output::sidecar_path(csv_path, ".tokens")
+}
+
+/// The path of the `reenrich` progress file of the CSV file at the given
+/// path.
+pub(crate) fn blocks_path(csv_path: &str) -> String {
+    output::sidecar_path(csv_path, ".reenrich")
+}
+
+/// Append the metadata of a newly resolved token, as returned by the chain.
+pub(crate) fn record_token(
+    csv_path: &str,
+    token: Address,
+    metadata: &TokenMetadata,
+) -> Result<(), Error> {
+    append_line(&tokens_path(csv_path), &(token, metadata))
+}
+
+/// Read the metadata of the tokens resolved by earlier runs.
+pub(crate) fn read_tokens(
+    csv_path: &str,
+) -> Result<HashMap<Address, TokenMetadata>, Error> {
+    read_lines(&tokens_path(csv_path))
+}
+
+/// Append the blocks fetched by a `reenrich` run.
+pub(crate) fn record_blocks(
+    csv_path: &str,
+    blocks: &BTreeMap<BlockNumber, BlockMetadata>,
+) -> Result<(), Error> {
+    let mut lines = String::new();
+    for block in blocks {
+        lines.push_str(&serde_json::to_string(&block)?);
+        lines.push('\n');
+    }
+
+    let mut blocks_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(blocks_path(csv_path))?;
+    blocks_file.write_all(lines.as_bytes())?;
+
+    Ok(())
+}
+
+/// Read the blocks fetched by an interrupted `reenrich` run.
+pub(crate) fn read_blocks(
+    csv_path: &str,
+) -> Result<BTreeMap<BlockNumber, BlockMetadata>, Error> {
+    read_lines(&blocks_path(csv_path))
+}
+
+/// Discard the blocks fetched by `reenrich` once it's done.
+pub(crate) fn reset_blocks(csv_path: &str) -> Result<(), Error> {
+    match std::fs::remove_file(blocks_path(csv_path)) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Append the given entry to the file at the given path as a line of JSON.
+fn append_line(path: &str, entry: &impl serde::Serialize) -> Result<(), Error> {
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(line.as_bytes())?;
+
+    Ok(())
+}
+
+/// Read the entries of the file at the given path, one line of JSON each,
+/// or none if it doesn't exist. A trailing line cut short by an interrupted
+/// write doesn't parse and is skipped, so at worst its entry is fetched
+/// again.
+fn read_lines<K, V, C>(path: &str) -> Result<C, Error>
+where
+    (K, V): serde::de::DeserializeOwned,
+    C: FromIterator<(K, V)>,
+{
+    let lines = match std::fs::read_to_string(path) {
+        Ok(lines) => lines,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return Ok(std::iter::empty().collect())
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    Ok(lines
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_blocks_skips_truncated_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv_path = dir.path().join("trades.csv");
+        let csv_path = csv_path.to_str().unwrap();
+        let block =
+            BlockMetadata { timestamp: 1_700_000_000, transactions: vec![] };
+        let blocks = BTreeMap::from([(100, block.clone()), (101, block)]);
+
+        assert!(read_blocks(csv_path).unwrap().is_empty());
+
+        record_blocks(csv_path, &blocks).unwrap();
+        let mut blocks_file = OpenOptions::new()
+            .append(true)
+            .open(blocks_path(csv_path))
+            .unwrap();
+        write!(blocks_file, "[102,{{\"timestamp\":").unwrap();
+
+        let read = read_blocks(csv_path).unwrap();
+        assert_eq!(read.keys().copied().collect::<Vec<_>>(), [100, 101]);
+
+        reset_blocks(csv_path).unwrap();
+        assert!(read_blocks(csv_path).unwrap().is_empty());
+    }
+}
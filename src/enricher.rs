@@ -0,0 +1,84 @@
+//! An extension point for library consumers to derive their own columns from
+//! each trade, e.g. a price from an oracle, without forking the crate.
+
+use alloy::primitives::BlockNumber;
+use std::collections::BTreeMap;
+
+use crate::compose::{BlockMetadata, ClearSide, TradeEvent, TradeLog};
+use crate::Trade;
+
+/// Derives extra columns of a trade from its log and the block it was
+/// emitted in, as `(column, value)` pairs. Should return the same columns,
+/// in the same order, for every trade, so that they line up as a table.
+pub trait Enricher {
+    fn enrich(
+        &self,
+        trade: &TradeLog,
+        block: &BlockMetadata,
+    ) -> Vec<(String, String)>;
+}
+
+/// A trade with the extra columns of the enrichers it was collected with,
+/// the columns of each enricher in the order the enrichers were given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnrichedTrade {
+    pub trade: Trade,
+    pub columns: Vec<(String, String)>,
+}
+
+/// The block, log index, event and side identifying a trade within a batch
+/// once logs sharing a log index are rejected.
+type ColumnKey = (BlockNumber, u64, TradeEvent, Option<ClearSide>);
+
+/// Run the given enrichers on each trade log, keeping their columns until
+/// the logs are turned into trades by [`attach_columns`].
+pub(crate) fn enrich_columns<'a>(
+    enrichers: &[&dyn Enricher],
+    trade_logs: impl IntoIterator<Item = &'a TradeLog>,
+    block_bodies: &BTreeMap<BlockNumber, BlockMetadata>,
+) -> BTreeMap<ColumnKey, Vec<(String, String)>> {
+    if enrichers.is_empty() {
+        return BTreeMap::new();
+    }
+
+    trade_logs
+        .into_iter()
+        .filter_map(|trade| {
+            let block = block_bodies.get(&trade.block_number)?;
+            let key = (
+                trade.block_number,
+                trade.log_index,
+                trade.event.clone(),
+                trade.fill.side,
+            );
+            let columns = enrichers
+                .iter()
+                .flat_map(|enricher| enricher.enrich(trade, block))
+                .collect();
+            Some((key, columns))
+        })
+        .collect()
+}
+
+/// Pair each trade with the columns computed for its log. Trades without a
+/// log, i.e. failed trades, get no columns.
+pub(crate) fn attach_columns(
+    trades: Vec<Trade>,
+    mut columns: BTreeMap<ColumnKey, Vec<(String, String)>>,
+) -> Vec<EnrichedTrade> {
+    trades
+        .into_iter()
+        .map(|trade| {
+            let key = (
+                trade.block_number,
+                trade.log_index,
+                trade.event.clone(),
+                trade.side,
+            );
+            EnrichedTrade {
+                columns: columns.remove(&key).unwrap_or_default(),
+                trade,
+            }
+        })
+        .collect()
+}
@@ -2,69 +2,2404 @@
 //! [`Env`] struct.
 
 use alloy::network::AnyNetwork;
-use alloy::primitives::Address;
+use alloy::primitives::{Address, BlockNumber, B256};
 use alloy::providers::ProviderBuilder;
-use clap::Parser;
+use alloy::rpc::client::RpcClient;
+use alloy::transports::http::reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue,
+};
+use alloy::transports::http::reqwest::{self, Url};
+use alloy::transports::http::Http;
+use clap::{Parser, Subcommand};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::WithExportConfig;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
 
-use crate::{IOrderBookV4, OrderbookContract};
+use crate::logs::{TradeEvent, DEFAULT_EVENTS};
+use crate::{
+    abi, output, Error, IOrderBookV4, OrderbookContract, OrderbookProvider,
+};
 
 /// Configuration options for the CLI tool.
 ///
 /// The options can be set by environment variables or command line arguments.
 #[derive(Debug, Parser)]
+#[clap(group(
+    clap::ArgGroup::new("log_destination").args(["log_file", "output_dir"])
+))]
 pub struct Env {
+    /// The command to run. Collects trades if omitted.
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
     /// The log level to use.
     #[clap(long, env, default_value = "DEBUG")]
     pub log_level: tracing::Level,
 
-    /// The path to the CSV file to read/write trades to/from.
+    /// A full tracing filter directive, e.g.
+    /// `rain_drops=debug,alloy=debug`, that overrides the filter derived
+    /// from --log-level, to see the logs of dependencies.
+    #[clap(long, env)]
+    pub trace_filter: Option<String>,
+
+    /// The OTLP gRPC endpoint to export traces to, e.g.
+    /// `http://localhost:4317`. Traces aren't exported if omitted.
+    #[clap(long, env)]
+    pub otlp_endpoint: Option<String>,
+
+    /// Also write the logs to this file, without colors, filtered like those
+    /// written to stderr. Appended to if it exists. Logs are only written to
+    /// stderr if omitted.
+    #[clap(long, env)]
+    pub log_file: Option<String>,
+
+    /// How often to start a new log file: `never`, or `daily`, which
+    /// suffixes the --log-file path with the date of each day's file, e.g.
+    /// `run.log.2024-01-31`.
+    #[clap(
+        long,
+        env,
+        value_enum,
+        default_value = "never",
+        requires = "log_destination"
+    )]
+    pub log_rotation: LogRotation,
+
+    /// The path to the CSV file to read/write trades to/from. Use `-` to
+    /// write to stdout, which always starts a fresh scan. `${VAR}` is
+    /// replaced with the environment variable `VAR`, and `${CHAIN_ID}` with
+    /// the ID of the connected chain, e.g. `trades-${CHAIN_ID}.csv`.
     #[clap(long, env, default_value = "trades.csv")]
     pub csv_path: String,
 
-    /// The URL of the JSON-RPC HTTP endpoint to use.
+    /// Lay the outputs out in this directory instead: the CSV file in
+    /// `data/`, the files kept alongside it, e.g. its metadata and progress,
+    /// in `meta/`, and the logs in `logs/`, all named after the connected
+    /// chain and the orderbook contracts, e.g. `data/42161-0x2f20….csv` and
+    /// `logs/42161-0x2f20….log`. The directories are created if missing.
+    #[clap(long, env, conflicts_with_all = ["csv_path", "log_file"])]
+    pub output_dir: Option<String>,
+
+    /// The compression to apply to the CSV file. Compressed output is written
+    /// to the CSV path with the matching extension appended.
+    #[clap(long, env, value_enum)]
+    pub compress: Option<Compression>,
+
+    /// Write CSV files without a header row, for pipelines that expect
+    /// headerless files. Saved files are read either way, detecting whether
+    /// they start with a header row.
+    #[clap(long, env)]
+    pub csv_no_headers: bool,
+
+    /// The unit of the timestamp column. Recorded in a metadata file next to
+    /// the CSV file, which later runs must match.
+    #[clap(long, env, value_enum, default_value = "seconds")]
+    pub timestamp_unit: TimestampUnit,
+
+    /// The layout of the CSV file: `raw` writes the columns in schema order
+    /// with integer timestamps and checksummed addresses, and `dune` writes
+    /// the layout expected by Dune uploads, with ISO-8601 UTC timestamps,
+    /// lowercase addresses and hashes, and the block and transaction columns
+    /// first. The `dune` dialect needs a header row and timestamps in
+    /// seconds. Recorded in the metadata file like the timestamp unit.
+    #[clap(long, env, value_enum, default_value = "raw")]
+    pub dialect: Dialect,
+
+    /// Only write these columns of the CSV file, in this order, separated by
+    /// commas, e.g. `timestamp,tx_hash,event`. Formatted as in the dialect.
+    /// Commands that read the trades back need the columns they can't do
+    /// without, and --reorg-safety-margin, --seen-set, --max-reorg-depth and
+    /// `reenrich` need `tx_hash,event,contract_address,block_number,log_index`
+    /// to tell saved trades apart. Recorded in the metadata file like the
+    /// dialect. All columns if omitted.
+    #[clap(long, env, value_delimiter = ',', conflicts_with = "postgres_url")]
+    pub columns: Vec<String>,
+
+    /// Split the CSV file into numbered parts of at most this many bytes,
+    /// e.g. `trades.001.csv` after `trades.csv`, each with its own header
+    /// row. A part only exceeds it if a single block batch does, and the
+    /// bytes are counted before compression. Later runs append to the last
+    /// part, and commands that read the trades back read all parts.
+    #[clap(
+        long,
+        env,
+        conflicts_with_all = ["overwrite", "postgres_url"],
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub max_file_size: Option<u64>,
+
+    /// Write the trades of each collected event to a CSV file of its own
+    /// instead of one with an `event` column, the event's name in lowercase
+    /// being inserted before the extension, e.g. `trades.clearv2.csv` and
+    /// `trades.takeorderv2.csv`. Each file has its own header row and is
+    /// resumed on its own, the run scanning from the earliest block any of
+    /// them resumes from.
+    #[clap(
+        long,
+        env,
+        conflicts_with_all = [
+            "overwrite",
+            "max_file_size",
+            "reverse",
+            "seen_set",
+            "max_reorg_depth",
+            "poll_interval",
+            "postgres_url"
+        ]
+    )]
+    pub split_by_event: bool,
+
+    /// The URL of the JSON-RPC HTTP endpoint to use. Only required by the
+    /// commands that fetch from the chain, so that saved trades can be
+    /// analysed offline.
+    #[clap(long, env)]
+    pub json_rpc_http_url: Option<String>,
+
+    /// The timeout of each JSON-RPC request in milliseconds. Requests that
+    /// time out are retried like connection errors. No timeout if omitted.
+    #[clap(long, env)]
+    pub rpc_timeout_ms: Option<u64>,
+
+    /// How many times a log query failing on a connection error, timeout,
+    /// rate limit or gateway error is retried. Errors returned by the node
+    /// are retried 3 times regardless, except for rejected requests, e.g.
+    /// invalid params or an unknown method, which fail right away.
+    #[clap(long, env, default_value = "3")]
+    pub network_timeout_retries: u64,
+
+    /// An HTTP header given as `<NAME>: <VALUE>` to send with every JSON-RPC
+    /// request, e.g. an API key that the provider takes in a header, which
+    /// unlike one in the URL can't end up in logs. Can be repeated. The
+    /// values are redacted whenever the configuration is printed.
+    #[clap(long, env, value_parser = parse_rpc_header)]
+    pub rpc_header: Vec<(HeaderName, HeaderValue)>,
+
+    /// Reuse the chain head fetched from the same JSON-RPC URL by a run at
+    /// most this many seconds ago instead of requesting it again, for
+    /// frequent runs in quick succession. The head is cached in the temporary
+    /// directory. Always requested if omitted.
+    #[clap(long, env)]
+    pub head_cache_ttl: Option<u64>,
+
+    /// Other JSON-RPC HTTP URLs of the same chain, separated by commas, whose
+    /// chain heads are compared with that of --json-rpc-http-url before
+    /// collecting. Endpoints lagging behind the most advanced one by more than
+    /// --max-head-divergence blocks are warned about, and trades are fetched
+    /// from the most advanced one. Not compared if omitted.
+    #[clap(long, env, value_delimiter = ',')]
+    pub compare_head_from_providers: Vec<String>,
+
+    /// The number of blocks an endpoint compared with
+    /// --compare-head-from-providers may lag behind the most advanced one
+    /// without a warning, since endpoints see new blocks at slightly
+    /// different times.
+    #[clap(long, env, default_value = "2")]
+    pub max_head_divergence: u64,
+
+    /// The addresses of the deployed OrderbookV4 contracts, separated by
+    /// commas. Required to collect trades.
+    #[clap(long, env, value_delimiter = ',')]
+    pub orderbookv4_deployment_address: Vec<String>,
+
+    /// Only collect trades sent by one of these addresses, separated by
+    /// commas. Filtered locally as the sender isn't an indexed event
+    /// parameter, so --verify-raw counts include other senders' trades.
+    #[clap(long, env, value_delimiter = ',')]
+    pub filter_sender: Vec<Address>,
+
+    /// Only collect trades of a token pair given as `<TOKEN_A>:<TOKEN_B>`, in
+    /// either direction. Can be repeated, or separated by commas, to collect
+    /// the trades of any of the pairs.
+    #[clap(
+        long,
+        env,
+        value_delimiter = ',',
+        value_parser = parse_token_pair
+    )]
+    pub pair: Vec<(Address, Address)>,
+
+    /// Only keep this fraction of the trades, between 0 and 1, chosen by
+    /// hashing their transaction hashes so that every run keeps the same
+    /// ones. For building a representative sample of a long history
+    /// cheaply, not for exact accounting.
+    #[clap(long, env, value_parser = parse_sample_rate)]
+    pub sample_rate: Option<f64>,
+
+    /// The events of the orderbook ABI to collect, by name, separated by
+    /// commas and matched ignoring case and underscores. ClearV2 and
+    /// TakeOrderV2 are decoded into tokens and amounts, any other event, e.g.
+    /// AddOrderV2 or Deposit, is collected as rows with the event name and
+    /// without tokens or amounts, so that events added to the contract can be
+    /// collected without code changes.
+    #[clap(
+        long,
+        env,
+        value_delimiter = ',',
+        default_value = "ClearV2,TakeOrderV2"
+    )]
+    pub abi_event_allowlist: Vec<TradeEvent>,
+
+    /// Write two rows per ClearV2 event, one per matched order, tagged with
+    /// the `side` column, instead of a single row for Alice's order.
+    #[clap(long, env)]
+    pub split_clear: bool,
+
+    /// Query the logs of all collected events of each contract concurrently
+    /// instead of one after the other. Providers that limit concurrent
+    /// requests may reject the extra requests, so it's off by default.
+    #[clap(long, env)]
+    pub parallel_event_types: bool,
+
+    /// Write the full decoded event of each trade as JSON to the
+    /// `event_json` column, including the order owners, nonces and IO
+    /// configs, at the cost of much larger files.
+    #[clap(long, env)]
+    pub emit_raw_event_json: bool,
+
+    /// Write the wall-clock time each trade was first seen by the tool to the
+    /// `first_seen` column, in the unit of `--timestamp-unit`, for measuring
+    /// how far collection lags behind the chain. Trades collected from
+    /// historical blocks are first seen at the time of the run.
+    #[clap(long, env)]
+    pub record_first_seen: bool,
+
+    /// Also collect transactions to the orderbook contracts that reverted, as
+    /// `FailedTrade` rows without tokens or amounts, since reverted
+    /// transactions emit no logs. Their `log_index` is the transaction's
+    /// index in its block. Needs the node to support `eth_getBlockReceipts`
+    /// and costs one request per scanned block.
+    #[clap(long, env)]
+    pub include_failed_txs: bool,
+
+    /// Fetch only the headers of blocks with trades instead of their full
+    /// bodies, which is much cheaper on busy chains but leaves out the
+    /// transaction metadata: trades are written with the zero address as
+    /// `tx_origin` and an empty `tx_type`, which re-enriching fills in later.
+    #[clap(long, env)]
+    pub timestamps_only: bool,
+
+    /// Skip fetching the blocks of trades altogether and write only the
+    /// columns taken from their logs, leaving out `timestamp`, `tx_origin`
+    /// and `tx_type`, which makes backfills much faster. The `reenrich`
+    /// command fills them in later. Recorded in the metadata file, so later
+    /// runs must use it too until the file is re-enriched.
+    #[clap(
+        long,
+        env,
+        conflicts_with_all = [
+            "timestamps_only",
+            "include_failed_txs",
+            "columns",
+            "csv_no_headers",
+            "postgres_url"
+        ]
+    )]
+    pub no_enrich: bool,
+
+    /// The block number when the OrderbookV4 contract was deployed. Unless
+    /// the deployment transaction is set or the deployment block is
+    /// auto-detected, required to collect trades from the deployment block.
+    #[clap(long, env)]
+    pub orderbookv4_deployment_block: Option<u64>,
+
+    /// The hash of the transaction that deployed the OrderbookV4 contract,
+    /// whose block is used as the deployment block unless
+    /// --orderbookv4-deployment-block is set. Resolved once and cached in the
+    /// metadata file.
+    #[clap(long, env)]
+    pub orderbookv4_deployment_tx: Option<B256>,
+
+    /// Without a deployment block or transaction, use the earliest block with
+    /// ClearV2 or TakeOrderV2 logs of the contracts as the deployment block,
+    /// found by binary search over progressively narrower block ranges. Takes
+    /// many log queries, so the result is cached in the metadata file.
+    #[clap(long, env)]
+    pub auto_detect_deployment: bool,
+
+    /// The block to start collecting trades from, overriding the resume
+    /// point inferred from the CSV file.
     #[clap(long, env)]
-    pub json_rpc_http_url: String,
+    pub from_block: Option<u64>,
 
-    /// The address of the deployed OrderbookV4 contract.
+    /// Start collecting trades from the block after the one that includes
+    /// this transaction, overriding the resume point inferred from the CSV
+    /// file. Fails if the transaction isn't found.
+    #[clap(long, env, conflicts_with = "from_block")]
+    pub since_tx: Option<B256>,
+
+    /// The last block to collect trades from, instead of the latest block
+    /// below --reorg-safety-margin unless that one is lower. Runs stop there
+    /// rather than polling for new blocks.
+    #[clap(long, env, conflicts_with = "poll_interval")]
+    pub to_block: Option<u64>,
+
+    /// Only collect one shard of the blocks from the deployment block to
+    /// --to-block, given as `<INDEX>/<COUNT>` numbered from 0, e.g. to spread
+    /// a backfill across machines. The blocks are split
+    /// into COUNT contiguous ranges of about the same size, and the shard's
+    /// trades are written to a CSV file of its own, e.g.
+    /// `trades.shard-0-of-4.csv`, which the `merge` command combines with the
+    /// others.
+    #[clap(
+        long,
+        env,
+        value_parser = Shard::parse,
+        requires = "to_block",
+        conflicts_with_all = [
+            "poll_interval",
+            "postgres_url",
+            "from_block",
+            "since_tx"
+        ]
+    )]
+    pub shard: Option<Shard>,
+
+    /// Replace the CSV file with all trades from the deployment block, or
+    /// --from-block if set, instead of appending to the saved trades. The new
+    /// file only replaces the old one once collection stops.
     #[clap(long, env)]
-    pub orderbookv4_deployment_address: String,
+    pub overwrite: bool,
+
+    /// Scan the blocks from the latest one down to the start block and write
+    /// the newest trades first, e.g. to quickly sample recent activity. The
+    /// CSV file is written from scratch and can't be appended to later, so
+    /// this needs --overwrite unless writing to stdout.
+    #[clap(
+        long,
+        env,
+        conflicts_with_all = [
+            "poll_interval",
+            "seen_set",
+            "postgres_url",
+            "verify_sorted",
+            "min_confirmations"
+        ]
+    )]
+    pub reverse: bool,
+
+    /// Require an explicit start source, either `--from-block` or a CSV file
+    /// with saved trades, and fail instead of rescanning from the deployment
+    /// block.
+    #[clap(long, env)]
+    pub resume_from_checkpoint_only: bool,
+
+    /// Ask for confirmation on the terminal before scanning more than this
+    /// many blocks, 1000000 if no number is given, e.g. when a run would
+    /// rescan from the deployment block. Without a terminal, such runs fail
+    /// unless --yes is set.
+    #[clap(long, env, num_args = 0..=1, default_missing_value = "1000000")]
+    pub confirm_before_rescan: Option<u64>,
 
-    /// The block number when the OrderbookV4 contract was deployed.
+    /// Scan any number of blocks without asking for confirmation.
     #[clap(long, env)]
-    pub orderbookv4_deployment_block: u64,
+    pub yes: bool,
+
+    /// The number of most recent blocks to leave unscanned until they are
+    /// deep enough to be safe from reorgs. Incremental runs also rescan this
+    /// many blocks before the resume point, skipping trades that were already
+    /// saved. Larger margins trade freshness for safety.
+    #[clap(long, env, default_value = "0")]
+    pub reorg_safety_margin: u64,
+
+    /// The block tag whose block is taken as the chain head. `finalized`
+    /// only scans blocks that can no longer be reorged, and `safe` those
+    /// unlikely to be, on chains whose nodes support the tags.
+    #[clap(long, env, value_enum, default_value = "latest")]
+    pub head_tag: HeadTag,
+
+    /// Only write trades at least this many blocks below the chain head at
+    /// the time their batch is fetched. Unlike --reorg-safety-margin, the
+    /// blocks are still scanned, and the first trade that's too recent and
+    /// all later ones are left for a later run, which resumes from it.
+    #[clap(long, env, default_value = "0")]
+    pub min_confirmations: u64,
+
+    /// Before resuming, compare the block hashes saved with the trades of the
+    /// last this many blocks of the CSV file to the chain, and drop the saved
+    /// trades from the first block that no longer matches on, so that it's
+    /// collected again. Trades saved without a block hash aren't checked.
+    #[clap(long, env)]
+    pub max_reorg_depth: Option<u64>,
+
+    /// Keep a seen-set file next to the CSV file with the key of every trade
+    /// written to it, and skip trades already in it, so that overlapping
+    /// rescans, e.g. with --from-block, never write a trade twice. Seeded
+    /// from the saved trades the first time it's used with an existing CSV
+    /// file.
+    #[clap(long, env, conflicts_with = "overwrite")]
+    pub seen_set: bool,
 
     /// The number of blocks to fetch event logs from at a time.
-    #[clap(long, env, default_value = "100000")]
+    #[clap(
+        long,
+        env,
+        default_value = "100000",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
     pub blocks_per_log_request: u64,
+
+    /// The maximum number of block bodies fetched concurrently when
+    /// enriching a batch of trades, independently of
+    /// --blocks-per-log-request. Larger values speed up batches with many
+    /// trade blocks at the cost of bursts of requests.
+    #[clap(
+        long,
+        env,
+        default_value = "1",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub block_body_batch_size: u64,
+
+    /// Fetch block bodies in JSON-RPC batches of up to this many calls, one
+    /// HTTP request each, instead of one request per block, cutting
+    /// round-trips on high-latency endpoints. Batches are sent one after the
+    /// other. Falls back to individual calls if the endpoint rejects batches.
+    #[clap(long, env, value_name = "SIZE")]
+    pub rpc_batch: Option<u64>,
+
+    /// The number of blocks with trades whose bodies are held in memory at
+    /// a time when enriching a batch of trades. A dense batch is enriched in
+    /// chunks of this many blocks, in order, so that its trades are the same
+    /// but peak memory stays bounded however many blocks it spans.
+    #[clap(
+        long,
+        env,
+        default_value = "1000",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub merge_chunk_size: u64,
+
+    /// How many batches ahead the logs of later batches may be fetched while
+    /// the blocks of a batch are, overlapping the two phases of each batch.
+    /// 0 fetches the logs and blocks of each batch one after the other, and 1
+    /// already overlaps them fully unless fetching logs takes longer for some
+    /// batches than others.
+    #[clap(long, env, default_value = "0")]
+    pub log_batches_ahead: u64,
+
+    /// Resolve the symbols and decimals of traded tokens to add symbol and
+    /// decimal-adjusted amount columns. Costs extra RPC calls per new token.
+    #[clap(long, env)]
+    pub resolve_tokens: bool,
+
+    /// The decimals to assume for tokens whose `decimals()` can't be
+    /// resolved with --resolve-tokens, instead of leaving their adjusted
+    /// amounts empty.
+    #[clap(long, env)]
+    pub default_decimals: Option<u8>,
+
+    /// Hardcode the decimals of a token as `<ADDRESS>:<DECIMALS>`, taking
+    /// precedence over its `decimals()` with --resolve-tokens. Can be
+    /// repeated, or separated by commas.
+    #[clap(
+        long,
+        env,
+        value_delimiter = ',',
+        value_parser = parse_decimals_override
+    )]
+    pub decimals_override: Vec<(Address, u8)>,
+
+    /// The number of block batches to process between flushes of the CSV
+    /// file. Trades are always flushed at the end of a run, including when
+    /// it's interrupted with Ctrl-C.
+    #[clap(
+        long,
+        env,
+        default_value = "1",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub flush_every: u64,
+
+    /// The number of fetched block batches to hold while their trades are
+    /// written, e.g. to a slow webhook or Postgres, before fetching waits for
+    /// the writer to catch up. Bounds the memory taken by trades fetched
+    /// ahead of the writer.
+    #[clap(
+        long,
+        env,
+        default_value = "1",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub write_buffer_size: u64,
+
+    /// A URL to POST each batch of newly collected trades to as a JSON array,
+    /// in addition to writing them to the CSV file. Failed requests and
+    /// non-2xx responses are retried.
+    #[clap(long, env)]
+    pub webhook_url: Option<String>,
+
+    /// Write trades to the `trades` table of the Postgres database at this
+    /// connection string instead of the CSV file, creating the table if
+    /// needed. Trades already in the table are skipped, and runs resume after
+    /// the block of its latest trade. Webhooks, audits and checkpoint files
+    /// only apply to the CSV file.
+    #[clap(long, env)]
+    pub postgres_url: Option<String>,
+
+    /// Store the address columns of the Postgres table as 20-byte `BYTEA`
+    /// instead of hex strings, which shrinks large tables. A `trades_hex`
+    /// view renders them back to hex for exporting. The CSV file always has
+    /// hex addresses. Must match the existing table.
+    #[clap(long, env, requires = "postgres_url")]
+    pub compact_addresses: bool,
+
+    /// Keep running after reaching the chain head, polling for new blocks
+    /// every this many seconds and appending their trades to the same CSV
+    /// file until interrupted. Combine with --reorg-safety-margin to stay
+    /// clear of blocks near the tip that may still be reorged. The logs of
+    /// each new block are fetched by its hash, so that they belong to the
+    /// block seen.
+    #[clap(
+        long,
+        env,
+        conflicts_with = "overwrite",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub poll_interval: Option<u64>,
+
+    /// Stop cleanly after the block batch during which this many JSON-RPC
+    /// requests were made, counting retries, so that the next run resumes
+    /// where this one stopped. Unlimited if omitted.
+    #[clap(long, env)]
+    pub max_rpc_calls: Option<u64>,
+
+    /// Print an estimate of the JSON-RPC requests collecting would make and
+    /// exit without collecting. Block body requests are extrapolated from
+    /// the blocks with trades in the last batch, whose logs are queried for
+    /// it. Retries and token metadata requests aren't counted.
+    #[clap(long, env)]
+    pub estimate_cost: bool,
+
+    /// Make at most this many JSON-RPC requests per second, counting retries,
+    /// by delaying requests instead of waiting for the provider to reject
+    /// them. The limit is shared by all concurrent requests, so it also caps
+    /// --parallel-event-types and --block-body-batch-size. Unlimited if
+    /// omitted.
+    #[clap(long, env, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_rps: Option<u32>,
+
+    /// Abort with a non-zero exit code if no block batch completes within
+    /// this many seconds, so that a supervisor can restart a stalled run.
+    /// Disabled if omitted.
+    #[clap(long, env, value_parser = clap::value_parser!(u64).range(1..))]
+    pub stall_timeout: Option<u64>,
+
+    /// Verify that the queried block ranges exactly cover the scanned range
+    /// and fail if any block was skipped or queried twice.
+    #[clap(long, env)]
+    pub audit: bool,
+
+    /// Cross-check every batch against a raw eth_getLogs query filtered by
+    /// contract address and event topic, warning if the log counts differ.
+    #[clap(long, env)]
+    pub verify_raw: bool,
+
+    /// Verify after collecting that the trades in the CSV file are strictly
+    /// ordered by block number and log index, and fail otherwise.
+    #[clap(long, env)]
+    pub verify_sorted: bool,
+
+    /// Fail when fetched data doesn't pass an integrity check, i.e. when
+    /// logs of a block share a log index, instead of rejecting the affected
+    /// logs with an error.
+    #[clap(long, env)]
+    pub strict: bool,
+}
+
+/// Commands other than the default trade collection.
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// Print how many blocks contain each number of trades in the CSV file.
+    Histogram {
+        /// Print the histogram as a JSON object instead of CSV.
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Print the total input and output volume of the saved trades per token,
+    /// overall and per event type, as raw amounts.
+    Stats {
+        /// Print the volumes as a JSON object instead of CSV.
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Print the signature and topic0 hash of each trade event that logs are
+    /// filtered on.
+    Signatures,
+
+    /// Combine the CSV files of a backfill collected with --shard into the
+    /// CSV file, sorted by block number and log index, along with the blocks
+    /// the shards scanned, so that later runs resume after them.
+    Merge {
+        /// The number of shards the backfill was split into.
+        #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+        shards: u64,
+    },
+
+    /// Rewrite the CSV file with all columns of the current schema, refetching
+    /// block bodies and, with --resolve-tokens, token metadata of the saved
+    /// trades without querying event logs again.
+    Reenrich,
+
+    /// Rewrite the CSV file with its trades sorted by block number and log
+    /// index.
+    Sort,
+
+    /// Package the CSV file and its metadata and progress files into a
+    /// `.tar.zst` archive next to it, with a manifest of the chain,
+    /// contracts, block range, row count and SHA-256 checksum of the trades.
+    Archive,
+
+    /// Replay the saved trades in order as if they were arriving live,
+    /// writing each to stdout, or posting it to the webhook if one is set,
+    /// after the gap between its timestamp and the previous one.
+    Replay {
+        /// How many times faster than real time to replay the trades, e.g.
+        /// 60 replays an hour of trades in a minute.
+        #[clap(long, default_value = "1", value_parser = parse_speed)]
+        speed: f64,
+    },
+
+    /// Compare the CSV file with another one, e.g. collected with other
+    /// batching or from another provider, and print how many rows only one
+    /// of them has, matching rows by transaction hash, log index, event and
+    /// side. Exits with code 1 if they differ.
+    Diff {
+        /// The CSV file to compare with.
+        other_csv_path: String,
+
+        /// Also print the rows only one of the files has as JSON, marked `<`
+        /// if only in the CSV file and `>` if only in the other one.
+        #[clap(long)]
+        rows: bool,
+    },
+
+    /// Check the block hash saved with each trade against the hash of the
+    /// block at its number on the chain, and print the rows that don't match
+    /// as JSON, marked `reorged`, or `invalid` if the chain has no such
+    /// block. Rows saved without a block hash are skipped. Exits with code 1
+    /// if any row doesn't match.
+    Validate {
+        /// Only check the trades of this share of transactions, between 0
+        /// and 1, sampled as with --sample-rate. All trades if omitted.
+        #[clap(long, value_parser = parse_sample_rate)]
+        sample: Option<f64>,
+    },
+}
+
+/// Build a tracing layer exporting spans to the given OTLP gRPC endpoint. The
+/// tracer provider is registered globally so that [`shutdown_tracing`] can
+/// flush it.
+fn otlp_layer<S>(
+    endpoint: &str,
+) -> Result<impl tracing_subscriber::Layer<S>, Error>
+where
+    S: tracing::Subscriber
+        + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()
+        .map_err(|err| {
+            Error::Config(format!("Invalid OTLP endpoint {endpoint}: {err}"))
+        })?;
+    let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+        .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
+        .build();
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    opentelemetry::global::set_tracer_provider(provider);
+
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Build a tracing layer writing logs without colors to the file at the
+/// given path, starting a new file as often as given. Writes aren't
+/// buffered, so nothing is lost when exiting.
+fn log_file_layer<S>(
+    log_file: &str,
+    rotation: LogRotation,
+) -> Result<impl tracing_subscriber::Layer<S>, Error>
+where
+    S: tracing::Subscriber
+        + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    let path = Path::new(log_file);
+    let file_name = path.file_name().ok_or_else(|| {
+        Error::Config(format!("The log file {log_file} isn't a file"))
+    })?;
+    let directory = match path.parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => directory,
+        _ => Path::new("."),
+    };
+
+    let appender = RollingFileAppender::builder()
+        .rotation(match rotation {
+            LogRotation::Never => Rotation::NEVER,
+            LogRotation::Daily => Rotation::DAILY,
+        })
+        .filename_prefix(file_name.to_string_lossy())
+        .build(directory)
+        .map_err(|err| {
+            Error::Config(format!("Can't write logs to {log_file}: {err}"))
+        })?;
+
+    Ok(tracing_subscriber::fmt::layer().with_ansi(false).with_writer(appender))
+}
+
+/// Flush the spans that weren't exported yet, if traces are exported. Should
+/// be called before exiting.
+pub fn shutdown_tracing() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Parse a `<TOKEN_A>:<TOKEN_B>` token pair.
+fn parse_token_pair(value: &str) -> Result<(Address, Address), String> {
+    let (token_a, token_b) = value
+        .split_once(':')
+        .ok_or_else(|| format!("expected <TOKEN_A>:<TOKEN_B>, got {value}"))?;
+    let parse_token = |token: &str| {
+        token
+            .parse()
+            .map_err(|err| format!("invalid token address {token}: {err}"))
+    };
+
+    Ok((parse_token(token_a)?, parse_token(token_b)?))
+}
+
+/// The variable of the CSV path expanded to the ID of the connected chain.
+const CHAIN_ID_VAR: &str = "CHAIN_ID";
+
+/// Replace each `${VAR}` in the given string with the value looked up for
+/// `VAR`. A `$` that doesn't start a variable is kept as is.
+fn interpolate(
+    value: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<String, Error> {
+    let mut interpolated = String::new();
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        interpolated.push_str(&rest[..start]);
+        let (name, after) =
+            rest[start + 2..].split_once('}').ok_or_else(|| {
+                Error::Config(format!("Unterminated variable in {value}"))
+            })?;
+        if name.is_empty()
+            || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(Error::Config(format!(
+                "Invalid variable name {name:?} in {value}"
+            )));
+        }
+
+        let expanded = lookup(name).ok_or_else(|| {
+            Error::Config(format!("Variable {name} in {value} isn't set"))
+        })?;
+        interpolated.push_str(&expanded);
+        rest = after;
+    }
+    interpolated.push_str(rest);
+
+    Ok(interpolated)
+}
+
+/// Parse a `<ADDRESS>:<DECIMALS>` token decimals override.
+fn parse_decimals_override(value: &str) -> Result<(Address, u8), String> {
+    let (token, decimals) = value
+        .split_once(':')
+        .ok_or_else(|| format!("expected <ADDRESS>:<DECIMALS>, got {value}"))?;
+    let token = token
+        .parse()
+        .map_err(|err| format!("invalid token address {token}: {err}"))?;
+    let decimals = decimals
+        .parse()
+        .map_err(|err| format!("invalid decimals {decimals}: {err}"))?;
+
+    Ok((token, decimals))
+}
+
+/// Parse a `<NAME>: <VALUE>` HTTP header. The value is marked as sensitive,
+/// which redacts it in debug output, and left out of errors.
+fn parse_rpc_header(value: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = value
+        .split_once(':')
+        .ok_or_else(|| "expected <NAME>: <VALUE>".to_string())?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|err| format!("invalid header name {name}: {err}"))?;
+    let mut value = HeaderValue::from_str(value.trim())
+        .map_err(|err| format!("invalid value of header {name}: {err}"))?;
+    value.set_sensitive(true);
+
+    Ok((name, value))
+}
+
+/// Parse a positive, finite replay speed.
+fn parse_speed(value: &str) -> Result<f64, String> {
+    let speed: f64 =
+        value.parse().map_err(|err| format!("invalid speed {value}: {err}"))?;
+    if !speed.is_finite() || speed <= 0.0 {
+        return Err(format!("speed must be positive, got {value}"));
+    }
+
+    Ok(speed)
+}
+
+/// Parse a sample rate between 0 and 1.
+fn parse_sample_rate(value: &str) -> Result<f64, String> {
+    let sample_rate: f64 = value
+        .parse()
+        .map_err(|err| format!("invalid sample rate {value}: {err}"))?;
+    if !(0.0..=1.0).contains(&sample_rate) {
+        return Err(format!(
+            "sample rate must be between 0 and 1, got {value}"
+        ));
+    }
+
+    Ok(sample_rate)
+}
+
+/// One of the contiguous block ranges a backfill is split into with
+/// `--shard`, numbered from 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    pub index: u64,
+    pub count: u64,
+}
+
+impl Shard {
+    /// Parse a `<INDEX>/<COUNT>` shard.
+    fn parse(value: &str) -> Result<Self, String> {
+        let (index, count) = value
+            .split_once('/')
+            .ok_or_else(|| format!("expected <INDEX>/<COUNT>, got {value}"))?;
+        let shard = Self {
+            index: index
+                .parse()
+                .map_err(|err| format!("invalid shard index {index}: {err}"))?,
+            count: count
+                .parse()
+                .map_err(|err| format!("invalid shard count {count}: {err}"))?,
+        };
+        if shard.index >= shard.count {
+            return Err(format!(
+                "shard {} doesn't exist among {} shards numbered from 0",
+                shard.index, shard.count
+            ));
+        }
+
+        Ok(shard)
+    }
+
+    /// The shard's part of the given inclusive block range, or `None` if the
+    /// range has fewer blocks than there are shards and it's left without
+    /// any. The parts of all shards follow each other without gaps, so that
+    /// every block is in exactly one of them.
+    pub(crate) fn range(
+        self,
+        start_block: BlockNumber,
+        end_block: BlockNumber,
+    ) -> Option<(BlockNumber, BlockNumber)> {
+        let block_count =
+            (end_block as u128 + 1).checked_sub(start_block as u128)?;
+        // The first block of each shard, as an offset from the start block.
+        let offset = |index: u64| {
+            (block_count * index as u128 / self.count as u128) as u64
+        };
+        let (start, next_start) = (offset(self.index), offset(self.index + 1));
+
+        (start < next_start)
+            .then(|| (start_block + start, start_block + next_start - 1))
+    }
+
+    /// The path of the shard's CSV file, e.g. `trades.shard-0-of-4.csv` for
+    /// `trades.csv`.
+    pub(crate) fn path(self, csv_path: &str) -> String {
+        let infix = format!("shard-{}-of-{}", self.index, self.count);
+        output::insert_infix(csv_path, &infix)
+    }
+}
+
+/// Compression formats supported for the CSV file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Compression {
+    Gzip,
+}
+
+/// Units the timestamp column can be written in.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum TimestampUnit {
+    Seconds,
+    Millis,
+}
+
+impl TimestampUnit {
+    /// Convert a block timestamp, which is in seconds, to this unit.
+    pub fn convert(self, seconds: u64) -> u64 {
+        match self {
+            TimestampUnit::Seconds => seconds,
+            TimestampUnit::Millis => seconds * 1000,
+        }
+    }
+
+    /// The current wall-clock time in this unit.
+    pub fn now(self) -> u64 {
+        let since_epoch =
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        match self {
+            TimestampUnit::Seconds => since_epoch.as_secs(),
+            TimestampUnit::Millis => since_epoch.as_millis() as u64,
+        }
+    }
+}
+
+/// The block tags the chain head can be resolved from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum HeadTag {
+    #[default]
+    Latest,
+    Safe,
+    Finalized,
+}
+
+/// How often a new log file is started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogRotation {
+    #[default]
+    Never,
+    Daily,
+}
+
+/// Layouts the CSV file can be written in.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Dialect {
+    #[default]
+    Raw,
+    Dune,
 }
 
 impl Env {
     /// Read the configuration from the environment and set up logging.
     pub fn init() -> Self {
+        let env = Self::load();
+        env.init_tracing();
+        env
+    }
+
+    /// Read the configuration from the environment without setting up
+    /// logging, e.g. to expand the CSV path first, which --output-dir names
+    /// the log file after.
+    pub fn load() -> Self {
         dotenv::dotenv().ok();
-        let env = Env::parse();
-        let env_filter =
-            format!("none,rain_drops={log_level}", log_level = &env.log_level);
+        Env::parse()
+    }
+
+    /// Set up logging as configured.
+    pub fn init_tracing(&self) {
+        let default_filter = || {
+            EnvFilter::new(format!(
+                "none,rain_drops={log_level}",
+                log_level = &self.log_level
+            ))
+        };
+        let (env_filter, filter_error) = match self.trace_filter.as_deref() {
+            None => (default_filter(), None),
+            Some(trace_filter) => match EnvFilter::try_new(trace_filter) {
+                Ok(env_filter) => (env_filter, None),
+                Err(err) => (default_filter(), Some(err)),
+            },
+        };
+
+        let (otlp_layer, otlp_error) =
+            match self.otlp_endpoint.as_deref().map(otlp_layer).transpose() {
+                Ok(otlp_layer) => (otlp_layer, None),
+                Err(err) => (None, Some(err)),
+            };
+
+        let (log_file_layer, log_file_error) = match self
+            .log_file
+            .as_deref()
+            .map(|log_file| log_file_layer(log_file, self.log_rotation))
+            .transpose()
+        {
+            Ok(log_file_layer) => (log_file_layer, None),
+            Err(err) => (None, Some(err)),
+        };
 
-        tracing_subscriber::fmt()
-            .with_max_level(env.log_level)
-            .with_env_filter(tracing_subscriber::EnvFilter::new(env_filter))
+        tracing_subscriber::registry()
+            .with(env_filter)
+            .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+            .with(log_file_layer)
+            .with(otlp_layer)
             .init();
 
-        env
+        // Logging is only available once the subscriber is set up.
+        if let Some(err) = filter_error {
+            tracing::warn!("Ignoring the invalid trace filter: {err}");
+        }
+        if let Some(err) = otlp_error {
+            tracing::warn!("Not exporting traces: {err}");
+        }
+        if let Some(err) = log_file_error {
+            tracing::warn!("Not writing logs to a file: {err}");
+        }
+    }
+
+    /// Whether the CSV path refers to the chain ID, which takes a request to
+    /// the node to expand.
+    pub fn csv_path_uses_chain_id(&self) -> bool {
+        self.output_dir.is_some()
+            || self.csv_path.contains(&format!("${{{CHAIN_ID_VAR}}}"))
+    }
+
+    /// Replace the `${VAR}` variables of the CSV path with the given chain ID
+    /// for `${CHAIN_ID}`, or else the environment variable of the same name.
+    /// Fails if a variable isn't set or the expanded path is a directory.
+    ///
+    /// With --output-dir, the CSV path and the log file are set to those of
+    /// the chain and contracts in its layout first, creating its directories.
+    pub fn expand_csv_path(
+        &mut self,
+        chain_id: Option<u64>,
+    ) -> Result<(), Error> {
+        let lookup = |name: &str| match (name, chain_id) {
+            (CHAIN_ID_VAR, Some(chain_id)) => Some(chain_id.to_string()),
+            _ => std::env::var(name).ok(),
+        };
+
+        if let Some(output_dir) = self.output_dir.clone() {
+            let name = interpolate(&self.output_name(), lookup)?;
+            output::create_layout(&output_dir)?;
+            self.csv_path =
+                format!("{output_dir}/{}/{name}.csv", output::DATA_DIR);
+            self.log_file =
+                Some(format!("{output_dir}/{}/{name}.log", output::LOGS_DIR));
+        }
+
+        let csv_path = interpolate(&self.csv_path, lookup)?;
+
+        if csv_path.is_empty() || std::path::Path::new(&csv_path).is_dir() {
+            return Err(Error::Config(format!(
+                "The CSV path {} expands to {csv_path:?}, which isn't a file",
+                self.csv_path
+            )));
+        }
+        if csv_path != self.csv_path {
+            tracing::debug!(
+                "Expanded the CSV path {} to {csv_path}",
+                self.csv_path
+            );
+            self.csv_path = csv_path;
+        }
+
+        Ok(())
+    }
+
+    /// The name of the files of the --output-dir layout, after the chain and
+    /// the orderbook contracts, e.g. `42161-0x2f20…`, with the chain ID left
+    /// as a variable to expand.
+    fn output_name(&self) -> String {
+        let contracts = self
+            .orderbookv4_deployment_address
+            .iter()
+            .map(|address| address.trim().to_lowercase())
+            .collect::<Vec<_>>();
+
+        format!("${{{CHAIN_ID_VAR}}}-{}", contracts.join("-"))
+    }
+
+    /// The path of the CSV file accounting for the configured compression,
+    /// or of the shard's CSV file with --shard.
+    pub fn output_path(&self) -> String {
+        let csv_path = match self.compress {
+            _ if self.csv_path == "-" => return self.csv_path.clone(),
+            Some(Compression::Gzip) if !self.csv_path.ends_with(".gz") => {
+                format!("{}.gz", self.csv_path)
+            }
+            _ => self.csv_path.clone(),
+        };
+
+        match self.shard {
+            Some(shard) => shard.path(&csv_path),
+            None => csv_path,
+        }
+    }
+
+    /// Create a provider connected to the blockchain via the configured
+    /// JSON-RPC HTTP URL, failing if none is configured.
+    pub fn connect_provider(&self) -> Result<OrderbookProvider, Error> {
+        self.connect_provider_at(self.rpc_url()?)
+    }
+
+    /// The configured JSON-RPC HTTP URL, failing if none is configured.
+    pub fn rpc_url(&self) -> Result<&str, Error> {
+        self.json_rpc_http_url.as_deref().ok_or_else(|| {
+            Error::Config(
+                "A JSON-RPC HTTP URL is required to fetch from the chain"
+                    .to_string(),
+            )
+        })
+    }
+
+    /// Connect to the JSON-RPC HTTP endpoint at the given URL with the
+    /// configured timeout, e.g. one of --compare-head-from-providers.
+    pub fn connect_provider_at(
+        &self,
+        rpc_url: &str,
+    ) -> Result<OrderbookProvider, Error> {
+        let rpc_url = rpc_url.parse::<Url>().map_err(|err| {
+            Error::Config(format!("Invalid JSON-RPC HTTP URL: {err}"))
+        })?;
+
+        let mut http_client = reqwest::Client::builder()
+            .default_headers(HeaderMap::from_iter(self.rpc_header.clone()));
+        if let Some(rpc_timeout_ms) = self.rpc_timeout_ms {
+            http_client =
+                http_client.timeout(Duration::from_millis(rpc_timeout_ms));
+        }
+        let http_client = http_client.build().map_err(|err| {
+            Error::Config(format!("Failed to build the HTTP client: {err}"))
+        })?;
+
+        let transport = Http::with_client(http_client, rpc_url);
+        let is_local = transport.guess_local();
+        let provider = ProviderBuilder::new()
+            .network::<AnyNetwork>()
+            .on_client(RpcClient::new(transport, is_local));
+
+        Ok(provider)
+    }
+
+    /// Create an instance of each configured orderbook contract connected to
+    /// the blockchain via the given provider, failing if none is configured.
+    pub fn connect_contracts(
+        &self,
+        provider: &OrderbookProvider,
+    ) -> Result<Vec<OrderbookContract>, Error> {
+        if self.orderbookv4_deployment_address.is_empty() {
+            return Err(Error::Config(
+                "At least one orderbook address is required".to_string(),
+            ));
+        }
+
+        self.orderbookv4_deployment_address
+            .iter()
+            .map(|address| {
+                let orderbook =
+                    address.trim().parse::<Address>().map_err(|err| {
+                        Error::Config(format!(
+                            "Invalid orderbook address {address}: {err}"
+                        ))
+                    })?;
+                Ok(IOrderBookV4::new(orderbook, provider.clone()))
+            })
+            .collect()
+    }
+
+    /// The orderbook contract if only one is configured, for the trades
+    /// saved without theirs.
+    pub(crate) fn single_orderbook_address(&self) -> Option<Address> {
+        match self.orderbookv4_deployment_address.as_slice() {
+            [address] => address.trim().parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// The columns written to the CSV file: those selected with --columns,
+    /// or with --no-enrich those taken from the logs of the trades. Empty if
+    /// all columns are written.
+    pub(crate) fn csv_columns(&self) -> Vec<String> {
+        if !self.no_enrich {
+            return self.columns.clone();
+        }
+
+        crate::CSV_HEADERS
+            .iter()
+            .filter(|column| !crate::BLOCK_COLUMNS.contains(column))
+            .map(|column| column.to_string())
+            .collect()
+    }
+
+    /// Check that the CSV dialect and columns can be written with the rest
+    /// of the configuration. Columns must be known and selected once. Dune
+    /// uploads need a header row and parse timestamps as seconds.
+    pub(crate) fn check_dialect(&self) -> Result<(), Error> {
+        for (index, column) in self.columns.iter().enumerate() {
+            if !crate::CSV_HEADERS.contains(&column.as_str()) {
+                return Err(Error::Config(format!(
+                    "{column} isn't a column, the columns are {}",
+                    crate::CSV_HEADERS.join(",")
+                )));
+            }
+            if self.columns[..index].contains(column) {
+                return Err(Error::Config(format!(
+                    "The {column} column is selected twice"
+                )));
+            }
+        }
+
+        if self.reorg_safety_margin > 0 {
+            self.check_key_columns("--reorg-safety-margin")?;
+        }
+        if self.seen_set {
+            self.check_key_columns("--seen-set")?;
+        }
+        if self.max_reorg_depth.is_some() {
+            self.check_key_columns("--max-reorg-depth")?;
+        }
+
+        if self.dialect != Dialect::Dune {
+            return Ok(());
+        }
+        if self.csv_no_headers {
+            return Err(Error::Config(
+                "The dune dialect needs a header row".to_string(),
+            ));
+        }
+        if self.timestamp_unit != TimestampUnit::Seconds {
+            return Err(Error::Config(
+                "The dune dialect needs timestamps in seconds".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Check that the columns written include those telling saved trades
+    /// apart, which the given user of the saved trades needs.
+    pub(crate) fn check_key_columns(&self, user: &str) -> Result<(), Error> {
+        if self.columns.is_empty() {
+            return Ok(());
+        }
+
+        let missing = crate::KEY_COLUMNS
+            .into_iter()
+            .filter(|column| !self.columns.iter().any(|c| c == column))
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return Err(Error::Config(format!(
+                "{user} needs the {} columns, which --columns leaves out",
+                missing.join(",")
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Start building the configuration in code rather than from the process
+    /// environment and command line arguments. Options that aren't set keep
+    /// the defaults of their CLI flags.
+    pub fn builder(
+        json_rpc_http_url: impl Into<String>,
+        orderbookv4_deployment_address: Vec<String>,
+    ) -> EnvBuilder {
+        EnvBuilder {
+            env: Env {
+                command: None,
+                log_level: tracing::Level::DEBUG,
+                trace_filter: None,
+                otlp_endpoint: None,
+                log_file: None,
+                log_rotation: LogRotation::Never,
+                csv_path: "trades.csv".to_string(),
+                output_dir: None,
+                compress: None,
+                csv_no_headers: false,
+                timestamp_unit: TimestampUnit::Seconds,
+                dialect: Dialect::Raw,
+                columns: vec![],
+                max_file_size: None,
+                split_by_event: false,
+                json_rpc_http_url: Some(json_rpc_http_url.into()),
+                rpc_timeout_ms: None,
+                network_timeout_retries: 3,
+                rpc_header: vec![],
+                head_cache_ttl: None,
+                compare_head_from_providers: vec![],
+                max_head_divergence: 2,
+                orderbookv4_deployment_address,
+                filter_sender: vec![],
+                pair: vec![],
+                sample_rate: None,
+                abi_event_allowlist: DEFAULT_EVENTS.to_vec(),
+                split_clear: false,
+                parallel_event_types: false,
+                emit_raw_event_json: false,
+                record_first_seen: false,
+                include_failed_txs: false,
+                timestamps_only: false,
+                no_enrich: false,
+                orderbookv4_deployment_block: None,
+                orderbookv4_deployment_tx: None,
+                auto_detect_deployment: false,
+                from_block: None,
+                since_tx: None,
+                to_block: None,
+                shard: None,
+                overwrite: false,
+                reverse: false,
+                resume_from_checkpoint_only: false,
+                confirm_before_rescan: None,
+                yes: false,
+                reorg_safety_margin: 0,
+                head_tag: HeadTag::Latest,
+                min_confirmations: 0,
+                max_reorg_depth: None,
+                seen_set: false,
+                blocks_per_log_request: 100_000,
+                block_body_batch_size: 1,
+                rpc_batch: None,
+                merge_chunk_size: 1_000,
+                log_batches_ahead: 0,
+                resolve_tokens: false,
+                default_decimals: None,
+                decimals_override: vec![],
+                flush_every: 1,
+                write_buffer_size: 1,
+                webhook_url: None,
+                postgres_url: None,
+                compact_addresses: false,
+                poll_interval: None,
+                max_rpc_calls: None,
+                estimate_cost: false,
+                max_rps: None,
+                stall_timeout: None,
+                audit: false,
+                verify_raw: false,
+                verify_sorted: false,
+                strict: false,
+            },
+        }
+    }
+}
+
+/// A builder of [`Env`] for embedding the crate, started with
+/// [`Env::builder`].
+#[derive(Debug)]
+pub struct EnvBuilder {
+    env: Env,
+}
+
+impl EnvBuilder {
+    /// Check the options the CLI would reject and return the configuration.
+    pub fn build(self) -> Result<Env, Error> {
+        let env = self.env;
+
+        if env.orderbookv4_deployment_address.is_empty() {
+            return Err(Error::Config(
+                "At least one orderbook address is required".to_string(),
+            ));
+        }
+        if env.orderbookv4_deployment_block.is_none()
+            && env.orderbookv4_deployment_tx.is_none()
+            && !env.auto_detect_deployment
+        {
+            return Err(Error::Config(
+                "Either the deployment block or the deployment transaction \
+                 is required, or auto-detecting the deployment block"
+                    .to_string(),
+            ));
+        }
+        if env.abi_event_allowlist.is_empty() {
+            return Err(Error::Config(
+                "At least one event is required".to_string(),
+            ));
+        }
+        for event in &env.abi_event_allowlist {
+            let abi_event =
+                abi::trade_event(event.name()).map_err(Error::Config)?;
+            if abi_event != *event {
+                return Err(Error::Config(format!(
+                    "{} should be selected as {}",
+                    event.name(),
+                    abi_event.name()
+                )));
+            }
+        }
+        if env.compact_addresses && env.postgres_url.is_none() {
+            return Err(Error::Config(
+                "Compact addresses are only stored in Postgres".to_string(),
+            ));
+        }
+        if env.log_rotation != LogRotation::Never
+            && env.log_file.is_none()
+            && env.output_dir.is_none()
+        {
+            return Err(Error::Config(
+                "Only a log file can be rotated".to_string(),
+            ));
+        }
+        if env.output_dir.is_some() && env.log_file.is_some() {
+            return Err(Error::Config(
+                "The logs are written to the output directory".to_string(),
+            ));
+        }
+        if !env.columns.is_empty() && env.postgres_url.is_some() {
+            return Err(Error::Config(
+                "Postgres stores all columns".to_string(),
+            ));
+        }
+        if env.no_enrich
+            && (env.timestamps_only
+                || env.include_failed_txs
+                || !env.columns.is_empty()
+                || env.csv_no_headers
+                || env.postgres_url.is_some())
+        {
+            return Err(Error::Config(
+                "Skipping enrichment fetches no blocks, not even their \
+                 timestamps or reverted transactions, and writes its own \
+                 columns to a CSV file with a header row"
+                    .to_string(),
+            ));
+        }
+        if env.max_file_size.is_some()
+            && (env.overwrite || env.postgres_url.is_some())
+        {
+            return Err(Error::Config(
+                "Only a CSV file that's appended to is split into parts"
+                    .to_string(),
+            ));
+        }
+        if env.split_by_event
+            && (env.overwrite
+                || env.max_file_size.is_some()
+                || env.reverse
+                || env.seen_set
+                || env.max_reorg_depth.is_some()
+                || env.poll_interval.is_some()
+                || env.postgres_url.is_some())
+        {
+            return Err(Error::Config(
+                "Splitting by event only appends to a CSV file per event, \
+                 without overwriting, parts, reverse scans, a seen-set, \
+                 reorg checks, polling or Postgres"
+                    .to_string(),
+            ));
+        }
+        if env.to_block.is_some() && env.poll_interval.is_some() {
+            return Err(Error::Config(
+                "Polling never stops at --to-block".to_string(),
+            ));
+        }
+        if let Some(shard) = env.shard {
+            if shard.index >= shard.count {
+                return Err(Error::Config(format!(
+                    "Shard {} doesn't exist among {} shards numbered from 0",
+                    shard.index, shard.count
+                )));
+            }
+            if env.to_block.is_none() {
+                return Err(Error::Config(
+                    "Sharding needs --to-block, so that all shards split the \
+                     same blocks"
+                        .to_string(),
+                ));
+            }
+            if env.poll_interval.is_some()
+                || env.postgres_url.is_some()
+                || env.from_block.is_some()
+                || env.since_tx.is_some()
+            {
+                return Err(Error::Config(
+                    "Shards are collected from the deployment block into CSV \
+                     files once, without polling, Postgres, --from-block or \
+                     --since-tx"
+                        .to_string(),
+                ));
+            }
+        }
+        if env.seen_set && env.overwrite {
+            return Err(Error::Config(
+                "The seen-set would outlive the overwritten trades".to_string(),
+            ));
+        }
+        if env.poll_interval.is_some() && env.overwrite {
+            return Err(Error::Config(
+                "Polling appends to the CSV file, which overwriting replaces"
+                    .to_string(),
+            ));
+        }
+        if env.reverse && !env.overwrite && env.csv_path != "-" {
+            return Err(Error::Config(
+                "Newest-first trades can't be appended, they need --overwrite"
+                    .to_string(),
+            ));
+        }
+        if env.reverse
+            && (env.poll_interval.is_some()
+                || env.seen_set
+                || env.postgres_url.is_some()
+                || env.verify_sorted
+                || env.min_confirmations > 0)
+        {
+            return Err(Error::Config(
+                "Scanning in reverse only writes a fresh CSV file once, \
+                 without polling, a seen-set, Postgres, a sort check or \
+                 deferring unconfirmed trades"
+                    .to_string(),
+            ));
+        }
+        if env.from_block.is_some() && env.since_tx.is_some() {
+            return Err(Error::Config(
+                "The start block and the start transaction conflict"
+                    .to_string(),
+            ));
+        }
+        for (name, value) in [
+            ("blocks_per_log_request", Some(env.blocks_per_log_request)),
+            ("block_body_batch_size", Some(env.block_body_batch_size)),
+            ("rpc_batch", env.rpc_batch),
+            ("merge_chunk_size", Some(env.merge_chunk_size)),
+            ("flush_every", Some(env.flush_every)),
+            ("write_buffer_size", Some(env.write_buffer_size)),
+            ("stall_timeout", env.stall_timeout),
+            ("max_rps", env.max_rps.map(u64::from)),
+            ("poll_interval", env.poll_interval),
+        ] {
+            if value == Some(0) {
+                return Err(Error::Config(format!(
+                    "{name} must be at least 1"
+                )));
+            }
+        }
+        env.check_dialect()?;
+
+        Ok(env)
+    }
+
+    /// Set [`Env::command`].
+    pub fn with_command(mut self, command: Command) -> Self {
+        self.env.command = Some(command);
+        self
+    }
+
+    /// Set [`Env::log_level`].
+    pub fn with_log_level(mut self, log_level: tracing::Level) -> Self {
+        self.env.log_level = log_level;
+        self
+    }
+
+    /// Set [`Env::trace_filter`].
+    pub fn with_trace_filter(
+        mut self,
+        trace_filter: impl Into<String>,
+    ) -> Self {
+        self.env.trace_filter = Some(trace_filter.into());
+        self
+    }
+
+    /// Set [`Env::otlp_endpoint`].
+    pub fn with_otlp_endpoint(
+        mut self,
+        otlp_endpoint: impl Into<String>,
+    ) -> Self {
+        self.env.otlp_endpoint = Some(otlp_endpoint.into());
+        self
+    }
+
+    /// Set [`Env::log_file`].
+    pub fn with_log_file(mut self, log_file: impl Into<String>) -> Self {
+        self.env.log_file = Some(log_file.into());
+        self
+    }
+
+    /// Set [`Env::log_rotation`].
+    pub fn with_log_rotation(mut self, log_rotation: LogRotation) -> Self {
+        self.env.log_rotation = log_rotation;
+        self
+    }
+
+    /// Set [`Env::csv_path`].
+    pub fn with_csv_path(mut self, csv_path: impl Into<String>) -> Self {
+        self.env.csv_path = csv_path.into();
+        self
+    }
+
+    /// Set [`Env::output_dir`].
+    pub fn with_output_dir(mut self, output_dir: impl Into<String>) -> Self {
+        self.env.output_dir = Some(output_dir.into());
+        self
+    }
+
+    /// Set [`Env::compress`].
+    pub fn with_compress(mut self, compress: Compression) -> Self {
+        self.env.compress = Some(compress);
+        self
+    }
+
+    /// Set [`Env::csv_no_headers`].
+    pub fn with_csv_no_headers(mut self, csv_no_headers: bool) -> Self {
+        self.env.csv_no_headers = csv_no_headers;
+        self
+    }
+
+    /// Set [`Env::timestamp_unit`].
+    pub fn with_timestamp_unit(
+        mut self,
+        timestamp_unit: TimestampUnit,
+    ) -> Self {
+        self.env.timestamp_unit = timestamp_unit;
+        self
+    }
+
+    /// Set [`Env::dialect`].
+    pub fn with_dialect(mut self, dialect: Dialect) -> Self {
+        self.env.dialect = dialect;
+        self
+    }
+
+    /// Set [`Env::columns`].
+    pub fn with_columns(mut self, columns: Vec<String>) -> Self {
+        self.env.columns = columns;
+        self
+    }
+
+    /// Set [`Env::max_file_size`].
+    pub fn with_max_file_size(mut self, max_file_size: u64) -> Self {
+        self.env.max_file_size = Some(max_file_size);
+        self
+    }
+
+    /// Set [`Env::split_by_event`].
+    pub fn with_split_by_event(mut self, split_by_event: bool) -> Self {
+        self.env.split_by_event = split_by_event;
+        self
+    }
+
+    /// Set [`Env::rpc_timeout_ms`].
+    pub fn with_rpc_timeout_ms(mut self, rpc_timeout_ms: u64) -> Self {
+        self.env.rpc_timeout_ms = Some(rpc_timeout_ms);
+        self
+    }
+
+    /// Set [`Env::network_timeout_retries`].
+    pub fn with_network_timeout_retries(
+        mut self,
+        network_timeout_retries: u64,
+    ) -> Self {
+        self.env.network_timeout_retries = network_timeout_retries;
+        self
+    }
+
+    /// Set [`Env::rpc_header`], marking the values as sensitive so that
+    /// they're redacted like parsed ones.
+    pub fn with_rpc_header(
+        mut self,
+        rpc_header: Vec<(HeaderName, HeaderValue)>,
+    ) -> Self {
+        self.env.rpc_header = rpc_header
+            .into_iter()
+            .map(|(name, mut value)| {
+                value.set_sensitive(true);
+                (name, value)
+            })
+            .collect();
+        self
+    }
+
+    /// Set [`Env::head_cache_ttl`].
+    pub fn with_head_cache_ttl(mut self, head_cache_ttl: u64) -> Self {
+        self.env.head_cache_ttl = Some(head_cache_ttl);
+        self
+    }
+
+    /// Set [`Env::compare_head_from_providers`].
+    pub fn with_compare_head_from_providers(
+        mut self,
+        compare_head_from_providers: Vec<String>,
+    ) -> Self {
+        self.env.compare_head_from_providers = compare_head_from_providers;
+        self
+    }
+
+    /// Set [`Env::max_head_divergence`].
+    pub fn with_max_head_divergence(
+        mut self,
+        max_head_divergence: u64,
+    ) -> Self {
+        self.env.max_head_divergence = max_head_divergence;
+        self
+    }
+
+    /// Set [`Env::filter_sender`].
+    pub fn with_filter_sender(mut self, filter_sender: Vec<Address>) -> Self {
+        self.env.filter_sender = filter_sender;
+        self
+    }
+
+    /// Set [`Env::pair`].
+    pub fn with_pair(mut self, pair: Vec<(Address, Address)>) -> Self {
+        self.env.pair = pair;
+        self
+    }
+
+    /// Set [`Env::sample_rate`].
+    pub fn with_sample_rate(mut self, sample_rate: f64) -> Self {
+        self.env.sample_rate = Some(sample_rate);
+        self
+    }
+
+    /// Set [`Env::abi_event_allowlist`].
+    pub fn with_abi_event_allowlist(
+        mut self,
+        abi_event_allowlist: Vec<TradeEvent>,
+    ) -> Self {
+        self.env.abi_event_allowlist = abi_event_allowlist;
+        self
+    }
+
+    /// Set [`Env::split_clear`].
+    pub fn with_split_clear(mut self, split_clear: bool) -> Self {
+        self.env.split_clear = split_clear;
+        self
+    }
+
+    /// Set [`Env::parallel_event_types`].
+    pub fn with_parallel_event_types(
+        mut self,
+        parallel_event_types: bool,
+    ) -> Self {
+        self.env.parallel_event_types = parallel_event_types;
+        self
+    }
+
+    /// Set [`Env::emit_raw_event_json`].
+    pub fn with_emit_raw_event_json(
+        mut self,
+        emit_raw_event_json: bool,
+    ) -> Self {
+        self.env.emit_raw_event_json = emit_raw_event_json;
+        self
+    }
+
+    /// Set [`Env::record_first_seen`].
+    pub fn with_record_first_seen(mut self, record_first_seen: bool) -> Self {
+        self.env.record_first_seen = record_first_seen;
+        self
+    }
+
+    /// Set [`Env::include_failed_txs`].
+    pub fn with_include_failed_txs(mut self, include_failed_txs: bool) -> Self {
+        self.env.include_failed_txs = include_failed_txs;
+        self
+    }
+
+    /// Set [`Env::timestamps_only`].
+    pub fn with_timestamps_only(mut self, timestamps_only: bool) -> Self {
+        self.env.timestamps_only = timestamps_only;
+        self
+    }
+
+    /// Set [`Env::no_enrich`].
+    pub fn with_no_enrich(mut self, no_enrich: bool) -> Self {
+        self.env.no_enrich = no_enrich;
+        self
+    }
+
+    /// Set [`Env::orderbookv4_deployment_block`].
+    pub fn with_orderbookv4_deployment_block(
+        mut self,
+        orderbookv4_deployment_block: u64,
+    ) -> Self {
+        self.env.orderbookv4_deployment_block =
+            Some(orderbookv4_deployment_block);
+        self
+    }
+
+    /// Set [`Env::orderbookv4_deployment_tx`].
+    pub fn with_orderbookv4_deployment_tx(
+        mut self,
+        orderbookv4_deployment_tx: B256,
+    ) -> Self {
+        self.env.orderbookv4_deployment_tx = Some(orderbookv4_deployment_tx);
+        self
+    }
+
+    /// Set [`Env::auto_detect_deployment`].
+    pub fn with_auto_detect_deployment(
+        mut self,
+        auto_detect_deployment: bool,
+    ) -> Self {
+        self.env.auto_detect_deployment = auto_detect_deployment;
+        self
+    }
+
+    /// Set [`Env::from_block`].
+    pub fn with_from_block(mut self, from_block: u64) -> Self {
+        self.env.from_block = Some(from_block);
+        self
+    }
+
+    /// Set [`Env::since_tx`].
+    pub fn with_since_tx(mut self, since_tx: B256) -> Self {
+        self.env.since_tx = Some(since_tx);
+        self
+    }
+
+    /// Set [`Env::to_block`].
+    pub fn with_to_block(mut self, to_block: u64) -> Self {
+        self.env.to_block = Some(to_block);
+        self
+    }
+
+    /// Set [`Env::shard`].
+    pub fn with_shard(mut self, shard: Shard) -> Self {
+        self.env.shard = Some(shard);
+        self
+    }
+
+    /// Set [`Env::overwrite`].
+    pub fn with_overwrite(mut self, overwrite: bool) -> Self {
+        self.env.overwrite = overwrite;
+        self
+    }
+
+    /// Set [`Env::reverse`].
+    pub fn with_reverse(mut self, reverse: bool) -> Self {
+        self.env.reverse = reverse;
+        self
+    }
+
+    /// Set [`Env::resume_from_checkpoint_only`].
+    pub fn with_resume_from_checkpoint_only(
+        mut self,
+        resume_from_checkpoint_only: bool,
+    ) -> Self {
+        self.env.resume_from_checkpoint_only = resume_from_checkpoint_only;
+        self
+    }
+
+    /// Set [`Env::confirm_before_rescan`].
+    pub fn with_confirm_before_rescan(
+        mut self,
+        confirm_before_rescan: u64,
+    ) -> Self {
+        self.env.confirm_before_rescan = Some(confirm_before_rescan);
+        self
+    }
+
+    /// Set [`Env::yes`].
+    pub fn with_yes(mut self, yes: bool) -> Self {
+        self.env.yes = yes;
+        self
+    }
+
+    /// Set [`Env::reorg_safety_margin`].
+    pub fn with_reorg_safety_margin(
+        mut self,
+        reorg_safety_margin: u64,
+    ) -> Self {
+        self.env.reorg_safety_margin = reorg_safety_margin;
+        self
+    }
+
+    /// Set [`Env::head_tag`].
+    pub fn with_head_tag(mut self, head_tag: HeadTag) -> Self {
+        self.env.head_tag = head_tag;
+        self
+    }
+
+    /// Set [`Env::min_confirmations`].
+    pub fn with_min_confirmations(mut self, min_confirmations: u64) -> Self {
+        self.env.min_confirmations = min_confirmations;
+        self
+    }
+
+    /// Set [`Env::max_reorg_depth`].
+    pub fn with_max_reorg_depth(mut self, max_reorg_depth: u64) -> Self {
+        self.env.max_reorg_depth = Some(max_reorg_depth);
+        self
+    }
+
+    /// Set [`Env::seen_set`].
+    pub fn with_seen_set(mut self, seen_set: bool) -> Self {
+        self.env.seen_set = seen_set;
+        self
+    }
+
+    /// Set [`Env::blocks_per_log_request`].
+    pub fn with_blocks_per_log_request(
+        mut self,
+        blocks_per_log_request: u64,
+    ) -> Self {
+        self.env.blocks_per_log_request = blocks_per_log_request;
+        self
+    }
+
+    /// Set [`Env::block_body_batch_size`].
+    pub fn with_block_body_batch_size(
+        mut self,
+        block_body_batch_size: u64,
+    ) -> Self {
+        self.env.block_body_batch_size = block_body_batch_size;
+        self
+    }
+
+    /// Set [`Env::merge_chunk_size`].
+    pub fn with_merge_chunk_size(mut self, merge_chunk_size: u64) -> Self {
+        self.env.merge_chunk_size = merge_chunk_size;
+        self
+    }
+
+    /// Set [`Env::rpc_batch`].
+    pub fn with_rpc_batch(mut self, rpc_batch: u64) -> Self {
+        self.env.rpc_batch = Some(rpc_batch);
+        self
+    }
+
+    /// Set [`Env::log_batches_ahead`].
+    pub fn with_log_batches_ahead(mut self, log_batches_ahead: u64) -> Self {
+        self.env.log_batches_ahead = log_batches_ahead;
+        self
+    }
+
+    /// Set [`Env::resolve_tokens`].
+    pub fn with_resolve_tokens(mut self, resolve_tokens: bool) -> Self {
+        self.env.resolve_tokens = resolve_tokens;
+        self
+    }
+
+    /// Set [`Env::default_decimals`].
+    pub fn with_default_decimals(mut self, default_decimals: u8) -> Self {
+        self.env.default_decimals = Some(default_decimals);
+        self
+    }
+
+    /// Set [`Env::decimals_override`].
+    pub fn with_decimals_override(
+        mut self,
+        decimals_override: Vec<(Address, u8)>,
+    ) -> Self {
+        self.env.decimals_override = decimals_override;
+        self
+    }
+
+    /// Set [`Env::flush_every`].
+    pub fn with_flush_every(mut self, flush_every: u64) -> Self {
+        self.env.flush_every = flush_every;
+        self
+    }
+
+    /// Set [`Env::write_buffer_size`].
+    pub fn with_write_buffer_size(mut self, write_buffer_size: u64) -> Self {
+        self.env.write_buffer_size = write_buffer_size;
+        self
+    }
+
+    /// Set [`Env::webhook_url`].
+    pub fn with_webhook_url(mut self, webhook_url: impl Into<String>) -> Self {
+        self.env.webhook_url = Some(webhook_url.into());
+        self
+    }
+
+    /// Set [`Env::postgres_url`].
+    pub fn with_postgres_url(
+        mut self,
+        postgres_url: impl Into<String>,
+    ) -> Self {
+        self.env.postgres_url = Some(postgres_url.into());
+        self
+    }
+
+    /// Set [`Env::compact_addresses`].
+    pub fn with_compact_addresses(mut self, compact_addresses: bool) -> Self {
+        self.env.compact_addresses = compact_addresses;
+        self
+    }
+
+    /// Set [`Env::poll_interval`].
+    pub fn with_poll_interval(mut self, poll_interval: u64) -> Self {
+        self.env.poll_interval = Some(poll_interval);
+        self
+    }
+
+    /// Set [`Env::max_rpc_calls`].
+    pub fn with_max_rpc_calls(mut self, max_rpc_calls: u64) -> Self {
+        self.env.max_rpc_calls = Some(max_rpc_calls);
+        self
+    }
+
+    /// Set [`Env::estimate_cost`].
+    pub fn with_estimate_cost(mut self, estimate_cost: bool) -> Self {
+        self.env.estimate_cost = estimate_cost;
+        self
+    }
+
+    /// Set [`Env::max_rps`].
+    pub fn with_max_rps(mut self, max_rps: u32) -> Self {
+        self.env.max_rps = Some(max_rps);
+        self
+    }
+
+    /// Set [`Env::stall_timeout`].
+    pub fn with_stall_timeout(mut self, stall_timeout: u64) -> Self {
+        self.env.stall_timeout = Some(stall_timeout);
+        self
+    }
+
+    /// Set [`Env::audit`].
+    pub fn with_audit(mut self, audit: bool) -> Self {
+        self.env.audit = audit;
+        self
+    }
+
+    /// Set [`Env::verify_raw`].
+    pub fn with_verify_raw(mut self, verify_raw: bool) -> Self {
+        self.env.verify_raw = verify_raw;
+        self
+    }
+
+    /// Set [`Env::verify_sorted`].
+    pub fn with_verify_sorted(mut self, verify_sorted: bool) -> Self {
+        self.env.verify_sorted = verify_sorted;
+        self
+    }
+
+    /// Set [`Env::strict`].
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.env.strict = strict;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamp_unit_convert() {
+        assert_eq!(
+            TimestampUnit::Seconds.convert(1_700_000_000),
+            1_700_000_000
+        );
+        assert_eq!(
+            TimestampUnit::Millis.convert(1_700_000_000),
+            1_700_000_000_000
+        );
+        assert_eq!(TimestampUnit::Millis.convert(0), 0);
+    }
+
+    #[test]
+    fn test_parse_token_pair() {
+        let (token_a, token_b) =
+            (Address::repeat_byte(1), Address::repeat_byte(2));
+
+        assert_eq!(
+            parse_token_pair(&format!("{token_a}:{token_b}")),
+            Ok((token_a, token_b))
+        );
+        assert!(parse_token_pair(&token_a.to_string()).is_err());
+        assert!(parse_token_pair(&format!("{token_a}:0x02")).is_err());
+    }
+
+    #[test]
+    fn test_interpolate_variables() {
+        let lookup = |name: &str| match name {
+            "CHAIN_ID" => Some("42161".to_string()),
+            "DIR" => Some("data".to_string()),
+            _ => None,
+        };
+
+        assert_eq!(
+            interpolate("${DIR}/trades-${CHAIN_ID}.csv", lookup).unwrap(),
+            "data/trades-42161.csv"
+        );
+        assert_eq!(interpolate("$trades.csv", lookup).unwrap(), "$trades.csv");
+        assert!(interpolate("trades-${UNSET}.csv", lookup).is_err());
+        assert!(interpolate("trades-${CHAIN_ID.csv", lookup).is_err());
+        assert!(interpolate("trades-${}.csv", lookup).is_err());
+    }
+
+    #[test]
+    fn test_expand_csv_path_with_chain_id() {
+        let mut env = Env::builder("http://localhost:8545", vec![])
+            .with_csv_path("trades-${CHAIN_ID}.csv")
+            .env;
+        assert!(env.csv_path_uses_chain_id());
+
+        env.expand_csv_path(Some(1)).unwrap();
+        assert_eq!(env.csv_path, "trades-1.csv");
+        assert!(!env.csv_path_uses_chain_id());
+    }
+
+    #[test]
+    fn test_output_dir_lays_out_files_by_chain_and_contract() {
+        let dir = tempfile::tempdir().unwrap();
+        let output_dir = dir.path().to_str().unwrap();
+        let contract = Address::repeat_byte(0x2f);
+        let mut env =
+            Env::builder("http://localhost:8545", vec![contract.to_string()])
+                .with_output_dir(output_dir)
+                .env;
+        assert!(env.csv_path_uses_chain_id());
+
+        env.expand_csv_path(Some(42161)).unwrap();
+        let name = format!("42161-{}", contract.to_string().to_lowercase());
+        assert_eq!(env.csv_path, format!("{output_dir}/data/{name}.csv"));
+        assert_eq!(
+            env.log_file.as_deref(),
+            Some(format!("{output_dir}/logs/{name}.log").as_str())
+        );
+        assert_eq!(
+            output::sidecar_path(&env.csv_path, ".progress"),
+            format!("{output_dir}/meta/{name}.csv.progress")
+        );
+
+        // A CSV file outside the layout keeps its files next to it.
+        let csv_path = format!("{output_dir}/logs/trades.csv");
+        assert_eq!(
+            output::sidecar_path(&csv_path, ".progress"),
+            format!("{csv_path}.progress")
+        );
+    }
+
+    #[test]
+    fn test_parse_decimals_override() {
+        let token = Address::repeat_byte(1);
+
+        assert_eq!(
+            parse_decimals_override(&format!("{token}:6")),
+            Ok((token, 6))
+        );
+        assert!(parse_decimals_override(&token.to_string()).is_err());
+        assert!(parse_decimals_override(&format!("{token}:256")).is_err());
+        assert!(parse_decimals_override("0x01:6").is_err());
+    }
+
+    #[test]
+    fn test_parse_rpc_header_redacts_value() {
+        let (name, value) = parse_rpc_header("X-Api-Key: secret").unwrap();
+
+        assert_eq!(name, "x-api-key");
+        assert_eq!(value, "secret");
+        assert!(!format!("{value:?}").contains("secret"));
+        assert!(parse_rpc_header("X-Api-Key").is_err());
+        assert!(parse_rpc_header("X Api Key: secret").is_err());
+
+        let env = Env::parse_from([
+            "rain-drops",
+            "--json-rpc-http-url",
+            "http://localhost:8545",
+            "--rpc-header",
+            "X-Api-Key: secret",
+            "--rpc-header",
+            "Authorization: Bearer token",
+        ]);
+        assert_eq!(env.rpc_header.len(), 2);
+        assert!(!format!("{env:?}").contains("secret"));
+        assert!(env.connect_provider().is_ok());
+    }
+
+    #[test]
+    fn test_shard_ranges_cover_blocks_once() {
+        assert_eq!(Shard::parse("1/4"), Ok(Shard { index: 1, count: 4 }));
+        assert!(Shard::parse("4/4").is_err());
+        assert!(Shard::parse("1").is_err());
+
+        for (start_block, end_block, count) in
+            [(100, 199, 4), (100, 200, 3), (7, 7, 1), (10, 12, 5)]
+        {
+            let ranges: Vec<_> = (0..count)
+                .filter_map(|index| {
+                    Shard { index, count }.range(start_block, end_block)
+                })
+                .collect();
+
+            assert_eq!(ranges.first().unwrap().0, start_block);
+            assert_eq!(ranges.last().unwrap().1, end_block);
+            for pair in ranges.windows(2) {
+                assert_eq!(pair[0].1 + 1, pair[1].0);
+            }
+        }
+        assert_eq!(
+            Shard { index: 1, count: 3 }.range(100, 200),
+            Some((133, 166))
+        );
+        assert_eq!(
+            Shard { index: 0, count: 4 }.path("out/trades.csv.gz"),
+            "out/trades.shard-0-of-4.csv.gz"
+        );
+    }
+
+    #[test]
+    fn test_builder_defaults_match_cli_defaults() {
+        let address = Address::ZERO.to_string();
+        let built =
+            Env::builder("http://localhost:8545", vec![address.clone()])
+                .with_orderbookv4_deployment_block(10)
+                .build()
+                .unwrap();
+        let parsed = Env::parse_from([
+            "rain-drops",
+            "--json-rpc-http-url",
+            "http://localhost:8545",
+            "--orderbookv4-deployment-address",
+            &address,
+            "--orderbookv4-deployment-block",
+            "10",
+        ]);
+
+        // Env doesn't implement PartialEq, but its Debug output covers every
+        // field.
+        assert_eq!(format!("{built:?}"), format!("{parsed:?}"));
+    }
+
+    #[test]
+    fn test_offline_commands_need_no_connection() {
+        let env = Env::try_parse_from(["rain-drops", "stats"]).unwrap();
+        assert!(matches!(env.command, Some(Command::Stats { json: false })));
+
+        let env = Env { json_rpc_http_url: None, ..env };
+        assert!(matches!(env.connect_provider(), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_log_file_layer_writes_plain_logs() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for rotation in [LogRotation::Never, LogRotation::Daily] {
+            let log_file = dir.path().join(format!("{rotation:?}.log"));
+            let layer =
+                log_file_layer(log_file.to_str().unwrap(), rotation).unwrap();
+            let subscriber = tracing_subscriber::registry()
+                .with(EnvFilter::new("info"))
+                .with(layer);
+            tracing::subscriber::with_default(subscriber, || {
+                tracing::info!("Logged to a file");
+                tracing::debug!("Filtered out");
+            });
+
+            let log_path = std::fs::read_dir(dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .find(|path| {
+                    path.file_name()
+                        .unwrap()
+                        .to_string_lossy()
+                        .starts_with(&format!("{rotation:?}.log"))
+                })
+                .unwrap();
+            let logs = std::fs::read_to_string(log_path).unwrap();
+            assert!(logs.contains("Logged to a file"));
+            assert!(!logs.contains("Filtered out"));
+            assert!(!logs.contains('\x1b'));
+        }
+
+        assert!(log_file_layer::<tracing_subscriber::Registry>(
+            "/",
+            LogRotation::Never
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_builder_rejects_what_the_cli_rejects() {
+        let builder = || {
+            Env::builder(
+                "http://localhost:8545",
+                vec![Address::ZERO.to_string()],
+            )
+        };
+
+        assert!(matches!(builder().build(), Err(Error::Config(_))));
+        assert!(builder()
+            .with_orderbookv4_deployment_tx(B256::ZERO)
+            .build()
+            .is_ok());
+        assert!(matches!(
+            builder()
+                .with_orderbookv4_deployment_block(10)
+                .with_flush_every(0)
+                .build(),
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(
+            builder()
+                .with_orderbookv4_deployment_block(10)
+                .with_from_block(20)
+                .with_since_tx(B256::ZERO)
+                .build(),
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(
+            builder()
+                .with_orderbookv4_deployment_block(10)
+                .with_dialect(Dialect::Dune)
+                .with_timestamp_unit(TimestampUnit::Millis)
+                .build(),
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(
+            builder()
+                .with_orderbookv4_deployment_block(10)
+                .with_poll_interval(12)
+                .with_overwrite(true)
+                .build(),
+            Err(Error::Config(_))
+        ));
     }
 
-    /// Create an instance of the orderbook contract connected to the blockchain
-    /// via the configured JSON-RPC HTTP URL.
-    pub fn connect_contract(&self) -> anyhow::Result<OrderbookContract> {
-        let rpc_url = self.json_rpc_http_url.parse()?;
-        let provider =
-            ProviderBuilder::new().network::<AnyNetwork>().on_http(rpc_url);
+    #[test]
+    fn test_rescanning_needs_key_columns() {
+        let builder = |columns: &str| {
+            Env::builder(
+                "http://localhost:8545",
+                vec![Address::ZERO.to_string()],
+            )
+            .with_orderbookv4_deployment_block(10)
+            .with_columns(columns.split(',').map(str::to_string).collect())
+        };
 
-        let orderbook =
-            self.orderbookv4_deployment_address.parse::<Address>()?;
-        let orderbook = IOrderBookV4::new(orderbook, provider.clone());
+        let columns = "timestamp,tx_hash,event";
+        assert!(builder(columns).build().is_ok());
+        assert!(matches!(
+            builder(columns).with_reorg_safety_margin(5).build(),
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(
+            builder(columns).with_seen_set(true).build(),
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(
+            builder(columns).with_max_reorg_depth(64).build(),
+            Err(Error::Config(_))
+        ));
 
-        Ok(orderbook)
+        let columns = "timestamp,tx_hash,event,contract_address,block_number,\
+            log_index";
+        assert!(builder(columns)
+            .with_reorg_safety_margin(5)
+            .with_seen_set(true)
+            .with_max_reorg_depth(64)
+            .build()
+            .is_ok());
     }
 }
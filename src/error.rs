@@ -0,0 +1,88 @@
+//! The error type returned by the public API, so that library consumers can
+//! handle different failure modes separately.
+
+use alloy::primitives::{Address, FixedBytes};
+use alloy::transports::TransportError;
+use std::time::Duration;
+
+/// Everything that can go wrong while collecting trades.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// A JSON-RPC request or contract query failed.
+    #[error("RPC request failed: {0}")]
+    Rpc(#[from] alloy::contract::Error),
+
+    /// A log query failed for good, on its last attempt if it was retried.
+    #[error(
+        "Querying {query} failed on attempt {attempts}{}: {source}",
+        backoff(.last_delay)
+    )]
+    LogQuery {
+        /// The event and blocks queried, e.g.
+        /// `ClearV2 logs from 100 to 199`.
+        query: String,
+        attempts: usize,
+        /// The delay before the last attempt, if there were several.
+        last_delay: Option<Duration>,
+        source: Box<Error>,
+    },
+
+    /// Reading or writing CSV records failed.
+    #[error("CSV error: {0}")]
+    Csv(#[from] csv::Error),
+
+    /// Accessing the local filesystem failed.
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// Serializing or deserializing JSON failed.
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    /// Data returned by the chain or read from disk couldn't be decoded.
+    #[error("Failed to decode {0}")]
+    Decode(String),
+
+    /// The configuration is invalid or insufficient for the requested run.
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+
+    /// A contract was requested that isn't among the connected contracts.
+    #[error("No contract connected at {0}")]
+    ContractNotFound(Address),
+
+    /// Posting trades to the webhook failed.
+    #[error("Webhook request failed: {0}")]
+    Webhook(#[from] alloy::transports::http::reqwest::Error),
+
+    /// A transaction expected to be on-chain wasn't found.
+    #[error("Transaction {0} not found on-chain")]
+    TransactionNotFound(FixedBytes<32>),
+
+    /// A Postgres query or connection failed.
+    #[error("Postgres error: {0}")]
+    Postgres(#[from] tokio_postgres::Error),
+
+    /// A data integrity check failed.
+    #[error("Integrity check failed: {0}")]
+    Integrity(String),
+
+    /// The callback given to [`crate::visit_trades`] failed.
+    #[error("Trade visitor failed: {0}")]
+    Visitor(Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// The delay before the last attempt of a query as part of its error
+/// message, if it was retried.
+fn backoff(last_delay: &Option<Duration>) -> String {
+    match last_delay {
+        Some(last_delay) => format!(" after backing off for {last_delay:?}"),
+        None => String::new(),
+    }
+}
+
+impl From<TransportError> for Error {
+    fn from(err: TransportError) -> Self {
+        Self::Rpc(err.into())
+    }
+}
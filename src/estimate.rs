@@ -0,0 +1,102 @@
+//! A pre-flight estimate of the JSON-RPC requests a collection run would
+//! make, printed with `--estimate-cost`, for choosing parameters before
+//! committing to a run on a provider that charges per request.
+
+use alloy::primitives::BlockNumber;
+use std::fmt;
+
+/// The estimated JSON-RPC requests of collecting the trades of a block range.
+/// Retries and token metadata requests with `--resolve-tokens` aren't
+/// counted.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct CostEstimate {
+    pub start_block: BlockNumber,
+    pub latest_block: BlockNumber,
+    /// The number of block batches the range is queried in.
+    pub batches: u64,
+    /// One `eth_getLogs` request per batch, contract and event, with ClearV2
+    /// and TakeOrderV2 sharing one, plus one per event with `--verify-raw`.
+    pub log_queries: u64,
+    /// One request per block with trades, whose body is fetched. Estimated
+    /// from the share of blocks with trades in a sampled batch.
+    pub block_body_calls: u64,
+    /// One `eth_getBlockReceipts` request per block with
+    /// `--include-failed-txs`.
+    pub receipt_calls: u64,
+}
+
+impl CostEstimate {
+    /// Estimate the requests of scanning the given block range in batches of
+    /// the given size, with the given number of log queries per batch, given
+    /// that `sampled_trade_blocks` of the `sampled_blocks` blocks of a sample
+    /// had trades.
+    pub(crate) fn new(
+        start_block: BlockNumber,
+        latest_block: BlockNumber,
+        blocks_per_batch: u64,
+        log_queries_per_batch: u64,
+        (sampled_trade_blocks, sampled_blocks): (u64, u64),
+        include_failed_txs: bool,
+    ) -> Self {
+        let block_count =
+            latest_block.saturating_add(1).saturating_sub(start_block);
+        let batches = block_count.div_ceil(blocks_per_batch.max(1));
+        let block_body_calls = match sampled_blocks {
+            0 => 0,
+            _ => (block_count as u128 * sampled_trade_blocks as u128)
+                .div_ceil(sampled_blocks as u128)
+                .min(block_count as u128) as u64,
+        };
+
+        Self {
+            start_block,
+            latest_block,
+            batches,
+            log_queries: batches.saturating_mul(log_queries_per_batch),
+            block_body_calls,
+            receipt_calls: if include_failed_txs { block_count } else { 0 },
+        }
+    }
+
+    /// The estimated number of requests in total.
+    pub fn total(&self) -> u64 {
+        self.log_queries
+            .saturating_add(self.block_body_calls)
+            .saturating_add(self.receipt_calls)
+    }
+}
+
+impl fmt::Display for CostEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Blocks {} to {} in {} batches",
+            self.start_block, self.latest_block, self.batches
+        )?;
+        writeln!(f, "Log queries:       {}", self.log_queries)?;
+        writeln!(f, "Block body calls: ~{}", self.block_body_calls)?;
+        writeln!(f, "Receipt calls:     {}", self.receipt_calls)?;
+        write!(f, "Total:            ~{}", self.total())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_extrapolates_sample() {
+        // 1,000 blocks in batches of 300, two contracts with two events.
+        let estimate = CostEstimate::new(1_000, 1_999, 300, 4, (3, 100), true);
+
+        assert_eq!(estimate.batches, 4);
+        assert_eq!(estimate.log_queries, 16);
+        assert_eq!(estimate.block_body_calls, 30);
+        assert_eq!(estimate.receipt_calls, 1_000);
+        assert_eq!(estimate.total(), 1_046);
+
+        // Nothing to scan.
+        let estimate = CostEstimate::new(2_000, 1_999, 300, 4, (0, 0), false);
+        assert_eq!(estimate.total(), 0);
+    }
+}
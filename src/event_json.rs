@@ -0,0 +1,205 @@
+//! A module for serializing decoded OrderbookV4 events in full, for research
+//! that needs more than the columns derived from them.
+//!
+//! The bindings generated from the ABI don't implement `Serialize`, so the
+//! events are mapped to JSON by hand, keeping the field names of the ABI.
+//! Integers are written as decimal strings and byte strings as hex so that
+//! no precision is lost.
+
+use alloy::dyn_abi::{DecodedEvent, DynSolValue};
+use alloy::json_abi::{Event, Param};
+use serde_json::{json, Map, Value};
+
+use crate::{abi, IOrderBookV4};
+
+/// Serialize a decoded ClearV2 event, including both matched orders and the
+/// clear config.
+pub(crate) fn clearv2(event: &IOrderBookV4::ClearV2) -> String {
+    let config = &event.clearConfig;
+
+    json!({
+        "sender": event.sender,
+        "alice": order(&event.alice),
+        "bob": order(&event.bob),
+        "clearConfig": {
+            "aliceInputIOIndex": config.aliceInputIOIndex.to_string(),
+            "aliceOutputIOIndex": config.aliceOutputIOIndex.to_string(),
+            "bobInputIOIndex": config.bobInputIOIndex.to_string(),
+            "bobOutputIOIndex": config.bobOutputIOIndex.to_string(),
+            "aliceBountyVaultId": config.aliceBountyVaultId.to_string(),
+            "bobBountyVaultId": config.bobBountyVaultId.to_string(),
+        },
+    })
+    .to_string()
+}
+
+/// Serialize a decoded TakeOrderV2 event, including the taken order and the
+/// take order config.
+pub(crate) fn takeorderv2(event: &IOrderBookV4::TakeOrderV2) -> String {
+    let config = &event.config;
+
+    json!({
+        "sender": event.sender,
+        "config": {
+            "order": order(&config.order),
+            "inputIOIndex": config.inputIOIndex.to_string(),
+            "outputIOIndex": config.outputIOIndex.to_string(),
+            "signedContext": config
+                .signedContext
+                .iter()
+                .map(|signed_context| json!({
+                    "signer": signed_context.signer,
+                    "context": signed_context
+                        .context
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>(),
+                    "signature": signed_context.signature.to_string(),
+                }))
+                .collect::<Vec<_>>(),
+        },
+        "input": event.input.to_string(),
+        "output": event.output.to_string(),
+    })
+    .to_string()
+}
+
+/// Serialize an event decoded through the ABI, keyed by the parameter
+/// names of the ABI like the events decoded through the bindings.
+pub(crate) fn abi_event(event: &Event, decoded: &DecodedEvent) -> String {
+    let params = abi::named_values(event, decoded)
+        .map(|(param, value)| {
+            (param.name.clone(), dyn_value(value, &param.components))
+        })
+        .collect::<Map<_, _>>();
+
+    Value::Object(params).to_string()
+}
+
+/// Map a value decoded through the ABI to JSON, naming the fields of tuples
+/// after the given components.
+fn dyn_value(value: &DynSolValue, components: &[Param]) -> Value {
+    match value {
+        DynSolValue::Bool(value) => json!(value),
+        DynSolValue::Int(value, _) => json!(value.to_string()),
+        DynSolValue::Uint(value, _) => json!(value.to_string()),
+        DynSolValue::Address(value) => json!(value),
+        DynSolValue::FixedBytes(word, size) => {
+            json!(alloy::hex::encode_prefixed(&word[..*size]))
+        }
+        DynSolValue::Bytes(value) => json!(alloy::hex::encode_prefixed(value)),
+        DynSolValue::String(value) => json!(value),
+        DynSolValue::Array(values) | DynSolValue::FixedArray(values) => {
+            json!(values
+                .iter()
+                .map(|value| dyn_value(value, components))
+                .collect::<Vec<_>>())
+        }
+        DynSolValue::Tuple(values) => Value::Object(
+            components
+                .iter()
+                .zip(values)
+                .map(|(component, value)| {
+                    (
+                        component.name.clone(),
+                        dyn_value(value, &component.components),
+                    )
+                })
+                .collect(),
+        ),
+        // Function pointers, which no orderbook event has.
+        _ => Value::Null,
+    }
+}
+
+fn order(order: &IOrderBookV4::OrderV3) -> Value {
+    json!({
+        "owner": order.owner,
+        "evaluable": {
+            "interpreter": order.evaluable.interpreter,
+            "store": order.evaluable.store,
+            "bytecode": order.evaluable.bytecode.to_string(),
+        },
+        "validInputs": order.validInputs.iter().map(io).collect::<Vec<_>>(),
+        "validOutputs": order.validOutputs.iter().map(io).collect::<Vec<_>>(),
+        "nonce": order.nonce.to_string(),
+    })
+}
+
+fn io(io: &IOrderBookV4::IO) -> Value {
+    json!({
+        "token": io.token,
+        "decimals": io.decimals,
+        "vaultId": io.vaultId.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::dyn_abi::EventExt;
+    use alloy::primitives::{Address, U256};
+    use alloy::sol_types::SolEvent;
+
+    use super::*;
+
+    #[test]
+    fn test_takeorderv2_keeps_full_precision() {
+        let event = IOrderBookV4::TakeOrderV2 {
+            sender: Address::repeat_byte(1),
+            config: IOrderBookV4::TakeOrderConfigV3 {
+                order: IOrderBookV4::OrderV3 {
+                    owner: Address::repeat_byte(2),
+                    evaluable: IOrderBookV4::EvaluableV3 {
+                        interpreter: Address::ZERO,
+                        store: Address::ZERO,
+                        bytecode: Default::default(),
+                    },
+                    validInputs: vec![IOrderBookV4::IO {
+                        token: Address::repeat_byte(3),
+                        decimals: 18,
+                        vaultId: U256::from(4),
+                    }],
+                    validOutputs: vec![],
+                    nonce: Default::default(),
+                },
+                inputIOIndex: U256::ZERO,
+                outputIOIndex: U256::ZERO,
+                signedContext: vec![],
+            },
+            input: U256::MAX,
+            output: U256::from(5),
+        };
+
+        let json: Value = serde_json::from_str(&takeorderv2(&event)).unwrap();
+
+        assert_eq!(json["input"], U256::MAX.to_string());
+        assert_eq!(json["config"]["order"]["validInputs"][0]["vaultId"], "4");
+        assert_eq!(
+            json["sender"],
+            serde_json::to_value(Address::repeat_byte(1)).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_abi_event_names_params() {
+        let event = IOrderBookV4::Deposit {
+            sender: Address::repeat_byte(1),
+            token: Address::repeat_byte(2),
+            vaultId: U256::from(3),
+            amount: U256::MAX,
+        };
+        let deposit = abi::event("Deposit").unwrap();
+        let decoded =
+            deposit.decode_log(&event.encode_log_data(), true).unwrap();
+
+        let json: Value =
+            serde_json::from_str(&abi_event(deposit, &decoded)).unwrap();
+
+        assert_eq!(json["vaultId"], "3");
+        assert_eq!(json["amount"], U256::MAX.to_string());
+        assert_eq!(
+            json["token"],
+            serde_json::to_value(Address::repeat_byte(2)).unwrap()
+        );
+    }
+}
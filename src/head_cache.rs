@@ -0,0 +1,90 @@
+//! A short-lived on-disk cache of the chain head, so that commands run in
+//! quick succession against the same node don't each have to request it.
+
+use alloy::primitives::BlockNumber;
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::*;
+
+/// The chain head as last fetched.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize,
+)]
+struct CachedHead {
+    block_number: BlockNumber,
+    /// When the head was fetched, in milliseconds since the Unix epoch.
+    fetched_at_ms: u64,
+}
+
+/// A cache of the chain head of one JSON-RPC endpoint in the temporary
+/// directory. It's only an optimization, so failing to read or write it
+/// falls back to requesting the head.
+#[derive(Debug, Clone)]
+pub(crate) struct HeadCache {
+    path: PathBuf,
+    ttl: Duration,
+}
+
+impl HeadCache {
+    /// Cache the head of the given endpoint for the given time. The cache
+    /// file is named after a hash of the URL rather than the chain ID, which
+    /// would take a request of its own, and so that API keys in the URL
+    /// aren't written to disk.
+    pub(crate) fn new(rpc_url: &str, ttl: Duration) -> Self {
+        let url_hash = alloy::hex::encode(Sha256::digest(rpc_url));
+        let file_name = format!("rain-drops-head-{}.json", &url_hash[..16]);
+
+        Self::at(std::env::temp_dir().join(file_name), ttl)
+    }
+
+    fn at(path: PathBuf, ttl: Duration) -> Self {
+        Self { path, ttl }
+    }
+
+    /// The cached head, unless it's older than the TTL.
+    pub(crate) fn get(&self) -> Option<BlockNumber> {
+        let cached: CachedHead =
+            serde_json::from_slice(&std::fs::read(&self.path).ok()?).ok()?;
+        let age_ms = now_ms().saturating_sub(cached.fetched_at_ms);
+
+        (u128::from(age_ms) < self.ttl.as_millis())
+            .then_some(cached.block_number)
+    }
+
+    /// Cache a freshly fetched head.
+    pub(crate) fn set(&self, block_number: BlockNumber) {
+        let cached = CachedHead { block_number, fetched_at_ms: now_ms() };
+        let result = serde_json::to_vec(&cached)
+            .map_err(std::io::Error::from)
+            .and_then(|bytes| std::fs::write(&self.path, bytes));
+        if let Err(err) = result {
+            debug!("Failed to cache the chain head: {err}");
+        }
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_head_expires_after_ttl() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("head.json");
+
+        let cache = HeadCache::at(path.clone(), Duration::from_secs(60));
+        assert_eq!(cache.get(), None);
+        cache.set(100);
+        assert_eq!(cache.get(), Some(100));
+
+        let expired = HeadCache::at(path, Duration::ZERO);
+        assert_eq!(expired.get(), None);
+    }
+}
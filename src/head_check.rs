@@ -0,0 +1,128 @@
+//! A comparison of the chain heads of several JSON-RPC endpoints of the same
+//! chain before collecting, with `--compare-head-from-providers`, so that an
+//! endpoint that lags behind or follows a fork is noticed before its view of
+//! the chain ends up in incremental data.
+
+use alloy::primitives::BlockNumber;
+use alloy::providers::Provider;
+use alloy::transports::http::reqwest::Url;
+use futures::future::join_all;
+use tracing::*;
+
+use crate::env::Env;
+use crate::Error;
+
+/// Compare the chain heads of `--json-rpc-http-url` and the endpoints of
+/// `--compare-head-from-providers`, warning about each endpoint lagging
+/// behind the most advanced one by more than `--max-head-divergence`
+/// blocks, and return the URL of the most advanced endpoint to fetch from.
+/// Endpoints whose head can't be fetched are warned about and left out.
+///
+/// Returns `--json-rpc-http-url` without requesting anything if there are no
+/// other endpoints to compare it with.
+pub async fn most_advanced_rpc_url(env: &Env) -> Result<String, Error> {
+    let rpc_url = env.rpc_url()?;
+    if env.compare_head_from_providers.is_empty() {
+        return Ok(rpc_url.to_string());
+    }
+
+    let rpc_urls: Vec<&str> = std::iter::once(rpc_url)
+        .chain(env.compare_head_from_providers.iter().map(String::as_str))
+        .collect();
+    let heads = join_all(rpc_urls.iter().map(|rpc_url| async move {
+        let provider = env.connect_provider_at(rpc_url)?;
+        Ok::<_, Error>(provider.get_block_number().await?)
+    }))
+    .await;
+
+    let mut reachable = vec![];
+    for (rpc_url, head) in rpc_urls.iter().zip(heads) {
+        match head {
+            Ok(head) => reachable.push((*rpc_url, head)),
+            Err(err) => {
+                warn!("Not comparing the head of {}: {err}", host(rpc_url))
+            }
+        }
+    }
+
+    let heads: Vec<BlockNumber> =
+        reachable.iter().map(|&(_, head)| head).collect();
+    let Some((most_advanced, lagging)) =
+        compare_heads(&heads, env.max_head_divergence)
+    else {
+        return Err(Error::Config(
+            "None of the JSON-RPC endpoints returned its chain head"
+                .to_string(),
+        ));
+    };
+
+    let (most_advanced_url, latest_head) = reachable[most_advanced];
+    for index in lagging {
+        let (rpc_url, head) = reachable[index];
+        warn!(
+            "{} is at block {head}, {} blocks behind {} at block \
+            {latest_head}",
+            host(rpc_url),
+            latest_head - head,
+            host(most_advanced_url)
+        );
+    }
+    info!(
+        "Fetching from {}, the most advanced of {} endpoints at block \
+        {latest_head}",
+        host(most_advanced_url),
+        reachable.len()
+    );
+
+    Ok(most_advanced_url.to_string())
+}
+
+/// The index of the most advanced of the given chain heads, the first one on
+/// a tie, and the indices of those lagging behind it by more than the given
+/// number of blocks, or `None` if there are no heads.
+fn compare_heads(
+    heads: &[BlockNumber],
+    max_divergence: u64,
+) -> Option<(usize, Vec<usize>)> {
+    let latest_head = *heads.iter().max()?;
+    let most_advanced = heads.iter().position(|&head| head == latest_head)?;
+    let lagging = heads
+        .iter()
+        .enumerate()
+        .filter(|&(_, &head)| latest_head - head > max_divergence)
+        .map(|(index, _)| index)
+        .collect();
+
+    Some((most_advanced, lagging))
+}
+
+/// The host of the given URL, to name an endpoint in logs without the API
+/// key that its path or query may contain.
+fn host(rpc_url: &str) -> String {
+    Url::parse(rpc_url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_else(|| "an invalid URL".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_heads_prefers_most_advanced() {
+        assert_eq!(compare_heads(&[], 2), None);
+        assert_eq!(compare_heads(&[100], 2), Some((0, vec![])));
+        assert_eq!(compare_heads(&[98, 100, 100, 97], 2), Some((1, vec![3])));
+        assert_eq!(compare_heads(&[90, 100, 95], 0), Some((1, vec![0, 2])));
+    }
+
+    #[test]
+    fn test_host_leaves_out_api_key() {
+        assert_eq!(
+            host("https://mainnet.example.com/v2/secret-key"),
+            "mainnet.example.com"
+        );
+        assert_eq!(host("not a url"), "an invalid URL");
+    }
+}
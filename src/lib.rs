@@ -2,9 +2,15 @@
 //! blockchain and saving them to a CSV file.
 
 use alloy::network::AnyNetwork;
-use alloy::primitives::{Address, BlockNumber, FixedBytes};
+use alloy::primitives::{Address, BlockNumber, FixedBytes, B256, U256};
 use alloy::providers::RootProvider;
 use alloy::{sol, transports::http};
+use itertools::Either;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::future::Future;
+use std::io::{BufRead, BufWriter, IsTerminal, Read, Write};
+use std::time::Duration;
+use tokio::sync::{mpsc, Semaphore};
 use tracing::*;
 
 sol! {
@@ -12,185 +18,4867 @@ sol! {
     IOrderBookV4, "./abi/orderbookv4.json"
 }
 
-mod compose;
+mod abi;
+pub mod analysis;
+mod archive;
+mod audit;
+pub mod compose;
+mod dune;
+mod enrich_cache;
+mod enricher;
 pub mod env;
+mod error;
+mod estimate;
+mod event_json;
+mod head_cache;
+mod head_check;
 mod logs;
+mod meta;
 pub mod onchain;
+mod output;
+mod parts;
+mod postgres;
+mod progress;
+mod replay;
+mod rpc_calls;
+mod seen;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod tokens;
+mod units;
+mod validate;
+mod watchdog;
+mod webhook;
 
-use logs::{TradeEvent, TradeLog};
-use onchain::OnChain;
+pub use enricher::{EnrichedTrade, Enricher};
+pub use error::Error;
+pub use estimate::CostEstimate;
+pub use head_check::most_advanced_rpc_url;
+pub use logs::print_event_signatures;
+pub use validate::{
+    print_validation, validate_trades_csv, Mismatch, ValidationSummary,
+};
+
+use audit::BlockCoverage;
+use dune::{DuneTrade, DUNE_HEADERS};
+use env::Dialect;
+use logs::{BlockTradeLogs, ClearSide, TradeEvent, TradeLog};
+use meta::{CsvMeta, Deployment, DetectedDeployment};
+use onchain::{FailedTx, OnChain};
+use tokens::TokenCache;
+use watchdog::Watchdog;
+use webhook::Webhook;
+
+/// Type alias for the provider connected to the configured JSON-RPC HTTP URL.
+pub type OrderbookProvider = RootProvider<http::Http<http::Client>, AnyNetwork>;
 
 /// Type alias for the OrderbookV4 contract instance connected to the
 /// configured JSON-RPC HTTP URL.
 pub type OrderbookContract = IOrderBookV4::IOrderBookV4Instance<
     http::Http<http::Client>,
-    RootProvider<http::Http<http::Client>, AnyNetwork>,
+    OrderbookProvider,
     AnyNetwork,
 >;
 
 /// Create or append to a CSV file containing all trades from the deployed
-/// OrderbookV4 contract.
-#[allow(private_bounds)]
+/// OrderbookV4 contract, returning the number of new trades written.
 pub async fn update_trades_csv(
     env: &env::Env,
     onchain: &impl OnChain,
-) -> anyhow::Result<()> {
-    let file_exists = std::fs::metadata(&env.csv_path).is_ok();
-    debug!("Does {} exist? {}", env.csv_path, file_exists);
+) -> Result<usize, Error> {
+    env.check_dialect()?;
+    if env.split_by_event {
+        return update_trades_csv_by_event(env, onchain).await;
+    }
+
+    let csv_path = env.output_path();
+    if env.seen_set && output::is_stdout(&csv_path) {
+        return Err(Error::Config(
+            "Can't keep a seen-set of trades written to stdout".to_string(),
+        ));
+    }
+    if env.max_file_size.is_some() && output::is_stdout(&csv_path) {
+        return Err(Error::Config(
+            "Can't split trades written to stdout into parts".to_string(),
+        ));
+    }
+    // Overwriting starts a fresh file, so any saved trades are disregarded.
+    let file_exists = !env.overwrite
+        && !output::is_stdout(&csv_path)
+        && std::fs::metadata(&csv_path).is_ok();
+    debug!("Does {csv_path} exist? {file_exists}");
+    if file_exists && meta::read(&csv_path)?.newest_first {
+        return Err(Error::Config(format!(
+            "{csv_path} was written newest first with --reverse, so it can \
+            only be appended to once sorted"
+        )));
+    }
+    let columns = match file_exists {
+        true => saved_columns(&csv_path, env)?,
+        false => env.csv_columns(),
+    };
+
+    if let Some(max_reorg_depth) = env.max_reorg_depth.filter(|_| file_exists) {
+        drop_reorged_trades(env, onchain, max_reorg_depth).await?;
+    }
 
     let start_block = get_start_block(env, onchain).await?;
-    info!("Starting trade collection from block {start_block}");
-    let latest_block = onchain.get_block_number().await?;
-    info!("Latest block is {latest_block}");
+    let (start_block, latest_block) =
+        confirmed_scan_range(env, onchain, start_block).await?;
 
-    let csv_file = std::fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&env.csv_path)
-        .unwrap();
+    // The keys of trades saved before this run. Its own batches never
+    // overlap, so the trades it writes needn't be added.
+    let saved_trade_keys = if env.seen_set {
+        read_seen_trade_keys(env, file_exists).await?
+    } else if env.reorg_safety_margin > 0 && file_exists {
+        read_saved_trade_keys(&csv_path, start_block).await?
+    } else {
+        HashSet::new()
+    };
 
-    let mut csv_writer =
-        csv::WriterBuilder::new().has_headers(false).from_writer(csv_file);
-    debug!("Set up CSV writer for {}", env.csv_path);
+    if !output::is_stdout(&csv_path) {
+        meta::ensure(&csv_path, file_exists, &CsvMeta::from_env(env))?;
+        if !file_exists {
+            progress::reset(&csv_path)?;
+        }
+    }
 
-    if !file_exists {
-        csv_writer.write_record([
-            "timestamp",
-            "tx_origin",
-            "tx_hash",
-            "event",
-        ])?;
-        debug!("Wrote headers to {}", env.csv_path);
-    }
-
-    info!("Fetching trades from blocks {start_block} to {latest_block}");
-    for block_batch_start in
-        (start_block..latest_block).step_by(env.blocks_per_log_request as usize)
-    {
-        let block_batch_end = block_batch_start + env.blocks_per_log_request;
-        process_block_batch(
-            &mut csv_writer,
-            onchain,
-            block_batch_start,
-            block_batch_end,
+    // A CSV file split into parts is appended to in its last one.
+    let part = match env.max_file_size {
+        Some(_) if file_exists => parts::last_part(&csv_path),
+        _ => 0,
+    };
+    let part_path = parts::part_path(&csv_path, part);
+    if file_exists {
+        output::drop_partial_row(&part_path)?;
+    }
+    let mut part_size = match file_exists {
+        true => std::fs::metadata(&part_path)?.len(),
+        false => 0,
+    };
+
+    // Overwriting writes to a temporary file that only replaces the CSV once
+    // collection stops, so a failed run leaves the previous file intact.
+    let (replacement_path, csv_file) =
+        if env.overwrite && !output::is_stdout(&csv_path) {
+            let (tmp_path, tmp_file) = output::create_replacement(&csv_path)?;
+            (Some(tmp_path), tmp_file)
+        } else {
+            (None, output::open_append(&part_path)?)
+        };
+
+    let mut writer = BufWriter::with_capacity(CSV_BUFFER_CAPACITY, csv_file);
+    debug!("Set up CSV writer for {part_path}");
+
+    let headers = match env.csv_no_headers {
+        true => vec![],
+        false => serialize_headers(env.dialect, &columns)?,
+    };
+    if !file_exists && !headers.is_empty() {
+        writer.write_all(&headers)?;
+        part_size += headers.len() as u64;
+        debug!("Wrote headers to {csv_path}");
+    }
+
+    let csv_file = CsvFile {
+        path: csv_path,
+        writer,
+        replacement_path,
+        columns,
+        headers,
+        part,
+        part_size,
+        unrecorded_keys: vec![],
+    };
+    write_scanned_trades(
+        env,
+        onchain,
+        csv_file,
+        &saved_trade_keys,
+        start_block,
+        latest_block,
+    )
+    .await
+}
+
+/// The CSV output of [`write_scanned_trades`], which picks the trades of
+/// each fetched batch that belong in it and writes them.
+trait TradeSink {
+    /// Keep the trades of a batch that are written to the output, before
+    /// they're posted to the webhook.
+    fn select(&self, trades: Vec<Trade>) -> Vec<Trade>;
+
+    /// Write the selected trades of a batch.
+    fn write(
+        &mut self,
+        env: &env::Env,
+        trades: Vec<Trade>,
+    ) -> Result<(), Error>;
+
+    /// Flush the trades written so far and record the blocks scanned from
+    /// the given start block.
+    fn checkpoint(
+        &mut self,
+        start_block: BlockNumber,
+        scanned_block: Option<BlockNumber>,
+    ) -> Result<(), Error>;
+
+    /// Flush and close the output, recording the blocks scanned from the
+    /// given start block.
+    fn finish(
+        self,
+        start_block: BlockNumber,
+        scanned_block: Option<BlockNumber>,
+    ) -> Result<(), Error>;
+
+    /// The paths of the CSV files written to.
+    fn paths(&self) -> Vec<String>;
+}
+
+/// The single CSV file trades are written to unless they're split by event,
+/// itself split into parts with `--max-file-size`.
+struct CsvFile {
+    path: String,
+    writer: BufWriter<Box<dyn Write + Send>>,
+    /// The temporary file written to instead when overwriting, which
+    /// replaces the CSV file once collection stops.
+    replacement_path: Option<String>,
+    columns: Vec<String>,
+    /// The header row each part starts with, empty with `--csv-no-headers`.
+    headers: Vec<u8>,
+    part: u32,
+    part_size: u64,
+    /// The keys of the trades written since the last checkpoint, which are
+    /// only added to the seen-set file once the trades are flushed.
+    unrecorded_keys: Vec<TradeKey>,
+}
+
+impl TradeSink for CsvFile {
+    fn select(&self, trades: Vec<Trade>) -> Vec<Trade> {
+        trades
+    }
+
+    fn write(
+        &mut self,
+        env: &env::Env,
+        trades: Vec<Trade>,
+    ) -> Result<(), Error> {
+        let rows = serialize_batch(&trades, env.dialect, &self.columns)?;
+        // A part that has trades is only continued if the batch fits, so
+        // only a single batch can exceed the limit.
+        if env.max_file_size.is_some_and(|max_file_size| {
+            !rows.is_empty()
+                && self.part_size > self.headers.len() as u64
+                && self.part_size + rows.len() as u64 > max_file_size
+        }) {
+            self.part += 1;
+            start_part(&mut self.writer, &self.path, self.part, &self.headers)?;
+            self.part_size = self.headers.len() as u64;
+        }
+        self.writer.write_all(&rows)?;
+        self.part_size += rows.len() as u64;
+
+        if env.seen_set {
+            self.unrecorded_keys.extend(trades.iter().map(trade_key));
+        }
+
+        Ok(())
+    }
+
+    fn checkpoint(
+        &mut self,
+        start_block: BlockNumber,
+        scanned_block: Option<BlockNumber>,
+    ) -> Result<(), Error> {
+        self.writer.flush()?;
+        // The checkpoint of a replacement is only saved once it's renamed
+        // over the CSV it describes.
+        if self.replacement_path.is_none() {
+            save_checkpoint(
+                &self.path,
+                start_block,
+                scanned_block,
+                &mut self.unrecorded_keys,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn finish(
+        mut self,
+        start_block: BlockNumber,
+        scanned_block: Option<BlockNumber>,
+    ) -> Result<(), Error> {
+        finish_output(
+            self.writer,
+            self.replacement_path.as_deref(),
+            &self.path,
+        )?;
+        save_checkpoint(
+            &self.path,
+            start_block,
+            scanned_block,
+            &mut self.unrecorded_keys,
         )
-        .await?;
     }
 
-    Ok(())
+    fn paths(&self) -> Vec<String> {
+        vec![self.path.clone()]
+    }
 }
 
-async fn read_trades_csv(env: &env::Env) -> anyhow::Result<Vec<Trade>> {
-    let mut csv_reader =
-        csv::ReaderBuilder::new().has_headers(true).from_path(&env.csv_path)?;
-    let saved_trades: Vec<Trade> =
-        csv_reader.deserialize().collect::<Result<_, _>>()?;
-    info!("Found {} saved trades", saved_trades.len());
-    Ok(saved_trades)
+/// Narrow the blocks from the given start block to the head of the chain
+/// down with [`scan_range`], and ask for confirmation before scanning more
+/// of them than `--confirm-before-rescan` allows.
+async fn confirmed_scan_range(
+    env: &env::Env,
+    onchain: &impl OnChain,
+    start_block: BlockNumber,
+) -> Result<(BlockNumber, BlockNumber), Error> {
+    let chain_head = onchain.get_block_number_by_tag(env.head_tag).await?;
+    let (start_block, latest_block) = scan_range(
+        env,
+        onchain,
+        start_block,
+        chain_head.saturating_sub(env.reorg_safety_margin),
+    )
+    .await?;
+    info!("Starting trade collection from block {start_block}");
+    info!("Latest block is {chain_head}, scanning up to {latest_block}");
+
+    let block_count = (latest_block + 1).saturating_sub(start_block);
+    if env
+        .confirm_before_rescan
+        .is_some_and(|max_blocks| !env.yes && block_count > max_blocks)
+    {
+        let stdin = std::io::stdin();
+        let input = stdin.is_terminal().then(|| stdin.lock());
+        confirm_scan(start_block, latest_block, input)?;
+    }
+
+    Ok((start_block, latest_block))
 }
 
-/// Determine the starting block for fetching event logs from.
-async fn get_start_block(
+/// Fetch the trades of the given blocks in batches and write them to the
+/// given output, polling for new blocks with `--poll-interval`, until the
+/// blocks run out or collection is interrupted. Returns the number of new
+/// trades written.
+async fn write_scanned_trades(
     env: &env::Env,
     onchain: &impl OnChain,
-) -> anyhow::Result<BlockNumber> {
-    if std::fs::metadata(&env.csv_path).is_err() {
-        return Ok(env.orderbookv4_deployment_block);
+    mut sink: impl TradeSink,
+    saved_trade_keys: &HashSet<TradeKey>,
+    start_block: BlockNumber,
+    latest_block: BlockNumber,
+) -> Result<usize, Error> {
+    let webhook = env.webhook_url.as_deref().map(Webhook::new).transpose()?;
+    let mut coverage = env.audit.then(BlockCoverage::default);
+    let mut token_cache = env
+        .resolve_tokens
+        .then(|| TokenCache::from_env(env).persisted(&env.output_path()))
+        .transpose()?;
+
+    let shutdown = tokio::signal::ctrl_c();
+    tokio::pin!(shutdown);
+
+    let spawn_watchdog = || {
+        env.stall_timeout.map(|stall_timeout| {
+            Watchdog::spawn(Duration::from_secs(stall_timeout), || {
+                error!("Collection stalled, aborting");
+                std::process::exit(1);
+            })
+        })
+    };
+    let mut watchdog = spawn_watchdog();
+
+    let mut scanned_block = None;
+    let mut trade_count = 0;
+    let mut scan_start = start_block;
+    let mut latest_block = latest_block;
+    // Blocks arriving while polling are fetched one at a time, pinned by
+    // their hashes, see `FetchOptions::by_block_hash`.
+    let mut blocks_per_batch = env.blocks_per_log_request;
+
+    loop {
+        info!("Fetching trades from blocks {scan_start} to {latest_block}");
+        // Batches are fetched ahead of writing them into a bounded channel,
+        // so that a slow webhook holds up fetching rather than buffering
+        // trades without bound.
+        let (batch_sender, batch_receiver) =
+            mpsc::channel(env.write_buffer_size as usize);
+        let (fetch_end, written) = tokio::join!(
+            fetch_batches(
+                onchain,
+                env,
+                token_cache.as_mut(),
+                saved_trade_keys,
+                scan_order(
+                    block_batches(scan_start, latest_block, blocks_per_batch),
+                    env.reverse
+                ),
+                &mut shutdown,
+                batch_sender,
+            ),
+            async {
+                let mut batch_receiver = batch_receiver;
+                while let Some(batch) = batch_receiver.recv().await {
+                    let FetchedBatch {
+                        index,
+                        start_block: batch_start,
+                        end_block: batch_end,
+                        trades,
+                    } = batch;
+                    if let Some(coverage) = coverage.as_mut() {
+                        coverage.record(batch_start, batch_end);
+                    }
+
+                    let trades = sink.select(trades);
+                    // Post before writing to the CSV so that trades the
+                    // webhook never received are collected again on the next
+                    // run.
+                    if let Some(webhook) = webhook.as_ref() {
+                        webhook.send(&trades).await?;
+                    }
+
+                    trade_count += trades.len();
+                    sink.write(env, trades)?;
+
+                    if let Some(watchdog) = watchdog.as_ref() {
+                        watchdog.record_progress();
+                    }
+
+                    // Newest-first batches only cover the range once all
+                    // of them are written.
+                    if !env.reverse {
+                        scanned_block = Some(batch_end);
+                    }
+
+                    if (index as u64 + 1).is_multiple_of(env.flush_every) {
+                        sink.checkpoint(start_block, scanned_block)?;
+                        debug!("Flushed trades up to block {batch_end}");
+                    }
+                }
+
+                Ok::<_, Error>(())
+            }
+        );
+        written?;
+
+        match fetch_end? {
+            FetchEnd::Done if env.reverse => {
+                scanned_block = Some(latest_block);
+            }
+            FetchEnd::Done => {}
+            FetchEnd::Deferred(deferred_block) => {
+                info!(
+                    "Left the trades from block {deferred_block} on until \
+                    they have {} confirmations",
+                    env.min_confirmations
+                );
+                latest_block = deferred_block - 1;
+            }
+            FetchEnd::Interrupted(block_batch_start) => {
+                sink.finish(start_block, scanned_block)?;
+                warn!("Interrupted, stopped before block {block_batch_start}");
+                return Ok(trade_count);
+            }
+            FetchEnd::OutOfBudget { rpc_calls, end_block } => {
+                sink.finish(start_block, scanned_block)?;
+                warn!(
+                    "Made {rpc_calls} RPC calls, which exhausts the budget, \
+                    stopped after block {end_block}"
+                );
+                return Ok(trade_count);
+            }
+        }
+
+        let Some(poll_interval) = env.poll_interval else {
+            break;
+        };
+
+        // Trades of this pass reach the CSV file before waiting for more.
+        sink.checkpoint(start_block, scanned_block)?;
+        // Waiting for new blocks isn't a stall.
+        drop(watchdog.take());
+        debug!("Polling for new blocks in {poll_interval} seconds");
+
+        let interrupted = tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(poll_interval)) => false,
+            _ = &mut shutdown => true,
+        };
+        if interrupted {
+            sink.finish(start_block, scanned_block)?;
+            warn!("Interrupted while polling after block {latest_block}");
+            return Ok(trade_count);
+        }
+
+        watchdog = spawn_watchdog();
+        scan_start = latest_block + 1;
+        blocks_per_batch = 1;
+        let chain_head = onchain.get_block_number_by_tag(env.head_tag).await?;
+        latest_block = chain_head
+            .saturating_sub(env.reorg_safety_margin)
+            .max(latest_block);
+        debug!("Latest block is {chain_head}, scanning up to {latest_block}");
     }
 
-    let saved_trades = read_trades_csv(env).await?;
-    let latest_trade = saved_trades.last();
-    if latest_trade.is_none() {
-        return Ok(env.orderbookv4_deployment_block);
+    let paths = sink.paths();
+    sink.finish(start_block, scanned_block)?;
+
+    if env.verify_sorted {
+        for path in &paths {
+            if output::is_stdout(path) {
+                warn!("Skipping the sort check of trades written to stdout");
+                continue;
+            }
+            let trade_count = audit::verify_sorted(stream_trades_at(path)?)?;
+            info!(
+                "Sort check passed: {trade_count} trades in {path} are in \
+                order"
+            );
+        }
     }
 
-    let latest_trade = latest_trade.unwrap();
-    debug!("Latest saved trade: {latest_trade:?}");
+    if let Some(coverage) = coverage {
+        coverage.verify(start_block, latest_block)?;
+        info!(
+            "Audit passed: blocks {start_block} to {latest_block} were each \
+            queried once"
+        );
+    }
 
-    let latest_trade_tx_hash = latest_trade.tx_hash;
-    debug!("Fetching transaction with hash {latest_trade_tx_hash}");
-    let start_block = onchain
-        .get_block_number_by_tx_hash(latest_trade_tx_hash)
-        .await?
-        .unwrap_or(env.orderbookv4_deployment_block);
+    let files = match paths.len() {
+        1 => String::new(),
+        file_count => format!(" to {file_count} files"),
+    };
+    info!(
+        "Wrote {trade_count} new trades{files} and rejected {} foreign logs",
+        onchain.foreign_logs()
+    );
 
-    Ok(start_block)
+    Ok(trade_count)
 }
 
-/// A trade with all required fields that combines partial trades
-/// enriched with block data.
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
-pub struct Trade {
-    timestamp: u64,
-    tx_origin: Address,
-    tx_hash: FixedBytes<32>,
-    event: TradeEvent,
+/// The CSV file the trades of one event are written to with
+/// `--split-by-event`.
+struct EventFile {
+    path: String,
+    /// The block this file resumes from. Trades of earlier blocks, which the
+    /// scan covers for the other files, are already saved in it.
+    start_block: BlockNumber,
+    writer: BufWriter<Box<dyn Write + Send>>,
+    columns: Vec<String>,
 }
 
-/// Collect and store a batch of trade logs from the given block range.
-async fn process_block_batch(
-    csv_writer: &mut csv::Writer<std::fs::File>,
+/// The CSV files of each collected event with `--split-by-event`.
+struct EventFiles {
+    files: BTreeMap<TradeEvent, EventFile>,
+}
+
+impl TradeSink for EventFiles {
+    fn select(&self, trades: Vec<Trade>) -> Vec<Trade> {
+        trades
+            .into_iter()
+            .filter(|trade| {
+                self.files
+                    .get(&trade.event)
+                    .is_some_and(|file| trade.block_number >= file.start_block)
+            })
+            .collect()
+    }
+
+    fn write(
+        &mut self,
+        env: &env::Env,
+        trades: Vec<Trade>,
+    ) -> Result<(), Error> {
+        let mut event_trades = BTreeMap::<_, Vec<Trade>>::new();
+        for trade in trades {
+            event_trades.entry(trade.event.clone()).or_default().push(trade);
+        }
+        for (event, trades) in event_trades {
+            if let Some(file) = self.files.get_mut(&event) {
+                write_batch(
+                    &mut file.writer,
+                    &trades,
+                    env.dialect,
+                    &file.columns,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn checkpoint(
+        &mut self,
+        start_block: BlockNumber,
+        scanned_block: Option<BlockNumber>,
+    ) -> Result<(), Error> {
+        for file in self.files.values_mut() {
+            file.writer.flush()?;
+            save_checkpoint(
+                &file.path,
+                start_block,
+                scanned_block,
+                &mut vec![],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn finish(
+        self,
+        start_block: BlockNumber,
+        scanned_block: Option<BlockNumber>,
+    ) -> Result<(), Error> {
+        for file in self.files.into_values() {
+            finish_output(file.writer, None, &file.path)?;
+            save_checkpoint(
+                &file.path,
+                start_block,
+                scanned_block,
+                &mut vec![],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn paths(&self) -> Vec<String> {
+        self.files.values().map(|file| file.path.clone()).collect()
+    }
+}
+
+/// Create or append to a CSV file per collected event with
+/// `--split-by-event`, returning the number of new trades written to all of
+/// them. The blocks are scanned once from the earliest block any of the
+/// files resumes from, so that none of them misses trades.
+async fn update_trades_csv_by_event(
+    env: &env::Env,
     onchain: &impl OnChain,
-    start_block: u64,
-    end_block: u64,
-) -> anyhow::Result<()> {
-    debug!("Fetching a batch of trade logs from blocks {start_block} to {end_block}");
+) -> Result<usize, Error> {
+    let csv_path = env.output_path();
+    if output::is_stdout(&csv_path) {
+        return Err(Error::Config(
+            "Can't split trades written to stdout by event".to_string(),
+        ));
+    }
 
-    let clearv2_trades =
-        onchain.fetch_clearv2_trades(start_block, end_block).await?;
+    // --from-block and --since-tx override the resume point of every file.
+    let start_override = if env.from_block.is_some() || env.since_tx.is_some() {
+        Some(get_start_block(env, onchain).await?)
+    } else {
+        None
+    };
 
-    let takeorderv2_trades =
-        onchain.fetch_takeorderv2_trades(start_block, end_block).await?;
+    let mut files = BTreeMap::new();
+    // The keys of the trades saved in the files within the reorg safety
+    // margin, which is rescanned.
+    let mut saved_trade_keys = HashSet::new();
+    for event in split_events(env) {
+        let path = event_path(&csv_path, &event);
+        let file_exists = std::fs::metadata(&path).is_ok();
+        let start_block = match start_override {
+            Some(start_block) => start_block,
+            None => {
+                let deployment_block =
+                    get_deployment_block(env, onchain).await?;
+                get_csv_start_block(env, onchain, &path, deployment_block)
+                    .await?
+            }
+        };
+        debug!("Resuming {path} from block {start_block}");
 
-    let block_bodies = onchain
-        .fetch_block_bodies(
-            clearv2_trades
-                .keys()
-                .copied()
-                .chain(takeorderv2_trades.keys().copied()),
+        let columns = match file_exists {
+            true => saved_columns(&path, env)?,
+            false => env.csv_columns(),
+        };
+        if env.reorg_safety_margin > 0 && file_exists {
+            saved_trade_keys
+                .extend(read_saved_trade_keys(&path, start_block).await?);
+        }
+
+        meta::ensure(&path, file_exists, &CsvMeta::from_env(env))?;
+        if file_exists {
+            output::drop_partial_row(&path)?;
+        } else {
+            progress::reset(&path)?;
+        }
+
+        let mut writer = BufWriter::with_capacity(
+            CSV_BUFFER_CAPACITY,
+            output::open_append(&path)?,
+        );
+        if !file_exists && !env.csv_no_headers {
+            writer.write_all(&serialize_headers(env.dialect, &columns)?)?;
+            debug!("Wrote headers to {path}");
+        }
+
+        files.insert(event, EventFile { path, start_block, writer, columns });
+    }
+
+    let Some(start_block) = files.values().map(|file| file.start_block).min()
+    else {
+        return Err(Error::Config(
+            "At least one event is required".to_string(),
+        ));
+    };
+    let (start_block, latest_block) =
+        confirmed_scan_range(env, onchain, start_block).await?;
+
+    write_scanned_trades(
+        env,
+        onchain,
+        EventFiles { files },
+        &saved_trade_keys,
+        start_block,
+        latest_block,
+    )
+    .await
+}
+
+/// The events whose trades get a CSV file of their own with
+/// `--split-by-event`: the collected events, and failed trades with
+/// `--include-failed-txs`.
+fn split_events(env: &env::Env) -> Vec<TradeEvent> {
+    let mut events = env.abi_event_allowlist.clone();
+    if env.include_failed_txs {
+        events.push(TradeEvent::FailedTrade);
+    }
+    events
+}
+
+/// The path of the CSV file of the given event's trades with
+/// `--split-by-event`, the event's name in lowercase being inserted before
+/// the extension of the CSV path, e.g. `trades.clearv2.csv`.
+fn event_path(csv_path: &str, event: &TradeEvent) -> String {
+    output::insert_infix(csv_path, &event.name().to_lowercase())
+}
+
+/// Insert all new trades from the deployed OrderbookV4 contract into the
+/// Postgres database at `--postgres-url`, resuming after the block of the
+/// latest saved trade, and return the number of trades inserted. Each batch
+/// is inserted in one transaction, so an interrupted run never leaves part
+/// of a batch behind.
+pub async fn update_trades_postgres(
+    env: &env::Env,
+    onchain: &impl OnChain,
+) -> Result<usize, Error> {
+    let postgres_url = env.postgres_url.as_deref().ok_or_else(|| {
+        Error::Config("Writing to Postgres requires --postgres-url".to_string())
+    })?;
+    let mut client =
+        postgres::connect(postgres_url, env.compact_addresses).await?;
+
+    let start_block = get_postgres_start_block(env, onchain, &client).await?;
+    let chain_head = onchain.get_block_number_by_tag(env.head_tag).await?;
+    let (start_block, latest_block) = scan_range(
+        env,
+        onchain,
+        start_block,
+        chain_head.saturating_sub(env.reorg_safety_margin),
+    )
+    .await?;
+    info!("Inserting trades from blocks {start_block} to {latest_block}");
+
+    let mut token_cache = env.resolve_tokens.then(|| TokenCache::from_env(env));
+    let shutdown = tokio::signal::ctrl_c();
+    tokio::pin!(shutdown);
+
+    // Trades saved by earlier runs are skipped by the unique constraint.
+    let saved_trade_keys = HashSet::new();
+    let (batch_sender, batch_receiver) =
+        mpsc::channel(env.write_buffer_size as usize);
+    let (fetch_end, inserted) = tokio::join!(
+        fetch_batches(
+            onchain,
+            env,
+            token_cache.as_mut(),
+            &saved_trade_keys,
+            block_batches(
+                start_block,
+                latest_block,
+                env.blocks_per_log_request
+            ),
+            &mut shutdown,
+            batch_sender,
+        ),
+        async {
+            let mut batch_receiver = batch_receiver;
+            let mut trade_count = 0;
+            while let Some(batch) = batch_receiver.recv().await {
+                let inserted = postgres::insert(
+                    &mut client,
+                    &batch.trades,
+                    env.compact_addresses,
+                )
+                .await?;
+                trade_count += inserted as usize;
+                debug!(
+                    "Inserted {inserted} trades up to block {}",
+                    batch.end_block
+                );
+            }
+
+            Ok::<_, Error>(trade_count)
+        }
+    );
+    let trade_count = inserted?;
+
+    match fetch_end? {
+        FetchEnd::Done => {}
+        FetchEnd::Deferred(deferred_block) => {
+            info!(
+                "Left the trades from block {deferred_block} on until they \
+                have {} confirmations",
+                env.min_confirmations
+            );
+        }
+        FetchEnd::Interrupted(block_batch_start) => {
+            warn!("Interrupted, stopped before block {block_batch_start}");
+        }
+        FetchEnd::OutOfBudget { rpc_calls, end_block } => {
+            warn!(
+                "Made {rpc_calls} RPC calls, which exhausts the budget, \
+                stopped after block {end_block}"
+            );
+        }
+    }
+
+    info!(
+        "Inserted {trade_count} new trades and rejected {} foreign logs",
+        onchain.foreign_logs()
+    );
+
+    Ok(trade_count)
+}
+
+/// Get the block to insert trades into Postgres from, after the block of the
+/// latest saved trade unless the start block is given.
+async fn get_postgres_start_block(
+    env: &env::Env,
+    onchain: &impl OnChain,
+    client: &tokio_postgres::Client,
+) -> Result<BlockNumber, Error> {
+    let latest_trade_block = postgres::latest_block(client).await?;
+
+    match (env.from_block, latest_trade_block) {
+        (Some(from_block), _) => Ok(from_block),
+        (None, Some(latest_trade_block)) => {
+            debug!(
+                "Resuming after the latest saved trade in block \
+                {latest_trade_block}"
+            );
+            Ok((latest_trade_block + 1).saturating_sub(env.reorg_safety_margin))
+        }
+        (None, None) => get_deployment_block(env, onchain).await,
+    }
+}
+
+/// Estimate the JSON-RPC requests of collecting the trades a run with the
+/// given configuration would collect, i.e. from the same start block up to
+/// the chain head. The share of blocks with trades is sampled from the last
+/// batch, whose logs are queried for that.
+pub async fn estimate_cost(
+    env: &env::Env,
+    onchain: &impl OnChain,
+) -> Result<CostEstimate, Error> {
+    let start_block = match env.postgres_url.as_deref() {
+        Some(postgres_url) => {
+            let client =
+                postgres::connect(postgres_url, env.compact_addresses).await?;
+            get_postgres_start_block(env, onchain, &client).await?
+        }
+        None => get_start_block(env, onchain).await?,
+    };
+    let chain_head = onchain.get_block_number_by_tag(env.head_tag).await?;
+    let (start_block, latest_block) = scan_range(
+        env,
+        onchain,
+        start_block,
+        chain_head.saturating_sub(env.reorg_safety_margin),
+    )
+    .await?;
+
+    let mut sampled_trade_blocks = BTreeSet::new();
+    let mut sampled_blocks = 0;
+    if let Some((sample_start, sample_end)) =
+        block_batches(start_block, latest_block, env.blocks_per_log_request)
+            .last()
+    {
+        debug!(
+            "Sampling blocks with trades from {sample_start} to {sample_end}"
+        );
+        for contract_address in onchain.contract_addresses() {
+            for event in &env.abi_event_allowlist {
+                let trades = fetch_event_trades(
+                    onchain,
+                    contract_address,
+                    event.clone(),
+                    false,
+                    sample_start,
+                    sample_end,
+                    None,
+                )
+                .await?;
+                sampled_trade_blocks.extend(trades.into_keys());
+            }
+        }
+        sampled_blocks = sample_end - sample_start + 1;
+    }
+
+    let events = env.abi_event_allowlist.iter().collect::<BTreeSet<_>>();
+    // Raw log counts are verified with one query per event.
+    let log_queries_per_batch = (onchain.contract_addresses().len()
+        * (log_queries(&events).len()
+            + if env.verify_raw { events.len() } else { 0 }))
+        as u64;
+
+    Ok(CostEstimate::new(
+        start_block,
+        latest_block,
+        env.blocks_per_log_request,
+        log_queries_per_batch,
+        (sampled_trade_blocks.len() as u64, sampled_blocks),
+        env.include_failed_txs,
+    ))
+}
+
+/// The number of blocks `reenrich` fetches between checkpoints.
+const REENRICH_CHECKPOINT_BLOCKS: usize = 100;
+
+/// Rewrite the CSV file with the enrichment of the current schema, refetching
+/// only the block bodies and token metadata of the saved trades instead of
+/// querying event logs again.
+///
+/// The blocks fetched so far are checkpointed next to the CSV file and the
+/// resolved tokens persisted, so that an interrupted run is resumed by the
+/// next one without fetching them again.
+pub async fn reenrich_trades_csv(
+    env: &env::Env,
+    onchain: &impl OnChain,
+) -> Result<(), Error> {
+    let csv_path = env.output_path();
+    if output::is_stdout(&csv_path) {
+        return Err(Error::Config(
+            "Can't re-enrich trades written to stdout".to_string(),
+        ));
+    }
+    if env.no_enrich {
+        return Err(Error::Config(
+            "Re-enriching fills in the columns --no-enrich leaves out"
+                .to_string(),
+        ));
+    }
+    env.check_dialect()?;
+    env.check_key_columns("Re-enriching")?;
+
+    // The trades of a file collected with --no-enrich have no block columns
+    // to match, and it takes on those configured once they're filled in.
+    // The metadata file is only written once the trades have been read, so
+    // that a file that can't be re-enriched is left as it was.
+    let trades = read_trades_csv(env).await?;
+    let unenriched = meta::read(&csv_path)?.unenriched;
+    if !unenriched {
+        meta::ensure(&csv_path, true, &CsvMeta::from_env(env))?;
+    }
+    let trades = refetch_legacy_trades(env, onchain, trades).await?;
+
+    let mut tx_hashes: BTreeMap<BlockNumber, HashSet<FixedBytes<32>>> =
+        BTreeMap::new();
+    for trade in &trades {
+        tx_hashes.entry(trade.block_number).or_default().insert(trade.tx_hash);
+    }
+
+    // A checkpointed block lacking a transaction of the saved trades was
+    // fetched before they were collected, so it's fetched again.
+    let mut block_bodies = enrich_cache::read_blocks(&csv_path)?;
+    block_bodies.retain(|block_number, block| {
+        tx_hashes.get(block_number).is_some_and(|tx_hashes| {
+            tx_hashes.iter().all(|tx_hash| {
+                block.transactions.iter().any(|tx| tx.hash == *tx_hash)
+            })
+        })
+    });
+    let remaining_blocks: Vec<BlockNumber> = tx_hashes
+        .keys()
+        .copied()
+        .filter(|block_number| !block_bodies.contains_key(block_number))
+        .collect();
+    info!(
+        "Re-enriching {} trades from {} blocks, {} of them fetched by an \
+        earlier run",
+        trades.len(),
+        tx_hashes.len(),
+        block_bodies.len()
+    );
+
+    for chunk in remaining_blocks.chunks(REENRICH_CHECKPOINT_BLOCKS) {
+        let mut fetched =
+            onchain.fetch_block_bodies(chunk.iter().copied()).await?;
+        // Only the transactions of trades are needed, which keeps the
+        // checkpoint small.
+        for (block_number, block) in fetched.iter_mut() {
+            block.transactions.retain(|tx| {
+                tx_hashes
+                    .get(block_number)
+                    .is_some_and(|tx_hashes| tx_hashes.contains(&tx.hash))
+            });
+        }
+        enrich_cache::record_blocks(&csv_path, &fetched)?;
+        block_bodies.extend(fetched);
+    }
+
+    let mut trades =
+        compose::reenrich(trades, &block_bodies, env.timestamp_unit);
+
+    if env.resolve_tokens {
+        let mut token_cache = TokenCache::from_env(env).persisted(&csv_path)?;
+        for trade in trades.iter_mut() {
+            token_cache.enrich(onchain, trade).await?;
+        }
+    }
+
+    rewrite_trades_csv(&csv_path, trades, env)?;
+    if unenriched {
+        meta::record_enriched(&csv_path, &CsvMeta::from_env(env))?;
+    }
+    enrich_cache::reset_blocks(&csv_path)
+}
+
+/// Replace the trades saved before the block_number and log_index columns
+/// were added with freshly fetched ones, as they can't be re-enriched or told
+/// apart from rescanned trades without them. The block of each of their
+/// transactions is looked up and its trades fetched, and trades whose
+/// transaction isn't found are kept as they are.
+async fn refetch_legacy_trades(
+    env: &env::Env,
+    onchain: &impl OnChain,
+    trades: Vec<Trade>,
+) -> Result<Vec<Trade>, Error> {
+    let (legacy_trades, mut trades): (Vec<_>, Vec<_>) =
+        trades.into_iter().partition(|trade| trade.block_number == 0);
+    if legacy_trades.is_empty() {
+        return Ok(trades);
+    }
+
+    let tx_hashes: HashSet<FixedBytes<32>> =
+        legacy_trades.iter().map(|trade| trade.tx_hash).collect();
+    info!(
+        "Refetching the trades of {} transactions saved without their \
+        blocks",
+        tx_hashes.len()
+    );
+    let mut blocks = BTreeSet::new();
+    for tx_hash in &tx_hashes {
+        // The block after the transaction's is returned to resume from.
+        match onchain.get_block_number_by_tx_hash(*tx_hash).await? {
+            Some(block_number) => {
+                blocks.insert(block_number.saturating_sub(1));
+            }
+            None => warn!(
+                "Transaction {tx_hash} of a saved trade wasn't found, keeping \
+                its trades as they are"
+            ),
+        }
+    }
+
+    let mut refetched_tx_hashes = HashSet::new();
+    for block_number in blocks {
+        let block_trades = fetch_trades(
+            onchain,
+            &env.abi_event_allowlist,
+            FetchOptions::from_env(env),
+            block_number,
+            block_number,
         )
         .await?;
+        for trade in block_trades {
+            if tx_hashes.contains(&trade.tx_hash) {
+                refetched_tx_hashes.insert(trade.tx_hash);
+                trades.push(trade);
+            }
+        }
+    }
+    trades.extend(
+        legacy_trades
+            .into_iter()
+            .filter(|trade| !refetched_tx_hashes.contains(&trade.tx_hash)),
+    );
+    trades.sort_by(|a, b| sort_key(a).cmp(&sort_key(b)));
+
+    Ok(trades)
+}
+
+/// Package the CSV file, its metadata and progress files and a manifest with
+/// the chain, contracts, block range, row count and checksum of the trades
+/// into a `.tar.zst` archive next to the CSV file, returning the archive's
+/// path.
+pub async fn archive_trades_csv(
+    env: &env::Env,
+    chain_id: u64,
+) -> Result<String, Error> {
+    let csv_path = env.output_path();
+    if output::is_stdout(&csv_path) {
+        return Err(Error::Config(
+            "Can't archive trades written to stdout".to_string(),
+        ));
+    }
+    parts::ensure_unsplit(&csv_path)?;
+
+    let mut row_count = 0;
+    let mut first_block = None;
+    let mut last_block = meta::read(&csv_path)?.last_scanned_block;
+    for trade in stream_trades_csv(env)? {
+        let block_number = trade?.block_number;
+        row_count += 1;
+        first_block = first_block.or(Some(block_number));
+        last_block = last_block.max(Some(block_number));
+    }
+
+    let csv_file = std::path::Path::new(&csv_path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(&csv_path)
+        .to_string();
+    let manifest = archive::Manifest {
+        chain_id,
+        contract_addresses: env.orderbookv4_deployment_address.clone(),
+        csv_file,
+        sha256: archive::sha256(&csv_path)?,
+        row_count,
+        first_block,
+        last_block,
+    };
+
+    let sidecar_paths =
+        [meta::meta_path(&csv_path), progress::progress_path(&csv_path)];
+    let mut files = vec![csv_path.as_str()];
+    for sidecar_path in &sidecar_paths {
+        if std::fs::metadata(sidecar_path).is_ok() {
+            files.push(sidecar_path);
+        }
+    }
+
+    let archive_path = format!("{csv_path}.tar.zst");
+    archive::write(&archive_path, &files, &manifest)?;
+    info!("Archived {row_count} trades to {archive_path}");
 
-    let trades = compose::enrich_and_merge(
-        clearv2_trades,
-        takeorderv2_trades,
-        block_bodies,
+    Ok(archive_path)
+}
+
+/// Replay the saved trades in order as a synthetic live feed, waiting the gap
+/// between consecutive timestamps divided by `speed` before each trade. The
+/// trades are written to stdout in the configured dialect, or posted to the
+/// webhook one at a time if one is set. Returns the number of replayed
+/// trades.
+pub async fn replay_trades_csv(
+    env: &env::Env,
+    speed: f64,
+) -> Result<usize, Error> {
+    if output::is_stdout(&env.output_path()) {
+        return Err(Error::Config(
+            "Can't replay trades written to stdout".to_string(),
+        ));
+    }
+    env.check_dialect()?;
+
+    let webhook = env.webhook_url.as_deref().map(Webhook::new).transpose()?;
+    let trades = read_trades_csv(env).await?;
+    let mut stdout = std::io::stdout();
+    if webhook.is_none() && !env.csv_no_headers {
+        stdout.write_all(&serialize_headers(env.dialect, &env.columns)?)?;
+    }
+
+    let delays = replay::delays(
+        trades.iter().map(|trade| trade.timestamp),
+        env.timestamp_unit,
+        speed,
     );
+    for (trade, delay) in trades.iter().zip(delays) {
+        tokio::time::sleep(delay).await;
+        let trade = std::slice::from_ref(trade);
+        match &webhook {
+            Some(webhook) => webhook.send(trade).await?,
+            None => {
+                write_batch(&mut stdout, trade, env.dialect, &env.columns)?;
+                stdout.flush()?;
+            }
+        }
+    }
+    info!("Replayed {} trades", trades.len());
 
-    for trade in trades {
-        csv_writer.serialize(trade)?;
+    Ok(trades.len())
+}
+
+/// Rewrite the CSV file with its trades sorted by `(block_number,
+/// log_index)`, e.g. after overlapping runs interleaved their trades.
+pub async fn sort_trades_csv(env: &env::Env) -> Result<(), Error> {
+    let csv_path = env.output_path();
+    if output::is_stdout(&csv_path) {
+        return Err(Error::Config(
+            "Can't sort trades written to stdout".to_string(),
+        ));
     }
-    csv_writer.flush()?;
+    env.check_dialect()?;
+    meta::ensure(&csv_path, true, &CsvMeta::from_env(env))?;
 
-    Ok(())
+    let mut trades = read_trades_csv(env).await?;
+    trades.sort_by(|a, b| sort_key(a).cmp(&sort_key(b)));
+
+    rewrite_trades_csv(&csv_path, trades, env)?;
+    meta::record_sorted(&csv_path)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Combine the CSV files of the given number of shards collected with
+/// `--shard` into the CSV file, sorted like [`sort_trades_csv`] sorts them,
+/// and their progress files into its progress file, so that later runs
+/// resume after the blocks the shards scanned without gaps. Returns the
+/// number of merged trades.
+pub async fn merge_shards_csv(
+    env: &env::Env,
+    shard_count: u64,
+) -> Result<usize, Error> {
+    let csv_path = env.output_path();
+    if output::is_stdout(&csv_path) || env.shard.is_some() {
+        return Err(Error::Config(
+            "Shards are merged into the CSV file, without --shard".to_string(),
+        ));
+    }
+    if !env.overwrite && std::fs::metadata(&csv_path).is_ok() {
+        return Err(Error::Config(format!(
+            "{csv_path} already exists, merging shards into it needs \
+            --overwrite"
+        )));
+    }
+    env.check_dialect()?;
 
-    use env::Env;
-    use onchain::mock::MockChain;
+    let mut trades = vec![];
+    let mut scanned_ranges = vec![];
+    for index in 0..shard_count {
+        let shard_path =
+            env::Shard { index, count: shard_count }.path(&csv_path);
+        if std::fs::metadata(&shard_path).is_err() {
+            return Err(Error::Config(format!(
+                "Shard {index} of {shard_count} wasn't collected into \
+                {shard_path}"
+            )));
+        }
+        meta::ensure(&shard_path, true, &CsvMeta::from_env(env))?;
+
+        for part_path in parts::part_paths(&shard_path) {
+            for trade in stream_trades_file(&part_path)? {
+                trades.push(trade?);
+            }
+        }
+        scanned_ranges.extend(progress::read(&shard_path)?);
+    }
+    trades.sort_by(|a, b| sort_key(a).cmp(&sort_key(b)));
+    let trade_count = trades.len();
+
+    meta::ensure(&csv_path, false, &CsvMeta::from_env(env))?;
+    progress::reset(&csv_path)?;
+    seen::reset(&csv_path)?;
+    rewrite_trades_csv(&csv_path, trades, env)?;
+    meta::record_sorted(&csv_path)?;
+
+    let scanned_ranges = progress::merge(scanned_ranges);
+    for &(start_block, end_block) in &scanned_ranges {
+        progress::record(&csv_path, start_block, end_block)?;
+    }
+    if let Some(&(_, scanned_block)) = scanned_ranges.first() {
+        meta::record_scanned(&csv_path, scanned_block)?;
+    }
+    if let [(_, scanned_block), (next_block, _), ..] = scanned_ranges[..] {
+        warn!(
+            "The shards left blocks {} to {} unscanned, later runs resume \
+            from them",
+            scanned_block + 1,
+            next_block - 1
+        );
+    }
+    info!(
+        "Merged {trade_count} trades of {shard_count} shards into {csv_path}"
+    );
+
+    Ok(trade_count)
+}
+
+/// The key trades are ordered by in the CSV file.
+fn sort_key(
+    trade: &Trade,
+) -> (BlockNumber, u64, &TradeEvent, Option<ClearSide>) {
+    (trade.block_number, trade.log_index, &trade.event, trade.side)
+}
+
+/// Replace the CSV file at the given path with the given trades in the
+/// configured dialect, preceded by a header row unless disabled. They are
+/// written to a temporary file first so that the original is only replaced
+/// once all trades are written.
+fn rewrite_trades_csv(
+    csv_path: &str,
+    trades: Vec<Trade>,
+    env: &env::Env,
+) -> Result<(), Error> {
+    parts::ensure_unsplit(csv_path)?;
+    let (tmp_path, tmp_file) = output::create_replacement(csv_path)?;
+    let mut csv_writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .buffer_capacity(CSV_BUFFER_CAPACITY)
+        .from_writer(tmp_file);
+    let columns = env.csv_columns();
+    if !env.csv_no_headers {
+        csv_writer.write_record(headers(env.dialect, &columns))?;
+    }
+    for trade in &trades {
+        serialize_trade(&mut csv_writer, trade, env.dialect, &columns)?;
+    }
+    drop(csv_writer.into_inner().map_err(|err| err.into_error())?);
+
+    std::fs::rename(&tmp_path, csv_path)?;
+    info!("Rewrote {csv_path}");
+
+    Ok(())
+}
+
+/// Record the last block whose trades are all written in the CSV file's
+/// metadata, and the range of blocks scanned by this run in its progress
+/// file, so that the next run resumes after it even if the blocks up to it
+/// had no trades. The keys of the trades written since the last checkpoint
+/// are moved to the seen-set file.
+fn save_checkpoint(
+    csv_path: &str,
+    start_block: BlockNumber,
+    scanned_block: Option<BlockNumber>,
+    unrecorded_keys: &mut Vec<TradeKey>,
+) -> Result<(), Error> {
+    if !unrecorded_keys.is_empty() {
+        seen::record(csv_path, unrecorded_keys)?;
+        unrecorded_keys.clear();
+    }
+
+    match scanned_block {
+        Some(scanned_block) if !output::is_stdout(csv_path) => {
+            progress::record(csv_path, start_block, scanned_block)?;
+            meta::record_scanned(csv_path, scanned_block)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Flush and close the CSV output, finishing the last gzip member, and rename
+/// the temporary file written when overwriting over the CSV.
+fn finish_output(
+    mut csv_writer: BufWriter<Box<dyn Write + Send>>,
+    replacement_path: Option<&str>,
+    csv_path: &str,
+) -> Result<(), Error> {
+    csv_writer.flush()?;
+    drop(csv_writer.into_inner().map_err(|err| err.into_error())?);
+
+    if let Some(tmp_path) = replacement_path {
+        std::fs::rename(tmp_path, csv_path)?;
+        debug!("Replaced {csv_path} with {tmp_path}");
+    }
+
+    Ok(())
+}
+
+/// Flush the current part of a CSV file split with `--max-file-size` and
+/// continue writing in the part with the given number, starting with the
+/// given header row.
+fn start_part(
+    csv_writer: &mut BufWriter<Box<dyn Write + Send>>,
+    csv_path: &str,
+    part: u32,
+    headers: &[u8],
+) -> Result<(), Error> {
+    let part_path = parts::part_path(csv_path, part);
+    let part_writer = BufWriter::with_capacity(
+        CSV_BUFFER_CAPACITY,
+        output::open_append(&part_path)?,
+    );
+    finish_output(std::mem::replace(csv_writer, part_writer), None, csv_path)?;
+    csv_writer.write_all(headers)?;
+    info!("Continuing in {part_path}");
+
+    Ok(())
+}
+
+/// Append a batch of trades to the CSV output as a whole. The rows are
+/// serialized in memory first, so that a batch that fails to serialize leaves
+/// nothing behind, and then written in a single call rather than row by row.
+fn write_batch(
+    output: &mut impl Write,
+    trades: &[Trade],
+    dialect: Dialect,
+    columns: &[String],
+) -> Result<(), Error> {
+    output.write_all(&serialize_batch(trades, dialect, columns)?)?;
+
+    Ok(())
+}
+
+/// Serialize a batch of trades as CSV rows without a header row.
+fn serialize_batch(
+    trades: &[Trade],
+    dialect: Dialect,
+    columns: &[String],
+) -> Result<Vec<u8>, Error> {
+    let mut batch_writer =
+        csv::WriterBuilder::new().has_headers(false).from_writer(vec![]);
+    for trade in trades {
+        serialize_trade(&mut batch_writer, trade, dialect, columns)?;
+    }
+
+    Ok(batch_writer.into_inner().map_err(|err| err.into_error())?)
+}
+
+/// Serialize a trade as a CSV row in the given dialect, with only the given
+/// columns in their order if any are given.
+fn serialize_trade<W: Write>(
+    csv_writer: &mut csv::Writer<W>,
+    trade: &Trade,
+    dialect: Dialect,
+    columns: &[String],
+) -> Result<(), Error> {
+    if !columns.is_empty() {
+        csv_writer.write_record(select_columns(trade, dialect, columns)?)?;
+        return Ok(());
+    }
+
+    match dialect {
+        Dialect::Raw => csv_writer.serialize(trade)?,
+        Dialect::Dune => csv_writer.serialize(DuneTrade::from(trade))?,
+    }
+    Ok(())
+}
+
+/// The fields of the given columns of a trade in the given dialect, as
+/// written to the CSV file. The trade is serialized to a record of all its
+/// columns by name first, so each field is formatted as in the full row.
+fn select_columns(
+    trade: &Trade,
+    dialect: Dialect,
+    columns: &[String],
+) -> Result<Vec<String>, Error> {
+    let record = match dialect {
+        Dialect::Raw => serde_json::to_value(trade)?,
+        Dialect::Dune => serde_json::to_value(DuneTrade::from(trade))?,
+    };
+
+    Ok(columns
+        .iter()
+        .map(|column| match &record[column] {
+            serde_json::Value::Null => String::new(),
+            serde_json::Value::String(field) => field.clone(),
+            field => field.to_string(),
+        })
+        .collect())
+}
+
+/// The CSV header row of the given dialect, or the given columns if any.
+fn headers(dialect: Dialect, columns: &[String]) -> Vec<&str> {
+    if !columns.is_empty() {
+        return columns.iter().map(String::as_str).collect();
+    }
+
+    match dialect {
+        Dialect::Raw => CSV_HEADERS.to_vec(),
+        Dialect::Dune => DUNE_HEADERS.to_vec(),
+    }
+}
+
+/// Serialize the CSV header row of the given dialect, or of the given
+/// columns if any.
+fn serialize_headers(
+    dialect: Dialect,
+    columns: &[String],
+) -> Result<Vec<u8>, Error> {
+    let mut header_writer = csv::Writer::from_writer(vec![]);
+    header_writer.write_record(headers(dialect, columns))?;
+    Ok(header_writer.into_inner().map_err(|err| err.into_error())?)
+}
+
+/// The columns to append to the CSV file at the given path with, so that the
+/// appended rows line up with the saved ones: those configured, or those of
+/// its header row if it was written with others, e.g. by an earlier release
+/// before columns were added, which re-enriching migrates it from. Without a
+/// header row, the rows are only checked to have as many columns as those
+/// configured. Every part of a split file has the header row of the first.
+fn saved_columns(csv_path: &str, env: &env::Env) -> Result<Vec<String>, Error> {
+    let columns = env.csv_columns();
+    let headers = headers(env.dialect, &columns);
+    let mut records = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(output::open_read(csv_path)?)
+        .into_records();
+    let Some(record) = records.next().transpose()? else {
+        return Ok(columns);
+    };
+
+    let saved_headers = record.iter().collect::<Vec<_>>();
+    if saved_headers == headers {
+        return Ok(columns);
+    }
+    if is_header(&record) {
+        warn!(
+            "{csv_path} has the columns {}, which the trades are appended \
+            with rather than {}; rewrite it with the reenrich subcommand to \
+            migrate it to the configured columns",
+            saved_headers.join(","),
+            headers.join(",")
+        );
+        return Ok(saved_headers.into_iter().map(str::to_string).collect());
+    }
+    if record.len() != headers.len() {
+        return Err(Error::Config(format!(
+            "{csv_path} has rows of {} columns, not {}, so appended rows \
+            wouldn't line up with the saved ones. Append to it with the \
+            --columns it was written with",
+            record.len(),
+            headers.len()
+        )));
+    }
+
+    Ok(columns)
+}
+
+/// The columns that tell saved trades apart, which rescanning and
+/// re-enriching match them by.
+const KEY_COLUMNS: [&str; 5] =
+    ["tx_hash", "event", "contract_address", "block_number", "log_index"];
+
+/// The columns filled in from the blocks of the trades, which --no-enrich
+/// leaves out.
+const BLOCK_COLUMNS: [&str; 3] = ["timestamp", "tx_origin", "tx_type"];
+
+/// The CSV header row, matching the field order of [`Trade`].
+const CSV_HEADERS: [&str; 23] = [
+    "timestamp",
+    "tx_origin",
+    "tx_hash",
+    "event",
+    "contract_address",
+    "tx_type",
+    "input_token",
+    "output_token",
+    "input_amount",
+    "output_amount",
+    "input_symbol",
+    "output_symbol",
+    "input_amount_adjusted",
+    "output_amount_adjusted",
+    "block_number",
+    "log_index",
+    "order_owner",
+    "side",
+    "event_json",
+    "block_hash",
+    "log_index_in_tx",
+    "first_seen",
+    "counterparty_owner",
+];
+
+/// The size of the in-memory CSV buffer, large enough to hold many batches'
+/// worth of trades between flushes.
+const CSV_BUFFER_CAPACITY: usize = 1 << 20;
+
+/// Split the blocks from `start_block` to `latest_block` into consecutive
+/// batches of at most `blocks_per_batch` blocks. Both the input range and the
+/// returned batches are inclusive, matching `eth_getLogs`, so the final batch
+/// ends exactly at `latest_block` and no block is queried twice.
+///
+/// A range smaller than one batch is queried as a single batch of exactly
+/// `[start_block, latest_block]`: the batch size is capped to the range, which
+/// also keeps it from being truncated when stepping on 32-bit targets.
+fn block_batches(
+    start_block: BlockNumber,
+    latest_block: BlockNumber,
+    blocks_per_batch: u64,
+) -> impl Iterator<Item = (BlockNumber, BlockNumber)> {
+    let range_size = latest_block.saturating_sub(start_block).saturating_add(1);
+    let blocks_per_batch = blocks_per_batch.min(range_size);
+    let step = usize::try_from(blocks_per_batch).unwrap_or(usize::MAX);
+
+    (start_block..=latest_block).step_by(step).map(move |batch_start| {
+        let batch_end =
+            batch_start.saturating_add(blocks_per_batch - 1).min(latest_block);
+        (batch_start, batch_end)
+    })
+}
+
+/// The given block batches in the order they're scanned, newest first with
+/// `--reverse`.
+fn scan_order(
+    block_batches: impl Iterator<Item = (BlockNumber, BlockNumber)>,
+    reverse: bool,
+) -> impl Iterator<Item = (BlockNumber, BlockNumber)> {
+    match reverse {
+        true => {
+            Either::Left(block_batches.collect::<Vec<_>>().into_iter().rev())
+        }
+        false => Either::Right(block_batches),
+    }
+}
+
+/// Identifies a trade by its transaction hash and block-wide log index.
+type TradeKey = (FixedBytes<32>, u64);
+
+fn trade_key(trade: &Trade) -> TradeKey {
+    (trade.tx_hash, trade.log_index)
+}
+
+/// Read the keys of saved trades from `from_block` onwards, which get
+/// rescanned within the reorg safety margin and must not be written twice.
+async fn read_saved_trade_keys(
+    csv_path: &str,
+    from_block: BlockNumber,
+) -> Result<HashSet<TradeKey>, Error> {
+    let saved_trade_keys = read_trades_at(csv_path)?
+        .into_iter()
+        .filter(|trade| trade.block_number >= from_block)
+        .map(|trade| trade_key(&trade))
+        .collect::<HashSet<_>>();
+    debug!(
+        "Found {} saved trades from block {from_block} onwards",
+        saved_trade_keys.len()
+    );
+
+    Ok(saved_trade_keys)
+}
+
+/// Read the keys of all trades written to the CSV file from its seen-set
+/// file, seeding the file from the saved trades if there is none yet, e.g.
+/// the first time `--seen-set` is used with an existing CSV file. A fresh
+/// CSV file starts with an empty seen-set.
+async fn read_seen_trade_keys(
+    env: &env::Env,
+    file_exists: bool,
+) -> Result<HashSet<TradeKey>, Error> {
+    let csv_path = env.output_path();
+    if !file_exists {
+        seen::reset(&csv_path)?;
+        return Ok(HashSet::new());
+    }
+
+    if let Some(seen_keys) = seen::read(&csv_path)? {
+        debug!("Found {} trades in the seen-set", seen_keys.len());
+        return Ok(seen_keys);
+    }
+
+    let saved_trade_keys = read_saved_trade_keys(&csv_path, 0).await?;
+    seen::record(
+        &csv_path,
+        &saved_trade_keys.iter().copied().collect::<Vec<_>>(),
+    )?;
+    info!("Seeded the seen-set with {} saved trades", saved_trade_keys.len());
+
+    Ok(saved_trade_keys)
+}
+
+/// Compare the block hashes saved with the trades of the last
+/// `max_reorg_depth` blocks of the CSV file to the chain, and drop the saved
+/// trades from the first block that no longer matches on, rewinding the
+/// checkpoints so that collection resumes from it.
+async fn drop_reorged_trades(
+    env: &env::Env,
+    onchain: &impl OnChain,
+    max_reorg_depth: u64,
+) -> Result<(), Error> {
+    let csv_path = env.output_path();
+    let mut last_block = None;
+    let mut saved_hashes = BTreeMap::new();
+    for trade in stream_trades_csv(env)? {
+        let trade = trade?;
+        last_block = last_block.max(Some(trade.block_number));
+        if let Some(block_hash) = trade.block_hash {
+            saved_hashes.insert(trade.block_number, block_hash);
+        }
+    }
+    let Some(last_block) = last_block else {
+        return Ok(());
+    };
+
+    let first_checked_block = (last_block + 1).saturating_sub(max_reorg_depth);
+    let mut reorged_block = None;
+    for (&block_number, &saved_hash) in
+        saved_hashes.range(first_checked_block..)
+    {
+        if onchain.get_block_hash(block_number).await? != Some(saved_hash) {
+            reorged_block = Some(block_number);
+            break;
+        }
+    }
+    let Some(reorged_block) = reorged_block else {
+        debug!(
+            "Saved trades from block {first_checked_block} on are canonical"
+        );
+        return Ok(());
+    };
+
+    warn!("Dropping the trades saved from reorged block {reorged_block} on");
+    let trades = read_trades_csv(env)
+        .await?
+        .into_iter()
+        .filter(|trade| trade.block_number < reorged_block)
+        .collect();
+    rewrite_trades_csv(&csv_path, trades, env)?;
+    progress::rewind(&csv_path, reorged_block)?;
+    // The seen-set is seeded again from the remaining trades, so that the
+    // dropped ones are written again once refetched.
+    seen::reset(&csv_path)?;
+    meta::rewind_scanned(&csv_path, reorged_block)
+}
+
+async fn read_trades_csv(env: &env::Env) -> Result<Vec<Trade>, Error> {
+    let saved_trades: Vec<Trade> =
+        stream_trades_csv(env)?.collect::<Result<_, _>>()?;
+    info!("Found {} saved trades", saved_trades.len());
+    Ok(saved_trades)
+}
+
+/// Read all trades saved in the CSV file at the given path.
+fn read_trades_at(csv_path: &str) -> Result<Vec<Trade>, Error> {
+    let saved_trades: Vec<Trade> =
+        stream_trades_at(csv_path)?.collect::<Result<_, _>>()?;
+    info!("Found {} saved trades", saved_trades.len());
+    Ok(saved_trades)
+}
+
+/// Stream the trades saved in the CSV file, through all its parts if it's
+/// split, one row at a time.
+fn stream_trades_csv(
+    env: &env::Env,
+) -> Result<impl Iterator<Item = Result<Trade, Error>>, Error> {
+    let contract_address = env.single_orderbook_address();
+    let trades = stream_trades_at(&env.output_path())?;
+
+    Ok(trades.map(move |trade| {
+        trade.map(|trade| fill_contract_address(trade, contract_address))
+    }))
+}
+
+/// Fill in the orderbook contract of a trade saved before the
+/// contract_address column was added, which can only be told when a single
+/// contract is configured.
+fn fill_contract_address(
+    mut trade: Trade,
+    contract_address: Option<Address>,
+) -> Trade {
+    if let Some(contract_address) = contract_address {
+        if trade.contract_address == Address::ZERO {
+            trade.contract_address = contract_address;
+        }
+    }
+
+    trade
+}
+
+/// Stream the trades saved in the CSV file at the given path, through all
+/// its parts if it's split, one row at a time.
+fn stream_trades_at(
+    csv_path: &str,
+) -> Result<impl Iterator<Item = Result<Trade, Error>>, Error> {
+    let part_trades = parts::part_paths(csv_path)
+        .iter()
+        .map(|part_path| stream_trades_file(part_path))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(part_trades.into_iter().flatten())
+}
+
+/// Stream the trades saved in the CSV file at the given path, which may be
+/// gzip-compressed, one row at a time.
+fn stream_trades_file(
+    csv_path: &str,
+) -> Result<impl Iterator<Item = Result<Trade, Error>>, Error> {
+    let csv_file = output::open_read(csv_path)?;
+
+    deserialize_trades(csv_file)
+}
+
+/// Deserialize trades from CSV rows, with or without a header row. The first
+/// row is taken to be a header if all its fields are known column names, in
+/// which case the columns are matched by name, and otherwise by position.
+fn deserialize_trades(
+    csv: impl Read,
+) -> Result<impl Iterator<Item = Result<Trade, Error>>, Error> {
+    let mut records = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(csv)
+        .into_records()
+        .peekable();
+
+    let headers = match records.peek() {
+        Some(Ok(record)) if is_header(record) => records.next().transpose()?,
+        _ => None,
+    };
+
+    Ok(records.map(move |record| {
+        record
+            .and_then(|record| record.deserialize(headers.as_ref()))
+            .map_err(Error::from)
+    }))
+}
+
+/// Whether the given CSV row is a header row, i.e. only has known column
+/// names. Data rows never are, as their timestamp is a number.
+fn is_header(record: &csv::StringRecord) -> bool {
+    record.iter().all(|field| CSV_HEADERS.contains(&field))
+}
+
+/// Determine the starting block for fetching event logs from.
+async fn get_start_block(
+    env: &env::Env,
+    onchain: &impl OnChain,
+) -> Result<BlockNumber, Error> {
+    if let Some(from_block) = env.from_block {
+        return Ok(from_block);
+    }
+
+    if let Some(since_tx) = env.since_tx {
+        return onchain
+            .get_block_number_by_tx_hash(since_tx)
+            .await?
+            .ok_or(Error::TransactionNotFound(since_tx));
+    }
+
+    let deployment_block = get_deployment_block(env, onchain).await?;
+
+    if output::is_stdout(&env.output_path()) {
+        return deployment_start_block(
+            env,
+            deployment_block,
+            "stdout can't be resumed from",
+        );
+    }
+
+    if env.overwrite {
+        return deployment_start_block(
+            env,
+            deployment_block,
+            "--overwrite discards saved trades",
+        );
+    }
+
+    get_csv_start_block(env, onchain, &env.output_path(), deployment_block)
+        .await
+}
+
+/// Determine the block to resume collecting into the CSV file at the given
+/// path from, falling back to the deployment block if it has no saved
+/// trades.
+async fn get_csv_start_block(
+    env: &env::Env,
+    onchain: &impl OnChain,
+    csv_path: &str,
+    deployment_block: BlockNumber,
+) -> Result<BlockNumber, Error> {
+    if std::fs::metadata(csv_path).is_err() {
+        return deployment_start_block(
+            env,
+            deployment_block,
+            "the CSV file doesn't exist",
+        );
+    }
+
+    if let Some(covered_block) =
+        progress::covered_until(progress::read(csv_path)?, deployment_block)
+    {
+        debug!(
+            "Resuming after block {covered_block}, scanned without gaps from \
+            the deployment block"
+        );
+        return Ok((covered_block + 1)
+            .saturating_sub(env.reorg_safety_margin)
+            .max(deployment_block));
+    }
+
+    if let Some(last_scanned_block) = meta::read(csv_path)?.last_scanned_block {
+        debug!("Resuming after the last scanned block {last_scanned_block}");
+        return Ok((last_scanned_block + 1)
+            .saturating_sub(env.reorg_safety_margin)
+            .max(deployment_block));
+    }
+
+    let saved_trades = read_trades_at(csv_path)?;
+    let latest_trade = saved_trades.last();
+    if latest_trade.is_none() {
+        return deployment_start_block(
+            env,
+            deployment_block,
+            "the CSV file has no trades",
+        );
+    }
+
+    let latest_trade = latest_trade.unwrap();
+    debug!("Latest saved trade: {latest_trade:?}");
+
+    let latest_trade_tx_hash = latest_trade.tx_hash;
+    debug!("Fetching transaction with hash {latest_trade_tx_hash}");
+    match onchain.get_block_number_by_tx_hash(latest_trade_tx_hash).await? {
+        // Trades saved without their block and log index can't be told apart
+        // from rescanned ones, so the blocks they're in aren't rescanned.
+        Some(start_block) if latest_trade.block_number == 0 => {
+            if env.reorg_safety_margin > 0 {
+                warn!(
+                    "Ignoring --reorg-safety-margin, as the saved trades have \
+                    no block numbers; rewrite the CSV file with the reenrich \
+                    subcommand to add them"
+                );
+            }
+            Ok(start_block.max(deployment_block))
+        }
+        Some(start_block) => Ok(start_block
+            .saturating_sub(env.reorg_safety_margin)
+            .max(deployment_block)),
+        None => deployment_start_block(
+            env,
+            deployment_block,
+            "the latest saved trade's transaction wasn't found",
+        ),
+    }
+}
+
+/// Narrow the blocks from the given start block to the given latest one down
+/// to those up to `--to-block` and, with `--shard`, to the shard's part of
+/// the blocks from the deployment block to `--to-block`.
+async fn scan_range(
+    env: &env::Env,
+    onchain: &impl OnChain,
+    start_block: BlockNumber,
+    latest_block: BlockNumber,
+) -> Result<(BlockNumber, BlockNumber), Error> {
+    let latest_block = env
+        .to_block
+        .map_or(latest_block, |to_block| latest_block.min(to_block));
+    let (Some(shard), Some(to_block)) = (env.shard, env.to_block) else {
+        return Ok((start_block, latest_block));
+    };
+
+    let deployment_block = get_deployment_block(env, onchain).await?;
+    match shard.range(deployment_block, to_block) {
+        Some((shard_start, shard_end)) => {
+            info!(
+                "Collecting shard {} of {}, blocks {shard_start} to \
+                {shard_end}",
+                shard.index, shard.count
+            );
+            Ok((start_block.max(shard_start), latest_block.min(shard_end)))
+        }
+        None => {
+            warn!(
+                "Shard {} is left without blocks, as there are fewer than {} \
+                from block {deployment_block} to {to_block}",
+                shard.index, shard.count
+            );
+            Ok((latest_block.saturating_add(1), latest_block))
+        }
+    }
+}
+
+/// Fall back to starting from the deployment block unless strict resume mode
+/// requires an explicit start source.
+fn deployment_start_block(
+    env: &env::Env,
+    deployment_block: BlockNumber,
+    reason: &str,
+) -> Result<BlockNumber, Error> {
+    if env.resume_from_checkpoint_only {
+        return Err(Error::Config(format!(
+            "Refusing to rescan from the deployment block because {reason}"
+        )));
+    }
+
+    Ok(deployment_block)
+}
+
+/// Ask on the terminal whether to scan the given blocks and fail unless the
+/// answer is yes. Fails right away without a terminal to read the answer
+/// from.
+fn confirm_scan(
+    start_block: BlockNumber,
+    latest_block: BlockNumber,
+    input: Option<impl BufRead>,
+) -> Result<(), Error> {
+    let block_count = (latest_block + 1).saturating_sub(start_block);
+    let Some(mut input) = input else {
+        return Err(Error::Config(format!(
+            "Refusing to scan {block_count} blocks from block {start_block} \
+            without confirmation, set --yes to allow it"
+        )));
+    };
+
+    eprint!(
+        "Scan {block_count} blocks from {start_block} to {latest_block}? [y/N] "
+    );
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(Error::Config(format!(
+            "Scanning {block_count} blocks wasn't confirmed"
+        ))),
+    }
+}
+
+/// Determine the block the OrderbookV4 contract was deployed in, either as
+/// configured, by looking up the deployment transaction, which is cached in
+/// the metadata file so that it's only looked up once, or with
+/// `--auto-detect-deployment` from the earliest trade.
+async fn get_deployment_block(
+    env: &env::Env,
+    onchain: &impl OnChain,
+) -> Result<BlockNumber, Error> {
+    if let Some(deployment_block) = env.orderbookv4_deployment_block {
+        return Ok(deployment_block);
+    }
+
+    if env.orderbookv4_deployment_tx.is_none() && env.auto_detect_deployment {
+        return detect_deployment_block(env, onchain).await;
+    }
+
+    let Some(tx_hash) = env.orderbookv4_deployment_tx else {
+        return Err(Error::Config(
+            "Either the deployment block or transaction is required, or \
+            --auto-detect-deployment"
+                .to_string(),
+        ));
+    };
+
+    let csv_path = env.output_path();
+    let cacheable = !output::is_stdout(&csv_path);
+    if cacheable {
+        if let Some(deployment_block) =
+            meta::cached_deployment_block(&csv_path, tx_hash)?
+        {
+            debug!("Using the cached deployment block {deployment_block}");
+            return Ok(deployment_block);
+        }
+    }
+
+    // The lookup returns the block after the transaction's to resume from.
+    let block_number = onchain
+        .get_block_number_by_tx_hash(tx_hash)
+        .await?
+        .ok_or(Error::TransactionNotFound(tx_hash))?
+        .saturating_sub(1);
+    info!("Resolved deployment transaction {tx_hash} to block {block_number}");
+
+    if cacheable {
+        meta::record_deployment(
+            &csv_path,
+            Deployment { tx_hash, block_number },
+        )?;
+    }
+
+    Ok(block_number)
+}
+
+/// Find the earliest block with trades of the orderbook contracts by binary
+/// search, which is cached in the metadata file like the deployment block
+/// resolved from a transaction.
+async fn detect_deployment_block(
+    env: &env::Env,
+    onchain: &impl OnChain,
+) -> Result<BlockNumber, Error> {
+    let contract_addresses = onchain.contract_addresses();
+    let csv_path = env.output_path();
+    let cacheable = !output::is_stdout(&csv_path);
+    if cacheable {
+        if let Some(deployment_block) = meta::cached_detected_deployment_block(
+            &csv_path,
+            &contract_addresses,
+        )? {
+            debug!(
+                "Using the cached detected deployment block {deployment_block}"
+            );
+            return Ok(deployment_block);
+        }
+    }
+
+    let latest_block = onchain.get_block_number().await?;
+    if !has_trades(onchain, &contract_addresses, 0, latest_block).await? {
+        return Err(Error::Config(format!(
+            "Can't detect the deployment block without any trades up to \
+            block {latest_block}"
+        )));
+    }
+
+    // The earliest block with trades stays within these bounds, and each
+    // query only covers the lower half of the remaining range.
+    let (mut low, mut high) = (0, latest_block);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if has_trades(onchain, &contract_addresses, low, mid).await? {
+            high = mid;
+        } else {
+            low = mid + 1;
+        }
+    }
+    info!("Detected deployment block {low} from the earliest trade");
+
+    if cacheable {
+        meta::record_detected_deployment(
+            &csv_path,
+            DetectedDeployment { contract_addresses, block_number: low },
+        )?;
+    }
+
+    Ok(low)
+}
+
+/// Whether any of the given contracts emitted trade events in the given
+/// block range.
+async fn has_trades(
+    onchain: &impl OnChain,
+    contract_addresses: &[Address],
+    start_block: BlockNumber,
+    end_block: BlockNumber,
+) -> Result<bool, Error> {
+    for &contract_address in contract_addresses {
+        for event in logs::DEFAULT_EVENTS {
+            let log_count = onchain
+                .count_raw_logs(contract_address, event, start_block, end_block)
+                .await?;
+            if log_count > 0 {
+                return Ok(true);
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+/// A trade with all required fields that combines partial trades
+/// enriched with block data.
+///
+/// Tokens and amounts are from the perspective of the filled order, see
+/// [`logs::TradeFill`], as are the owners of the filled order and, for
+/// ClearV2, of the order it was cleared against. The side is only set for
+/// ClearV2 trades with `--split-clear`. Symbols and adjusted amounts are
+/// only filled in with `--resolve-tokens`, the event JSON with
+/// `--emit-raw-event-json`, and the time the trade was first seen by the
+/// tool, in the unit of the timestamp, with `--record-first-seen`.
+///
+/// Columns added after the first release default to empty, or zero for the
+/// contract address, block number and log index, when reading older CSV
+/// files, and the block columns to zero when reading those collected with
+/// `--no-enrich`, so that they can be filled in by re-enriching. The
+/// contract address is filled in right away if a single orderbook is
+/// configured.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Trade {
+    #[serde(default, deserialize_with = "units::timestamp::deserialize")]
+    pub timestamp: u64,
+    #[serde(default)]
+    pub tx_origin: Address,
+    pub tx_hash: FixedBytes<32>,
+    pub event: TradeEvent,
+    #[serde(default)]
+    pub contract_address: Address,
+    #[serde(default)]
+    pub tx_type: String,
+    pub input_token: Option<Address>,
+    pub output_token: Option<Address>,
+    #[serde(default, with = "units::decimal")]
+    pub input_amount: Option<U256>,
+    #[serde(default, with = "units::decimal")]
+    pub output_amount: Option<U256>,
+    pub input_symbol: Option<String>,
+    pub output_symbol: Option<String>,
+    pub input_amount_adjusted: Option<String>,
+    pub output_amount_adjusted: Option<String>,
+    #[serde(default)]
+    pub block_number: BlockNumber,
+    #[serde(default)]
+    pub log_index: u64,
+    pub order_owner: Option<Address>,
+    pub side: Option<ClearSide>,
+    pub event_json: Option<String>,
+    pub block_hash: Option<B256>,
+    pub log_index_in_tx: Option<u64>,
+    pub first_seen: Option<u64>,
+    pub counterparty_owner: Option<Address>,
+}
+
+/// Collect all trades emitted by the chain's orderbook contracts in the given
+/// inclusive block range, ordered as described in
+/// [`compose::enrich_and_merge`], without resolving tokens or writing them
+/// anywhere.
+///
+/// ```
+/// use alloy::primitives::{Address, B256};
+/// use rain_drops::collect_trades;
+/// use rain_drops::compose::{
+///     BlockMetadata, TradeEvent, TradeFill, TradeLog, TxMetadata,
+/// };
+/// use rain_drops::onchain::in_memory::InMemoryChain;
+/// use std::collections::BTreeMap;
+///
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), rain_drops::Error> {
+/// let tx_hash = B256::repeat_byte(1);
+/// let blocks = BTreeMap::from([(
+///     100,
+///     BlockMetadata {
+///         timestamp: 1_700_000_000,
+///         transactions: vec![TxMetadata {
+///             origin: Address::repeat_byte(2),
+///             hash: tx_hash,
+///             tx_type: "eip1559".to_string(),
+///         }],
+///     },
+/// )]);
+/// let trade = TradeLog {
+///     log_index: 0,
+///     block_number: 100,
+///     block_hash: B256::ZERO,
+///     tx_hash,
+///     event: TradeEvent::TakeOrderV2,
+///     contract_address: Address::repeat_byte(3),
+///     fill: TradeFill::default(),
+///     event_json: None,
+/// };
+/// let onchain = InMemoryChain::new(200, blocks, [trade]);
+///
+/// let trades = collect_trades(&onchain, 0, 200).await?;
+/// assert_eq!(trades.len(), 1);
+/// assert_eq!(trades[0].block_number, 100);
+/// assert_eq!(trades[0].timestamp, 1_700_000_000);
+/// assert_eq!(trades[0].tx_origin, Address::repeat_byte(2));
+/// assert!(collect_trades(&onchain, 101, 200).await?.is_empty());
+/// # Ok(())
+/// # }
+/// ```
+pub async fn collect_trades(
+    onchain: &impl OnChain,
+    start_block: u64,
+    end_block: u64,
+) -> Result<Vec<Trade>, Error> {
+    fetch_trades(
+        onchain,
+        &logs::DEFAULT_EVENTS,
+        FetchOptions::default(),
+        start_block,
+        end_block,
+    )
+    .await
+}
+
+/// Collect the trades in the given inclusive block range like
+/// [`collect_trades`], each with the extra columns the given enrichers derive
+/// from its log and block, in the order of the enrichers. Failed trades have
+/// no log to derive columns from and get none.
+pub async fn collect_enriched_trades(
+    onchain: &impl OnChain,
+    start_block: u64,
+    end_block: u64,
+    enrichers: &[&dyn Enricher],
+) -> Result<Vec<EnrichedTrade>, Error> {
+    let options = FetchOptions::default();
+    let batch_logs = fetch_batch_logs(
+        onchain,
+        &logs::DEFAULT_EVENTS,
+        options,
+        start_block,
+        end_block,
+    )
+    .await?;
+
+    enrich_batch_logs(onchain, batch_logs, options, enrichers).await
+}
+
+/// Collect the trades in the given inclusive block range like
+/// [`collect_trades`], but hand each one to `visit` in the same order as
+/// soon as its batch of `blocks_per_batch` blocks is fetched, instead of
+/// returning them all at the end, so that long ranges can be processed as a
+/// stream. Returns the number of trades visited.
+///
+/// `visit` runs inline on the collecting task, so it should be fast: the next
+/// batch is only fetched once it returned for every trade of the current
+/// one. An error returned by it stops collection and is returned as
+/// [`Error::Visitor`].
+///
+/// ```
+/// use alloy::primitives::{Address, B256};
+/// use rain_drops::compose::{
+///     BlockMetadata, TradeEvent, TradeFill, TradeLog, TxMetadata,
+/// };
+/// use rain_drops::onchain::in_memory::InMemoryChain;
+/// use rain_drops::visit_trades;
+/// use std::collections::BTreeMap;
+///
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), rain_drops::Error> {
+/// let tx_hash = B256::repeat_byte(1);
+/// let blocks = BTreeMap::from([(
+///     100,
+///     BlockMetadata {
+///         timestamp: 1_700_000_000,
+///         transactions: vec![TxMetadata {
+///             origin: Address::repeat_byte(2),
+///             hash: tx_hash,
+///             tx_type: "eip1559".to_string(),
+///         }],
+///     },
+/// )]);
+/// let trade = TradeLog {
+///     log_index: 0,
+///     block_number: 100,
+///     block_hash: B256::ZERO,
+///     tx_hash,
+///     event: TradeEvent::TakeOrderV2,
+///     contract_address: Address::repeat_byte(3),
+///     fill: TradeFill::default(),
+///     event_json: None,
+/// };
+/// let onchain = InMemoryChain::new(200, blocks, [trade]);
+///
+/// let mut lines = vec![];
+/// let trade_count = visit_trades(&onchain, 0, 200, 50, |trade| {
+///     lines.push(serde_json::to_string(trade)?);
+///     Ok::<_, serde_json::Error>(())
+/// })
+/// .await?;
+/// assert_eq!(trade_count, 1);
+/// assert_eq!(lines.len(), 1);
+/// # Ok(())
+/// # }
+/// ```
+pub async fn visit_trades<E>(
+    onchain: &impl OnChain,
+    start_block: u64,
+    end_block: u64,
+    blocks_per_batch: u64,
+    mut visit: impl FnMut(&Trade) -> Result<(), E>,
+) -> Result<usize, Error>
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    if blocks_per_batch == 0 {
+        return Err(Error::Config(
+            "Batches must have at least 1 block".to_string(),
+        ));
+    }
+
+    let mut trade_count = 0;
+    for (batch_start, batch_end) in
+        block_batches(start_block, end_block, blocks_per_batch)
+    {
+        let trades = fetch_trades(
+            onchain,
+            &logs::DEFAULT_EVENTS,
+            FetchOptions::default(),
+            batch_start,
+            batch_end,
+        )
+        .await?;
+        for trade in &trades {
+            visit(trade).map_err(|err| Error::Visitor(err.into()))?;
+            trade_count += 1;
+        }
+    }
+
+    Ok(trade_count)
+}
+
+/// How [`fetch_trades`] fetches and enriches a batch of trades.
+#[derive(Debug, Clone, Copy, Default)]
+struct FetchOptions {
+    /// Cross-check the logs against raw `eth_getLogs` queries.
+    verify_raw: bool,
+    /// Query the logs of each contract for all events concurrently instead
+    /// of one event after the other.
+    parallel_event_types: bool,
+    /// Merge in reverted transactions to the contracts as failed trades.
+    include_failed_txs: bool,
+    /// Only fetch the timestamps of blocks with trades, leaving out the
+    /// transaction metadata.
+    timestamps_only: bool,
+    /// Fail on logs of a block sharing a log index instead of rejecting them.
+    strict: bool,
+    /// Fetch no blocks at all, leaving the trades without timestamps or
+    /// transaction metadata.
+    no_enrich: bool,
+    /// Fetch the ClearV2 and TakeOrderV2 logs of a single block by its hash
+    /// rather than its number, so that they belong to the block seen.
+    by_block_hash: bool,
+}
+
+impl FetchOptions {
+    fn from_env(env: &env::Env) -> Self {
+        Self {
+            verify_raw: env.verify_raw,
+            parallel_event_types: env.parallel_event_types,
+            include_failed_txs: env.include_failed_txs,
+            timestamps_only: env.timestamps_only,
+            no_enrich: env.no_enrich,
+            strict: env.strict,
+            by_block_hash: env.poll_interval.is_some(),
+        }
+    }
+}
+
+/// Fetch the logs of the given events of all contracts in the given block
+/// range and merge them into trades enriched with block metadata, as set by
+/// the given options.
+async fn fetch_trades(
+    onchain: &impl OnChain,
+    events: &[TradeEvent],
+    options: FetchOptions,
+    start_block: u64,
+    end_block: u64,
+) -> Result<Vec<Trade>, Error> {
+    let batch_logs =
+        fetch_batch_logs(onchain, events, options, start_block, end_block)
+            .await?;
+
+    let trades = enrich_batch_logs(onchain, batch_logs, options, &[]).await?;
+    Ok(trades.into_iter().map(|trade| trade.trade).collect())
+}
+
+/// The trade logs and reverted transactions of a block range, before the
+/// blocks they're in are fetched to enrich them.
+#[derive(Debug, Default)]
+struct BatchLogs {
+    clearv2_trades: BTreeMap<BlockNumber, Vec<TradeLog>>,
+    other_trades: BTreeMap<BlockNumber, Vec<TradeLog>>,
+    failed_txs: Vec<FailedTx>,
+}
+
+impl BatchLogs {
+    /// Split the logs into chunks of at most the given number of blocks with
+    /// logs or reverted transactions, in block order. Trades are merged
+    /// block by block, so enriching the chunks one after the other yields the
+    /// same trades as enriching the whole batch.
+    fn into_chunks(mut self, chunk_blocks: usize) -> Vec<BatchLogs> {
+        let blocks: BTreeSet<BlockNumber> = self
+            .clearv2_trades
+            .keys()
+            .chain(self.other_trades.keys())
+            .copied()
+            .chain(self.failed_txs.iter().map(|tx| tx.block_number))
+            .collect();
+        let chunk_starts: Vec<BlockNumber> =
+            blocks.into_iter().step_by(chunk_blocks.max(1)).skip(1).collect();
+
+        let mut chunks = vec![];
+        for &chunk_start in chunk_starts.iter().rev() {
+            let (failed_txs, earlier_failed_txs) =
+                std::mem::take(&mut self.failed_txs)
+                    .into_iter()
+                    .partition(|tx| tx.block_number >= chunk_start);
+            self.failed_txs = earlier_failed_txs;
+            chunks.push(BatchLogs {
+                clearv2_trades: self.clearv2_trades.split_off(&chunk_start),
+                other_trades: self.other_trades.split_off(&chunk_start),
+                failed_txs,
+            });
+        }
+        chunks.push(self);
+        chunks.reverse();
+
+        chunks
+    }
+}
+
+/// Fetch the logs of the given events of all contracts in the given block
+/// range, the first phase of [`fetch_trades`].
+async fn fetch_batch_logs(
+    onchain: &impl OnChain,
+    events: &[TradeEvent],
+    options: FetchOptions,
+    start_block: u64,
+    end_block: u64,
+) -> Result<BatchLogs, Error> {
+    debug!("Fetching a batch of trade logs from blocks {start_block} to {end_block}");
+
+    // Selecting an event twice mustn't collect its trades twice.
+    let events: BTreeSet<&TradeEvent> = events.iter().collect();
+    let mut batch_logs = BatchLogs::default();
+
+    let block_hash = match options.by_block_hash && start_block == end_block {
+        true => onchain.get_block_hash(start_block).await?,
+        false => None,
+    };
+
+    for contract_address in onchain.contract_addresses() {
+        let event_trades = log_queries(&events).into_iter().map(|query| {
+            fetch_query_trades(
+                onchain,
+                contract_address,
+                query,
+                options.verify_raw,
+                start_block,
+                end_block,
+                block_hash,
+            )
+        });
+
+        let event_trades = if options.parallel_event_types {
+            futures::future::try_join_all(event_trades).await?
+        } else {
+            let mut sequential_trades = vec![];
+            for trades in event_trades {
+                sequential_trades.push(trades.await?);
+            }
+            sequential_trades
+        };
+
+        for (event, contract_trades) in event_trades.into_iter().flatten() {
+            let trades = match event {
+                TradeEvent::ClearV2 => &mut batch_logs.clearv2_trades,
+                _ => &mut batch_logs.other_trades,
+            };
+            compose::merge_by_block(trades, contract_trades);
+        }
+    }
+
+    if options.include_failed_txs {
+        batch_logs.failed_txs =
+            onchain.fetch_failed_txs(start_block, end_block).await?;
+    }
+
+    Ok(batch_logs)
+}
+
+/// Fetch the blocks the given logs were emitted in and merge the logs into
+/// trades enriched with their metadata and the columns of the given
+/// enrichers, the second phase of [`fetch_trades`].
+async fn enrich_batch_logs(
+    onchain: &impl OnChain,
+    batch_logs: BatchLogs,
+    options: FetchOptions,
+    enrichers: &[&dyn Enricher],
+) -> Result<Vec<EnrichedTrade>, Error> {
+    let BatchLogs { mut clearv2_trades, mut other_trades, failed_txs } =
+        batch_logs;
+
+    // Trades with a shared log index can't be ordered, so whichever log is
+    // wrong, none of them are trusted.
+    let duplicates =
+        compose::duplicate_log_indices(&clearv2_trades, &other_trades);
+    for &(block_number, log_index) in &duplicates {
+        let message = format!(
+            "Several logs of block {block_number} have the log index \
+            {log_index}"
+        );
+        if options.strict {
+            return Err(Error::Integrity(message));
+        }
+        error!("{message}, rejecting them");
+    }
+    compose::reject_log_indices(&mut clearv2_trades, &duplicates);
+    compose::reject_log_indices(&mut other_trades, &duplicates);
+
+    // Block bodies are fetched by the hashes the logs were emitted in, so
+    // that a reorg since the logs were queried can't pair them with another
+    // block's transactions.
+    let blocks: BTreeMap<BlockNumber, B256> = clearv2_trades
+        .values()
+        .chain(other_trades.values())
+        .flatten()
+        .map(|trade| (trade.block_number, trade.block_hash))
+        .chain(failed_txs.iter().map(|tx| (tx.block_number, tx.block_hash)))
+        .collect();
+    let block_bodies = if options.timestamps_only || options.no_enrich {
+        let timestamps = match options.no_enrich {
+            // Stand-in zero timestamps, whose column isn't written.
+            true => blocks
+                .into_keys()
+                .map(|block_number| (block_number, 0))
+                .collect(),
+            false => onchain.fetch_block_timestamps(blocks).await?,
+        };
+        let tx_hashes = clearv2_trades
+            .values()
+            .chain(other_trades.values())
+            .flatten()
+            .map(|trade| (trade.block_number, trade.tx_hash))
+            .chain(failed_txs.iter().map(|tx| (tx.block_number, tx.tx_hash)));
+        compose::header_only_blocks(timestamps, tx_hashes)
+    } else {
+        onchain.fetch_block_bodies_by_hash(blocks).await?
+    };
+
+    let columns = enricher::enrich_columns(
+        enrichers,
+        clearv2_trades.values().chain(other_trades.values()).flatten(),
+        &block_bodies,
+    );
+    let failed_trades = compose::enrich_failed_txs(failed_txs, &block_bodies);
+    let mut trades =
+        compose::enrich_and_merge(clearv2_trades, other_trades, block_bodies);
+    if !failed_trades.is_empty() {
+        trades.extend(failed_trades);
+        trades.sort_by(|a, b| sort_key(a).cmp(&sort_key(b)));
+    }
+
+    Ok(enricher::attach_columns(trades, columns))
+}
+
+/// Enrich the logs fetched from the given block range into the trades that
+/// weren't saved yet.
+#[instrument(skip(onchain, env, token_cache, saved_trade_keys, batch_logs))]
+async fn process_block_batch(
+    onchain: &impl OnChain,
+    env: &env::Env,
+    token_cache: Option<&mut TokenCache>,
+    saved_trade_keys: &HashSet<TradeKey>,
+    start_block: u64,
+    end_block: u64,
+    batch_logs: BatchLogs,
+) -> Result<Vec<Trade>, Error> {
+    // Only the block bodies of one chunk are held at a time.
+    let mut trades = vec![];
+    for chunk_logs in batch_logs.into_chunks(env.merge_chunk_size as usize) {
+        let chunk_trades = enrich_batch_logs(
+            onchain,
+            chunk_logs,
+            FetchOptions::from_env(env),
+            &[],
+        )
+        .await?;
+        trades.extend(chunk_trades.into_iter().map(|trade| trade.trade));
+    }
+
+    for trade in trades.iter_mut() {
+        trade.timestamp = env.timestamp_unit.convert(trade.timestamp);
+    }
+
+    let trade_count = trades.len();
+    trades.retain(|trade| !saved_trade_keys.contains(&trade_key(trade)));
+    if trades.len() < trade_count {
+        debug!(
+            "Skipped {} trades that were already saved",
+            trade_count - trades.len()
+        );
+    }
+
+    if let Some(sample_rate) = env.sample_rate {
+        trades.retain(|trade| sampled(&trade.tx_hash, sample_rate));
+    }
+
+    if let Some(token_cache) = token_cache {
+        for trade in trades.iter_mut() {
+            token_cache.enrich(onchain, trade).await?;
+        }
+    }
+
+    if env.record_first_seen {
+        let first_seen = env.timestamp_unit.now();
+        for trade in trades.iter_mut() {
+            trade.first_seen = Some(first_seen);
+        }
+    }
+
+    if !env.pair.is_empty() {
+        retain_pairs(&mut trades, &env.pair);
+    }
+
+    if env.reverse {
+        trades.reverse();
+    }
+
+    Ok(trades)
+}
+
+/// A batch of trades fetched from an inclusive block range, handed from
+/// [`fetch_batches`] to the writer.
+#[derive(Debug)]
+struct FetchedBatch {
+    /// The position of the batch in the scanned range.
+    index: usize,
+    start_block: BlockNumber,
+    end_block: BlockNumber,
+    trades: Vec<Trade>,
+}
+
+/// Why [`fetch_batches`] stopped.
+#[derive(Debug)]
+enum FetchEnd {
+    /// Every batch was fetched, or the writer stopped taking them.
+    Done,
+    /// Interrupted before the batch starting at the given block, in scan
+    /// order, was handed to the writer.
+    Interrupted(BlockNumber),
+    /// The given number of RPC calls exhausted the budget while fetching the
+    /// batch ending at the given block, which was handed to the writer.
+    OutOfBudget { rpc_calls: u64, end_block: BlockNumber },
+    /// The trades from the given block on don't have enough confirmations
+    /// yet, so the batches before it were handed to the writer and the rest
+    /// is left for a later run.
+    Deferred(BlockNumber),
+}
+
+/// Fetch the given block batches in order, handing each to the writer
+/// through the given channel. Fetching waits whenever the writer falls
+/// behind by the channel's capacity, so trades fetched ahead of it never take
+/// more memory than that many batches.
+///
+/// The logs and the blocks of a batch are fetched in two stages, so that the
+/// logs of up to `--log-batches-ahead` later batches are fetched while the
+/// blocks of a batch are. Each stage handles one batch at a time and they're
+/// connected by a channel, so batches reach the writer in order.
+async fn fetch_batches(
+    onchain: &impl OnChain,
+    env: &env::Env,
+    mut token_cache: Option<&mut TokenCache>,
+    saved_trade_keys: &HashSet<TradeKey>,
+    block_batches: impl Iterator<Item = (BlockNumber, BlockNumber)>,
+    shutdown: &mut (impl Future<Output = std::io::Result<()>> + Unpin),
+    batch_sender: mpsc::Sender<FetchedBatch>,
+) -> Result<FetchEnd, Error> {
+    let options = FetchOptions::from_env(env);
+    let mut block_batches = block_batches.peekable();
+    // The first block of a batch in scan order, which is its last one when
+    // scanning in reverse.
+    let first_block = |(start_block, end_block): (BlockNumber, BlockNumber)| {
+        match env.reverse {
+            true => end_block,
+            false => start_block,
+        }
+    };
+    let mut next_block = block_batches.peek().copied().map_or(0, first_block);
+
+    // Each batch takes a permit before its logs are fetched, which is only
+    // returned once its blocks were fetched.
+    let permits = Semaphore::new(env.log_batches_ahead as usize + 1);
+    let (logs_sender, mut logs_receiver) =
+        mpsc::channel(env.log_batches_ahead as usize + 1);
+
+    let fetch_logs = async {
+        // Moved in so that the blocks stage sees the channel close once the
+        // logs of all batches were fetched.
+        let logs_sender = logs_sender;
+        for (start_block, end_block) in block_batches {
+            let Ok(permit) = permits.acquire().await else {
+                return;
+            };
+            permit.forget();
+
+            let batch_logs = fetch_batch_logs(
+                onchain,
+                &env.abi_event_allowlist,
+                options,
+                start_block,
+                end_block,
+            )
+            .await;
+            let failed = batch_logs.is_err();
+            let batch = (start_block, end_block, batch_logs);
+            if logs_sender.send(batch).await.is_err() || failed {
+                return;
+            }
+        }
+    };
+
+    let fetch_blocks = async {
+        for index in 0.. {
+            // Batches are only handed over once all their data is fetched, so
+            // interrupting one never leaves a partially written batch behind.
+            let batch = tokio::select! {
+                batch = async {
+                    let Some((start_block, end_block, batch_logs)) =
+                        logs_receiver.recv().await
+                    else {
+                        return Ok(None);
+                    };
+                    let mut trades = process_block_batch(
+                        onchain,
+                        env,
+                        token_cache.as_deref_mut(),
+                        saved_trade_keys,
+                        start_block,
+                        end_block,
+                        batch_logs?,
+                    )
+                    .await?;
+                    let deferred_block =
+                        defer_unconfirmed(onchain, env, &mut trades).await?;
+                    Ok::<_, Error>(Some((
+                        start_block,
+                        end_block,
+                        trades,
+                        deferred_block,
+                    )))
+                } => batch?,
+                _ = &mut *shutdown => {
+                    return Ok(FetchEnd::Interrupted(next_block));
+                }
+            };
+            let Some((start_block, end_block, trades, deferred_block)) = batch
+            else {
+                break;
+            };
+            permits.add_permits(1);
+            next_block = match env.reverse {
+                true => start_block.saturating_sub(1),
+                false => end_block + 1,
+            };
+
+            // A batch with deferred trades only covers the blocks before
+            // them, so that the checkpoint never skips them.
+            let end_block = match deferred_block {
+                Some(deferred_block) if deferred_block <= start_block => {
+                    return Ok(FetchEnd::Deferred(deferred_block));
+                }
+                Some(deferred_block) => deferred_block - 1,
+                None => end_block,
+            };
+
+            let batch = FetchedBatch { index, start_block, end_block, trades };
+            let sent = tokio::select! {
+                sent = batch_sender.send(batch) => sent.is_ok(),
+                _ = &mut *shutdown => {
+                    return Ok(FetchEnd::Interrupted(first_block((
+                        start_block,
+                        end_block,
+                    ))));
+                }
+            };
+            // The writer only hangs up once it failed, which it reports
+            // itself.
+            if !sent {
+                break;
+            }
+
+            let rpc_calls = onchain.rpc_calls();
+            if env
+                .max_rpc_calls
+                .is_some_and(|max_rpc_calls| rpc_calls >= max_rpc_calls)
+            {
+                return Ok(FetchEnd::OutOfBudget { rpc_calls, end_block });
+            }
+
+            if let Some(deferred_block) = deferred_block {
+                return Ok(FetchEnd::Deferred(deferred_block));
+            }
+        }
+
+        Ok(FetchEnd::Done)
+    };
+
+    // Once the blocks stage stops, the logs of later batches are no longer
+    // needed, so fetching them is cancelled.
+    tokio::pin!(fetch_logs, fetch_blocks);
+    tokio::select! {
+        fetch_end = &mut fetch_blocks => fetch_end,
+        () = &mut fetch_logs => fetch_blocks.await,
+    }
+}
+
+/// Drop the trades less than `--min-confirmations` blocks below the chain
+/// head, along with all later ones, and return the block of the first one
+/// dropped, if any.
+async fn defer_unconfirmed(
+    onchain: &impl OnChain,
+    env: &env::Env,
+    trades: &mut Vec<Trade>,
+) -> Result<Option<BlockNumber>, Error> {
+    if env.min_confirmations == 0 {
+        return Ok(None);
+    }
+
+    let chain_head = onchain.get_block_number_by_tag(env.head_tag).await?;
+    let confirmed_block = chain_head.saturating_sub(env.min_confirmations);
+    let Some(deferred) =
+        trades.iter().position(|trade| trade.block_number > confirmed_block)
+    else {
+        return Ok(None);
+    };
+
+    let deferred_block = trades[deferred].block_number;
+    debug!(
+        "Deferring {} trades from block {deferred_block}, which is above the \
+        confirmed block {confirmed_block}",
+        trades.len() - deferred
+    );
+    trades.truncate(deferred);
+
+    Ok(Some(deferred_block))
+}
+
+/// Whether the trades of the transaction with the given hash are kept when
+/// sampling at the given rate, decided by the leading bytes of the hash of
+/// the transaction hash so that all runs keep the same trades.
+fn sampled(tx_hash: &FixedBytes<32>, sample_rate: f64) -> bool {
+    let hash = alloy::primitives::keccak256(tx_hash);
+    let bucket = u64::from_be_bytes(hash[..8].try_into().unwrap());
+    // Dividing by 2^64 maps the bucket to [0, 1], where only the largest
+    // buckets round up to 1.
+    sample_rate >= 1.0 || (bucket as f64 / 2f64.powi(64)) < sample_rate
+}
+
+/// Keep only the trades exchanging the tokens of one of the given pairs, in
+/// either direction.
+fn retain_pairs(trades: &mut Vec<Trade>, pairs: &[(Address, Address)]) {
+    trades.retain(|trade| {
+        let (Some(input_token), Some(output_token)) =
+            (trade.input_token, trade.output_token)
+        else {
+            return false;
+        };
+
+        pairs.iter().any(|&pair| {
+            pair == (input_token, output_token)
+                || pair == (output_token, input_token)
+        })
+    });
+}
+
+/// A log query of a batch made for each contract.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum LogQuery {
+    /// ClearV2 and TakeOrderV2 at once, when both are collected, see
+    /// [`OnChain::fetch_all_trades`].
+    AllTrades,
+    /// A single event.
+    Event(TradeEvent),
+}
+
+/// The log queries for the given events: one for ClearV2 and TakeOrderV2
+/// together if both are selected, and one for each other event.
+fn log_queries(events: &BTreeSet<&TradeEvent>) -> Vec<LogQuery> {
+    let all_trades = events.contains(&TradeEvent::ClearV2)
+        && events.contains(&TradeEvent::TakeOrderV2);
+    let single_events = events
+        .iter()
+        .filter(|event| {
+            !all_trades
+                || !matches!(
+                    event,
+                    TradeEvent::ClearV2 | TradeEvent::TakeOrderV2
+                )
+        })
+        .map(|&event| LogQuery::Event(event.clone()));
+
+    all_trades
+        .then_some(LogQuery::AllTrades)
+        .into_iter()
+        .chain(single_events)
+        .collect()
+}
+
+/// Fetch the trade logs of the given query of the given contract by event,
+/// optionally cross-checking them against raw `eth_getLogs` queries. Given
+/// the hash of the single block of the range, the logs are fetched by it.
+async fn fetch_query_trades(
+    onchain: &impl OnChain,
+    contract_address: Address,
+    query: LogQuery,
+    verify_raw: bool,
+    start_block: u64,
+    end_block: u64,
+    block_hash: Option<B256>,
+) -> Result<Vec<(TradeEvent, BlockTradeLogs)>, Error> {
+    match query {
+        LogQuery::AllTrades => {
+            let (clearv2_trades, takeorderv2_trades) = match block_hash {
+                Some(block_hash) => {
+                    onchain
+                        .fetch_block_trades(contract_address, block_hash)
+                        .await?
+                }
+                None => {
+                    onchain
+                        .fetch_all_trades(
+                            contract_address,
+                            start_block,
+                            end_block,
+                        )
+                        .await?
+                }
+            };
+            let event_trades = vec![
+                (TradeEvent::ClearV2, clearv2_trades),
+                (TradeEvent::TakeOrderV2, takeorderv2_trades),
+            ];
+
+            if verify_raw {
+                for (event, trades) in &event_trades {
+                    verify_raw_log_count(
+                        onchain,
+                        contract_address,
+                        event.clone(),
+                        trades,
+                        start_block,
+                        end_block,
+                    )
+                    .await?;
+                }
+            }
+
+            Ok(event_trades)
+        }
+        LogQuery::Event(event) => {
+            let trades = fetch_event_trades(
+                onchain,
+                contract_address,
+                event.clone(),
+                verify_raw,
+                start_block,
+                end_block,
+                block_hash,
+            )
+            .await?;
+
+            Ok(vec![(event, trades)])
+        }
+    }
+}
+
+/// Fetch the trade logs of a single event of the given contract, optionally
+/// cross-checking them against a raw `eth_getLogs` query. Given the hash of
+/// the single block of the range, they're fetched by it, ClearV2 and
+/// TakeOrderV2 logs along with those of the other event.
+async fn fetch_event_trades(
+    onchain: &impl OnChain,
+    contract_address: Address,
+    event: TradeEvent,
+    verify_raw: bool,
+    start_block: u64,
+    end_block: u64,
+    block_hash: Option<B256>,
+) -> Result<BTreeMap<BlockNumber, Vec<TradeLog>>, Error> {
+    let trades = match (&event, block_hash) {
+        // Reverted transactions emit no logs, see `fetch_trades`.
+        (TradeEvent::FailedTrade, _) => return Ok(BTreeMap::new()),
+        (TradeEvent::ClearV2, Some(block_hash)) => {
+            let (clearv2_trades, _) = onchain
+                .fetch_block_trades(contract_address, block_hash)
+                .await?;
+            clearv2_trades
+        }
+        (TradeEvent::TakeOrderV2, Some(block_hash)) => {
+            let (_, takeorderv2_trades) = onchain
+                .fetch_block_trades(contract_address, block_hash)
+                .await?;
+            takeorderv2_trades
+        }
+        (TradeEvent::Abi(event_name), Some(block_hash)) => {
+            onchain
+                .fetch_block_abi_event_trades(
+                    contract_address,
+                    event_name,
+                    block_hash,
+                )
+                .await?
+        }
+        (TradeEvent::ClearV2, None) => {
+            onchain
+                .fetch_clearv2_trades(contract_address, start_block, end_block)
+                .await?
+        }
+        (TradeEvent::TakeOrderV2, None) => {
+            onchain
+                .fetch_takeorderv2_trades(
+                    contract_address,
+                    start_block,
+                    end_block,
+                )
+                .await?
+        }
+        (TradeEvent::Abi(event_name), None) => {
+            onchain
+                .fetch_abi_event_trades(
+                    contract_address,
+                    event_name,
+                    start_block,
+                    end_block,
+                )
+                .await?
+        }
+    };
+
+    if verify_raw {
+        verify_raw_log_count(
+            onchain,
+            contract_address,
+            event,
+            &trades,
+            start_block,
+            end_block,
+        )
+        .await?;
+    }
+
+    Ok(trades)
+}
+
+/// Cross-check the number of trades decoded through the contract bindings
+/// against a raw `eth_getLogs` query for the same contract, event and block
+/// range. A mismatch points at the bindings and the node disagreeing on
+/// which logs match, or at logs dropped for missing fields.
+async fn verify_raw_log_count(
+    onchain: &impl OnChain,
+    contract_address: Address,
+    event: TradeEvent,
+    trades: &BTreeMap<BlockNumber, Vec<TradeLog>>,
+    start_block: u64,
+    end_block: u64,
+) -> Result<(), Error> {
+    let raw_count = onchain
+        .count_raw_logs(contract_address, event.clone(), start_block, end_block)
+        .await?;
+    let decoded_count: usize = trades.values().map(Vec::len).sum();
+
+    if raw_count != decoded_count {
+        warn!(
+            "Raw eth_getLogs returned {raw_count} {event:?} logs of \
+            {contract_address} from blocks {start_block} to {end_block}, but \
+            {decoded_count} trades were decoded through the contract bindings"
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use env::{Env, EnvBuilder, TimestampUnit};
+    use onchain::in_memory::InMemoryChain;
+    use onchain::mock::MockChain;
+    use onchain::{BlockMetadata, FailedTx, TxMetadata};
+
+    /// The configuration of a test collecting the trades of the orderbook at
+    /// the zero address from block 1,000 into the given file in `dir`.
+    fn test_env(dir: &tempfile::TempDir, csv_file: &str) -> EnvBuilder {
+        Env::builder("http://localhost:8545", vec![Address::ZERO.to_string()])
+            .with_csv_path(dir.path().join(csv_file).to_str().unwrap())
+            .with_orderbookv4_deployment_block(1_000)
+    }
+
+    #[tokio::test]
+    async fn test_resume_after_run_without_trades() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = test_env(&dir, "trades.csv")
+            .with_orderbookv4_deployment_block(10)
+            .build()
+            .unwrap();
+
+        let onchain = InMemoryChain::new(100, BTreeMap::new(), []);
+        assert_eq!(update_trades_csv(&env, &onchain).await.unwrap(), 0);
+        assert!(read_trades_csv(&env).await.unwrap().is_empty());
+
+        let onchain = InMemoryChain::new(200, BTreeMap::new(), []);
+        assert_eq!(get_start_block(&env, &onchain).await.unwrap(), 101);
+    }
+
+    #[tokio::test]
+    async fn test_reorged_blocks_are_dropped_and_refetched() {
+        let tx_hash = |block_number: BlockNumber| {
+            B256::with_last_byte(block_number as u8)
+        };
+        let blocks = (100..=103)
+            .map(|block_number| {
+                let block = BlockMetadata {
+                    timestamp: block_number,
+                    transactions: vec![TxMetadata {
+                        origin: Address::ZERO,
+                        hash: tx_hash(block_number),
+                        tx_type: "eip1559".to_string(),
+                    }],
+                };
+                (block_number, block)
+            })
+            .collect::<BTreeMap<_, _>>();
+        let block_hash = |block_number: BlockNumber, fork: u8| {
+            B256::left_padding_from(&[fork, block_number as u8])
+        };
+        let chain = |forked_from: BlockNumber| {
+            let trades = (100..=103).map(|block_number| TradeLog {
+                log_index: 0,
+                block_number,
+                block_hash: block_hash(
+                    block_number,
+                    u8::from(block_number >= forked_from),
+                ),
+                tx_hash: tx_hash(block_number),
+                event: TradeEvent::TakeOrderV2,
+                contract_address: Address::ZERO,
+                fill: logs::TradeFill::default(),
+                event_json: None,
+            });
+            InMemoryChain::new(110, blocks.clone(), trades)
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let env = test_env(&dir, "trades.csv")
+            .with_orderbookv4_deployment_block(100)
+            .with_max_reorg_depth(5)
+            .build()
+            .unwrap();
+
+        let onchain = chain(u64::MAX);
+        assert_eq!(update_trades_csv(&env, &onchain).await.unwrap(), 4);
+        assert_eq!(update_trades_csv(&env, &onchain).await.unwrap(), 0);
+
+        // The last two blocks with trades are replaced by a reorg.
+        let onchain = chain(102);
+        assert_eq!(update_trades_csv(&env, &onchain).await.unwrap(), 2);
+
+        let trades = read_trades_csv(&env).await.unwrap();
+        let saved_blocks = trades
+            .iter()
+            .map(|trade| (trade.block_number, trade.block_hash))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            saved_blocks,
+            vec![
+                (100, Some(block_hash(100, 0))),
+                (101, Some(block_hash(101, 0))),
+                (102, Some(block_hash(102, 1))),
+                (103, Some(block_hash(103, 1))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_confirm_scan() {
+        let no_terminal: Option<&[u8]> = None;
+        assert!(matches!(
+            confirm_scan(100, 199, no_terminal),
+            Err(Error::Config(_))
+        ));
+        assert!(confirm_scan(100, 199, Some("y\n".as_bytes())).is_ok());
+        assert!(confirm_scan(100, 199, Some("YES\n".as_bytes())).is_ok());
+        assert!(confirm_scan(100, 199, Some("n\n".as_bytes())).is_err());
+        assert!(confirm_scan(100, 199, Some("".as_bytes())).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_start_block_since_tx() {
+        let tx_hash = B256::repeat_byte(1);
+        let blocks = BTreeMap::from([(
+            100,
+            BlockMetadata {
+                timestamp: 0,
+                transactions: vec![TxMetadata {
+                    origin: Address::ZERO,
+                    hash: tx_hash,
+                    tx_type: "eip1559".to_string(),
+                }],
+            },
+        )]);
+        let onchain = InMemoryChain::new(200, blocks, []);
+
+        let env_since = |since_tx| {
+            Env::builder(
+                "http://localhost:8545",
+                vec![Address::ZERO.to_string()],
+            )
+            .with_csv_path("-")
+            .with_orderbookv4_deployment_block(10)
+            .with_since_tx(since_tx)
+            .build()
+            .unwrap()
+        };
+
+        let env = env_since(tx_hash);
+        assert_eq!(get_start_block(&env, &onchain).await.unwrap(), 101);
+
+        let env = env_since(B256::repeat_byte(2));
+        assert!(matches!(
+            get_start_block(&env, &onchain).await,
+            Err(Error::TransactionNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_deployment_block_from_tx_is_cached() {
+        let tx_hash = B256::repeat_byte(1);
+        let blocks = BTreeMap::from([(
+            100,
+            BlockMetadata {
+                timestamp: 0,
+                transactions: vec![TxMetadata {
+                    origin: Address::ZERO,
+                    hash: tx_hash,
+                    tx_type: "eip1559".to_string(),
+                }],
+            },
+        )]);
+        let dir = tempfile::tempdir().unwrap();
+        let csv_path = dir.path().join("trades.csv");
+
+        let env = Env::builder(
+            "http://localhost:8545",
+            vec![Address::ZERO.to_string()],
+        )
+        .with_csv_path(csv_path.to_str().unwrap())
+        .with_orderbookv4_deployment_tx(tx_hash)
+        .build()
+        .unwrap();
+
+        let onchain = InMemoryChain::new(200, blocks, []);
+        assert_eq!(get_start_block(&env, &onchain).await.unwrap(), 100);
+
+        // The second run doesn't need to find the transaction again.
+        let onchain = InMemoryChain::new(200, BTreeMap::new(), []);
+        assert_eq!(get_start_block(&env, &onchain).await.unwrap(), 100);
+    }
+
+    #[tokio::test]
+    async fn test_deployment_block_is_detected_and_cached() {
+        let trade = |block_number, contract_address| TradeLog {
+            log_index: 0,
+            block_number,
+            block_hash: B256::ZERO,
+            tx_hash: B256::ZERO,
+            event: TradeEvent::ClearV2,
+            contract_address,
+            fill: logs::TradeFill::default(),
+            event_json: None,
+        };
+        let onchain = InMemoryChain::new(
+            10_000,
+            BTreeMap::new(),
+            [
+                trade(5_000, Address::repeat_byte(1)),
+                trade(1_234, Address::repeat_byte(2)),
+                trade(7_777, Address::repeat_byte(1)),
+            ],
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let csv_path = dir.path().join("trades.csv");
+        let env = Env::builder(
+            "http://localhost:8545",
+            vec![Address::ZERO.to_string()],
+        )
+        .with_csv_path(csv_path.to_str().unwrap())
+        .with_auto_detect_deployment(true)
+        .build()
+        .unwrap();
+
+        assert_eq!(get_start_block(&env, &onchain).await.unwrap(), 1_234);
+
+        // The second run reuses the detected block for the same contracts.
+        let onchain = InMemoryChain::new(
+            10_000,
+            BTreeMap::new(),
+            [
+                trade(9_000, Address::repeat_byte(1)),
+                trade(9_000, Address::repeat_byte(2)),
+            ],
+        );
+        assert_eq!(get_start_block(&env, &onchain).await.unwrap(), 1_234);
+
+        let onchain = InMemoryChain::new(10_000, BTreeMap::new(), []);
+        assert!(matches!(
+            detect_deployment_block(&env, &onchain).await,
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_parallel_event_types_fetch_same_trades() {
+        let tx_hash = B256::repeat_byte(1);
+        let blocks = BTreeMap::from([(
+            100,
+            BlockMetadata {
+                timestamp: 0,
+                transactions: vec![TxMetadata {
+                    origin: Address::ZERO,
+                    hash: tx_hash,
+                    tx_type: "eip1559".to_string(),
+                }],
+            },
+        )]);
+        let trade = |log_index, event| TradeLog {
+            log_index,
+            block_number: 100,
+            block_hash: B256::ZERO,
+            tx_hash,
+            event,
+            contract_address: Address::ZERO,
+            fill: logs::TradeFill::default(),
+            event_json: None,
+        };
+        let onchain = InMemoryChain::new(
+            200,
+            blocks,
+            [trade(0, TradeEvent::TakeOrderV2), trade(1, TradeEvent::ClearV2)],
+        );
+
+        let sequential = fetch_trades(
+            &onchain,
+            &logs::DEFAULT_EVENTS,
+            FetchOptions::default(),
+            0,
+            200,
+        )
+        .await
+        .unwrap();
+        let parallel = fetch_trades(
+            &onchain,
+            &logs::DEFAULT_EVENTS,
+            FetchOptions { parallel_event_types: true, ..Default::default() },
+            0,
+            200,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(sequential.len(), 2);
+        assert_eq!(parallel, sequential);
+    }
+
+    #[tokio::test]
+    async fn test_single_block_is_fetched_by_hash() {
+        let trade = |log_index, block_hash| TradeLog {
+            log_index,
+            block_number: 100,
+            block_hash,
+            tx_hash: B256::ZERO,
+            event: TradeEvent::TakeOrderV2,
+            contract_address: Address::ZERO,
+            fill: logs::TradeFill::default(),
+            event_json: None,
+        };
+        // The chain knows block 100 by the hash of its first log, so the
+        // second one stands in for a log of a block reorged out since.
+        let onchain = InMemoryChain::new(
+            200,
+            BTreeMap::new(),
+            [trade(0, B256::ZERO), trade(1, B256::repeat_byte(1))],
+        );
+        let fetch_logs = |options| {
+            fetch_batch_logs(&onchain, &logs::DEFAULT_EVENTS, options, 100, 100)
+        };
+
+        let by_number = fetch_logs(FetchOptions::default()).await.unwrap();
+        assert_eq!(by_number.other_trades[&100].len(), 2);
+
+        let by_hash = fetch_logs(FetchOptions {
+            by_block_hash: true,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let block_trades = &by_hash.other_trades[&100];
+        assert_eq!(block_trades.len(), 1);
+        assert_eq!(block_trades[0].block_hash, B256::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_single_event_queries_are_fetched_by_hash() {
+        let trade = |event, log_index, block_hash| TradeLog {
+            log_index,
+            block_number: 100,
+            block_hash,
+            tx_hash: B256::ZERO,
+            event,
+            contract_address: Address::ZERO,
+            fill: logs::TradeFill::default(),
+            event_json: None,
+        };
+        let add_order = TradeEvent::Abi("AddOrderV2".to_string());
+        // As above, the logs of the second block hash are reorged out.
+        let onchain = InMemoryChain::new(
+            200,
+            BTreeMap::new(),
+            [
+                trade(TradeEvent::TakeOrderV2, 0, B256::ZERO),
+                trade(TradeEvent::TakeOrderV2, 1, B256::repeat_byte(1)),
+                trade(add_order.clone(), 2, B256::ZERO),
+                trade(add_order.clone(), 3, B256::repeat_byte(1)),
+            ],
+        );
+        let events = [TradeEvent::TakeOrderV2, add_order];
+
+        for parallel_event_types in [false, true] {
+            let by_hash = fetch_batch_logs(
+                &onchain,
+                &events,
+                FetchOptions {
+                    parallel_event_types,
+                    by_block_hash: true,
+                    ..Default::default()
+                },
+                100,
+                100,
+            )
+            .await
+            .unwrap();
+            let block_trades = &by_hash.other_trades[&100];
+            assert_eq!(block_trades.len(), 2);
+            assert!(block_trades
+                .iter()
+                .all(|trade| trade.block_hash == B256::ZERO));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_estimate_cost_samples_last_batch() {
+        let env = Env::builder(
+            "http://localhost:8545",
+            vec![Address::ZERO.to_string()],
+        )
+        .with_orderbookv4_deployment_block(0)
+        .with_from_block(0)
+        .with_blocks_per_log_request(100)
+        .with_verify_raw(true)
+        .build()
+        .unwrap();
+        let trade = |block_number| TradeLog {
+            log_index: 0,
+            block_number,
+            block_hash: B256::ZERO,
+            tx_hash: B256::ZERO,
+            event: TradeEvent::TakeOrderV2,
+            contract_address: Address::ZERO,
+            fill: logs::TradeFill::default(),
+            event_json: None,
+        };
+        let onchain = InMemoryChain::new(
+            199,
+            BTreeMap::new(),
+            [trade(50), trade(150), trade(160), trade(160)],
+        );
+
+        let estimate = estimate_cost(&env, &onchain).await.unwrap();
+
+        assert_eq!((estimate.start_block, estimate.latest_block), (0, 199));
+        assert_eq!(estimate.batches, 2);
+        // Both events in one query, each also counted raw.
+        assert_eq!(estimate.log_queries, 6);
+        // Two of the last 100 blocks have trades.
+        assert_eq!(estimate.block_body_calls, 4);
+        assert_eq!(estimate.receipt_calls, 0);
+    }
+
+    #[tokio::test]
+    async fn test_fetching_waits_for_slow_writer() {
+        let env = Env::builder(
+            "http://localhost:8545",
+            vec![Address::ZERO.to_string()],
+        )
+        .with_orderbookv4_deployment_block(0)
+        .build()
+        .unwrap();
+        let onchain = InMemoryChain::seeded(11, 0, 999);
+        let mut shutdown = std::future::pending();
+        let (batch_sender, mut batch_receiver) = mpsc::channel(2);
+        let saved_trade_keys = HashSet::new();
+
+        let fetch = fetch_batches(
+            &onchain,
+            &env,
+            None,
+            &saved_trade_keys,
+            block_batches(0, 999, 100),
+            &mut shutdown,
+            batch_sender,
+        );
+        tokio::pin!(fetch);
+
+        // A writer that takes no batches holds up fetching once the buffer
+        // is full.
+        let stalled =
+            tokio::time::timeout(Duration::from_millis(100), &mut fetch).await;
+        assert!(stalled.is_err());
+        assert_eq!(batch_receiver.len(), 2);
+
+        // A slow writer gets every batch in order, never more than the
+        // buffer behind.
+        let write = async {
+            let mut end_blocks = vec![];
+            while let Some(batch) = batch_receiver.recv().await {
+                assert!(batch_receiver.len() <= 2);
+                end_blocks.push(batch.end_block);
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            end_blocks
+        };
+        let (fetch_end, end_blocks) = tokio::join!(fetch, write);
+
+        assert!(matches!(fetch_end.unwrap(), FetchEnd::Done));
+        assert_eq!(
+            end_blocks,
+            (1..=10).map(|i| i * 100 - 1).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_abi_event_allowlist_selects_events() {
+        let tx_hash = B256::repeat_byte(1);
+        let blocks = BTreeMap::from([(
+            100,
+            BlockMetadata {
+                timestamp: 0,
+                transactions: vec![TxMetadata {
+                    origin: Address::ZERO,
+                    hash: tx_hash,
+                    tx_type: "eip1559".to_string(),
+                }],
+            },
+        )]);
+        let deposit = TradeEvent::Abi("Deposit".to_string());
+        let trade = |log_index, event| TradeLog {
+            log_index,
+            block_number: 100,
+            block_hash: B256::ZERO,
+            tx_hash,
+            event,
+            contract_address: Address::ZERO,
+            fill: logs::TradeFill::default(),
+            event_json: None,
+        };
+        let onchain = InMemoryChain::new(
+            200,
+            blocks,
+            [
+                trade(0, TradeEvent::TakeOrderV2),
+                trade(1, deposit.clone()),
+                trade(2, TradeEvent::ClearV2),
+            ],
+        );
+
+        let trades = fetch_trades(
+            &onchain,
+            &[TradeEvent::TakeOrderV2, deposit.clone(), deposit.clone()],
+            FetchOptions::default(),
+            0,
+            200,
+        )
+        .await
+        .unwrap();
+        let events: Vec<_> =
+            trades.iter().map(|trade| trade.event.clone()).collect();
+        assert_eq!(events, [TradeEvent::TakeOrderV2, deposit.clone()]);
+
+        // The event is written as its name and read back from it.
+        let mut csv_writer = csv::Writer::from_writer(vec![]);
+        csv_writer.serialize(&trades[1]).unwrap();
+        let csv = String::from_utf8(csv_writer.into_inner().unwrap()).unwrap();
+        assert!(csv.contains(",Deposit,"));
+        let read: Trade = csv::Reader::from_reader(csv.as_bytes())
+            .deserialize()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(read, trades[1]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_txs_are_merged_in_order() {
+        let tx = |byte| TxMetadata {
+            origin: Address::repeat_byte(byte),
+            hash: B256::repeat_byte(byte),
+            tx_type: "eip1559".to_string(),
+        };
+        let blocks = BTreeMap::from([(
+            100,
+            BlockMetadata {
+                timestamp: 1_700_000_000,
+                transactions: vec![tx(1), tx(2), tx(3)],
+            },
+        )]);
+        let trade = |log_index, tx_byte| TradeLog {
+            log_index,
+            block_number: 100,
+            block_hash: B256::ZERO,
+            tx_hash: B256::repeat_byte(tx_byte),
+            event: TradeEvent::TakeOrderV2,
+            contract_address: Address::ZERO,
+            fill: logs::TradeFill::default(),
+            event_json: None,
+        };
+        let failed_tx = FailedTx {
+            block_number: 100,
+            block_hash: B256::ZERO,
+            tx_hash: B256::repeat_byte(2),
+            tx_index: 1,
+            contract_address: Address::ZERO,
+        };
+        let onchain =
+            InMemoryChain::new(200, blocks, [trade(0, 1), trade(5, 3)])
+                .with_failed_txs([failed_tx]);
+
+        let trades = fetch_trades(
+            &onchain,
+            &logs::DEFAULT_EVENTS,
+            FetchOptions::default(),
+            0,
+            200,
+        )
+        .await
+        .unwrap();
+        assert_eq!(trades.len(), 2);
+
+        let trades = fetch_trades(
+            &onchain,
+            &logs::DEFAULT_EVENTS,
+            FetchOptions { include_failed_txs: true, ..Default::default() },
+            0,
+            200,
+        )
+        .await
+        .unwrap();
+        let events: Vec<_> =
+            trades.iter().map(|trade| trade.event.clone()).collect();
+        assert_eq!(
+            events,
+            [
+                TradeEvent::TakeOrderV2,
+                TradeEvent::FailedTrade,
+                TradeEvent::TakeOrderV2
+            ]
+        );
+        assert_eq!(trades[1].tx_origin, Address::repeat_byte(2));
+        assert_eq!(trades[1].timestamp, 1_700_000_000);
+        assert_eq!(trades[1].input_amount, None);
+        audit::verify_sorted(trades.into_iter().map(Ok)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_timestamps_only_leaves_out_tx_metadata() {
+        let onchain = InMemoryChain::seeded(3, 1_000, 1_999);
+        let full = fetch_trades(
+            &onchain,
+            &logs::DEFAULT_EVENTS,
+            FetchOptions::default(),
+            1_000,
+            1_999,
+        )
+        .await
+        .unwrap();
+        let timestamps_only = fetch_trades(
+            &onchain,
+            &logs::DEFAULT_EVENTS,
+            FetchOptions { timestamps_only: true, ..Default::default() },
+            1_000,
+            1_999,
+        )
+        .await
+        .unwrap();
+
+        assert!(!full.is_empty());
+        assert_eq!(timestamps_only.len(), full.len());
+        for (trade, full_trade) in timestamps_only.iter().zip(&full) {
+            assert_eq!(trade.tx_origin, Address::ZERO);
+            assert_eq!(trade.tx_type, "");
+
+            let mut trade = trade.clone();
+            trade.tx_origin = full_trade.tx_origin;
+            trade.tx_type = full_trade.tx_type.clone();
+            assert_eq!(&trade, full_trade);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_seeded_chain_is_reproducible() {
+        let collect = |seed| async move {
+            let onchain = InMemoryChain::seeded(seed, 1_000, 1_999);
+            let dir = tempfile::tempdir().unwrap();
+            let env = test_env(&dir, "trades.csv")
+                .with_blocks_per_log_request(250)
+                .build()
+                .unwrap();
+
+            let trade_count = update_trades_csv(&env, &onchain).await.unwrap();
+            let trades = read_trades_csv(&env).await.unwrap();
+            assert_eq!(trades.len(), trade_count);
+            trades
+        };
+
+        let trades = collect(7).await;
+        assert!(!trades.is_empty());
+        assert!(trades
+            .iter()
+            .all(|trade| (1_000..=1_999).contains(&trade.block_number)));
+        assert_eq!(trades, collect(7).await);
+        assert_ne!(trades, collect(8).await);
+    }
+
+    #[tokio::test]
+    async fn test_log_batches_ahead_keep_batches_in_order() {
+        let onchain = InMemoryChain::seeded(5, 1_000, 1_999);
+        let expected = collect_trades(&onchain, 1_000, 1_999).await.unwrap();
+        assert!(!expected.is_empty());
+
+        for log_batches_ahead in [0, 1, 4] {
+            let env = Env::builder(
+                "http://localhost:8545",
+                vec![Address::ZERO.to_string()],
+            )
+            .with_orderbookv4_deployment_block(1_000)
+            .with_log_batches_ahead(log_batches_ahead)
+            .build()
+            .unwrap();
+            let mut shutdown = std::future::pending();
+            let (batch_sender, mut batch_receiver) = mpsc::channel(1);
+            let saved_trade_keys = HashSet::new();
+            let fetch = fetch_batches(
+                &onchain,
+                &env,
+                None,
+                &saved_trade_keys,
+                block_batches(1_000, 1_999, 50),
+                &mut shutdown,
+                batch_sender,
+            );
+            let write = async {
+                let mut batches = vec![];
+                while let Some(batch) = batch_receiver.recv().await {
+                    batches.push(batch);
+                }
+                batches
+            };
+            let (fetch_end, batches) = tokio::join!(fetch, write);
+
+            assert!(matches!(fetch_end.unwrap(), FetchEnd::Done));
+            assert!(batches
+                .iter()
+                .enumerate()
+                .all(|(index, batch)| batch.index == index
+                    && batch.start_block == 1_000 + index as u64 * 50));
+            let trades: Vec<_> =
+                batches.into_iter().flat_map(|batch| batch.trades).collect();
+            assert_eq!(trades, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flush_every_leaves_csv_complete() {
+        let onchain = InMemoryChain::seeded(7, 1_000, 1_999);
+        let expected = collect_trades(&onchain, 1_000, 1_999).await.unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let env = |csv_file: &str, max_rpc_calls: Option<u64>| {
+            let env = test_env(&dir, csv_file)
+                .with_blocks_per_log_request(100)
+                .with_flush_every(3);
+            match max_rpc_calls {
+                Some(max_rpc_calls) => env.with_max_rpc_calls(max_rpc_calls),
+                None => env,
+            }
+            .build()
+            .unwrap()
+        };
+
+        // The last of the 10 batches is written after the last checkpoint.
+        let env_normal = env("normal.csv", None);
+        update_trades_csv(&env_normal, &onchain).await.unwrap();
+        assert_eq!(read_trades_csv(&env_normal).await.unwrap(), expected);
+
+        // Running out of RPC calls stops the run between two checkpoints,
+        // and the batches after the last one are still written.
+        let env_interrupted =
+            env("interrupted.csv", Some(onchain.rpc_calls() + 95));
+        update_trades_csv(&env_interrupted, &onchain).await.unwrap();
+        let scanned_block = progress::covered_until(
+            progress::read(&env_interrupted.csv_path).unwrap(),
+            1_000,
+        )
+        .unwrap();
+        assert!(scanned_block < 1_999);
+        assert!(!(scanned_block + 1 - 1_000).is_multiple_of(300));
+        assert_eq!(
+            read_trades_csv(&env_interrupted).await.unwrap(),
+            expected
+                .iter()
+                .filter(|trade| trade.block_number <= scanned_block)
+                .cloned()
+                .collect::<Vec<_>>()
+        );
+
+        // Resuming collects the rest without gaps or duplicates.
+        update_trades_csv(&env("interrupted.csv", None), &onchain)
+            .await
+            .unwrap();
+        assert_eq!(read_trades_csv(&env_interrupted).await.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_reverse_writes_newest_trades_first() {
+        let onchain = InMemoryChain::seeded(9, 1_000, 1_999);
+        let mut expected =
+            collect_trades(&onchain, 1_000, 1_999).await.unwrap();
+        expected.reverse();
+        assert!(!expected.is_empty());
+
+        let dir = tempfile::tempdir().unwrap();
+        let env = |reverse| {
+            test_env(&dir, "trades.csv")
+                .with_blocks_per_log_request(100)
+                .with_overwrite(reverse)
+                .with_reverse(reverse)
+                .build()
+                .unwrap()
+        };
+
+        update_trades_csv(&env(true), &onchain).await.unwrap();
+        assert_eq!(read_trades_csv(&env(true)).await.unwrap(), expected);
+
+        // Appending would mix up the order until the file is sorted.
+        assert!(matches!(
+            update_trades_csv(&env(false), &onchain).await,
+            Err(Error::Config(_))
+        ));
+        sort_trades_csv(&env(false)).await.unwrap();
+        assert_eq!(update_trades_csv(&env(false), &onchain).await.unwrap(), 0);
+
+        assert!(matches!(
+            Env::builder(
+                "http://localhost:8545",
+                vec![Address::ZERO.to_string()],
+            )
+            .with_reverse(true)
+            .build(),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_min_confirmations_defer_recent_trades() {
+        let tx = |byte| TxMetadata {
+            origin: Address::repeat_byte(byte),
+            hash: B256::repeat_byte(byte),
+            tx_type: "eip1559".to_string(),
+        };
+        let blocks = BTreeMap::from([
+            (100, BlockMetadata { timestamp: 1, transactions: vec![tx(1)] }),
+            (195, BlockMetadata { timestamp: 2, transactions: vec![tx(2)] }),
+        ]);
+        let trade = |block_number, tx_byte| TradeLog {
+            log_index: 0,
+            block_number,
+            block_hash: B256::ZERO,
+            tx_hash: B256::repeat_byte(tx_byte),
+            event: TradeEvent::TakeOrderV2,
+            contract_address: Address::ZERO,
+            fill: logs::TradeFill::default(),
+            event_json: None,
+        };
+        let trades = [trade(100, 1), trade(195, 2)];
+
+        let dir = tempfile::tempdir().unwrap();
+        let env = test_env(&dir, "trades.csv")
+            .with_orderbookv4_deployment_block(0)
+            .with_blocks_per_log_request(1_000)
+            .with_min_confirmations(10)
+            .build()
+            .unwrap();
+
+        // The second trade is only 5 blocks below the head.
+        let onchain = InMemoryChain::new(200, blocks.clone(), trades.clone());
+        assert_eq!(update_trades_csv(&env, &onchain).await.unwrap(), 1);
+        let saved = read_trades_csv(&env).await.unwrap();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].block_number, 100);
+
+        // A later run resumes from the deferred trade once it's confirmed.
+        let onchain = InMemoryChain::new(205, blocks, trades);
+        assert_eq!(update_trades_csv(&env, &onchain).await.unwrap(), 1);
+        let saved = read_trades_csv(&env).await.unwrap();
+        assert_eq!(
+            saved.iter().map(|trade| trade.block_number).collect::<Vec<_>>(),
+            [100, 195]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_record_first_seen_stamps_written_trades() {
+        let onchain = InMemoryChain::seeded(7, 1_000, 1_999);
+        let dir = tempfile::tempdir().unwrap();
+        let env = test_env(&dir, "trades.csv")
+            .with_timestamp_unit(TimestampUnit::Millis)
+            .with_record_first_seen(true)
+            .build()
+            .unwrap();
+
+        let started = TimestampUnit::Millis.now();
+        assert!(update_trades_csv(&env, &onchain).await.unwrap() > 0);
+        let finished = TimestampUnit::Millis.now();
+
+        let saved = read_trades_csv(&env).await.unwrap();
+        assert!(saved.iter().all(|trade| trade.first_seen.is_some_and(
+            |first_seen| (started..=finished).contains(&first_seen)
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_appending_to_csv_with_other_columns() {
+        let onchain = InMemoryChain::seeded(7, 1_000, 1_999);
+        let dir = tempfile::tempdir().unwrap();
+        let csv_path = dir.path().join("trades.csv");
+        let env = |csv_no_headers| {
+            test_env(&dir, "trades.csv")
+                .with_csv_no_headers(csv_no_headers)
+                .build()
+                .unwrap()
+        };
+
+        // Rows are appended with the columns of the first release.
+        let old_headers = "timestamp,tx_origin,tx_hash,event";
+        std::fs::write(&csv_path, format!("{old_headers}\n")).unwrap();
+        let trade_count =
+            update_trades_csv(&env(false), &onchain).await.unwrap();
+        assert!(trade_count > 0);
+        let saved = std::fs::read_to_string(&csv_path).unwrap();
+        assert!(saved.starts_with(&format!("{old_headers}\n")));
+        assert!(saved.lines().all(|line| line.split(',').count() == 4));
+        let trades = read_trades_csv(&env(false)).await.unwrap();
+        assert_eq!(trades.len(), trade_count);
+
+        // Without a header row, the saved columns aren't known.
+        let old_row = vec!["0"; CSV_HEADERS.len() - 1].join(",");
+        std::fs::write(&csv_path, format!("{old_row}\n")).unwrap();
+        let err = update_trades_csv(&env(true), &onchain).await.unwrap_err();
+        assert!(matches!(err, Error::Config(_)), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_reenrich_migrates_csv_of_first_release() {
+        let onchain = InMemoryChain::seeded(7, 1_000, 1_999);
+        let dir = tempfile::tempdir().unwrap();
+        let env = |csv_file| test_env(&dir, csv_file).build().unwrap();
+        update_trades_csv(&env("trades.csv"), &onchain).await.unwrap();
+        let trades = read_trades_csv(&env("trades.csv")).await.unwrap();
+
+        let mut old_csv = "timestamp,tx_origin,tx_hash,event\n".to_string();
+        for trade in &trades {
+            old_csv += &format!(
+                "{},{},{},{}\n",
+                trade.timestamp,
+                trade.tx_origin,
+                trade.tx_hash,
+                trade.event.name()
+            );
+        }
+        std::fs::write(dir.path().join("old.csv"), old_csv).unwrap();
+        reenrich_trades_csv(&env("old.csv"), &onchain).await.unwrap();
+
+        assert_eq!(read_trades_csv(&env("old.csv")).await.unwrap(), trades);
+    }
+
+    #[tokio::test]
+    async fn test_max_file_size_splits_csv_into_parts() {
+        let onchain = InMemoryChain::seeded(7, 1_000, 1_999);
+        let dir = tempfile::tempdir().unwrap();
+        let csv_path = dir.path().join("trades.csv");
+        let csv_path = csv_path.to_str().unwrap();
+        let env = test_env(&dir, "trades.csv")
+            .with_blocks_per_log_request(100)
+            .with_max_file_size(1)
+            .build()
+            .unwrap();
+
+        let trade_count = update_trades_csv(&env, &onchain).await.unwrap();
+        let last_part = parts::last_part(csv_path);
+        assert!(last_part > 0);
+        for part_path in parts::part_paths(csv_path) {
+            let part = std::fs::read_to_string(part_path).unwrap();
+            assert!(part.starts_with("timestamp,tx_origin,"));
+        }
+
+        let saved = read_trades_csv(&env).await.unwrap();
+        assert_eq!(saved.len(), trade_count);
+        assert!(saved
+            .windows(2)
+            .all(|pair| pair[0].block_number <= pair[1].block_number));
+
+        // Resuming appends to the last part.
+        assert_eq!(update_trades_csv(&env, &onchain).await.unwrap(), 0);
+        assert_eq!(parts::last_part(csv_path), last_part);
+    }
+
+    #[tokio::test]
+    async fn test_merged_shards_match_unsharded_run() {
+        let onchain = InMemoryChain::seeded(7, 1_000, 1_999);
+        let dir = tempfile::tempdir().unwrap();
+        let env = |csv_file: &str| {
+            test_env(&dir, csv_file)
+                .with_to_block(1_999)
+                .with_blocks_per_log_request(64)
+        };
+
+        let unsharded = env("unsharded.csv").build().unwrap();
+        update_trades_csv(&unsharded, &onchain).await.unwrap();
+
+        for index in 0..3 {
+            let shard = env("trades.csv")
+                .with_shard(env::Shard { index, count: 3 })
+                .build()
+                .unwrap();
+            update_trades_csv(&shard, &onchain).await.unwrap();
+        }
+        let merged = env("trades.csv").build().unwrap();
+        let trade_count = merge_shards_csv(&merged, 3).await.unwrap();
+
+        let trades = read_trades_csv(&merged).await.unwrap();
+        assert_eq!(trades.len(), trade_count);
+        assert_eq!(trades, read_trades_csv(&unsharded).await.unwrap());
+        assert_eq!(
+            meta::read(&merged.output_path()).unwrap().last_scanned_block,
+            Some(1_999)
+        );
+    }
+
+    /// Adds the number of transactions in the block of each trade, and
+    /// whether the trade is in the first one.
+    struct BlockPosition;
+
+    impl Enricher for BlockPosition {
+        fn enrich(
+            &self,
+            trade: &TradeLog,
+            block: &BlockMetadata,
+        ) -> Vec<(String, String)> {
+            let first_tx = block.transactions.first().map(|tx| tx.hash);
+            vec![
+                (
+                    "block_tx_count".to_string(),
+                    block.transactions.len().to_string(),
+                ),
+                (
+                    "first_in_block".to_string(),
+                    (first_tx == Some(trade.tx_hash)).to_string(),
+                ),
+            ]
+        }
+    }
+
+    #[tokio::test]
+    async fn test_enrichers_add_columns_to_collected_trades() {
+        let onchain = InMemoryChain::seeded(7, 1_000, 1_999);
+        let trades = collect_trades(&onchain, 1_000, 1_999).await.unwrap();
+        assert!(!trades.is_empty());
+
+        let enriched =
+            collect_enriched_trades(&onchain, 1_000, 1_999, &[&BlockPosition])
+                .await
+                .unwrap();
+        assert_eq!(
+            enriched.iter().map(|trade| &trade.trade).collect::<Vec<_>>(),
+            trades.iter().collect::<Vec<_>>()
+        );
+        for trade in &enriched {
+            let names: Vec<&str> =
+                trade.columns.iter().map(|(name, _)| name.as_str()).collect();
+            assert_eq!(names, ["block_tx_count", "first_in_block"]);
+        }
+
+        let unenriched =
+            collect_enriched_trades(&onchain, 1_000, 1_999, &[]).await.unwrap();
+        assert!(unenriched.iter().all(|trade| trade.columns.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn test_reenrich_resumes_after_checkpointed_blocks() {
+        let (trade_logs, blocks) = crate::testing::seeded_chain_data(
+            7,
+            1_000,
+            1_999,
+            crate::testing::ArbSizes::default(),
+        );
+        let onchain = InMemoryChain::new(1_999, blocks.clone(), trade_logs);
+        let dir = tempfile::tempdir().unwrap();
+        let env = test_env(&dir, "trades.csv").build().unwrap();
+        let csv_path = env.output_path();
+
+        update_trades_csv(&env, &onchain).await.unwrap();
+        let trades = read_trades_csv(&env).await.unwrap();
+        assert!(!trades.is_empty());
+
+        // An interrupted run fetched the first block with trades, whose
+        // timestamp is checkpointed off by one to tell it apart.
+        let first_block = trades[0].block_number;
+        let mut checkpointed = blocks[&first_block].clone();
+        checkpointed.timestamp += 1;
+        enrich_cache::record_blocks(
+            &csv_path,
+            &BTreeMap::from([(first_block, checkpointed)]),
+        )
+        .unwrap();
+
+        reenrich_trades_csv(&env, &onchain).await.unwrap();
+
+        let reenriched = read_trades_csv(&env).await.unwrap();
+        assert_eq!(reenriched.len(), trades.len());
+        for (reenriched, trade) in reenriched.iter().zip(&trades) {
+            let offset = u64::from(trade.block_number == first_block);
+            assert_eq!(reenriched.timestamp, trade.timestamp + offset);
+        }
+        assert!(enrich_cache::read_blocks(&csv_path).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_no_enrich_is_filled_in_by_reenrich() {
+        let onchain = InMemoryChain::seeded(7, 1_000, 1_999);
+        let dir = tempfile::tempdir().unwrap();
+        let env = |csv_file: &str, no_enrich| {
+            test_env(&dir, csv_file).with_no_enrich(no_enrich).build().unwrap()
+        };
+
+        let enriched_env = env("enriched.csv", false);
+        update_trades_csv(&enriched_env, &onchain).await.unwrap();
+        let enriched = read_trades_csv(&enriched_env).await.unwrap();
+        assert!(!enriched.is_empty());
+
+        let unenriched_env = env("trades.csv", true);
+        update_trades_csv(&unenriched_env, &onchain).await.unwrap();
+        let csv =
+            std::fs::read_to_string(unenriched_env.output_path()).unwrap();
+        let header = csv.lines().next().unwrap();
+        assert!(header.starts_with("tx_hash,event,contract_address,"));
+        assert!(!header.contains("tx_origin"));
+        let unenriched = read_trades_csv(&unenriched_env).await.unwrap();
+        assert_eq!(unenriched.len(), enriched.len());
+        assert!(unenriched.iter().all(|trade| trade.timestamp == 0));
+
+        // Only re-enriching lets the file be collected into without it.
+        let env = env("trades.csv", false);
+        assert!(matches!(
+            update_trades_csv(&env, &onchain).await,
+            Err(Error::Config(_))
+        ));
+        reenrich_trades_csv(&env, &onchain).await.unwrap();
+        assert_eq!(read_trades_csv(&env).await.unwrap(), enriched);
+        assert!(!meta::read(&env.output_path()).unwrap().unenriched);
+        update_trades_csv(&env, &onchain).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_head_tag_selects_chain_head() {
+        let onchain = InMemoryChain::seeded(7, 1_000, 1_999)
+            .with_tagged_heads(1_800, 1_500);
+        let dir = tempfile::tempdir().unwrap();
+        let env = |csv_file: &str, head_tag| {
+            test_env(&dir, csv_file).with_head_tag(head_tag).build().unwrap()
+        };
+
+        for (csv_file, head_tag, head) in [
+            ("latest.csv", env::HeadTag::Latest, 1_999),
+            ("safe.csv", env::HeadTag::Safe, 1_800),
+            ("finalized.csv", env::HeadTag::Finalized, 1_500),
+        ] {
+            let env = env(csv_file, head_tag);
+            update_trades_csv(&env, &onchain).await.unwrap();
+
+            let trades = read_trades_csv(&env).await.unwrap();
+            assert!(trades.iter().all(|trade| trade.block_number <= head));
+            assert_eq!(
+                meta::read(&env.output_path()).unwrap().last_scanned_block,
+                Some(head)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sample_rate_keeps_same_subset() {
+        let onchain = InMemoryChain::seeded(7, 1_000, 1_999);
+        let dir = tempfile::tempdir().unwrap();
+        let collect = |csv_file: &str, sample_rate| {
+            let env = test_env(&dir, csv_file)
+                .with_sample_rate(sample_rate)
+                .build()
+                .unwrap();
+            let onchain = onchain.clone();
+            async move {
+                update_trades_csv(&env, &onchain).await.unwrap();
+                read_trades_csv(&env).await.unwrap()
+            }
+        };
+
+        let all = collect("all.csv", 1.0).await;
+        let sample = collect("sample.csv", 0.5).await;
+        assert_eq!(collect("resample.csv", 0.5).await, sample);
+        assert!(collect("none.csv", 0.0).await.is_empty());
+
+        assert!(!sample.is_empty() && sample.len() < all.len());
+        let sampled_txs: HashSet<_> =
+            sample.iter().map(|trade| trade.tx_hash).collect();
+        let expected: Vec<&Trade> = all
+            .iter()
+            .filter(|trade| sampled_txs.contains(&trade.tx_hash))
+            .collect();
+        assert_eq!(sample.iter().collect::<Vec<_>>(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_split_by_event_writes_each_event_to_own_file() {
+        let onchain = InMemoryChain::seeded(7, 1_000, 1_999);
+        let dir = tempfile::tempdir().unwrap();
+        let env = |csv_file: &str, split_by_event, to_block| {
+            test_env(&dir, csv_file)
+                .with_split_by_event(split_by_event)
+                .with_to_block(to_block)
+                .build()
+                .unwrap()
+        };
+
+        let all_env = env("all.csv", false, 1_999);
+        update_trades_csv(&all_env, &onchain).await.unwrap();
+        let all = read_trades_csv(&all_env).await.unwrap();
+
+        let csv_path = dir.path().join("split.csv");
+        let csv_path = csv_path.to_str().unwrap();
+        let clear_path = event_path(csv_path, &TradeEvent::ClearV2);
+        let take_path = event_path(csv_path, &TradeEvent::TakeOrderV2);
+        assert!(clear_path.ends_with("split.clearv2.csv"));
+
+        update_trades_csv(&env("split.csv", true, 1_499), &onchain)
+            .await
+            .unwrap();
+        assert!(!std::path::Path::new(csv_path).exists());
+
+        // The TakeOrderV2 file starts over from the deployment block while
+        // the ClearV2 one resumes after block 1,499.
+        for entry in std::fs::read_dir(dir.path()).unwrap() {
+            let path = entry.unwrap().path();
+            if path.to_str().unwrap().starts_with(&take_path) {
+                std::fs::remove_file(path).unwrap();
+            }
+        }
+        let trade_count =
+            update_trades_csv(&env("split.csv", true, 1_999), &onchain)
+                .await
+                .unwrap();
+
+        let clear_trades = read_trades_at(&clear_path).unwrap();
+        let take_trades = read_trades_at(&take_path).unwrap();
+        assert!(!clear_trades.is_empty() && !take_trades.is_empty());
+        assert_eq!(
+            trade_count,
+            take_trades.len()
+                + clear_trades
+                    .iter()
+                    .filter(|trade| trade.block_number >= 1_500)
+                    .count()
+        );
+        for (trades, event) in [
+            (clear_trades, TradeEvent::ClearV2),
+            (take_trades, TradeEvent::TakeOrderV2),
+        ] {
+            let expected: Vec<Trade> = all
+                .iter()
+                .filter(|trade| trade.event == event)
+                .cloned()
+                .collect();
+            assert_eq!(trades, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_merge_chunk_size_keeps_trades() {
+        let onchain = InMemoryChain::seeded(7, 1_000, 1_999);
+        let dir = tempfile::tempdir().unwrap();
+        let collect = |csv_file: &str, merge_chunk_size| {
+            let env = test_env(&dir, csv_file)
+                .with_merge_chunk_size(merge_chunk_size)
+                .build()
+                .unwrap();
+            let onchain = onchain.clone();
+            async move {
+                update_trades_csv(&env, &onchain).await.unwrap();
+                std::fs::read_to_string(env.output_path()).unwrap()
+            }
+        };
+
+        let unchunked = collect("unchunked.csv", 1_000).await;
+        assert!(unchunked.lines().count() > 3);
+        assert_eq!(collect("single.csv", 1).await, unchunked);
+        assert_eq!(collect("three.csv", 3).await, unchunked);
+    }
+
+    #[tokio::test]
+    async fn test_visit_trades_streams_collected_trades_in_order() {
+        let onchain = InMemoryChain::seeded(7, 1_000, 1_999);
+        let trades = collect_trades(&onchain, 1_000, 1_999).await.unwrap();
+
+        let mut visited = vec![];
+        let trade_count = visit_trades(&onchain, 1_000, 1_999, 100, |trade| {
+            visited.push(trade.clone());
+            anyhow::Ok(())
+        })
+        .await
+        .unwrap();
+        assert_eq!(trade_count, trades.len());
+        assert_eq!(visited, trades);
+
+        let result = visit_trades(&onchain, 1_000, 1_999, 100, |_| {
+            Err(anyhow::anyhow!("stop"))
+        })
+        .await;
+        assert!(matches!(result, Err(Error::Visitor(_))));
+    }
+
+    #[tokio::test]
+    async fn test_seen_set_skips_trades_of_overlapping_rescans() {
+        let onchain = InMemoryChain::seeded(7, 1_000, 1_999);
+        let dir = tempfile::tempdir().unwrap();
+        let csv_path = dir.path().join("trades.csv");
+        let env = |from_block| {
+            test_env(&dir, "trades.csv")
+                .with_from_block(from_block)
+                .with_blocks_per_log_request(250)
+                .with_seen_set(true)
+                .build()
+                .unwrap()
+        };
+
+        let trade_count =
+            update_trades_csv(&env(1_000), &onchain).await.unwrap();
+        assert!(trade_count > 0);
+        for from_block in [1_500, 1_000, 1_250] {
+            assert_eq!(
+                update_trades_csv(&env(from_block), &onchain).await.unwrap(),
+                0
+            );
+        }
+
+        let trades = read_trades_csv(&env(1_000)).await.unwrap();
+        assert_eq!(trades.len(), trade_count);
+        // Without the seen-set file, it's seeded from the saved trades.
+        seen::reset(csv_path.to_str().unwrap()).unwrap();
+        assert_eq!(update_trades_csv(&env(1_000), &onchain).await.unwrap(), 0);
+        assert_eq!(read_trades_csv(&env(1_000)).await.unwrap(), trades);
+    }
+
+    #[test]
+    fn test_retain_pairs_matches_either_direction() {
+        let [weth, usdc, dai, wbtc] = [1, 2, 3, 4].map(Address::repeat_byte);
+        let trade = |input_token, output_token| Trade {
+            input_token,
+            output_token,
+            ..crate::testing::trade()
+        };
+        let mut trades = vec![
+            trade(Some(weth), Some(usdc)),
+            trade(Some(usdc), Some(weth)),
+            trade(Some(weth), Some(dai)),
+            trade(Some(wbtc), Some(dai)),
+            trade(Some(dai), Some(wbtc)),
+            trade(Some(usdc), Some(dai)),
+            trade(Some(weth), None),
+        ];
+
+        retain_pairs(&mut trades, &[(weth, usdc), (dai, wbtc)]);
+
+        assert_eq!(
+            trades,
+            vec![
+                trade(Some(weth), Some(usdc)),
+                trade(Some(usdc), Some(weth)),
+                trade(Some(wbtc), Some(dai)),
+                trade(Some(dai), Some(wbtc)),
+            ]
+        );
+    }
+
+    /// A writer that fails once it has taken the given number of bytes, like
+    /// a full disk, taking the part of a buffer that fits first.
+    struct FailingWriter {
+        written: Vec<u8>,
+        capacity: usize,
+    }
+
+    impl Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(self.capacity - self.written.len());
+            if len == 0 && !buf.is_empty() {
+                return Err(std::io::Error::other("disk full"));
+            }
+            self.written.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn trade_at(log_index: u64) -> Trade {
+        let mut trade = crate::testing::trade();
+        trade.log_index = log_index;
+        trade
+    }
+
+    #[test]
+    fn test_failed_batch_write_leaves_csv_parseable() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv_path = dir.path().join("trades.csv");
+        let csv_path = csv_path.to_str().unwrap();
+        let mut csv = serialize_headers(Dialect::Raw, &[]).unwrap();
+        csv.extend(
+            serialize_batch(&[trade_at(0), trade_at(1)], Dialect::Raw, &[])
+                .unwrap(),
+        );
+        let mut output = FailingWriter { written: vec![], capacity: csv.len() };
+
+        output.write_all(&csv).unwrap();
+        // The disk fills up in the middle of the first row.
+        output.capacity += 10;
+        assert!(write_batch(
+            &mut output,
+            &[trade_at(2), trade_at(3)],
+            Dialect::Raw,
+            &[]
+        )
+        .is_err());
+        assert_eq!(output.written.len(), csv.len() + 10);
+        std::fs::write(csv_path, &output.written).unwrap();
+
+        assert_eq!(
+            read_trades_at(csv_path).unwrap(),
+            vec![trade_at(0), trade_at(1)]
+        );
+
+        // Appending starts on a line of its own.
+        output::drop_partial_row(csv_path).unwrap();
+        write_batch(
+            &mut output::open_append(csv_path).unwrap(),
+            &[trade_at(2)],
+            Dialect::Raw,
+            &[],
+        )
+        .unwrap();
+        assert_eq!(
+            read_trades_at(csv_path).unwrap(),
+            vec![trade_at(0), trade_at(1), trade_at(2)]
+        );
+    }
+
+    #[test]
+    fn test_unfinished_gzip_member_leaves_csv_parseable() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv_path = dir.path().join("trades.csv.gz");
+        let csv_path = csv_path.to_str().unwrap();
+        let mut first_run = output::open_append(csv_path).unwrap();
+        first_run
+            .write_all(&serialize_headers(Dialect::Raw, &[]).unwrap())
+            .unwrap();
+        write_batch(&mut first_run, &[trade_at(0)], Dialect::Raw, &[]).unwrap();
+        drop(first_run);
+
+        // The second run is killed after flushing the middle of a row.
+        let rows =
+            serialize_batch(&[trade_at(1), trade_at(2)], Dialect::Raw, &[])
+                .unwrap();
+        let mut second_run = flate2::write::GzEncoder::new(
+            vec![],
+            flate2::Compression::default(),
+        );
+        second_run.write_all(&rows[..rows.len() - 10]).unwrap();
+        second_run.flush().unwrap();
+        let mut file =
+            std::fs::OpenOptions::new().append(true).open(csv_path).unwrap();
+        file.write_all(second_run.get_ref()).unwrap();
+        drop(file);
+
+        assert_eq!(
+            read_trades_at(csv_path).unwrap(),
+            vec![trade_at(0), trade_at(1)]
+        );
+
+        output::drop_partial_row(csv_path).unwrap();
+        let mut third_run = output::open_append(csv_path).unwrap();
+        write_batch(&mut third_run, &[trade_at(2)], Dialect::Raw, &[]).unwrap();
+        drop(third_run);
+        assert_eq!(
+            read_trades_at(csv_path).unwrap(),
+            vec![trade_at(0), trade_at(1), trade_at(2)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reading_csv_of_first_release() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv_path = dir.path().join("trades.csv");
+        std::fs::write(
+            &csv_path,
+            "\
+timestamp,tx_origin,tx_hash,event
+1700000000,0x0101010101010101010101010101010101010101,\
+0x0202020202020202020202020202020202020202020202020202020202020202,ClearV2
+1700000012,0x0404040404040404040404040404040404040404,\
+0x0505050505050505050505050505050505050505050505050505050505050505,\
+TakeOrderV2
+",
+        )
+        .unwrap();
+        let orderbook = Address::repeat_byte(3);
+        let env =
+            Env::builder("http://localhost:8545", vec![orderbook.to_string()])
+                .with_csv_path(csv_path.to_str().unwrap())
+                .with_orderbookv4_deployment_block(0)
+                .build()
+                .unwrap();
+
+        let trades = read_trades_csv(&env).await.unwrap();
+        assert_eq!(
+            trades,
+            vec![
+                Trade {
+                    timestamp: 1_700_000_000,
+                    tx_origin: Address::repeat_byte(1),
+                    tx_hash: B256::repeat_byte(2),
+                    event: TradeEvent::ClearV2,
+                    contract_address: orderbook,
+                    tx_type: String::new(),
+                    ..crate::testing::trade()
+                },
+                Trade {
+                    timestamp: 1_700_000_012,
+                    tx_origin: Address::repeat_byte(4),
+                    tx_hash: B256::repeat_byte(5),
+                    event: TradeEvent::TakeOrderV2,
+                    contract_address: orderbook,
+                    tx_type: String::new(),
+                    ..crate::testing::trade()
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resuming_csv_without_block_columns() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv_path = dir.path().join("trades.csv");
+        let csv_path = csv_path.to_str().unwrap();
+        // Written before the block_number and log_index columns were added.
+        std::fs::write(
+            csv_path,
+            "\
+timestamp,tx_origin,tx_hash,event,contract_address,tx_type,input_token,\
+output_token,input_amount,output_amount,input_symbol,output_symbol,\
+input_amount_adjusted,output_amount_adjusted
+1700000000,0x0101010101010101010101010101010101010101,\
+0x0202020202020202020202020202020202020202020202020202020202020202,\
+TakeOrderV2,0x0303030303030303030303030303030303030303,eip1559,,,,,,,,
+",
+        )
+        .unwrap();
+        let blocks = BTreeMap::from([(
+            150,
+            BlockMetadata {
+                timestamp: 1_700_000_000,
+                transactions: vec![TxMetadata {
+                    origin: Address::repeat_byte(1),
+                    hash: B256::repeat_byte(2),
+                    tx_type: "eip1559".to_string(),
+                }],
+            },
+        )]);
+        let onchain = InMemoryChain::new(200, blocks, []);
+        let env = test_env(&dir, "trades.csv")
+            .with_orderbookv4_deployment_block(0)
+            .with_reorg_safety_margin(10)
+            .build()
+            .unwrap();
+
+        // Resumes after the block of the last trade's transaction without
+        // the safety margin, as the rescanned trades couldn't be told apart.
+        let start_block =
+            get_csv_start_block(&env, &onchain, csv_path, 0).await.unwrap();
+        assert_eq!(start_block, 151);
+
+        let saved_trade_keys =
+            read_saved_trade_keys(csv_path, 0).await.unwrap();
+        assert_eq!(
+            saved_trade_keys,
+            HashSet::from([(B256::repeat_byte(2), 0)])
+        );
+    }
+
+    #[test]
+    fn test_trade_deserializes_without_later_columns() {
+        // The columns of the first release.
+        let csv = "\
+timestamp,tx_origin,tx_hash,event
+1700000000,0x0000000000000000000000000000000000000001,\
+0x0000000000000000000000000000000000000000000000000000000000000002,\
+TakeOrderV2
+";
+        let trades: Vec<Trade> = deserialize_trades(csv.as_bytes())
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].timestamp, 1_700_000_000);
+        assert_eq!(trades[0].event, TradeEvent::TakeOrderV2);
+        assert_eq!(trades[0].contract_address, Address::ZERO);
+        assert_eq!(trades[0].tx_type, "");
+        assert_eq!(trades[0].input_amount, None);
+        assert_eq!(trades[0].output_symbol, None);
+        assert_eq!(trades[0].block_number, 0);
+        assert_eq!(trades[0].log_index, 0);
+    }
+
+    #[tokio::test]
+    async fn test_failed_reenrich_leaves_no_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv_path = dir.path().join("trades.csv");
+        std::fs::write(&csv_path, "timestamp,tx_origin,tx_hash,event\n1,2\n")
+            .unwrap();
+        let env = test_env(&dir, "trades.csv")
+            .with_orderbookv4_deployment_block(0)
+            .build()
+            .unwrap();
+        let onchain = InMemoryChain::new(0, BTreeMap::new(), []);
+
+        assert!(reenrich_trades_csv(&env, &onchain).await.is_err());
+        let meta_path = meta::meta_path(csv_path.to_str().unwrap());
+        assert!(!std::path::Path::new(&meta_path).exists());
+    }
+
+    #[test]
+    fn test_trades_deserialize_with_or_without_headers() {
+        let trades = vec![crate::testing::trade(), crate::testing::trade()];
+        let mut rows = vec![];
+        write_batch(&mut rows, &trades, Dialect::Raw, &[]).unwrap();
+
+        let headerless: Vec<Trade> = deserialize_trades(rows.as_slice())
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(headerless, trades);
+
+        let mut csv = serialize_headers(Dialect::Raw, &[]).unwrap();
+        csv.extend(rows);
+        let with_headers: Vec<Trade> = deserialize_trades(csv.as_slice())
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(with_headers, trades);
+    }
+
+    #[test]
+    fn test_columns_select_fields_of_full_row() {
+        let mut trade = crate::testing::trade();
+        trade.tx_origin = Address::repeat_byte(0xab);
+        trade.input_amount = Some(U256::from(1_500_000));
+        trade.log_index = 7;
+        let columns =
+            ["log_index", "tx_origin", "input_amount", "side", "event"]
+                .map(String::from);
+
+        for dialect in [Dialect::Raw, Dialect::Dune] {
+            let mut full = serialize_headers(dialect, &[]).unwrap();
+            write_batch(&mut full, &[trade.clone()], dialect, &[]).unwrap();
+            let mut selected = serialize_headers(dialect, &columns).unwrap();
+            write_batch(&mut selected, &[trade.clone()], dialect, &columns)
+                .unwrap();
+
+            let mut full = csv::Reader::from_reader(full.as_slice());
+            let full_headers = full.headers().unwrap().clone();
+            let full_row = full.records().next().unwrap().unwrap();
+            let mut selected = csv::Reader::from_reader(selected.as_slice());
+            assert_eq!(selected.headers().unwrap(), columns.as_slice());
+            let selected_row = selected.records().next().unwrap().unwrap();
+
+            let expected: Vec<_> = columns
+                .iter()
+                .map(|column| {
+                    let index = full_headers
+                        .iter()
+                        .position(|header| header == column)
+                        .unwrap();
+                    &full_row[index]
+                })
+                .collect();
+            assert_eq!(selected_row.iter().collect::<Vec<_>>(), expected);
+            assert_eq!(&selected_row[0], "7");
+            assert_eq!(&selected_row[3], "");
+        }
+
+        let env = |columns: &[&str]| {
+            Env::builder(
+                "http://localhost:8545",
+                vec![Address::ZERO.to_string()],
+            )
+            .with_orderbookv4_deployment_block(0)
+            .with_columns(
+                columns.iter().map(|column| column.to_string()).collect(),
+            )
+            .build()
+        };
+        assert!(env(&["timestamp", "tx_hash", "event"]).is_ok());
+        assert!(matches!(env(&["timestamp", "price"]), Err(Error::Config(_))));
+        assert!(matches!(env(&["event", "event"]), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_dune_trades_read_back() {
+        let mut trade = crate::testing::trade();
+        trade.timestamp = 1_700_000_000;
+        trade.tx_origin = Address::repeat_byte(0xab);
+        trade.block_hash = Some(B256::repeat_byte(0xcd));
+        trade.input_amount = Some(U256::from(1_500_000));
+        trade.log_index_in_tx = Some(2);
+        let mut csv = serialize_headers(Dialect::Dune, &[]).unwrap();
+        write_batch(&mut csv, &[trade.clone()], Dialect::Dune, &[]).unwrap();
+
+        let csv_text = String::from_utf8(csv.clone()).unwrap();
+        assert!(csv_text.starts_with("timestamp,block_number,block_hash,"));
+        assert!(csv_text.contains("\n2023-11-14T22:13:20Z,0,0xcdcd"));
+
+        let trades: Vec<Trade> = deserialize_trades(csv.as_slice())
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(trades, vec![trade]);
+    }
+
+    #[test]
+    fn test_block_batches_clamps_final_batch() {
+        let batches = block_batches(1_000, 1_250, 100).collect::<Vec<_>>();
+        assert_eq!(
+            batches,
+            vec![(1_000, 1_099), (1_100, 1_199), (1_200, 1_250)]
+        );
+
+        let (_, last_to_block) = batches.last().copied().unwrap();
+        assert_eq!(last_to_block, 1_250);
+    }
+
+    #[test]
+    fn test_block_batches_queries_small_range_once() {
+        let batches = block_batches(1_000, 1_049, 100_000).collect::<Vec<_>>();
+        assert_eq!(batches, vec![(1_000, 1_049)]);
+
+        let batches = block_batches(1_000, 1_000, u64::MAX).collect::<Vec<_>>();
+        assert_eq!(batches, vec![(1_000, 1_000)]);
+    }
 
     #[tokio::test]
     async fn test_get_start_block() -> anyhow::Result<()> {
         let mut env = Env::init();
         env.csv_path = "test_trades.csv".to_string();
         env.json_rpc_http_url =
-            std::env::var("ARBITRUM_JSON_RPC_HTTP_URL").unwrap();
+            Some(std::env::var("ARBITRUM_JSON_RPC_HTTP_URL").unwrap());
 
         // fake deployment block to speed up the test
-        env.orderbookv4_deployment_block = 267_500_000;
+        env.orderbookv4_deployment_block = Some(267_500_000);
 
         let current_block: BlockNumber = 267_750_000;
-        let orderbook = env.connect_contract()?;
-        let mut onchain = MockChain::new(current_block, orderbook);
+        let provider = env.connect_provider()?;
+        let orderbooks = env.connect_contracts(&provider)?;
+        let mut onchain = MockChain::new(current_block, provider, orderbooks);
 
-        if std::fs::metadata(&env.csv_path).is_ok() {
-            std::fs::remove_file(&env.csv_path)?;
+        for path in [
+            env.csv_path.clone(),
+            meta::meta_path(&env.csv_path),
+            progress::progress_path(&env.csv_path),
+        ] {
+            if std::fs::metadata(&path).is_ok() {
+                std::fs::remove_file(&path)?;
+            }
         }
 
-        update_trades_csv(&env, &onchain).await?;
+        assert_eq!(update_trades_csv(&env, &onchain).await?, 17);
         assert!(std::fs::metadata(&env.csv_path).is_ok());
 
         let saved_trades = read_trades_csv(&env).await?;
@@ -210,7 +4898,7 @@ mod tests {
 
         let current_block: BlockNumber = 268_000_000;
         onchain.set_current_block(current_block);
-        update_trades_csv(&env, &onchain).await?;
+        assert_eq!(update_trades_csv(&env, &onchain).await?, 15);
 
         let saved_trades = read_trades_csv(&env).await?;
         assert_eq!(saved_trades.len(), 32);
@@ -228,6 +4916,7 @@ mod tests {
         assert_eq!(takeorderv2_trade_count, 31);
 
         std::fs::remove_file(&env.csv_path)?;
+        std::fs::remove_file(meta::meta_path(&env.csv_path))?;
 
         Ok(())
     }
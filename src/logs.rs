@@ -1,38 +1,422 @@
 //! A module for fetching and parsing OrderbookV4 event logs from the blockchain.
 
+use alloy::dyn_abi::EventExt;
+use alloy::json_abi::Event;
+use alloy::primitives::Address;
 use alloy::primitives::BlockNumber;
 use alloy::primitives::FixedBytes;
-use alloy::rpc::types::Log;
+use alloy::primitives::B256;
+use alloy::primitives::U256;
+use alloy::providers::Provider;
+use alloy::rpc::types::{Filter, Log};
+use alloy::sol_types::SolEvent;
+use alloy::transports::{RpcError, TransportError, TransportErrorKind};
 use backon::ExponentialBuilder;
 use backon::Retryable;
 use std::collections::BTreeMap;
+use std::fmt::{Debug, Display};
+use std::future::Future;
+use std::str::FromStr;
 use tracing::*;
 
-use crate::OrderbookContract;
+use crate::rpc_calls::RpcCalls;
+use crate::{abi, event_json, Error, IOrderBookV4, OrderbookContract};
+
+/// Trade logs keyed by the number of the block they were emitted in.
+pub(crate) type BlockTradeLogs = BTreeMap<BlockNumber, Vec<TradeLog>>;
 
 /// A partial trade is a trade that has been parsed from a log event.
 #[derive(Debug, Clone)]
-pub(crate) struct TradeLog {
-    pub(crate) log_index: u64,
-    pub(crate) block_number: BlockNumber,
-    pub(crate) tx_hash: FixedBytes<32>,
-    pub(crate) event: TradeEvent,
+pub struct TradeLog {
+    pub log_index: u64,
+    pub block_number: BlockNumber,
+    /// The hash of the block the log was emitted in, which the block body is
+    /// fetched by.
+    pub block_hash: B256,
+    pub tx_hash: FixedBytes<32>,
+    pub event: TradeEvent,
+    pub contract_address: Address,
+    pub fill: TradeFill,
+    /// The full decoded event as JSON, only kept with `--emit-raw-event-json`.
+    pub event_json: Option<String>,
+}
+
+/// The tokens and amounts exchanged in a trade from the perspective of the
+/// filled order, i.e. the taken order for TakeOrderV2 and Alice's order for
+/// ClearV2, unless ClearV2 events are split into one fill per side.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TradeFill {
+    pub input_token: Option<Address>,
+    pub output_token: Option<Address>,
+    pub input_amount: Option<U256>,
+    pub output_amount: Option<U256>,
+    /// The owner of the filled order.
+    pub owner: Option<Address>,
+    /// The owner of the order it was cleared against, i.e. Bob's for ClearV2
+    /// and Alice's for Bob's side of a split ClearV2. TakeOrderV2 has none,
+    /// its taker being the transaction's sender.
+    pub counterparty_owner: Option<Address>,
+    /// The side of a split ClearV2 event the fill is for.
+    pub side: Option<ClearSide>,
+}
+
+/// The two orders matched by a ClearV2 event.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ClearSide {
+    Alice,
+    Bob,
+}
+
+impl TradeFill {
+    /// ClearV2 events only carry the matched orders and their IO indices,
+    /// the cleared amounts are emitted separately in AfterClear.
+    fn from_clearv2(event: &IOrderBookV4::ClearV2) -> Self {
+        let alice = &event.alice;
+        let config = &event.clearConfig;
+
+        Self {
+            input_token: io_token(&alice.validInputs, config.aliceInputIOIndex),
+            output_token: io_token(
+                &alice.validOutputs,
+                config.aliceOutputIOIndex,
+            ),
+            input_amount: None,
+            output_amount: None,
+            owner: Some(alice.owner),
+            counterparty_owner: Some(event.bob.owner),
+            side: None,
+        }
+    }
+
+    /// One fill per order matched by a ClearV2 event, Alice's first. Like
+    /// [`TradeFill::from_clearv2`], they carry no amounts.
+    fn split_clearv2(event: &IOrderBookV4::ClearV2) -> [Self; 2] {
+        let IOrderBookV4::ClearV2 { alice, bob, clearConfig: config, .. } =
+            event;
+
+        [
+            Self { side: Some(ClearSide::Alice), ..Self::from_clearv2(event) },
+            Self {
+                input_token: io_token(&bob.validInputs, config.bobInputIOIndex),
+                output_token: io_token(
+                    &bob.validOutputs,
+                    config.bobOutputIOIndex,
+                ),
+                input_amount: None,
+                output_amount: None,
+                owner: Some(bob.owner),
+                counterparty_owner: Some(alice.owner),
+                side: Some(ClearSide::Bob),
+            },
+        ]
+    }
+
+    /// TakeOrderV2 amounts are from the taker's perspective, so the taker's
+    /// output is the order's input and vice versa.
+    fn from_takeorderv2(event: &IOrderBookV4::TakeOrderV2) -> Self {
+        let order = &event.config.order;
+
+        Self {
+            input_token: io_token(
+                &order.validInputs,
+                event.config.inputIOIndex,
+            ),
+            output_token: io_token(
+                &order.validOutputs,
+                event.config.outputIOIndex,
+            ),
+            input_amount: Some(event.output),
+            output_amount: Some(event.input),
+            owner: Some(order.owner),
+            counterparty_owner: None,
+            side: None,
+        }
+    }
+}
+
+/// Look up the token of the order IO at the given index, if it exists.
+fn io_token(ios: &[IOrderBookV4::IO], index: U256) -> Option<Address> {
+    ios.get(index.saturating_to::<usize>()).map(|io| io.token)
 }
 
-/// An enum representing the kind of trade event that occurred.
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
-pub(crate) enum TradeEvent {
+/// An enum representing the kind of trade event that occurred. Ordered by
+/// declaration to break ties between trades with the same log index.
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+pub enum TradeEvent {
     ClearV2,
     TakeOrderV2,
+    /// A transaction to an orderbook contract that reverted, collected with
+    /// `--include-failed-txs`. Reverted transactions emit no logs, so there
+    /// is no event behind it.
+    FailedTrade,
+    /// Any other event of the orderbook ABI, selected with
+    /// `--abi-event-allowlist` and written as its name. Its logs are
+    /// collected without tokens or amounts.
+    #[serde(untagged)]
+    Abi(String),
+}
+
+/// Parses the name of an event, ignoring case and underscores, e.g.
+/// `ClearV2`, `clearv2` or `clear_v2`, so that flags can take events
+/// directly. Names other than FailedTrade must be events of the orderbook ABI.
+impl FromStr for TradeEvent {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if abi::normalize(value) == abi::normalize("FailedTrade") {
+            return Ok(TradeEvent::FailedTrade);
+        }
+
+        abi::trade_event(value)
+    }
 }
 
-/// Fetch all ClearV2 trades from the given block range.
+/// The events collected unless others are selected.
+pub(crate) const DEFAULT_EVENTS: [TradeEvent; 2] =
+    [TradeEvent::ClearV2, TradeEvent::TakeOrderV2];
+
+impl TradeEvent {
+    /// The topic0 hash that logs of this event are filtered on, if it is
+    /// logged at all.
+    pub(crate) fn signature_hash(&self) -> Option<B256> {
+        match self {
+            TradeEvent::ClearV2 => Some(IOrderBookV4::ClearV2::SIGNATURE_HASH),
+            TradeEvent::TakeOrderV2 => {
+                Some(IOrderBookV4::TakeOrderV2::SIGNATURE_HASH)
+            }
+            TradeEvent::FailedTrade => None,
+            TradeEvent::Abi(name) => abi::event(name).map(Event::selector),
+        }
+    }
+
+    /// The name of the event, as written to the `event` column.
+    pub fn name(&self) -> &str {
+        match self {
+            TradeEvent::ClearV2 => "ClearV2",
+            TradeEvent::TakeOrderV2 => "TakeOrderV2",
+            TradeEvent::FailedTrade => "FailedTrade",
+            TradeEvent::Abi(name) => name,
+        }
+    }
+}
+
+/// The name, signature and topic0 hash of each trade event, as derived from
+/// the generated bindings.
+pub(crate) fn trade_event_signatures() -> [(TradeEvent, &'static str, B256); 2]
+{
+    [
+        (
+            TradeEvent::ClearV2,
+            IOrderBookV4::ClearV2::SIGNATURE,
+            IOrderBookV4::ClearV2::SIGNATURE_HASH,
+        ),
+        (
+            TradeEvent::TakeOrderV2,
+            IOrderBookV4::TakeOrderV2::SIGNATURE,
+            IOrderBookV4::TakeOrderV2::SIGNATURE_HASH,
+        ),
+    ]
+}
+
+/// Print the signature and topic0 hash of each trade event that logs are
+/// filtered on, to check them against the logs returned by a node.
+pub fn print_event_signatures() {
+    for (event, signature, topic0) in trade_event_signatures() {
+        println!("{event:?}");
+        println!("  signature: {signature}");
+        println!("  topic0:    {topic0}");
+    }
+}
+
+/// The number of times a query failing with an error returned by the server
+/// is retried, e.g. on a timeout of the node's own log index.
+const SERVER_RETRIES: usize = 3;
+
+/// The kind of a failed JSON-RPC request, which decides whether it's
+/// retried and from which budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Failure {
+    /// The request didn't get a response, e.g. on a connection reset or
+    /// timeout, or got a gateway error or rate limit response.
+    Network,
+    /// The node returned an error that may not happen again.
+    Server,
+    /// The node rejected the request itself, e.g. as a bad request or for a
+    /// method it doesn't have, which would fail the same way every time.
+    Permanent,
+}
+
+/// An error of a log query that can be classified for retrying.
+trait Classify {
+    fn failure(&self) -> Failure;
+}
+
+impl Classify for TransportError {
+    fn failure(&self) -> Failure {
+        match self {
+            RpcError::ErrorResp(payload) => match payload.code {
+                // Parse error, invalid request, method not found and invalid
+                // params, as defined by the JSON-RPC specification.
+                -32700 | -32600 | -32601 | -32602 => Failure::Permanent,
+                _ => Failure::Server,
+            },
+            RpcError::Transport(TransportErrorKind::HttpError(err)) => {
+                match err.status {
+                    408 | 429 | 500..=599 => Failure::Network,
+                    _ => Failure::Permanent,
+                }
+            }
+            RpcError::Transport(_) => Failure::Network,
+            RpcError::NullResp | RpcError::DeserError { .. } => Failure::Server,
+            _ => Failure::Permanent,
+        }
+    }
+}
+
+impl Classify for alloy::contract::Error {
+    fn failure(&self) -> Failure {
+        match self {
+            alloy::contract::Error::TransportError(err) => err.failure(),
+            _ => Failure::Permanent,
+        }
+    }
+}
+
+/// The retries a query has left for each kind of failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RetryBudget {
+    network: usize,
+    server: usize,
+}
+
+impl RetryBudget {
+    fn new(network_retries: usize) -> Self {
+        Self { network: network_retries, server: SERVER_RETRIES }
+    }
+
+    /// Whether to retry after a failure of the given kind, spending a retry
+    /// from its budget.
+    fn retry(&mut self, failure: Failure) -> bool {
+        let retries = match failure {
+            Failure::Network => &mut self.network,
+            Failure::Server => &mut self.server,
+            Failure::Permanent => return false,
+        };
+        match retries.checked_sub(1) {
+            Some(left) => {
+                *retries = left;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Run the given query, retrying it with exponential backoff on connection
+/// errors up to `network_retries` times and on errors returned by the node
+/// up to [`SERVER_RETRIES`] times. Requests the node rejects fail right away.
+/// The error of the last attempt is returned as [`Error::LogQuery`], with
+/// the given description of the query and how often it was attempted.
+async fn retry_query<T, E, Fut>(
+    query: impl FnMut() -> Fut,
+    network_retries: usize,
+    description: impl Display,
+) -> Result<T, Error>
+where
+    E: Classify + Debug + Into<Error>,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let backoff = ExponentialBuilder::default()
+        .with_max_times(network_retries + SERVER_RETRIES);
+
+    retry_query_with(query, network_retries, backoff, description).await
+}
+
+/// Run the given query like [`retry_query`], waiting between attempts as
+/// the given backoff does.
+async fn retry_query_with<T, E, Fut>(
+    mut query: impl FnMut() -> Fut,
+    network_retries: usize,
+    backoff: ExponentialBuilder,
+    description: impl Display,
+) -> Result<T, Error>
+where
+    E: Classify + Debug + Into<Error>,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut budget = RetryBudget::new(network_retries);
+    let mut attempts = 0;
+    let mut last_delay = None;
+
+    let result = (|| {
+        attempts += 1;
+        query()
+    })
+    .retry(backoff)
+    .when(|err: &E| budget.retry(err.failure()))
+    .notify(|err, dur| {
+        warn!("Retrying querying {description} in {dur:?} due to {err:?}");
+        last_delay = Some(dur);
+    })
+    .await;
+
+    result.map_err(|err| Error::LogQuery {
+        query: description.to_string(),
+        attempts,
+        last_delay,
+        source: Box::new(err.into()),
+    })
+}
+
+/// The settings shared by the log queries of a chain.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct QueryOptions<'a> {
+    /// Only trades sent by one of these are kept, or all if empty.
+    pub(crate) senders: &'a [Address],
+    pub(crate) rpc_calls: &'a RpcCalls,
+    /// The number of times a query is retried on connection errors, see
+    /// [`retry_query`].
+    pub(crate) network_retries: usize,
+    /// Whether each trade keeps the full decoded event as JSON.
+    pub(crate) raw_event_json: bool,
+}
+
+/// Fetch all ClearV2 trades from the given block range, keeping only those
+/// sent by one of the configured senders unless none are. With
+/// `split_clear`, each event yields one trade per matched order. With
+/// `raw_event_json`, each trade keeps the full decoded event. Also returns
+/// the number of foreign logs rejected, see [`reject_foreign`].
 pub(crate) async fn fetch_clearv2_trades(
     start_block: u64,
     end_block: u64,
     orderbook: &OrderbookContract,
-) -> anyhow::Result<BTreeMap<BlockNumber, Vec<TradeLog>>> {
+    options: QueryOptions<'_>,
+    split_clear: bool,
+) -> Result<(BTreeMap<BlockNumber, Vec<TradeLog>>, usize), Error> {
     let clearv2_query = || async {
+        options.rpc_calls.start().await;
         orderbook
             .ClearV2_filter()
             .from_block(start_block)
@@ -41,62 +425,47 @@ pub(crate) async fn fetch_clearv2_trades(
             .await
     };
 
-    let clearv2_logs = clearv2_query
-            .retry(ExponentialBuilder::default())
-            .notify(|err, dur| {
-                warn!("Retrying querying ClearV2 logs from {start_block} to {end_block} in {dur:?} due to {err:?}");
-            })
-            .await?;
+    let mut clearv2_logs = retry_query(
+        clearv2_query,
+        options.network_retries,
+        format!("ClearV2 logs from {start_block} to {end_block}"),
+    )
+    .await?;
 
-    let mut clearv2_trades = BTreeMap::<BlockNumber, Vec<TradeLog>>::new();
-
-    let clearv2_trades_iter = clearv2_logs.into_iter().filter_map(
-        |(
-            _event,
-            Log {
-                log_index,
-                block_number,
-                transaction_hash,
-                ..
-            },
-        )| {
-            trace!(
-                "ClearV2 log: log_index={log_index:?} block_number={block_number:?} \
-                    transaction_hash={transaction_hash:?}"
-            );
-
-            let log_index = log_index?;
-            let tx_hash = transaction_hash?;
-            let block_number = block_number?;
-
-            let trade = TradeLog {
-                log_index,
-                event: TradeEvent::ClearV2,
-                tx_hash,
-                block_number,
-            };
-
-            Some((block_number, trade))
-        },
+    let foreign = reject_foreign(
+        &mut clearv2_logs,
+        "ClearV2",
+        IOrderBookV4::ClearV2::SIGNATURE_HASH,
+        |(_, log)| log,
+    );
+    let (clearv2_trades, dropped) = group_clearv2_logs(
+        clearv2_logs,
+        *orderbook.address(),
+        options.senders,
+        split_clear,
+        options.raw_event_json,
     );
 
-    for (block_number, trade) in clearv2_trades_iter {
-        clearv2_trades
-            .entry(block_number)
-            .and_modify(|trades| trades.push(trade.clone()))
-            .or_insert(vec![trade]);
-    }
+    debug!(
+        "Dropped {dropped} ClearV2 logs with missing fields from {start_block} \
+            to {end_block}"
+    );
 
-    Ok(clearv2_trades)
+    Ok((clearv2_trades, foreign))
 }
 
-/// Fetch all TakeOrderV2 trades from the given block range.
+/// Fetch all TakeOrderV2 trades from the given block range, keeping only those
+/// sent by one of the configured senders unless none are. With
+/// `raw_event_json`, each trade keeps the full decoded event. Also returns
+/// the number of foreign logs rejected, see [`reject_foreign`].
 pub(crate) async fn fetch_takeorderv2_trades(
     start_block: u64,
     end_block: u64,
     orderbook: &OrderbookContract,
-) -> anyhow::Result<BTreeMap<BlockNumber, Vec<TradeLog>>> {
+    options: QueryOptions<'_>,
+) -> Result<(BTreeMap<BlockNumber, Vec<TradeLog>>, usize), Error> {
     let takeorderv2_query = || async {
+        options.rpc_calls.start().await;
         orderbook
             .TakeOrderV2_filter()
             .from_block(start_block)
@@ -105,50 +474,914 @@ pub(crate) async fn fetch_takeorderv2_trades(
             .await
     };
 
-    let takeorderv2_logs = takeorderv2_query
-            .retry(ExponentialBuilder::default())
-            .notify(|err, dur| {
-                warn!("Retrying querying TakeOrderV2 logs from {start_block} to {end_block} in {dur:?} due to {err:?}");
-            })
-            .await?;
-
-    let mut takeorderv2_trades = BTreeMap::<BlockNumber, Vec<TradeLog>>::new();
-
-    let takeorderv2_trades_iter = takeorderv2_logs
-            .into_iter()
-            .filter_map(
-                |(
-                    _event,
-                    Log {
-                        log_index,
-                        block_number,
-                        transaction_hash,
-                        ..
-                    },
-                )| {
-                    trace!("TakeOrderV2 log: log_index={log_index:?} block_number={block_number:?} transaction_hash={transaction_hash:?}");
+    let mut takeorderv2_logs = retry_query(
+        takeorderv2_query,
+        options.network_retries,
+        format!("TakeOrderV2 logs from {start_block} to {end_block}"),
+    )
+    .await?;
+
+    let foreign = reject_foreign(
+        &mut takeorderv2_logs,
+        "TakeOrderV2",
+        IOrderBookV4::TakeOrderV2::SIGNATURE_HASH,
+        |(_, log)| log,
+    );
+    let (takeorderv2_trades, dropped) = group_takeorderv2_logs(
+        takeorderv2_logs,
+        *orderbook.address(),
+        options.senders,
+        options.raw_event_json,
+    );
+
+    debug!(
+        "Dropped {dropped} TakeOrderV2 logs with missing fields from \
+            {start_block} to {end_block}"
+    );
+
+    Ok((takeorderv2_trades, foreign))
+}
+
+/// Fetch all ClearV2 and TakeOrderV2 trades from the given block range with
+/// a single `eth_getLogs` request matching either signature, instead of one
+/// request per event like [`fetch_clearv2_trades`] and
+/// [`fetch_takeorderv2_trades`], which are otherwise equivalent. Returns the
+/// ClearV2 trades, the TakeOrderV2 trades and the number of foreign logs
+/// rejected.
+pub(crate) async fn fetch_all_trades(
+    start_block: u64,
+    end_block: u64,
+    orderbook: &OrderbookContract,
+    options: QueryOptions<'_>,
+    split_clear: bool,
+) -> Result<(BlockTradeLogs, BlockTradeLogs, usize), Error> {
+    let filter = all_trades_filter(orderbook)
+        .from_block(start_block)
+        .to_block(end_block);
+
+    fetch_filtered_trades(
+        filter,
+        format!("from {start_block} to {end_block}"),
+        orderbook,
+        options,
+        split_clear,
+    )
+    .await
+}
+
+/// Fetch all ClearV2 and TakeOrderV2 trades of the block with the given hash
+/// like [`fetch_all_trades`]. Unlike a query by block number, it can't return
+/// the logs of another block at the same height after a reorg: the node
+/// fails it instead if the block is no longer known.
+pub(crate) async fn fetch_block_trades(
+    block_hash: B256,
+    orderbook: &OrderbookContract,
+    options: QueryOptions<'_>,
+    split_clear: bool,
+) -> Result<(BlockTradeLogs, BlockTradeLogs, usize), Error> {
+    let filter = all_trades_filter(orderbook).at_block_hash(block_hash);
+
+    fetch_filtered_trades(
+        filter,
+        format!("of block {block_hash}"),
+        orderbook,
+        options,
+        split_clear,
+    )
+    .await
+}
+
+/// A filter for the ClearV2 and TakeOrderV2 logs of the given orderbook, to
+/// be restricted to the blocks to query.
+fn all_trades_filter(orderbook: &OrderbookContract) -> Filter {
+    Filter::new().address(*orderbook.address()).event_signature(vec![
+        IOrderBookV4::ClearV2::SIGNATURE_HASH,
+        IOrderBookV4::TakeOrderV2::SIGNATURE_HASH,
+    ])
+}
+
+/// Fetch the ClearV2 and TakeOrderV2 trades matching the given filter, see
+/// [`fetch_all_trades`], describing the blocks it matches in logs as given.
+async fn fetch_filtered_trades(
+    filter: Filter,
+    blocks: String,
+    orderbook: &OrderbookContract,
+    options: QueryOptions<'_>,
+    split_clear: bool,
+) -> Result<(BlockTradeLogs, BlockTradeLogs, usize), Error> {
+    let all_trades_query = || async {
+        options.rpc_calls.start().await;
+        orderbook.provider().get_logs(&filter).await
+    };
+
+    let logs = retry_query(
+        all_trades_query,
+        options.network_retries,
+        format!("ClearV2 and TakeOrderV2 logs {blocks}"),
+    )
+    .await?;
+
+    let DemuxedLogs { clearv2_logs, takeorderv2_logs, foreign, undecodable } =
+        demux_trade_logs(logs);
+    if foreign > 0 {
+        warn!(
+            "Rejected {foreign} foreign logs returned for ClearV2 and \
+            TakeOrderV2"
+        );
+    }
+
+    let (clearv2_trades, clearv2_dropped) = group_clearv2_logs(
+        clearv2_logs,
+        *orderbook.address(),
+        options.senders,
+        split_clear,
+        options.raw_event_json,
+    );
+    let (takeorderv2_trades, takeorderv2_dropped) = group_takeorderv2_logs(
+        takeorderv2_logs,
+        *orderbook.address(),
+        options.senders,
+        options.raw_event_json,
+    );
+
+    debug!(
+        "Dropped {} ClearV2 and TakeOrderV2 logs with missing fields and \
+            {undecodable} that didn't decode {blocks}",
+        clearv2_dropped + takeorderv2_dropped
+    );
+
+    Ok((clearv2_trades, takeorderv2_trades, foreign))
+}
 
-                    let log_index = log_index?;
-                    let tx_hash = transaction_hash?;
-                    let block_number = block_number?;
+/// The logs of a query for both ClearV2 and TakeOrderV2, split by event.
+#[derive(Default)]
+struct DemuxedLogs {
+    clearv2_logs: Vec<(IOrderBookV4::ClearV2, Log)>,
+    takeorderv2_logs: Vec<(IOrderBookV4::TakeOrderV2, Log)>,
+    /// Logs whose topic0 is neither signature, see [`reject_foreign`].
+    foreign: usize,
+    /// Logs with either signature whose data didn't decode.
+    undecodable: usize,
+}
+
+/// Split logs queried for both ClearV2 and TakeOrderV2 by their topic0 and
+/// decode them through the bindings.
+fn demux_trade_logs(logs: Vec<Log>) -> DemuxedLogs {
+    let mut demuxed = DemuxedLogs::default();
+
+    for log in logs {
+        match log.topics().first() {
+            Some(&IOrderBookV4::ClearV2::SIGNATURE_HASH) => {
+                match IOrderBookV4::ClearV2::decode_log_data(log.data(), true) {
+                    Ok(event) => demuxed.clearv2_logs.push((event, log)),
+                    Err(err) => {
+                        trace!("Failed to decode ClearV2 log {log:?}: {err}");
+                        demuxed.undecodable += 1;
+                    }
+                }
+            }
+            Some(&IOrderBookV4::TakeOrderV2::SIGNATURE_HASH) => {
+                match IOrderBookV4::TakeOrderV2::decode_log_data(
+                    log.data(),
+                    true,
+                ) {
+                    Ok(event) => demuxed.takeorderv2_logs.push((event, log)),
+                    Err(err) => {
+                        trace!(
+                            "Failed to decode TakeOrderV2 log {log:?}: {err}"
+                        );
+                        demuxed.undecodable += 1;
+                    }
+                }
+            }
+            _ => demuxed.foreign += 1,
+        }
+    }
+
+    demuxed
+}
+
+/// Group decoded ClearV2 logs sent by one of the given senders, or by anyone
+/// if none are given, into trades, see [`fetch_clearv2_trades`].
+fn group_clearv2_logs(
+    mut logs: Vec<(IOrderBookV4::ClearV2, Log)>,
+    contract_address: Address,
+    senders: &[Address],
+    split_clear: bool,
+    raw_event_json: bool,
+) -> (BlockTradeLogs, usize) {
+    retain_senders(&mut logs, senders, |event| event.sender);
+    group_by_block(
+        logs,
+        TradeEvent::ClearV2,
+        contract_address,
+        |event| match split_clear {
+            true => TradeFill::split_clearv2(event).to_vec(),
+            false => vec![TradeFill::from_clearv2(event)],
+        },
+        |event| raw_event_json.then(|| event_json::clearv2(event)),
+    )
+}
+
+/// Group decoded TakeOrderV2 logs sent by one of the given senders, or by
+/// anyone if none are given, into trades, see [`fetch_takeorderv2_trades`].
+fn group_takeorderv2_logs(
+    mut logs: Vec<(IOrderBookV4::TakeOrderV2, Log)>,
+    contract_address: Address,
+    senders: &[Address],
+    raw_event_json: bool,
+) -> (BlockTradeLogs, usize) {
+    retain_senders(&mut logs, senders, |event| event.sender);
+    group_by_block(
+        logs,
+        TradeEvent::TakeOrderV2,
+        contract_address,
+        |event| vec![TradeFill::from_takeorderv2(event)],
+        |event| raw_event_json.then(|| event_json::takeorderv2(event)),
+    )
+}
+
+/// Fetch all logs of the given event of the orderbook ABI from the given
+/// block range, keeping only those sent by one of the configured senders
+/// unless none are. The logs are decoded through the ABI rather than the
+/// bindings, so their trades carry no tokens or amounts. With
+/// `raw_event_json`, each trade keeps the full decoded event. Also returns
+/// the number of foreign logs rejected, see [`reject_foreign`].
+pub(crate) async fn fetch_abi_event_trades(
+    start_block: u64,
+    end_block: u64,
+    orderbook: &OrderbookContract,
+    event_name: &str,
+    options: QueryOptions<'_>,
+) -> Result<(BTreeMap<BlockNumber, Vec<TradeLog>>, usize), Error> {
+    fetch_filtered_abi_event_trades(
+        Filter::new().from_block(start_block).to_block(end_block),
+        format!("from {start_block} to {end_block}"),
+        orderbook,
+        event_name,
+        options,
+    )
+    .await
+}
+
+/// Fetch all logs of the orderbook ABI event with the given name in the
+/// block with the given hash like [`fetch_abi_event_trades`], see
+/// [`fetch_block_trades`].
+pub(crate) async fn fetch_block_abi_event_trades(
+    block_hash: B256,
+    orderbook: &OrderbookContract,
+    event_name: &str,
+    options: QueryOptions<'_>,
+) -> Result<(BTreeMap<BlockNumber, Vec<TradeLog>>, usize), Error> {
+    fetch_filtered_abi_event_trades(
+        Filter::new().at_block_hash(block_hash),
+        format!("of block {block_hash}"),
+        orderbook,
+        event_name,
+        options,
+    )
+    .await
+}
+
+/// Fetch the logs of the orderbook ABI event with the given name in the
+/// blocks the given filter is restricted to, see [`fetch_abi_event_trades`],
+/// describing those blocks in logs as given.
+async fn fetch_filtered_abi_event_trades(
+    filter: Filter,
+    blocks: String,
+    orderbook: &OrderbookContract,
+    event_name: &str,
+    options: QueryOptions<'_>,
+) -> Result<(BTreeMap<BlockNumber, Vec<TradeLog>>, usize), Error> {
+    let event = abi::event(event_name).ok_or_else(|| {
+        Error::Config(format!("{event_name} isn't an orderbook event"))
+    })?;
+    let filter =
+        filter.address(*orderbook.address()).event_signature(event.selector());
 
-                    let trade = TradeLog {
-                        log_index,
-                        event: TradeEvent::TakeOrderV2,
-                        tx_hash,
-                        block_number,
-                    };
+    let abi_event_query = || async {
+        options.rpc_calls.start().await;
+        orderbook.provider().get_logs(&filter).await
+    };
+
+    let mut abi_event_logs = retry_query(
+        abi_event_query,
+        options.network_retries,
+        format!("{event_name} logs {blocks}"),
+    )
+    .await?;
+
+    let foreign = reject_foreign(
+        &mut abi_event_logs,
+        event_name,
+        event.selector(),
+        |log| log,
+    );
+    let mut undecodable = 0;
+    let mut abi_event_logs = abi_event_logs
+        .into_iter()
+        .filter_map(|log| match event.decode_log(log.data(), true) {
+            Ok(decoded) => Some((decoded, log)),
+            Err(err) => {
+                trace!("Failed to decode {event_name} log {log:?}: {err}");
+                undecodable += 1;
+                None
+            }
+        })
+        .collect::<Vec<_>>();
 
-                    Some((block_number, trade))
+    // Events without a sender can't be attributed to any sender.
+    if !options.senders.is_empty() {
+        abi_event_logs.retain(|(decoded, _)| {
+            abi::named_values(event, decoded)
+                .find(|(param, _)| param.name == "sender")
+                .and_then(|(_, sender)| sender.as_address())
+                .is_some_and(|sender| options.senders.contains(&sender))
+        });
+    }
+    let (abi_event_trades, dropped) = group_by_block(
+        abi_event_logs,
+        TradeEvent::Abi(event_name.to_string()),
+        *orderbook.address(),
+        |_| vec![TradeFill::default()],
+        |decoded| {
+            options
+                .raw_event_json
+                .then(|| event_json::abi_event(event, decoded))
+        },
+    );
+
+    debug!(
+        "Dropped {dropped} {event_name} logs with missing fields and \
+            {undecodable} that didn't decode {blocks}"
+    );
+
+    Ok((abi_event_trades, foreign))
+}
+
+/// Drop the logs whose topic0 isn't the signature hash of the queried event,
+/// returning the number dropped. The node is only asked for logs with that
+/// signature, so any other log is foreign, e.g. returned by a provider that
+/// ignores topic filters, and would otherwise be misdecoded or misattributed.
+fn reject_foreign<T>(
+    logs: &mut Vec<T>,
+    event_name: &str,
+    signature_hash: B256,
+    log: impl Fn(&T) -> &Log,
+) -> usize {
+    let log_count = logs.len();
+    logs.retain(|item| log(item).topics().first() == Some(&signature_hash));
+
+    let foreign = log_count - logs.len();
+    if foreign > 0 {
+        warn!("Rejected {foreign} foreign logs returned for {event_name}");
+    }
+
+    foreign
+}
+
+/// Keep only the logs sent by one of the given senders, or all logs if no
+/// senders are given.
+///
+/// `sender` isn't an indexed parameter of the OrderbookV4 events, so unlike
+/// the contract address and event signature it can't be filtered on through
+/// topics by the node and is filtered locally instead.
+fn retain_senders<E>(
+    logs: &mut Vec<(E, Log)>,
+    senders: &[Address],
+    sender: impl Fn(&E) -> Address,
+) {
+    if !senders.is_empty() {
+        logs.retain(|(event, _)| senders.contains(&sender(event)));
+    }
+}
+
+/// Group decoded logs into trades by block number, one per fill of each log,
+/// returning the number of logs dropped for lacking a log index, block
+/// number, block hash or transaction hash. Some providers return pending logs
+/// with these fields set to null.
+fn group_by_block<E>(
+    logs: Vec<(E, Log)>,
+    event: TradeEvent,
+    contract_address: Address,
+    fills: impl Fn(&E) -> Vec<TradeFill>,
+    event_json: impl Fn(&E) -> Option<String>,
+) -> (BTreeMap<BlockNumber, Vec<TradeLog>>, usize) {
+    let mut trades = BTreeMap::<BlockNumber, Vec<TradeLog>>::new();
+    let mut dropped = 0;
+
+    for (decoded, log) in logs {
+        let Log {
+            log_index, block_number, block_hash, transaction_hash, ..
+        } = log;
+
+        trace!(
+            "{event:?} log: log_index={log_index:?} \
+                block_number={block_number:?} \
+                block_hash={block_hash:?} \
+                transaction_hash={transaction_hash:?}"
+        );
+
+        let (
+            Some(log_index),
+            Some(block_number),
+            Some(block_hash),
+            Some(tx_hash),
+        ) = (log_index, block_number, block_hash, transaction_hash)
+        else {
+            dropped += 1;
+            continue;
+        };
+
+        let event_json = event_json(&decoded);
+        trades.entry(block_number).or_default().extend(
+            fills(&decoded).into_iter().map(|fill| TradeLog {
+                log_index,
+                event: event.clone(),
+                tx_hash,
+                block_number,
+                block_hash,
+                contract_address,
+                fill,
+                event_json: event_json.clone(),
+            }),
+        );
+    }
+
+    (trades, dropped)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::transports::HttpError;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::env::Env;
+
+    fn log(
+        log_index: Option<u64>,
+        block_number: Option<u64>,
+        transaction_hash: Option<B256>,
+    ) -> Log {
+        Log {
+            log_index,
+            block_number,
+            block_hash: Some(B256::ZERO),
+            transaction_hash,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_group_by_block_drops_pending_logs() {
+        let tx_hash = B256::repeat_byte(1);
+        let logs = vec![
+            ((), log(Some(0), Some(100), Some(tx_hash))),
+            ((), log(None, None, None)),
+            ((), log(Some(1), None, Some(tx_hash))),
+            ((), log(Some(2), Some(100), None)),
+            ((), log(None, Some(100), Some(tx_hash))),
+            (
+                (),
+                Log {
+                    block_hash: None,
+                    ..log(Some(3), Some(100), Some(tx_hash))
                 },
-            );
+            ),
+        ];
+
+        let (trades, dropped) = group_by_block(
+            logs,
+            TradeEvent::TakeOrderV2,
+            Address::ZERO,
+            |_| vec![TradeFill::default()],
+            |_| None,
+        );
+
+        assert_eq!(dropped, 5);
+        assert_eq!(trades.len(), 1);
+        let block_trades = &trades[&100];
+        assert_eq!(block_trades.len(), 1);
+        assert_eq!(block_trades[0].log_index, 0);
+        assert_eq!(block_trades[0].tx_hash, tx_hash);
+        assert_eq!(block_trades[0].event, TradeEvent::TakeOrderV2);
+    }
+
+    #[tokio::test]
+    async fn test_rpc_timeout_triggers_retry() {
+        // A node that accepts connections but never responds.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let rpc_url = format!("http://{}", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            let mut sockets = vec![];
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, Ordering::SeqCst);
+                sockets.push(socket);
+            }
+        });
+
+        let env = Env::builder(rpc_url, vec![Address::ZERO.to_string()])
+            .with_orderbookv4_deployment_block(0)
+            .with_rpc_timeout_ms(50)
+            .build()
+            .unwrap();
+        let provider = env.connect_provider().unwrap();
+        let orderbooks = env.connect_contracts(&provider).unwrap();
+
+        let rpc_calls = RpcCalls::default();
+        let options = QueryOptions {
+            senders: &[],
+            rpc_calls: &rpc_calls,
+            network_retries: 2,
+            raw_event_json: false,
+        };
+        let result =
+            fetch_clearv2_trades(0, 0, &orderbooks[0], options, false).await;
+
+        assert!(result.is_err());
+        assert_eq!(connections.load(Ordering::SeqCst), 3);
+        assert_eq!(
+            rpc_calls.count() as usize,
+            connections.load(Ordering::SeqCst)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rejected_request_fails_without_retry() {
+        // A node that rejects every request as having invalid params.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let rpc_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 4096];
+                let read = socket.read(&mut request).await.unwrap();
+                let request = String::from_utf8_lossy(&request[..read]);
+                let id: u64 = request
+                    .split("\"id\":")
+                    .nth(1)
+                    .and_then(|rest| {
+                        rest.chars()
+                            .take_while(char::is_ascii_digit)
+                            .collect::<String>()
+                            .parse()
+                            .ok()
+                    })
+                    .unwrap_or(0);
+                let body = serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": { "code": -32602, "message": "invalid params" },
+                })
+                .to_string();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                    Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let env = Env::builder(rpc_url, vec![Address::ZERO.to_string()])
+            .with_orderbookv4_deployment_block(0)
+            .build()
+            .unwrap();
+        let provider = env.connect_provider().unwrap();
+        let orderbooks = env.connect_contracts(&provider).unwrap();
+
+        let rpc_calls = RpcCalls::default();
+        let options = QueryOptions {
+            senders: &[],
+            rpc_calls: &rpc_calls,
+            network_retries: 5,
+            raw_event_json: false,
+        };
+        let result =
+            fetch_clearv2_trades(0, 0, &orderbooks[0], options, false).await;
+
+        assert!(result.is_err());
+        assert_eq!(rpc_calls.count(), 1);
+    }
+
+    #[test]
+    fn test_failures_are_classified_for_retrying() {
+        let error_resp = |code| {
+            TransportError::ErrorResp(
+                serde_json::from_value(
+                    serde_json::json!({"code": code, "message": "error"}),
+                )
+                .unwrap(),
+            )
+        };
+        let http_error = |status| {
+            TransportError::Transport(TransportErrorKind::HttpError(
+                HttpError { status, body: String::new() },
+            ))
+        };
+
+        assert_eq!(error_resp(-32602).failure(), Failure::Permanent);
+        assert_eq!(error_resp(-32601).failure(), Failure::Permanent);
+        assert_eq!(error_resp(-32000).failure(), Failure::Server);
+        assert_eq!(http_error(400).failure(), Failure::Permanent);
+        assert_eq!(http_error(429).failure(), Failure::Network);
+        assert_eq!(http_error(503).failure(), Failure::Network);
+        assert_eq!(
+            TransportErrorKind::backend_gone().failure(),
+            Failure::Network
+        );
+        assert_eq!(
+            alloy::contract::Error::TransportError(error_resp(-32601))
+                .failure(),
+            Failure::Permanent
+        );
+    }
+
+    #[test]
+    fn test_retry_budgets_are_separate() {
+        let mut budget = RetryBudget::new(1);
+
+        assert!(!budget.retry(Failure::Permanent));
+        assert!(budget.retry(Failure::Network));
+        assert!(!budget.retry(Failure::Network));
+        for _ in 0..SERVER_RETRIES {
+            assert!(budget.retry(Failure::Server));
+        }
+        assert!(!budget.retry(Failure::Server));
+
+        let mut budget = RetryBudget::new(0);
+        assert!(!budget.retry(Failure::Network));
+        assert!(budget.retry(Failure::Server));
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_retries_are_reported_with_query() {
+        // Backing off without waiting keeps the test fast.
+        let backoff = ExponentialBuilder::default()
+            .with_min_delay(Duration::ZERO)
+            .with_max_times(1 + SERVER_RETRIES);
+        let result: Result<(), _> = retry_query_with(
+            || async { Err(TransportErrorKind::backend_gone()) },
+            1,
+            backoff,
+            "ClearV2 logs from 100 to 199",
+        )
+        .await;
+
+        let err = result.unwrap_err();
+        assert!(matches!(
+            err,
+            Error::LogQuery { attempts: 2, last_delay: Some(_), .. }
+        ));
+        let message = err.to_string();
+        assert!(message.starts_with(
+            "Querying ClearV2 logs from 100 to 199 failed on attempt 2 after \
+            backing off for "
+        ));
+        assert!(message.contains("RPC request failed"));
+    }
+
+    #[test]
+    fn test_reject_foreign_logs() {
+        let signature_hash = IOrderBookV4::ClearV2::SIGNATURE_HASH;
+        let with_topics = |topics: Vec<B256>| Log {
+            inner: alloy::primitives::Log::new_unchecked(
+                Address::ZERO,
+                topics,
+                Default::default(),
+            ),
+            ..Default::default()
+        };
+        let mut logs = vec![
+            ((), with_topics(vec![signature_hash])),
+            ((), with_topics(vec![IOrderBookV4::TakeOrderV2::SIGNATURE_HASH])),
+            ((), with_topics(vec![])),
+            ((), with_topics(vec![signature_hash, B256::ZERO])),
+        ];
+
+        let foreign =
+            reject_foreign(&mut logs, "ClearV2", signature_hash, |(_, log)| {
+                log
+            });
+
+        assert_eq!(foreign, 2);
+        assert_eq!(logs.len(), 2);
+        assert!(logs
+            .iter()
+            .all(|(_, log)| log.topics().first() == Some(&signature_hash)));
+    }
+
+    #[test]
+    fn test_retain_senders() {
+        let alice = Address::repeat_byte(1);
+        let bob = Address::repeat_byte(2);
+        let carol = Address::repeat_byte(3);
+        let logs = vec![
+            (alice, Log::default()),
+            (bob, Log::default()),
+            (carol, Log::default()),
+            (alice, Log::default()),
+        ];
+
+        let mut unfiltered = logs.clone();
+        retain_senders(&mut unfiltered, &[], |sender| *sender);
+        assert_eq!(unfiltered.len(), 4);
+
+        let mut filtered = logs;
+        retain_senders(&mut filtered, &[alice, carol], |sender| *sender);
+        let senders: Vec<_> =
+            filtered.iter().map(|(sender, _)| *sender).collect();
+        assert_eq!(senders, vec![alice, carol, alice]);
+    }
+
+    fn order(
+        owner: u8,
+        input_token: u8,
+        output_token: u8,
+    ) -> IOrderBookV4::OrderV3 {
+        let io = |token| IOrderBookV4::IO {
+            token: Address::repeat_byte(token),
+            decimals: 18,
+            vaultId: U256::ZERO,
+        };
+
+        IOrderBookV4::OrderV3 {
+            owner: Address::repeat_byte(owner),
+            evaluable: IOrderBookV4::EvaluableV3 {
+                interpreter: Address::ZERO,
+                store: Address::ZERO,
+                bytecode: Default::default(),
+            },
+            validInputs: vec![io(0xff), io(input_token)],
+            validOutputs: vec![io(output_token)],
+            nonce: B256::ZERO,
+        }
+    }
+
+    #[test]
+    fn test_split_clearv2() {
+        let event = IOrderBookV4::ClearV2 {
+            sender: Address::ZERO,
+            alice: order(0xa1, 1, 2),
+            bob: order(0xb0, 2, 1),
+            clearConfig: IOrderBookV4::ClearConfig {
+                aliceInputIOIndex: U256::from(1),
+                aliceOutputIOIndex: U256::ZERO,
+                bobInputIOIndex: U256::from(1),
+                bobOutputIOIndex: U256::ZERO,
+                aliceBountyVaultId: U256::ZERO,
+                bobBountyVaultId: U256::ZERO,
+            },
+        };
 
-    for (block_number, trade) in takeorderv2_trades_iter {
-        takeorderv2_trades
-            .entry(block_number)
-            .and_modify(|trades| trades.push(trade.clone()))
-            .or_insert(vec![trade]);
+        let [alice, bob] = TradeFill::split_clearv2(&event);
+
+        assert_eq!(alice.side, Some(ClearSide::Alice));
+        assert_eq!(alice.owner, Some(Address::repeat_byte(0xa1)));
+        assert_eq!(alice.counterparty_owner, Some(Address::repeat_byte(0xb0)));
+        assert_eq!(alice.input_token, Some(Address::repeat_byte(1)));
+        assert_eq!(alice.output_token, Some(Address::repeat_byte(2)));
+        assert_eq!(bob.side, Some(ClearSide::Bob));
+        assert_eq!(bob.owner, Some(Address::repeat_byte(0xb0)));
+        assert_eq!(bob.counterparty_owner, Some(Address::repeat_byte(0xa1)));
+        assert_eq!(bob.input_token, Some(Address::repeat_byte(2)));
+        assert_eq!(bob.output_token, Some(Address::repeat_byte(1)));
+        assert_eq!(
+            TradeFill { side: None, ..alice },
+            TradeFill::from_clearv2(&event)
+        );
     }
 
-    Ok(takeorderv2_trades)
+    #[test]
+    fn test_demux_trade_logs_by_topic0() {
+        let clearv2 = IOrderBookV4::ClearV2 {
+            sender: Address::repeat_byte(1),
+            alice: order(0xa1, 1, 2),
+            bob: order(0xb0, 2, 1),
+            clearConfig: IOrderBookV4::ClearConfig {
+                aliceInputIOIndex: U256::ZERO,
+                aliceOutputIOIndex: U256::ZERO,
+                bobInputIOIndex: U256::ZERO,
+                bobOutputIOIndex: U256::ZERO,
+                aliceBountyVaultId: U256::ZERO,
+                bobBountyVaultId: U256::ZERO,
+            },
+        };
+        let takeorderv2 = IOrderBookV4::TakeOrderV2 {
+            sender: Address::repeat_byte(2),
+            config: IOrderBookV4::TakeOrderConfigV3 {
+                order: order(0xc0, 1, 2),
+                inputIOIndex: U256::ZERO,
+                outputIOIndex: U256::ZERO,
+                signedContext: vec![],
+            },
+            input: U256::from(3),
+            output: U256::from(4),
+        };
+        let deposit = IOrderBookV4::Deposit {
+            sender: Address::repeat_byte(3),
+            token: Address::repeat_byte(1),
+            vaultId: U256::ZERO,
+            amount: U256::from(5),
+        };
+        let with_data = |log_index, data| Log {
+            inner: alloy::primitives::Log { address: Address::ZERO, data },
+            log_index: Some(log_index),
+            ..Default::default()
+        };
+        let logs = vec![
+            with_data(0, takeorderv2.encode_log_data()),
+            with_data(1, clearv2.encode_log_data()),
+            with_data(2, deposit.encode_log_data()),
+            with_data(
+                3,
+                alloy::primitives::LogData::new_unchecked(
+                    vec![IOrderBookV4::ClearV2::SIGNATURE_HASH],
+                    Default::default(),
+                ),
+            ),
+            with_data(4, takeorderv2.encode_log_data()),
+        ];
+
+        let demuxed = demux_trade_logs(logs);
+
+        assert_eq!(demuxed.foreign, 1);
+        assert_eq!(demuxed.undecodable, 1);
+        let clearv2_indices: Vec<_> =
+            demuxed.clearv2_logs.iter().map(|(_, log)| log.log_index).collect();
+        assert_eq!(clearv2_indices, [Some(1)]);
+        assert_eq!(demuxed.clearv2_logs[0].0.bob.owner, clearv2.bob.owner);
+        let takeorderv2_indices: Vec<_> = demuxed
+            .takeorderv2_logs
+            .iter()
+            .map(|(_, log)| log.log_index)
+            .collect();
+        assert_eq!(takeorderv2_indices, [Some(0), Some(4)]);
+        assert!(demuxed
+            .takeorderv2_logs
+            .iter()
+            .all(|(event, _)| event.output == U256::from(4)));
+    }
+
+    #[test]
+    fn test_group_by_block_keeps_every_fill() {
+        let tx_hash = B256::repeat_byte(1);
+        let logs = vec![((), log(Some(3), Some(100), Some(tx_hash)))];
+
+        let (trades, dropped) = group_by_block(
+            logs,
+            TradeEvent::ClearV2,
+            Address::ZERO,
+            |_| {
+                vec![
+                    TradeFill {
+                        side: Some(ClearSide::Alice),
+                        ..Default::default()
+                    },
+                    TradeFill {
+                        side: Some(ClearSide::Bob),
+                        ..Default::default()
+                    },
+                ]
+            },
+            |_| Some("{}".to_string()),
+        );
+
+        assert_eq!(dropped, 0);
+        let sides: Vec<_> =
+            trades[&100].iter().map(|trade| trade.fill.side).collect();
+        assert_eq!(sides, vec![Some(ClearSide::Alice), Some(ClearSide::Bob)]);
+        assert!(trades[&100].iter().all(|trade| trade.log_index == 3));
+        // Both fills of an event share its JSON.
+        assert!(trades[&100]
+            .iter()
+            .all(|trade| trade.event_json.as_deref() == Some("{}")));
+    }
+
+    #[test]
+    fn test_trade_event_from_str() {
+        for value in ["ClearV2", "clearv2", "CLEARV2", "clear_v2"] {
+            assert_eq!(value.parse(), Ok(TradeEvent::ClearV2));
+        }
+        assert_eq!("take_order_v2".parse(), Ok(TradeEvent::TakeOrderV2));
+        assert_eq!("failed_trade".parse(), Ok(TradeEvent::FailedTrade));
+        assert_eq!(
+            "add_order_v2".parse(),
+            Ok(TradeEvent::Abi("AddOrderV2".to_string()))
+        );
+
+        for value in ["", "clear", "ClearV3", "clear-v2"] {
+            let err = value.parse::<TradeEvent>().unwrap_err();
+            assert!(err.contains("isn't an event of the orderbook ABI"));
+            assert!(err.contains("ClearV2"));
+        }
+    }
 }
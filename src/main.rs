@@ -1,16 +1,159 @@
 #![warn(clippy::complexity)]
 
-use ::rain_drops::env::Env;
+use std::num::NonZeroU32;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use ::rain_drops::analysis::{print_diff, print_histogram, print_volume};
+use ::rain_drops::env::{shutdown_tracing, Command, Env};
 use ::rain_drops::onchain::real::RealChain;
-use ::rain_drops::update_trades_csv;
+use ::rain_drops::{
+    archive_trades_csv, estimate_cost, merge_shards_csv, most_advanced_rpc_url,
+    print_event_signatures, print_validation, reenrich_trades_csv,
+    replay_trades_csv, sort_trades_csv, update_trades_csv,
+    update_trades_postgres,
+};
+use alloy::providers::Provider;
+
+/// The exit code of a collection run that completed without finding any new
+/// trades, so that scripts can skip downstream processing.
+const NO_NEW_TRADES: u8 = 10;
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let env = Env::init();
-    let orderbook = env.connect_contract()?;
-    let onchain = RealChain::new(orderbook);
+async fn main() -> anyhow::Result<ExitCode> {
+    let mut env = Env::load();
+    // The CSV path is expanded before logging is set up, since the log file
+    // of --output-dir is named after the chain.
+    let expanded = expand_csv_path(&mut env).await;
+    env.init_tracing();
+    let result = match expanded {
+        Ok(()) => run(&env).await,
+        Err(err) => Err(err),
+    };
+    shutdown_tracing();
+
+    result
+}
+
+/// Expand the variables of the configured CSV path, only requesting the chain
+/// ID if the path refers to it.
+async fn expand_csv_path(env: &mut Env) -> anyhow::Result<()> {
+    let chain_id = match env.csv_path_uses_chain_id() {
+        true => Some(env.connect_provider()?.get_chain_id().await?),
+        false => None,
+    };
+    env.expand_csv_path(chain_id)?;
+
+    Ok(())
+}
+
+/// Run the configured command.
+async fn run(env: &Env) -> anyhow::Result<ExitCode> {
+    match &env.command {
+        None => return collect(env).await,
+        Some(Command::Histogram { json }) => {
+            print_histogram(env, *json).await?
+        }
+        Some(Command::Stats { json }) => print_volume(env, *json).await?,
+        Some(Command::Signatures) => print_event_signatures(),
+        Some(Command::Merge { shards }) => {
+            merge_shards_csv(env, *shards).await?;
+        }
+        Some(Command::Reenrich) => reenrich(env).await?,
+        Some(Command::Sort) => sort_trades_csv(env).await?,
+        Some(Command::Archive) => archive(env).await?,
+        Some(Command::Replay { speed }) => {
+            replay_trades_csv(env, *speed).await?;
+        }
+        Some(Command::Diff { other_csv_path, rows }) => {
+            let summary = print_diff(env, other_csv_path, *rows).await?;
+            if !summary.is_same() {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Some(Command::Validate { sample }) => {
+            if !validate(env, *sample).await? {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Collect new trades into the configured CSV file, exiting with
+/// [`NO_NEW_TRADES`] if there were none.
+async fn collect(env: &Env) -> anyhow::Result<ExitCode> {
+    let rpc_url = most_advanced_rpc_url(env).await?;
+    let provider = env.connect_provider_at(&rpc_url)?;
+    let orderbooks = env.connect_contracts(&provider)?;
+    let mut onchain = RealChain::new(provider, orderbooks)
+        .with_senders(env.filter_sender.clone())
+        .with_split_clear(env.split_clear)
+        .with_raw_event_json(env.emit_raw_event_json)
+        .with_block_body_batch_size(env.block_body_batch_size as usize)
+        .with_rpc_batch(env.rpc_batch.map(|rpc_batch| rpc_batch as usize))
+        .with_network_timeout_retries(env.network_timeout_retries as usize)
+        .with_max_rps(env.max_rps.and_then(NonZeroU32::new));
+    if let Some(head_cache_ttl) = env.head_cache_ttl {
+        onchain = onchain
+            .with_head_cache(&rpc_url, Duration::from_secs(head_cache_ttl));
+    }
+
+    if env.estimate_cost {
+        let estimate = estimate_cost(env, &onchain).await?;
+        println!("{estimate}");
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let trade_count = match env.postgres_url {
+        Some(_) => update_trades_postgres(env, &onchain).await?,
+        None => update_trades_csv(env, &onchain).await?,
+    };
+
+    match trade_count {
+        0 => Ok(ExitCode::from(NO_NEW_TRADES)),
+        _ => Ok(ExitCode::SUCCESS),
+    }
+}
+
+/// Rewrite the configured CSV file with freshly fetched enrichment.
+async fn reenrich(env: &Env) -> anyhow::Result<()> {
+    let provider = env.connect_provider()?;
+    let orderbooks = env.connect_contracts(&provider)?;
+    let onchain = RealChain::new(provider, orderbooks)
+        .with_block_body_batch_size(env.block_body_batch_size as usize)
+        .with_rpc_batch(env.rpc_batch.map(|rpc_batch| rpc_batch as usize))
+        .with_network_timeout_retries(env.network_timeout_retries as usize)
+        .with_max_rps(env.max_rps.and_then(NonZeroU32::new));
+
+    reenrich_trades_csv(env, &onchain).await?;
+
+    Ok(())
+}
+
+/// Check the configured CSV file against the chain, returning whether all
+/// checked rows match it.
+async fn validate(env: &Env, sample: Option<f64>) -> anyhow::Result<bool> {
+    let provider = env.connect_provider()?;
+    let orderbooks = env.connect_contracts(&provider)?;
+    let onchain = RealChain::new(provider, orderbooks)
+        .with_network_timeout_retries(env.network_timeout_retries as usize)
+        .with_max_rps(env.max_rps.and_then(NonZeroU32::new));
+
+    let summary = print_validation(env, &onchain, sample).await?;
+
+    Ok(summary.is_valid())
+}
+
+/// Package the configured CSV file into an archive, recording the chain it
+/// was collected from.
+async fn archive(env: &Env) -> anyhow::Result<()> {
+    let provider = env.connect_provider()?;
+    let chain_id = provider.get_chain_id().await?;
 
-    update_trades_csv(&env, &onchain).await?;
+    let archive_path = archive_trades_csv(env, chain_id).await?;
+    println!("{archive_path}");
 
     Ok(())
 }
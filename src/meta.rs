@@ -0,0 +1,374 @@
+//! The metadata file stored next to the CSV file, recording how its columns
+//! are encoded so that consumers don't have to guess, and how far the chain
+//! has been scanned.
+
+use alloy::primitives::{Address, BlockNumber, B256};
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+
+use crate::env::{Dialect, Env, TimestampUnit};
+use crate::{output, Error};
+
+/// How the columns of a CSV file are encoded and how far it is complete.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct CsvMeta {
+    pub(crate) timestamp_unit: TimestampUnit,
+    /// The column layout, raw for CSV files written before dialects existed.
+    #[serde(default)]
+    pub(crate) dialect: Dialect,
+    /// The columns selected with `--columns`, empty if all are written.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) columns: Vec<String>,
+    /// The last block whose trades are all saved, which may be later than
+    /// the block of the last saved trade if the blocks after it had none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) last_scanned_block: Option<BlockNumber>,
+    /// The deployment block resolved from `--orderbookv4-deployment-tx`, so
+    /// that it's only looked up once.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) deployment: Option<Deployment>,
+    /// The deployment block found with `--auto-detect-deployment`, so that
+    /// the search only runs once.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) detected_deployment: Option<DetectedDeployment>,
+    /// Whether the trades were written newest first with `--reverse`, so
+    /// that the file isn't appended to in the wrong order.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) newest_first: bool,
+    /// Whether the trades were collected with `--no-enrich` and lack the
+    /// columns of their blocks until they're re-enriched.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) unenriched: bool,
+}
+
+/// The transaction that deployed the OrderbookV4 contract and its block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Deployment {
+    pub(crate) tx_hash: B256,
+    pub(crate) block_number: BlockNumber,
+}
+
+/// The earliest block with trades of the OrderbookV4 contracts, found by
+/// searching their logs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct DetectedDeployment {
+    pub(crate) contract_addresses: Vec<Address>,
+    pub(crate) block_number: BlockNumber,
+}
+
+impl CsvMeta {
+    /// The encoding the current configuration writes trades in.
+    pub(crate) fn from_env(env: &Env) -> Self {
+        Self {
+            timestamp_unit: env.timestamp_unit,
+            dialect: env.dialect,
+            columns: env.csv_columns(),
+            last_scanned_block: None,
+            deployment: None,
+            detected_deployment: None,
+            newest_first: env.reverse,
+            unenriched: env.no_enrich,
+        }
+    }
+
+    /// The encoding of CSV files written before the metadata file existed.
+    fn legacy() -> Self {
+        Self {
+            timestamp_unit: TimestampUnit::Seconds,
+            dialect: Dialect::Raw,
+            columns: vec![],
+            last_scanned_block: None,
+            deployment: None,
+            detected_deployment: None,
+            newest_first: false,
+            unenriched: false,
+        }
+    }
+}
+
+/// The path of the metadata file of the CSV file at the given path.
+pub(crate) fn meta_path(csv_path: &str) -> String {
+    output::sidecar_path(csv_path, ".meta.json")
+}
+
+/// Read the metadata of an existing CSV file, falling back to the legacy
+/// encoding for CSV files without a metadata file.
+pub(crate) fn read(csv_path: &str) -> Result<CsvMeta, Error> {
+    match std::fs::read(meta_path(csv_path)) {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(CsvMeta::legacy()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn write(csv_path: &str, meta: &CsvMeta) -> Result<(), Error> {
+    std::fs::write(meta_path(csv_path), serde_json::to_vec_pretty(meta)?)?;
+    Ok(())
+}
+
+/// Check that an existing CSV file was written with the given encoding, so
+/// that differently encoded rows never end up in the same file, and record
+/// the encoding in the metadata file. New CSV files get a fresh metadata
+/// file, which only keeps the resolved deployment blocks.
+pub(crate) fn ensure(
+    csv_path: &str,
+    csv_exists: bool,
+    meta: &CsvMeta,
+) -> Result<(), Error> {
+    let mut meta = meta.clone();
+    let saved_meta = read(csv_path)?;
+    meta.deployment = saved_meta.deployment;
+    meta.detected_deployment = saved_meta.detected_deployment;
+
+    if csv_exists {
+        if saved_meta.timestamp_unit != meta.timestamp_unit {
+            return Err(Error::Config(format!(
+                "{csv_path} has timestamps in {:?} but the current \
+                configuration writes {:?}",
+                saved_meta.timestamp_unit, meta.timestamp_unit
+            )));
+        }
+        if saved_meta.dialect != meta.dialect {
+            return Err(Error::Config(format!(
+                "{csv_path} is in the {:?} dialect but the current \
+                configuration writes {:?}",
+                saved_meta.dialect, meta.dialect
+            )));
+        }
+        if saved_meta.unenriched && !meta.unenriched {
+            return Err(Error::Config(format!(
+                "{csv_path} was collected with --no-enrich, which needs to \
+                be kept until it's re-enriched"
+            )));
+        }
+        if !saved_meta.unenriched && meta.unenriched {
+            return Err(Error::Config(format!(
+                "{csv_path} was collected with enrichment, which can't be \
+                skipped for the trades added to it"
+            )));
+        }
+        if saved_meta.columns != meta.columns {
+            return Err(Error::Config(format!(
+                "{csv_path} has the columns {:?} but the current \
+                configuration writes {:?}",
+                saved_meta.columns, meta.columns
+            )));
+        }
+
+        meta.last_scanned_block = saved_meta.last_scanned_block;
+        meta.newest_first = saved_meta.newest_first;
+    }
+
+    write(csv_path, &meta)
+}
+
+/// Record that the trades of all blocks up to the given one are saved. Never
+/// moves the recorded block backwards, e.g. when rescanning older blocks with
+/// `--from-block`.
+pub(crate) fn record_scanned(
+    csv_path: &str,
+    last_scanned_block: BlockNumber,
+) -> Result<(), Error> {
+    let saved_meta = read(csv_path)?;
+    let last_scanned_block = saved_meta
+        .last_scanned_block
+        .map_or(last_scanned_block, |saved| saved.max(last_scanned_block));
+    let meta =
+        CsvMeta { last_scanned_block: Some(last_scanned_block), ..saved_meta };
+
+    write(csv_path, &meta)
+}
+
+/// Forget that the trades of the given block and later are saved, e.g. after
+/// they were dropped because the block was reorged.
+pub(crate) fn rewind_scanned(
+    csv_path: &str,
+    from_block: BlockNumber,
+) -> Result<(), Error> {
+    let saved_meta = read(csv_path)?;
+    let last_scanned_block = saved_meta
+        .last_scanned_block
+        .filter(|_| from_block > 0)
+        .map(|saved| saved.min(from_block - 1));
+    let meta = CsvMeta { last_scanned_block, ..saved_meta };
+
+    write(csv_path, &meta)
+}
+
+/// The deployment block previously resolved from the given deployment
+/// transaction, if any.
+pub(crate) fn cached_deployment_block(
+    csv_path: &str,
+    tx_hash: B256,
+) -> Result<Option<BlockNumber>, Error> {
+    Ok(read(csv_path)?
+        .deployment
+        .filter(|deployment| deployment.tx_hash == tx_hash)
+        .map(|deployment| deployment.block_number))
+}
+
+/// Record the deployment block resolved from a deployment transaction.
+pub(crate) fn record_deployment(
+    csv_path: &str,
+    deployment: Deployment,
+) -> Result<(), Error> {
+    let meta = CsvMeta { deployment: Some(deployment), ..read(csv_path)? };
+
+    write(csv_path, &meta)
+}
+
+/// The deployment block previously detected for the given contracts, if any.
+pub(crate) fn cached_detected_deployment_block(
+    csv_path: &str,
+    contract_addresses: &[Address],
+) -> Result<Option<BlockNumber>, Error> {
+    Ok(read(csv_path)?
+        .detected_deployment
+        .filter(|detected| detected.contract_addresses == contract_addresses)
+        .map(|detected| detected.block_number))
+}
+
+/// Record that the trades of the CSV file are in order, e.g. after sorting
+/// a file written newest first.
+pub(crate) fn record_sorted(csv_path: &str) -> Result<(), Error> {
+    let meta = CsvMeta { newest_first: false, ..read(csv_path)? };
+
+    write(csv_path, &meta)
+}
+
+/// Record that the trades of a CSV file collected with `--no-enrich` were
+/// rewritten with the columns of their blocks in the given encoding.
+pub(crate) fn record_enriched(
+    csv_path: &str,
+    meta: &CsvMeta,
+) -> Result<(), Error> {
+    let meta = CsvMeta {
+        timestamp_unit: meta.timestamp_unit,
+        dialect: meta.dialect,
+        columns: meta.columns.clone(),
+        unenriched: false,
+        ..read(csv_path)?
+    };
+
+    write(csv_path, &meta)
+}
+
+/// Record the deployment block detected from the contracts' logs.
+pub(crate) fn record_detected_deployment(
+    csv_path: &str,
+    detected_deployment: DetectedDeployment,
+) -> Result<(), Error> {
+    let meta = CsvMeta {
+        detected_deployment: Some(detected_deployment),
+        ..read(csv_path)?
+    };
+
+    write(csv_path, &meta)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis() -> CsvMeta {
+        CsvMeta {
+            timestamp_unit: TimestampUnit::Millis,
+            dialect: Dialect::Raw,
+            columns: vec![],
+            last_scanned_block: None,
+            deployment: None,
+            detected_deployment: None,
+            newest_first: false,
+            unenriched: false,
+        }
+    }
+
+    #[test]
+    fn test_ensure_writes_meta_for_new_csv() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv_path = dir.path().join("trades.csv");
+        let csv_path = csv_path.to_str().unwrap();
+
+        ensure(csv_path, false, &millis()).unwrap();
+
+        assert_eq!(read(csv_path).unwrap(), millis());
+        ensure(csv_path, true, &millis()).unwrap();
+    }
+
+    #[test]
+    fn test_ensure_rejects_unit_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv_path = dir.path().join("trades.csv");
+        let csv_path = csv_path.to_str().unwrap();
+
+        ensure(csv_path, false, &CsvMeta::legacy()).unwrap();
+
+        assert!(matches!(
+            ensure(csv_path, true, &millis()),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn test_ensure_rejects_dialect_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv_path = dir.path().join("trades.csv");
+        let csv_path = csv_path.to_str().unwrap();
+        let dune = CsvMeta { dialect: Dialect::Dune, ..CsvMeta::legacy() };
+
+        ensure(csv_path, false, &CsvMeta::legacy()).unwrap();
+
+        assert!(matches!(ensure(csv_path, true, &dune), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_ensure_treats_csv_without_meta_as_seconds() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv_path = dir.path().join("trades.csv");
+        let csv_path = csv_path.to_str().unwrap();
+
+        assert!(matches!(
+            ensure(csv_path, true, &millis()),
+            Err(Error::Config(_))
+        ));
+        ensure(csv_path, true, &CsvMeta::legacy()).unwrap();
+    }
+
+    #[test]
+    fn test_last_scanned_block_survives_ensure() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv_path = dir.path().join("trades.csv");
+        let csv_path = csv_path.to_str().unwrap();
+
+        ensure(csv_path, false, &millis()).unwrap();
+        record_scanned(csv_path, 100).unwrap();
+        ensure(csv_path, true, &millis()).unwrap();
+        assert_eq!(read(csv_path).unwrap().last_scanned_block, Some(100));
+
+        // A fresh CSV file starts without a scanned block.
+        ensure(csv_path, false, &millis()).unwrap();
+        assert_eq!(read(csv_path).unwrap().last_scanned_block, None);
+    }
+
+    #[test]
+    fn test_deployment_survives_fresh_csv() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv_path = dir.path().join("trades.csv");
+        let csv_path = csv_path.to_str().unwrap();
+        let deployment =
+            Deployment { tx_hash: B256::repeat_byte(1), block_number: 100 };
+
+        record_deployment(csv_path, deployment).unwrap();
+        ensure(csv_path, false, &millis()).unwrap();
+
+        assert_eq!(
+            cached_deployment_block(csv_path, deployment.tx_hash).unwrap(),
+            Some(100)
+        );
+        // A different deployment transaction isn't served from the cache.
+        assert_eq!(
+            cached_deployment_block(csv_path, B256::repeat_byte(2)).unwrap(),
+            None
+        );
+    }
+}
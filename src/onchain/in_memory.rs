@@ -0,0 +1,382 @@
+//! An in-memory implementation of the [`OnChain`] trait serving user-supplied
+//! data, for examples, doctests and offline testing.
+
+use alloy::primitives::{Address, BlockNumber, FixedBytes, B256};
+use itertools::Itertools;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use super::{BlockMetadata, FailedTx, OnChain};
+use crate::env::HeadTag;
+use crate::logs::{BlockTradeLogs, TradeEvent, TradeLog};
+use crate::tokens::TokenMetadata;
+use crate::Error;
+
+/// A chain that only consists of the given blocks, trade logs and reverted
+/// transactions. Unlike the `MockChain` used in tests, it never falls back to
+/// a real node, and tokens have no metadata.
+#[derive(Debug, Clone)]
+pub struct InMemoryChain {
+    current_block: BlockNumber,
+    safe_block: Option<BlockNumber>,
+    finalized_block: Option<BlockNumber>,
+    blocks: BTreeMap<BlockNumber, BlockMetadata>,
+    trades: Vec<TradeLog>,
+    failed_txs: Vec<FailedTx>,
+    /// The number of queries served, each counted as one RPC call so that
+    /// `--max-rpc-calls` also stops offline runs. Shared by clones.
+    queries: Arc<AtomicU64>,
+}
+
+impl InMemoryChain {
+    /// Create a chain at the given block number from the bodies of its
+    /// blocks and the trade logs emitted in them. Every trade's transaction
+    /// should be included in its block's body.
+    pub fn new(
+        current_block: BlockNumber,
+        blocks: BTreeMap<BlockNumber, BlockMetadata>,
+        trades: impl IntoIterator<Item = TradeLog>,
+    ) -> Self {
+        Self {
+            current_block,
+            safe_block: None,
+            finalized_block: None,
+            blocks,
+            trades: trades.into_iter().collect(),
+            failed_txs: vec![],
+            queries: Arc::default(),
+        }
+    }
+
+    /// Set the blocks the `safe` and `finalized` tags resolve to, which are
+    /// the current block unless set.
+    pub fn with_tagged_heads(
+        mut self,
+        safe_block: BlockNumber,
+        finalized_block: BlockNumber,
+    ) -> Self {
+        self.safe_block = Some(safe_block);
+        self.finalized_block = Some(finalized_block);
+        self
+    }
+
+    /// Add transactions to the orderbook contracts that reverted. Each
+    /// transaction should be included in its block's body.
+    pub fn with_failed_txs(
+        mut self,
+        failed_txs: impl IntoIterator<Item = FailedTx>,
+    ) -> Self {
+        self.failed_txs.extend(failed_txs);
+        self.failed_txs.sort_by_key(|tx| (tx.block_number, tx.tx_index));
+        self
+    }
+
+    /// Create a chain with synthetic trades generated from the given seed in
+    /// the given block range, at its end block. The same seed always yields
+    /// the same chain, for reproducible tests without a network.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn seeded(
+        seed: u64,
+        start_block: BlockNumber,
+        end_block: BlockNumber,
+    ) -> Self {
+        let (trades, blocks) = crate::testing::seeded_chain_data(
+            seed,
+            start_block,
+            end_block,
+            crate::testing::ArbSizes::default(),
+        );
+
+        Self::new(end_block, blocks, trades)
+    }
+
+    /// Count a query as an RPC call.
+    fn query(&self) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Get the trade logs of the given event emitted by the given contract in
+    /// the given block range, grouped by block.
+    fn trades(
+        &self,
+        event: TradeEvent,
+        contract_address: Address,
+        start_block: u64,
+        end_block: u64,
+    ) -> BTreeMap<BlockNumber, Vec<TradeLog>> {
+        self.trades_matching(event, contract_address, |trade| {
+            (start_block..=end_block).contains(&trade.block_number)
+        })
+    }
+
+    /// Get the trade logs of the given event emitted by the given contract
+    /// that match the given predicate, grouped by block.
+    fn trades_matching(
+        &self,
+        event: TradeEvent,
+        contract_address: Address,
+        matches: impl Fn(&TradeLog) -> bool,
+    ) -> BTreeMap<BlockNumber, Vec<TradeLog>> {
+        let mut trades = BTreeMap::<BlockNumber, Vec<TradeLog>>::new();
+
+        for trade in self.trades.iter().filter(|trade| {
+            trade.event == event
+                && trade.contract_address == contract_address
+                && matches(trade)
+        }) {
+            trades.entry(trade.block_number).or_default().push(trade.clone());
+        }
+
+        trades
+    }
+}
+
+impl super::sealed::Sealed for InMemoryChain {}
+
+impl OnChain for InMemoryChain {
+    fn contract_addresses(&self) -> Vec<Address> {
+        self.trades
+            .iter()
+            .map(|trade| trade.contract_address)
+            .sorted()
+            .dedup()
+            .collect()
+    }
+
+    fn rpc_calls(&self) -> u64 {
+        self.queries.load(Ordering::Relaxed)
+    }
+
+    fn foreign_logs(&self) -> u64 {
+        0
+    }
+
+    async fn get_block_number(&self) -> Result<BlockNumber, Error> {
+        self.query();
+        Ok(self.current_block)
+    }
+
+    async fn get_block_number_by_tag(
+        &self,
+        tag: HeadTag,
+    ) -> Result<BlockNumber, Error> {
+        self.query();
+        Ok(match tag {
+            HeadTag::Latest => self.current_block,
+            HeadTag::Safe => self.safe_block.unwrap_or(self.current_block),
+            HeadTag::Finalized => {
+                self.finalized_block.unwrap_or(self.current_block)
+            }
+        })
+    }
+
+    async fn get_block_hash(
+        &self,
+        block_number: BlockNumber,
+    ) -> Result<Option<B256>, Error> {
+        self.query();
+        // Only blocks with trades have a known hash, taken from their logs.
+        Ok(self
+            .trades
+            .iter()
+            .find(|trade| trade.block_number == block_number)
+            .map(|trade| trade.block_hash))
+    }
+
+    async fn get_block_number_by_tx_hash(
+        &self,
+        tx_hash: FixedBytes<32>,
+    ) -> Result<Option<BlockNumber>, Error> {
+        self.query();
+        // Matches the real chain, which returns the block after the
+        // transaction's to resume from.
+        let block_number = self
+            .blocks
+            .iter()
+            .find(|(_, block)| {
+                block.transactions.iter().any(|tx| tx.hash == tx_hash)
+            })
+            .map(|(block_number, _)| block_number + 1);
+
+        Ok(block_number)
+    }
+
+    async fn fetch_clearv2_trades(
+        &self,
+        contract_address: Address,
+        start_block: u64,
+        end_block: u64,
+    ) -> Result<BTreeMap<BlockNumber, Vec<TradeLog>>, Error> {
+        self.query();
+        Ok(self.trades(
+            TradeEvent::ClearV2,
+            contract_address,
+            start_block,
+            end_block,
+        ))
+    }
+
+    async fn fetch_takeorderv2_trades(
+        &self,
+        contract_address: Address,
+        start_block: u64,
+        end_block: u64,
+    ) -> Result<BTreeMap<BlockNumber, Vec<TradeLog>>, Error> {
+        self.query();
+        Ok(self.trades(
+            TradeEvent::TakeOrderV2,
+            contract_address,
+            start_block,
+            end_block,
+        ))
+    }
+
+    async fn fetch_all_trades(
+        &self,
+        contract_address: Address,
+        start_block: u64,
+        end_block: u64,
+    ) -> Result<(BlockTradeLogs, BlockTradeLogs), Error> {
+        self.query();
+        Ok((
+            self.trades(
+                TradeEvent::ClearV2,
+                contract_address,
+                start_block,
+                end_block,
+            ),
+            self.trades(
+                TradeEvent::TakeOrderV2,
+                contract_address,
+                start_block,
+                end_block,
+            ),
+        ))
+    }
+
+    async fn fetch_block_trades(
+        &self,
+        contract_address: Address,
+        block_hash: B256,
+    ) -> Result<(BlockTradeLogs, BlockTradeLogs), Error> {
+        self.query();
+        let in_block = |trade: &TradeLog| trade.block_hash == block_hash;
+        Ok((
+            self.trades_matching(
+                TradeEvent::ClearV2,
+                contract_address,
+                in_block,
+            ),
+            self.trades_matching(
+                TradeEvent::TakeOrderV2,
+                contract_address,
+                in_block,
+            ),
+        ))
+    }
+
+    async fn fetch_abi_event_trades(
+        &self,
+        contract_address: Address,
+        event_name: &str,
+        start_block: u64,
+        end_block: u64,
+    ) -> Result<BTreeMap<BlockNumber, Vec<TradeLog>>, Error> {
+        self.query();
+        Ok(self.trades(
+            TradeEvent::Abi(event_name.to_string()),
+            contract_address,
+            start_block,
+            end_block,
+        ))
+    }
+
+    async fn fetch_block_abi_event_trades(
+        &self,
+        contract_address: Address,
+        event_name: &str,
+        block_hash: B256,
+    ) -> Result<BTreeMap<BlockNumber, Vec<TradeLog>>, Error> {
+        self.query();
+        Ok(self.trades_matching(
+            TradeEvent::Abi(event_name.to_string()),
+            contract_address,
+            |trade| trade.block_hash == block_hash,
+        ))
+    }
+
+    async fn count_raw_logs(
+        &self,
+        contract_address: Address,
+        event: TradeEvent,
+        start_block: u64,
+        end_block: u64,
+    ) -> Result<usize, Error> {
+        self.query();
+        let trades =
+            self.trades(event, contract_address, start_block, end_block);
+
+        Ok(trades.values().map(Vec::len).sum())
+    }
+
+    async fn fetch_failed_txs(
+        &self,
+        start_block: u64,
+        end_block: u64,
+    ) -> Result<Vec<FailedTx>, Error> {
+        self.query();
+        Ok(self
+            .failed_txs
+            .iter()
+            .filter(|tx| (start_block..=end_block).contains(&tx.block_number))
+            .cloned()
+            .collect())
+    }
+
+    async fn fetch_token_metadata(
+        &self,
+        _token: Address,
+    ) -> Result<TokenMetadata, Error> {
+        self.query();
+        Ok(TokenMetadata::default())
+    }
+
+    async fn fetch_block_bodies(
+        &self,
+        block_numbers: impl IntoIterator<Item = BlockNumber>,
+    ) -> Result<BTreeMap<BlockNumber, BlockMetadata>, Error> {
+        self.query();
+        Ok(block_numbers
+            .into_iter()
+            .filter_map(|block_number| {
+                let block = self.blocks.get(&block_number)?;
+                Some((block_number, block.clone()))
+            })
+            .collect())
+    }
+
+    async fn fetch_block_bodies_by_hash(
+        &self,
+        blocks: impl IntoIterator<Item = (BlockNumber, B256)>,
+    ) -> Result<BTreeMap<BlockNumber, BlockMetadata>, Error> {
+        // The in-memory chain never reorgs, so the hashes can be ignored, and
+        // the query is counted by `fetch_block_bodies`.
+        self.fetch_block_bodies(blocks.into_iter().map(|(number, _)| number))
+            .await
+    }
+
+    async fn fetch_block_timestamps(
+        &self,
+        blocks: impl IntoIterator<Item = (BlockNumber, B256)>,
+    ) -> Result<BTreeMap<BlockNumber, u64>, Error> {
+        self.query();
+        Ok(blocks
+            .into_iter()
+            .filter_map(|(block_number, _)| {
+                let block = self.blocks.get(&block_number)?;
+                Some((block_number, block.timestamp))
+            })
+            .collect())
+    }
+}
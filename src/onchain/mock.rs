@@ -1,13 +1,15 @@
 //! A mock implementation of the [`OnChain`] trait that allows for
 //! deterministic testing by mocking the current block number.
 
-use alloy::primitives::{BlockNumber, FixedBytes};
+use alloy::primitives::{Address, BlockNumber, FixedBytes, B256};
 use std::collections::BTreeMap;
 
 use super::real::RealChain;
-use super::{BlockMetadata, OnChain};
-use crate::logs::TradeLog;
-use crate::OrderbookContract;
+use super::{BlockMetadata, FailedTx, OnChain};
+use crate::env::HeadTag;
+use crate::logs::{BlockTradeLogs, TradeEvent, TradeLog};
+use crate::tokens::TokenMetadata;
+use crate::{Error, OrderbookContract, OrderbookProvider};
 
 /// A wrapper around the real chain that allows for mocking the block number
 /// for deterministic testing
@@ -17,13 +19,17 @@ pub(crate) struct MockChain {
 }
 
 impl MockChain {
-    /// Create a new [`MockChain`] wrapper around the given orderbook
-    /// contract.
+    /// Create a new [`MockChain`] wrapper around the given provider and
+    /// orderbook contracts.
     pub(crate) fn new(
         current_block: BlockNumber,
-        orderbook_contract: OrderbookContract,
+        provider: OrderbookProvider,
+        orderbook_contracts: Vec<OrderbookContract>,
     ) -> Self {
-        Self { current_block, real_chain: RealChain::new(orderbook_contract) }
+        Self {
+            current_block,
+            real_chain: RealChain::new(provider, orderbook_contracts),
+        }
     }
 
     /// Set the current block number.
@@ -32,38 +38,164 @@ impl MockChain {
     }
 }
 
+impl super::sealed::Sealed for MockChain {}
+
 impl OnChain for MockChain {
-    async fn get_block_number(&self) -> anyhow::Result<BlockNumber> {
+    fn contract_addresses(&self) -> Vec<Address> {
+        self.real_chain.contract_addresses()
+    }
+
+    fn rpc_calls(&self) -> u64 {
+        self.real_chain.rpc_calls()
+    }
+
+    fn foreign_logs(&self) -> u64 {
+        self.real_chain.foreign_logs()
+    }
+
+    async fn get_block_number(&self) -> Result<BlockNumber, Error> {
         Ok(self.current_block)
     }
 
+    async fn get_block_number_by_tag(
+        &self,
+        _tag: HeadTag,
+    ) -> Result<BlockNumber, Error> {
+        Ok(self.current_block)
+    }
+
+    async fn get_block_hash(
+        &self,
+        block_number: BlockNumber,
+    ) -> Result<Option<B256>, Error> {
+        self.real_chain.get_block_hash(block_number).await
+    }
+
     async fn get_block_number_by_tx_hash(
         &self,
         tx_hash: FixedBytes<32>,
-    ) -> anyhow::Result<Option<BlockNumber>> {
+    ) -> Result<Option<BlockNumber>, Error> {
         self.real_chain.get_block_number_by_tx_hash(tx_hash).await
     }
 
     async fn fetch_clearv2_trades(
         &self,
+        contract_address: Address,
         start_block: u64,
         end_block: u64,
-    ) -> anyhow::Result<BTreeMap<BlockNumber, Vec<TradeLog>>> {
-        self.real_chain.fetch_clearv2_trades(start_block, end_block).await
+    ) -> Result<BTreeMap<BlockNumber, Vec<TradeLog>>, Error> {
+        self.real_chain
+            .fetch_clearv2_trades(contract_address, start_block, end_block)
+            .await
     }
 
     async fn fetch_takeorderv2_trades(
         &self,
+        contract_address: Address,
+        start_block: u64,
+        end_block: u64,
+    ) -> Result<BTreeMap<BlockNumber, Vec<TradeLog>>, Error> {
+        self.real_chain
+            .fetch_takeorderv2_trades(contract_address, start_block, end_block)
+            .await
+    }
+
+    async fn fetch_all_trades(
+        &self,
+        contract_address: Address,
         start_block: u64,
         end_block: u64,
-    ) -> anyhow::Result<BTreeMap<BlockNumber, Vec<TradeLog>>> {
-        self.real_chain.fetch_takeorderv2_trades(start_block, end_block).await
+    ) -> Result<(BlockTradeLogs, BlockTradeLogs), Error> {
+        self.real_chain
+            .fetch_all_trades(contract_address, start_block, end_block)
+            .await
+    }
+
+    async fn fetch_block_trades(
+        &self,
+        contract_address: Address,
+        block_hash: B256,
+    ) -> Result<(BlockTradeLogs, BlockTradeLogs), Error> {
+        self.real_chain.fetch_block_trades(contract_address, block_hash).await
+    }
+
+    async fn fetch_abi_event_trades(
+        &self,
+        contract_address: Address,
+        event_name: &str,
+        start_block: u64,
+        end_block: u64,
+    ) -> Result<BTreeMap<BlockNumber, Vec<TradeLog>>, Error> {
+        self.real_chain
+            .fetch_abi_event_trades(
+                contract_address,
+                event_name,
+                start_block,
+                end_block,
+            )
+            .await
+    }
+
+    async fn fetch_block_abi_event_trades(
+        &self,
+        contract_address: Address,
+        event_name: &str,
+        block_hash: B256,
+    ) -> Result<BTreeMap<BlockNumber, Vec<TradeLog>>, Error> {
+        self.real_chain
+            .fetch_block_abi_event_trades(
+                contract_address,
+                event_name,
+                block_hash,
+            )
+            .await
+    }
+
+    async fn count_raw_logs(
+        &self,
+        contract_address: Address,
+        event: TradeEvent,
+        start_block: u64,
+        end_block: u64,
+    ) -> Result<usize, Error> {
+        self.real_chain
+            .count_raw_logs(contract_address, event, start_block, end_block)
+            .await
+    }
+
+    async fn fetch_failed_txs(
+        &self,
+        start_block: u64,
+        end_block: u64,
+    ) -> Result<Vec<FailedTx>, Error> {
+        self.real_chain.fetch_failed_txs(start_block, end_block).await
+    }
+
+    async fn fetch_token_metadata(
+        &self,
+        token: Address,
+    ) -> Result<TokenMetadata, Error> {
+        self.real_chain.fetch_token_metadata(token).await
     }
 
     async fn fetch_block_bodies(
         &self,
         block_numbers: impl IntoIterator<Item = BlockNumber>,
-    ) -> anyhow::Result<BTreeMap<BlockNumber, BlockMetadata>> {
+    ) -> Result<BTreeMap<BlockNumber, BlockMetadata>, Error> {
         self.real_chain.fetch_block_bodies(block_numbers).await
     }
+
+    async fn fetch_block_bodies_by_hash(
+        &self,
+        blocks: impl IntoIterator<Item = (BlockNumber, B256)>,
+    ) -> Result<BTreeMap<BlockNumber, BlockMetadata>, Error> {
+        self.real_chain.fetch_block_bodies_by_hash(blocks).await
+    }
+
+    async fn fetch_block_timestamps(
+        &self,
+        blocks: impl IntoIterator<Item = (BlockNumber, B256)>,
+    ) -> Result<BTreeMap<BlockNumber, u64>, Error> {
+        self.real_chain.fetch_block_timestamps(blocks).await
+    }
 }
@@ -1,60 +1,201 @@
 //! A layer of abstraction for controlling interactions with the blockchain
 //! depending on whether we are running in a test environment or not.
 
-use alloy::primitives::{Address, BlockNumber, FixedBytes};
+use alloy::primitives::{Address, BlockNumber, FixedBytes, B256};
 use std::collections::BTreeMap;
 
-use crate::logs::TradeLog;
+use crate::env::HeadTag;
+use crate::logs::{BlockTradeLogs, TradeEvent, TradeLog};
+use crate::tokens::TokenMetadata;
+use crate::Error;
 
+pub mod in_memory;
 #[cfg(test)]
 pub mod mock;
 pub mod real;
 
 /// Simplified block representation that only includes metadata relevant to us.
 /// This helps with auto-generating test data.
-#[derive(Debug, Clone)]
-pub(crate) struct BlockMetadata {
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct BlockMetadata {
     pub timestamp: u64,
     pub transactions: Vec<TxMetadata>,
 }
 
 /// Simplified transaction representation that only includes relevant metadata.
 /// This helps with auto-generating test data.
-#[derive(Debug, Clone)]
-pub(crate) struct TxMetadata {
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct TxMetadata {
     pub origin: Address,
     pub hash: FixedBytes<32>,
+    /// The transaction type, e.g. `legacy` or `eip1559`.
+    pub tx_type: String,
 }
 
-/// A trait for interacting with the blockchain and deployed orderbook contract.
-pub(crate) trait OnChain {
+/// A transaction to an orderbook contract that reverted, found through its
+/// receipt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FailedTx {
+    pub block_number: BlockNumber,
+    pub block_hash: B256,
+    pub tx_hash: FixedBytes<32>,
+    /// The index of the transaction in its block.
+    pub tx_index: u64,
+    pub contract_address: Address,
+}
+
+mod sealed {
+    /// Keeps [`super::OnChain`] from being implemented outside this crate,
+    /// so that methods can be added to it.
+    pub trait Sealed {}
+}
+
+/// A trait for interacting with the blockchain and deployed orderbook
+/// contracts, implemented by [`real::RealChain`] and
+/// [`in_memory::InMemoryChain`] for the library functions taking a chain.
+///
+/// It is sealed, so its futures are only ever awaited by this crate and
+/// needn't promise to be `Send`.
+#[allow(async_fn_in_trait)]
+pub trait OnChain: sealed::Sealed {
+    /// Get the addresses of all orderbook contracts to collect trades from.
+    fn contract_addresses(&self) -> Vec<Address>;
+
+    /// Get the number of JSON-RPC requests made so far, including retries.
+    fn rpc_calls(&self) -> u64;
+
+    /// Get the number of logs rejected so far for lacking the signature of
+    /// the event they were queried for.
+    fn foreign_logs(&self) -> u64;
+
     /// Get the current block number.
-    async fn get_block_number(&self) -> anyhow::Result<BlockNumber>;
+    async fn get_block_number(&self) -> Result<BlockNumber, Error>;
+
+    /// Get the number of the block the given tag resolves to, the current
+    /// block for [`HeadTag::Latest`].
+    async fn get_block_number_by_tag(
+        &self,
+        tag: HeadTag,
+    ) -> Result<BlockNumber, Error>;
+
+    /// Get the hash of the canonical block with the given number, if the chain
+    /// has reached it.
+    async fn get_block_hash(
+        &self,
+        block_number: BlockNumber,
+    ) -> Result<Option<B256>, Error>;
 
     /// Get the block number in which a transaction with the given hash was
     /// included.
     async fn get_block_number_by_tx_hash(
         &self,
         tx_hash: FixedBytes<32>,
-    ) -> anyhow::Result<Option<BlockNumber>>;
+    ) -> Result<Option<BlockNumber>, Error>;
 
-    /// Fetch all ClearV2 trades from the given block range.
+    /// Fetch all ClearV2 trades emitted by the given contract in the given
+    /// block range.
     async fn fetch_clearv2_trades(
         &self,
+        contract_address: Address,
         start_block: u64,
         end_block: u64,
-    ) -> anyhow::Result<BTreeMap<BlockNumber, Vec<TradeLog>>>;
+    ) -> Result<BTreeMap<BlockNumber, Vec<TradeLog>>, Error>;
 
-    /// Fetch all TakeOrderV2 trades from the given block range.
+    /// Fetch all TakeOrderV2 trades emitted by the given contract in the given
+    /// block range.
     async fn fetch_takeorderv2_trades(
         &self,
+        contract_address: Address,
+        start_block: u64,
+        end_block: u64,
+    ) -> Result<BTreeMap<BlockNumber, Vec<TradeLog>>, Error>;
+
+    /// Fetch all ClearV2 and TakeOrderV2 trades emitted by the given contract
+    /// in the given block range at once, returning them by event.
+    async fn fetch_all_trades(
+        &self,
+        contract_address: Address,
         start_block: u64,
         end_block: u64,
-    ) -> anyhow::Result<BTreeMap<BlockNumber, Vec<TradeLog>>>;
+    ) -> Result<(BlockTradeLogs, BlockTradeLogs), Error>;
+
+    /// Fetch all ClearV2 and TakeOrderV2 trades emitted by the given contract
+    /// in the block with the given hash, returning them by event. Unlike
+    /// [`OnChain::fetch_all_trades`], the trades are sure to belong to that
+    /// block even if another one took its place.
+    async fn fetch_block_trades(
+        &self,
+        contract_address: Address,
+        block_hash: B256,
+    ) -> Result<(BlockTradeLogs, BlockTradeLogs), Error>;
+
+    /// Fetch all logs of the orderbook ABI event with the given name emitted
+    /// by the given contract in the given block range, as trades without
+    /// tokens or amounts.
+    async fn fetch_abi_event_trades(
+        &self,
+        contract_address: Address,
+        event_name: &str,
+        start_block: u64,
+        end_block: u64,
+    ) -> Result<BTreeMap<BlockNumber, Vec<TradeLog>>, Error>;
+
+    /// Fetch all logs of the orderbook ABI event with the given name emitted
+    /// by the given contract in the block with the given hash, like
+    /// [`OnChain::fetch_block_trades`].
+    async fn fetch_block_abi_event_trades(
+        &self,
+        contract_address: Address,
+        event_name: &str,
+        block_hash: B256,
+    ) -> Result<BTreeMap<BlockNumber, Vec<TradeLog>>, Error>;
+
+    /// Count the logs of the given event emitted by the given contract in the
+    /// given block range with a raw `eth_getLogs` query, bypassing the
+    /// contract bindings.
+    async fn count_raw_logs(
+        &self,
+        contract_address: Address,
+        event: TradeEvent,
+        start_block: u64,
+        end_block: u64,
+    ) -> Result<usize, Error>;
+
+    /// Fetch the transactions to the orderbook contracts in the given block
+    /// range that reverted, ordered by block and index in the block.
+    async fn fetch_failed_txs(
+        &self,
+        start_block: u64,
+        end_block: u64,
+    ) -> Result<Vec<FailedTx>, Error>;
+
+    /// Fetch the ERC-20 metadata of the given token, leaving out whatever
+    /// the token doesn't implement.
+    async fn fetch_token_metadata(
+        &self,
+        token: Address,
+    ) -> Result<TokenMetadata, Error>;
 
     /// Fetch block bodies for a sequence of block numbers.
     async fn fetch_block_bodies(
         &self,
         block_numbers: impl IntoIterator<Item = BlockNumber>,
-    ) -> anyhow::Result<BTreeMap<BlockNumber, BlockMetadata>>;
+    ) -> Result<BTreeMap<BlockNumber, BlockMetadata>, Error>;
+
+    /// Fetch block bodies for a sequence of block numbers by their hashes, so
+    /// that a block reorged after its logs were queried isn't replaced by a
+    /// different block with the same number.
+    async fn fetch_block_bodies_by_hash(
+        &self,
+        blocks: impl IntoIterator<Item = (BlockNumber, B256)>,
+    ) -> Result<BTreeMap<BlockNumber, BlockMetadata>, Error>;
+
+    /// Fetch the timestamps of the blocks with the given numbers and hashes
+    /// from their headers, without downloading their transactions, for
+    /// callers that don't need transaction origins. Blocks that don't exist
+    /// are left out.
+    async fn fetch_block_timestamps(
+        &self,
+        blocks: impl IntoIterator<Item = (BlockNumber, B256)>,
+    ) -> Result<BTreeMap<BlockNumber, u64>, Error>;
 }
@@ -1,44 +1,432 @@
 //! A real implementation of the [`OnChain`] trait that interacts with the
 //! blockchain.
 
-use alloy::eips::BlockNumberOrTag;
-use alloy::network::TransactionResponse;
-use alloy::primitives::{BlockNumber, FixedBytes};
+use alloy::consensus::TxEnvelope;
+use alloy::eips::{BlockId, BlockNumberOrTag};
+use alloy::network::{
+    AnyNetwork, AnyTxEnvelope, Network, ReceiptResponse, TransactionResponse,
+};
+use alloy::primitives::{Address, BlockNumber, FixedBytes, B256};
 use alloy::providers::Provider;
-use alloy::rpc::types::{Block, BlockTransactionsKind};
+use alloy::rpc::client::{BatchRequest, Waiter};
+use alloy::rpc::types::{Block, BlockTransactionsKind, Filter};
+use alloy::transports::RpcError;
+use futures::future::try_join_all;
 use itertools::Itertools;
+use serde_json::Value;
 use std::collections::BTreeMap;
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
 use tracing::*;
 
 use super::OnChain;
-use crate::onchain::{BlockMetadata, TxMetadata};
-use crate::{OrderbookContract, TradeLog};
+use crate::env::HeadTag;
+use crate::head_cache::HeadCache;
+use crate::logs::{BlockTradeLogs, QueryOptions};
+use crate::onchain::{BlockMetadata, FailedTx, TxMetadata};
+use crate::rpc_calls::RpcCalls;
+use crate::tokens::{IERC20Metadata, TokenMetadata};
+use crate::{
+    Error, OrderbookContract, OrderbookProvider, TradeEvent, TradeLog,
+};
 
-/// A wrapper around the connected orderbook contract that implements the
-/// [`OnChain`] trait.
+/// A wrapper around the connected provider and orderbook contracts that
+/// implements the [`OnChain`] trait.
 pub struct RealChain {
-    contract: OrderbookContract,
+    provider: OrderbookProvider,
+    contracts: Vec<OrderbookContract>,
+    senders: Vec<Address>,
+    split_clear: bool,
+    raw_event_json: bool,
+    block_body_batch_size: usize,
+    rpc_batch: Option<usize>,
+    /// Whether the endpoint rejected a JSON-RPC batch, after which block
+    /// bodies are fetched with individual calls.
+    batches_rejected: AtomicBool,
+    rpc_calls: RpcCalls,
+    network_timeout_retries: usize,
+    /// The number of logs rejected so far for lacking the signature of the
+    /// queried event.
+    foreign_logs: AtomicU64,
+    head_cache: Option<HeadCache>,
 }
 
 impl RealChain {
-    /// Create a new [`RealChain`] wrapper around the given orderbook
-    /// contract.
-    pub fn new(contract: OrderbookContract) -> Self {
-        Self { contract }
+    /// Create a new [`RealChain`] wrapper around the given provider and
+    /// orderbook contracts.
+    pub fn new(
+        provider: OrderbookProvider,
+        contracts: Vec<OrderbookContract>,
+    ) -> Self {
+        Self {
+            provider,
+            contracts,
+            senders: vec![],
+            split_clear: false,
+            raw_event_json: false,
+            block_body_batch_size: 1,
+            rpc_batch: None,
+            batches_rejected: AtomicBool::new(false),
+            rpc_calls: RpcCalls::default(),
+            network_timeout_retries: 3,
+            foreign_logs: AtomicU64::new(0),
+            head_cache: None,
+        }
+    }
+
+    /// Only collect trades sent by one of the given addresses. Collects all
+    /// trades if empty.
+    pub fn with_senders(mut self, senders: Vec<Address>) -> Self {
+        self.senders = senders;
+        self
+    }
+
+    /// Collect one trade per matched order of each ClearV2 event instead of
+    /// one trade for Alice's order.
+    pub fn with_split_clear(mut self, split_clear: bool) -> Self {
+        self.split_clear = split_clear;
+        self
+    }
+
+    /// Keep the full decoded event of each trade as JSON.
+    pub fn with_raw_event_json(mut self, raw_event_json: bool) -> Self {
+        self.raw_event_json = raw_event_json;
+        self
+    }
+
+    /// Fetch up to the given number of block bodies concurrently instead of
+    /// one at a time.
+    pub fn with_block_body_batch_size(
+        mut self,
+        block_body_batch_size: usize,
+    ) -> Self {
+        self.block_body_batch_size = block_body_batch_size.max(1);
+        self
+    }
+
+    /// Fetch block bodies in JSON-RPC batches of up to the given number of
+    /// calls, one HTTP request each, instead of one request per block.
+    pub fn with_rpc_batch(mut self, rpc_batch: Option<usize>) -> Self {
+        self.rpc_batch = rpc_batch.map(|rpc_batch| rpc_batch.max(1));
+        self
+    }
+
+    /// Make at most the given number of JSON-RPC requests per second, across
+    /// all concurrent requests, or any number if unset.
+    pub fn with_max_rps(mut self, max_rps: Option<NonZeroU32>) -> Self {
+        self.rpc_calls = RpcCalls::new(max_rps);
+        self
+    }
+
+    /// Retry log queries failing on connection errors or timeouts up to the
+    /// given number of times, independently of the retries of errors
+    /// returned by the node.
+    pub fn with_network_timeout_retries(
+        mut self,
+        network_timeout_retries: usize,
+    ) -> Self {
+        self.network_timeout_retries = network_timeout_retries;
+        self
+    }
+
+    /// Reuse the chain head fetched from the given JSON-RPC endpoint by an
+    /// earlier run for up to the given time instead of requesting it again.
+    pub fn with_head_cache(mut self, rpc_url: &str, ttl: Duration) -> Self {
+        self.head_cache = Some(HeadCache::new(rpc_url, ttl));
+        self
+    }
+
+    /// Add to the number of foreign logs rejected.
+    fn count_foreign(&self, foreign: usize) {
+        self.foreign_logs.fetch_add(foreign as u64, Ordering::Relaxed);
+    }
+
+    /// The settings of the log queries made through this chain.
+    fn query_options(&self) -> QueryOptions<'_> {
+        QueryOptions {
+            senders: &self.senders,
+            rpc_calls: &self.rpc_calls,
+            network_retries: self.network_timeout_retries,
+            raw_event_json: self.raw_event_json,
+        }
+    }
+
+    /// Find the connected contract with the given address.
+    fn contract(
+        &self,
+        contract_address: Address,
+    ) -> Result<&OrderbookContract, Error> {
+        self.contracts
+            .iter()
+            .find(|contract| *contract.address() == contract_address)
+            .ok_or(Error::ContractNotFound(contract_address))
+    }
+
+    /// Fetch the bodies of the given blocks, keyed by their numbers, in
+    /// JSON-RPC batches with `--rpc-batch` or with individual calls
+    /// otherwise.
+    async fn fetch_blocks(
+        &self,
+        blocks: Vec<(BlockNumber, BlockId)>,
+    ) -> Result<BTreeMap<BlockNumber, BlockMetadata>, Error> {
+        let bodies = match self.rpc_batch {
+            None => self.fetch_blocks_individually(&blocks).await?,
+            Some(rpc_batch) => {
+                let mut bodies = Vec::with_capacity(blocks.len());
+                for chunk in blocks.chunks(rpc_batch) {
+                    bodies.extend(self.fetch_block_batch(chunk).await?);
+                }
+                bodies
+            }
+        };
+
+        let mut block_bodies = BTreeMap::new();
+        for (&(block_number, block_id), body) in blocks.iter().zip(bodies) {
+            match body {
+                None => error!("Get block {block_id} returned None"),
+                Some(body) => {
+                    block_bodies.insert(block_number, body);
+                }
+            }
+        }
+
+        Ok(block_bodies)
+    }
+
+    /// Fetch the bodies of the given blocks with one call each. Blocks
+    /// within a chunk are fetched concurrently and chunks one after the
+    /// other, bounding the number of requests in flight.
+    async fn fetch_blocks_individually(
+        &self,
+        blocks: &[(BlockNumber, BlockId)],
+    ) -> Result<Vec<Option<BlockMetadata>>, Error> {
+        let mut bodies = Vec::with_capacity(blocks.len());
+
+        for chunk in blocks.chunks(self.block_body_batch_size) {
+            trace!("Fetching blocks {chunk:?}");
+
+            bodies.extend(
+                try_join_all(
+                    chunk
+                        .iter()
+                        .map(|&(_, block_id)| self.fetch_block(block_id)),
+                )
+                .await?,
+            );
+        }
+
+        Ok(bodies)
+    }
+
+    /// Fetch the bodies of the given blocks in a single JSON-RPC batch,
+    /// falling back to individual calls if the batch fails. An endpoint
+    /// rejecting the batch as a whole is never sent another one.
+    async fn fetch_block_batch(
+        &self,
+        blocks: &[(BlockNumber, BlockId)],
+    ) -> Result<Vec<Option<BlockMetadata>>, Error> {
+        if self.batches_rejected.load(Ordering::Relaxed) {
+            return self.fetch_blocks_individually(blocks).await;
+        }
+        trace!("Fetching blocks {blocks:?} in a batch");
+
+        let client = self.provider.client();
+        let mut batch = BatchRequest::new(client);
+        let mut waiters: Vec<Waiter<Value>> = Vec::with_capacity(blocks.len());
+        for &(_, block_id) in blocks {
+            // Providers bill and rate-limit each call of a batch.
+            self.rpc_calls.start().await;
+            waiters.push(match block_id {
+                BlockId::Number(number) => {
+                    batch.add_call("eth_getBlockByNumber", &(number, true))?
+                }
+                BlockId::Hash(hash) => batch
+                    .add_call("eth_getBlockByHash", &(hash.block_hash, true))?,
+            });
+        }
+
+        if let Err(err) = batch.send().await {
+            warn!("Fetching blocks with individual calls, batch failed: {err}");
+            self.batches_rejected.store(true, Ordering::Relaxed);
+            return self.fetch_blocks_individually(blocks).await;
+        }
+        let blocks_json = match try_join_all(waiters).await {
+            Ok(blocks_json) => blocks_json,
+            Err(err) => {
+                warn!("Refetching a batch of blocks individually: {err}");
+                return self.fetch_blocks_individually(blocks).await;
+            }
+        };
+
+        blocks
+            .iter()
+            .zip(blocks_json)
+            .map(|(&(_, block_id), block)| {
+                if block.is_null() {
+                    return Ok(None);
+                }
+                match serde_json::from_value(block.clone()) {
+                    Ok(block) => Ok(Some(block_metadata(block))),
+                    Err(err) => {
+                        warn!(
+                            "Reading the raw JSON of block {block_id}: {err}"
+                        );
+                        raw_block_metadata(&block).map(Some)
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Fetch the timestamp and transactions of the given block, if it
+    /// exists. Blocks that don't decode into the typed block, e.g. on
+    /// networks whose blocks lack or add fields, fall back to reading only
+    /// the fields needed from the raw JSON.
+    async fn fetch_block(
+        &self,
+        block_id: BlockId,
+    ) -> Result<Option<BlockMetadata>, Error> {
+        self.rpc_calls.start().await;
+        let block = match self
+            .provider
+            .get_block(block_id, BlockTransactionsKind::Full)
+            .await
+        {
+            Ok(block) => block,
+            Err(RpcError::DeserError { err, text }) => {
+                warn!("Reading the raw JSON of block {block_id}: {err}");
+                return raw_block(&text)?
+                    .map(|block| raw_block_metadata(&block))
+                    .transpose();
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(block.map(block_metadata))
+    }
+
+    /// Fetch the timestamp of the given block from its header, if it exists,
+    /// falling back to the raw JSON like [`RealChain::fetch_block`].
+    async fn fetch_block_timestamp(
+        &self,
+        block_id: BlockId,
+    ) -> Result<Option<u64>, Error> {
+        self.rpc_calls.start().await;
+        let block = match self
+            .provider
+            .get_block(block_id, BlockTransactionsKind::Hashes)
+            .await
+        {
+            Ok(block) => block,
+            Err(RpcError::DeserError { err, text }) => {
+                warn!("Reading the raw JSON of block {block_id}: {err}");
+                return raw_block(&text)?
+                    .map(|block| raw_quantity(&block, "timestamp"))
+                    .transpose();
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(block.map(|block| block.inner.header.timestamp))
     }
 }
 
+impl super::sealed::Sealed for RealChain {}
+
 impl OnChain for RealChain {
-    async fn get_block_number(&self) -> anyhow::Result<BlockNumber> {
-        Ok(self.contract.provider().get_block_number().await?)
+    fn contract_addresses(&self) -> Vec<Address> {
+        self.contracts.iter().map(|contract| *contract.address()).collect()
+    }
+
+    fn rpc_calls(&self) -> u64 {
+        self.rpc_calls.count()
+    }
+
+    fn foreign_logs(&self) -> u64 {
+        self.foreign_logs.load(Ordering::Relaxed)
+    }
+
+    async fn get_block_number(&self) -> Result<BlockNumber, Error> {
+        if let Some(head) = self.head_cache.as_ref().and_then(HeadCache::get) {
+            debug!("Using the cached chain head {head}");
+            return Ok(head);
+        }
+
+        self.rpc_calls.start().await;
+        let head = self.provider.get_block_number().await?;
+        if let Some(head_cache) = self.head_cache.as_ref() {
+            head_cache.set(head);
+        }
+
+        Ok(head)
+    }
+
+    /// The cached chain head is only used for [`HeadTag::Latest`], since the
+    /// blocks of the other tags lag behind it.
+    async fn get_block_number_by_tag(
+        &self,
+        tag: HeadTag,
+    ) -> Result<BlockNumber, Error> {
+        let tag = match tag {
+            HeadTag::Latest => return self.get_block_number().await,
+            HeadTag::Safe => BlockNumberOrTag::Safe,
+            HeadTag::Finalized => BlockNumberOrTag::Finalized,
+        };
+
+        self.rpc_calls.start().await;
+        let block = match self
+            .provider
+            .get_block(tag.into(), BlockTransactionsKind::Hashes)
+            .await
+        {
+            Ok(block) => block.map(|block| block.inner.header.number),
+            Err(RpcError::DeserError { err, text }) => {
+                warn!("Reading the raw JSON of the {tag} block: {err}");
+                raw_block(&text)?
+                    .map(|block| raw_quantity(&block, "number"))
+                    .transpose()?
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        block.ok_or_else(|| {
+            Error::Config(format!(
+                "The JSON-RPC endpoint has no {tag} block, so it can't be \
+                used as the chain head"
+            ))
+        })
+    }
+
+    async fn get_block_hash(
+        &self,
+        block_number: BlockNumber,
+    ) -> Result<Option<B256>, Error> {
+        self.rpc_calls.start().await;
+        let block = match self
+            .provider
+            .get_block(block_number.into(), BlockTransactionsKind::Hashes)
+            .await
+        {
+            Ok(block) => block,
+            Err(RpcError::DeserError { err, text }) => {
+                warn!("Reading the raw JSON of block {block_number}: {err}");
+                return raw_block(&text)?
+                    .map(|block| raw_field(&block, "hash"))
+                    .transpose();
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(block.map(|block| block.inner.header.hash))
     }
 
     async fn get_block_number_by_tx_hash(
         &self,
         tx_hash: FixedBytes<32>,
-    ) -> anyhow::Result<Option<BlockNumber>> {
-        let tx =
-            self.contract.provider().get_transaction_by_hash(tx_hash).await?;
+    ) -> Result<Option<BlockNumber>, Error> {
+        self.rpc_calls.start().await;
+        let tx = self.provider.get_transaction_by_hash(tx_hash).await?;
 
         let block_number =
             tx.and_then(|tx| tx.block_number).map(|block_num| block_num + 1);
@@ -48,80 +436,645 @@ impl OnChain for RealChain {
 
     async fn fetch_clearv2_trades(
         &self,
+        contract_address: Address,
         start_block: u64,
         end_block: u64,
-    ) -> anyhow::Result<BTreeMap<BlockNumber, Vec<TradeLog>>> {
+    ) -> Result<BTreeMap<BlockNumber, Vec<TradeLog>>, Error> {
         debug!(
-            "Fetching ClearV2 trades from blocks {start_block} to {end_block}"
+            "Fetching ClearV2 trades of {contract_address} from blocks \
+            {start_block} to {end_block}"
         );
-        crate::logs::fetch_clearv2_trades(
+        let (trades, foreign) = crate::logs::fetch_clearv2_trades(
             start_block,
             end_block,
-            &self.contract,
+            self.contract(contract_address)?,
+            self.query_options(),
+            self.split_clear,
         )
-        .await
+        .await?;
+        self.count_foreign(foreign);
+
+        Ok(trades)
     }
 
     async fn fetch_takeorderv2_trades(
         &self,
+        contract_address: Address,
         start_block: u64,
         end_block: u64,
-    ) -> anyhow::Result<BTreeMap<BlockNumber, Vec<TradeLog>>> {
+    ) -> Result<BTreeMap<BlockNumber, Vec<TradeLog>>, Error> {
         debug!(
-            "Fetching TakeOrderV2 trades from blocks {start_block} to {end_block}"
+            "Fetching TakeOrderV2 trades of {contract_address} from blocks \
+            {start_block} to {end_block}"
         );
-        crate::logs::fetch_takeorderv2_trades(
+        let (trades, foreign) = crate::logs::fetch_takeorderv2_trades(
             start_block,
             end_block,
-            &self.contract,
+            self.contract(contract_address)?,
+            self.query_options(),
         )
-        .await
+        .await?;
+        self.count_foreign(foreign);
+
+        Ok(trades)
+    }
+
+    async fn fetch_all_trades(
+        &self,
+        contract_address: Address,
+        start_block: u64,
+        end_block: u64,
+    ) -> Result<(BlockTradeLogs, BlockTradeLogs), Error> {
+        debug!(
+            "Fetching ClearV2 and TakeOrderV2 trades of {contract_address} \
+            from blocks {start_block} to {end_block}"
+        );
+        let (clearv2_trades, takeorderv2_trades, foreign) =
+            crate::logs::fetch_all_trades(
+                start_block,
+                end_block,
+                self.contract(contract_address)?,
+                self.query_options(),
+                self.split_clear,
+            )
+            .await?;
+        self.count_foreign(foreign);
+
+        Ok((clearv2_trades, takeorderv2_trades))
+    }
+
+    async fn fetch_block_trades(
+        &self,
+        contract_address: Address,
+        block_hash: B256,
+    ) -> Result<(BlockTradeLogs, BlockTradeLogs), Error> {
+        debug!(
+            "Fetching ClearV2 and TakeOrderV2 trades of {contract_address} \
+            from block {block_hash}"
+        );
+        let (clearv2_trades, takeorderv2_trades, foreign) =
+            crate::logs::fetch_block_trades(
+                block_hash,
+                self.contract(contract_address)?,
+                self.query_options(),
+                self.split_clear,
+            )
+            .await?;
+        self.count_foreign(foreign);
+
+        Ok((clearv2_trades, takeorderv2_trades))
+    }
+
+    async fn fetch_abi_event_trades(
+        &self,
+        contract_address: Address,
+        event_name: &str,
+        start_block: u64,
+        end_block: u64,
+    ) -> Result<BTreeMap<BlockNumber, Vec<TradeLog>>, Error> {
+        debug!(
+            "Fetching {event_name} logs of {contract_address} from blocks \
+            {start_block} to {end_block}"
+        );
+        let (trades, foreign) = crate::logs::fetch_abi_event_trades(
+            start_block,
+            end_block,
+            self.contract(contract_address)?,
+            event_name,
+            self.query_options(),
+        )
+        .await?;
+        self.count_foreign(foreign);
+
+        Ok(trades)
+    }
+
+    async fn fetch_block_abi_event_trades(
+        &self,
+        contract_address: Address,
+        event_name: &str,
+        block_hash: B256,
+    ) -> Result<BTreeMap<BlockNumber, Vec<TradeLog>>, Error> {
+        debug!(
+            "Fetching {event_name} logs of {contract_address} from block \
+            {block_hash}"
+        );
+        let (trades, foreign) = crate::logs::fetch_block_abi_event_trades(
+            block_hash,
+            self.contract(contract_address)?,
+            event_name,
+            self.query_options(),
+        )
+        .await?;
+        self.count_foreign(foreign);
+
+        Ok(trades)
+    }
+
+    async fn count_raw_logs(
+        &self,
+        contract_address: Address,
+        event: TradeEvent,
+        start_block: u64,
+        end_block: u64,
+    ) -> Result<usize, Error> {
+        debug!(
+            "Counting raw {event:?} logs of {contract_address} from blocks \
+            {start_block} to {end_block}"
+        );
+        // Reverted transactions emit no logs.
+        let Some(signature_hash) = event.signature_hash() else {
+            return Ok(0);
+        };
+        let filter = Filter::new()
+            .address(contract_address)
+            .event_signature(signature_hash)
+            .from_block(start_block)
+            .to_block(end_block);
+
+        // Foreign logs are rejected from the decoded logs, so they aren't
+        // counted here either.
+        self.rpc_calls.start().await;
+        Ok(self
+            .provider
+            .get_logs(&filter)
+            .await?
+            .iter()
+            .filter(|log| log.topics().first() == Some(&signature_hash))
+            .count())
+    }
+
+    /// Scans the receipts of every block in the range with
+    /// `eth_getBlockReceipts`, one request per block, fetched concurrently
+    /// like block bodies. Only transactions sent by one of the configured
+    /// senders are kept, if any are configured.
+    async fn fetch_failed_txs(
+        &self,
+        start_block: u64,
+        end_block: u64,
+    ) -> Result<Vec<FailedTx>, Error> {
+        debug!(
+            "Fetching failed transactions from blocks {start_block} to \
+            {end_block}"
+        );
+        let contract_addresses = self.contract_addresses();
+        let block_numbers = (start_block..=end_block).collect_vec();
+        let mut failed_txs = vec![];
+
+        for chunk in block_numbers.chunks(self.block_body_batch_size) {
+            let block_receipts =
+                try_join_all(chunk.iter().map(|&block_number| async move {
+                    self.rpc_calls.start().await;
+                    self.provider.get_block_receipts(block_number.into()).await
+                }))
+                .await?;
+
+            for (&block_number, receipts) in chunk.iter().zip(block_receipts) {
+                for receipt in receipts.into_iter().flatten() {
+                    let Some(contract_address) = receipt
+                        .to()
+                        .filter(|to| contract_addresses.contains(to))
+                    else {
+                        continue;
+                    };
+                    let Some(block_hash) = receipt.block_hash() else {
+                        continue;
+                    };
+                    if receipt.status()
+                        || !(self.senders.is_empty()
+                            || self.senders.contains(&receipt.from()))
+                    {
+                        continue;
+                    }
+
+                    failed_txs.push(FailedTx {
+                        block_number,
+                        block_hash,
+                        tx_hash: receipt.transaction_hash(),
+                        tx_index: receipt.transaction_index().unwrap_or(0),
+                        contract_address,
+                    });
+                }
+            }
+        }
+
+        Ok(failed_txs)
+    }
+
+    async fn fetch_token_metadata(
+        &self,
+        token: Address,
+    ) -> Result<TokenMetadata, Error> {
+        let erc20 = IERC20Metadata::new(token, self.provider.clone());
+
+        self.rpc_calls.start().await;
+        let symbol = match erc20.symbol().call().await {
+            Ok(symbol) => Some(symbol._0),
+            Err(err) => {
+                warn!("Failed to resolve the symbol of token {token}: {err}");
+                None
+            }
+        };
+
+        self.rpc_calls.start().await;
+        let decimals = match erc20.decimals().call().await {
+            Ok(decimals) => Some(decimals._0),
+            Err(err) => {
+                warn!("Failed to resolve the decimals of token {token}: {err}");
+                None
+            }
+        };
+
+        Ok(TokenMetadata { symbol, decimals })
     }
 
     async fn fetch_block_bodies(
         &self,
         block_numbers: impl IntoIterator<Item = BlockNumber>,
-    ) -> anyhow::Result<BTreeMap<BlockNumber, BlockMetadata>> {
+    ) -> Result<BTreeMap<BlockNumber, BlockMetadata>, Error> {
         debug!("Fetching block bodies...");
-        let mut block_bodies = BTreeMap::new();
+        self.fetch_blocks(
+            block_numbers
+                .into_iter()
+                .map(|block_number| (block_number, block_number.into()))
+                .collect(),
+        )
+        .await
+    }
 
-        for block_number in block_numbers {
-            trace!("Fetching block #{block_number}");
-            let block = self
-                .contract
-                .provider()
-                .get_block_by_number(
-                    BlockNumberOrTag::Number(block_number),
-                    BlockTransactionsKind::Full,
-                )
+    async fn fetch_block_bodies_by_hash(
+        &self,
+        blocks: impl IntoIterator<Item = (BlockNumber, B256)>,
+    ) -> Result<BTreeMap<BlockNumber, BlockMetadata>, Error> {
+        debug!("Fetching block bodies by hash...");
+        self.fetch_blocks(
+            blocks
+                .into_iter()
+                .map(|(block_number, block_hash)| {
+                    (block_number, block_hash.into())
+                })
+                .collect(),
+        )
+        .await
+    }
+
+    /// Fetches blocks with their transaction hashes only, the lightest form
+    /// `eth_getBlockByHash` returns, concurrently like block bodies.
+    async fn fetch_block_timestamps(
+        &self,
+        blocks: impl IntoIterator<Item = (BlockNumber, B256)>,
+    ) -> Result<BTreeMap<BlockNumber, u64>, Error> {
+        debug!("Fetching block timestamps by hash...");
+        let blocks = blocks.into_iter().collect_vec();
+        let mut timestamps = BTreeMap::new();
+
+        for chunk in blocks.chunks(self.block_body_batch_size) {
+            let chunk_timestamps =
+                try_join_all(chunk.iter().map(|&(_, block_hash)| {
+                    self.fetch_block_timestamp(block_hash.into())
+                }))
                 .await?;
 
-            match block {
-                None => {
-                    error!(
-                        "Get block with number {block_number} returned None"
-                    );
-                    continue;
+            for (&(block_number, block_hash), timestamp) in
+                chunk.iter().zip(chunk_timestamps)
+            {
+                match timestamp {
+                    None => error!("Get block {block_hash} returned None"),
+                    Some(timestamp) => {
+                        timestamps.insert(block_number, timestamp);
+                    }
                 }
-                Some(block) => {
-                    let Block { header, transactions, .. } = block.inner;
-
-                    let block = BlockMetadata {
-                        timestamp: header.timestamp,
-                        transactions: transactions
-                            .into_transactions()
-                            .map(|tx| TxMetadata {
-                                hash: tx.tx_hash(),
-                                origin: tx.from,
-                            })
-                            .collect_vec(),
-                    };
+            }
+        }
+
+        Ok(timestamps)
+    }
+}
 
-                    block_bodies.insert(block_number, block);
+/// The timestamp and transactions of the given block.
+fn block_metadata(
+    block: <AnyNetwork as Network>::BlockResponse,
+) -> BlockMetadata {
+    let Block { header, transactions, .. } = block.inner;
+
+    BlockMetadata {
+        timestamp: header.timestamp,
+        transactions: transactions
+            .into_transactions()
+            .map(|tx| TxMetadata {
+                hash: tx.tx_hash(),
+                origin: tx.from,
+                tx_type: tx_type(&tx.inner.inner),
+            })
+            .collect_vec(),
+    }
+}
+
+/// Name the type of the given transaction envelope, falling back to the hex
+/// type byte for types without a well-known name.
+fn tx_type(envelope: &AnyTxEnvelope) -> String {
+    match envelope {
+        AnyTxEnvelope::Ethereum(envelope) => match envelope {
+            TxEnvelope::Legacy(_) => "legacy".to_string(),
+            TxEnvelope::Eip2930(_) => "eip2930".to_string(),
+            TxEnvelope::Eip1559(_) => "eip1559".to_string(),
+            TxEnvelope::Eip4844(_) => "eip4844".to_string(),
+            TxEnvelope::Eip7702(_) => "eip7702".to_string(),
+            #[allow(unreachable_patterns)]
+            _ => format!("0x{:02x}", u8::from(envelope.tx_type())),
+        },
+        AnyTxEnvelope::Unknown(envelope) => {
+            format!("0x{:02x}", envelope.inner.ty.0)
+        }
+    }
+}
+
+/// Parse the raw JSON of a block that failed to decode, which is either the
+/// block itself or a JSON-RPC response containing it, `null` if the block
+/// doesn't exist.
+fn raw_block(text: &str) -> Result<Option<Value>, Error> {
+    let mut block: Value = serde_json::from_str(text)?;
+    if let Some(result) = block.get_mut("result") {
+        block = result.take();
+    }
+
+    Ok(Some(block).filter(|block| !block.is_null()))
+}
+
+/// Read the timestamp and transactions from the raw JSON of a block,
+/// ignoring all other fields, so that blocks without uncles or withdrawals
+/// or with fields specific to their network still yield their metadata.
+fn raw_block_metadata(block: &Value) -> Result<BlockMetadata, Error> {
+    let timestamp = raw_quantity(block, "timestamp")?;
+    let transactions = match block.get("transactions") {
+        None | Some(Value::Null) => vec![],
+        Some(Value::Array(transactions)) => transactions
+            .iter()
+            .map(|tx| {
+                Ok(TxMetadata {
+                    hash: raw_field(tx, "hash")?,
+                    origin: raw_field(tx, "from")?,
+                    tx_type: raw_tx_type(tx)?,
+                })
+            })
+            .collect::<Result<_, Error>>()?,
+        Some(transactions) => {
+            return Err(Error::Decode(format!(
+                "block transactions {transactions}"
+            )))
+        }
+    };
+
+    Ok(BlockMetadata { timestamp, transactions })
+}
+
+/// Name the type of a raw JSON transaction like [`tx_type`], treating
+/// transactions without a type as legacy ones.
+fn raw_tx_type(tx: &Value) -> Result<String, Error> {
+    if tx.get("type").is_none() {
+        return Ok("legacy".to_string());
+    }
+
+    let tx_type = match raw_quantity(tx, "type")? {
+        0 => "legacy".to_string(),
+        1 => "eip2930".to_string(),
+        2 => "eip1559".to_string(),
+        3 => "eip4844".to_string(),
+        4 => "eip7702".to_string(),
+        ty => format!("0x{ty:02x}"),
+    };
+
+    Ok(tx_type)
+}
+
+/// Parse the given field of a raw JSON object with its serde representation.
+fn raw_field<T: serde::de::DeserializeOwned>(
+    object: &Value,
+    field: &str,
+) -> Result<T, Error> {
+    let value = object
+        .get(field)
+        .ok_or_else(|| Error::Decode(format!("missing {field} field")))?;
+
+    serde_json::from_value(value.clone())
+        .map_err(|err| Error::Decode(format!("{field} field {value}: {err}")))
+}
+
+/// Parse the given hex quantity field of a raw JSON object.
+fn raw_quantity(object: &Value, field: &str) -> Result<u64, Error> {
+    let quantity: alloy::primitives::U64 = raw_field(object, field)?;
+
+    Ok(quantity.to())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::time::Instant;
+
+    use super::*;
+    use crate::env::Env;
+
+    /// A node answering each HTTP request after the given latency, counting
+    /// the requests. Each block's timestamp is its number. Batches are
+    /// rejected with HTTP 400 unless `accept_batches` is set.
+    async fn serve_blocks(
+        latency: Duration,
+        accept_batches: bool,
+    ) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let rpc_url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counted = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let counted = counted.clone();
+                tokio::spawn(async move {
+                    let mut socket = BufReader::new(socket);
+                    while let Some(body) = read_request(&mut socket).await {
+                        counted.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(latency).await;
+                        let request: Value =
+                            serde_json::from_slice(&body).unwrap();
+                        let (status, response) = match request {
+                            Value::Array(_) if !accept_batches => {
+                                ("400 Bad Request", json!("no batches"))
+                            }
+                            Value::Array(calls) => (
+                                "200 OK",
+                                calls.iter().map(block_response).collect(),
+                            ),
+                            call => ("200 OK", block_response(&call)),
+                        };
+                        let body = response.to_string();
+                        let response = format!(
+                            "HTTP/1.1 {status}\r\nContent-Type: \
+                            application/json\r\nContent-Length: {}\r\n\r\n\
+                            {body}",
+                            body.len()
+                        );
+                        let socket = socket.get_mut();
+                        if socket.write_all(response.as_bytes()).await.is_err()
+                        {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+
+        (rpc_url, requests)
+    }
+
+    /// Read the body of the next HTTP request of a connection, or `None`
+    /// once it's closed.
+    async fn read_request(
+        socket: &mut BufReader<TcpStream>,
+    ) -> Option<Vec<u8>> {
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            if socket.read_line(&mut line).await.ok()? == 0 {
+                return None;
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().ok()?;
                 }
             }
         }
 
-        Ok(block_bodies)
+        let mut body = vec![0; content_length];
+        socket.read_exact(&mut body).await.ok()?;
+        Some(body)
+    }
+
+    /// The response to an `eth_getBlockByNumber` call: a block without
+    /// transactions, read through the raw JSON fallback.
+    fn block_response(call: &Value) -> Value {
+        let number = &call["params"][0];
+        json!({
+            "jsonrpc": "2.0",
+            "id": call["id"],
+            "result": {
+                "number": number,
+                "hash": B256::repeat_byte(1),
+                "timestamp": number,
+                "transactions": [],
+            },
+        })
+    }
+
+    /// Fetch blocks 100 to 119 through a chain at the given URL, returning
+    /// how long it took.
+    async fn fetch_blocks(rpc_url: &str, rpc_batch: Option<usize>) -> Duration {
+        let env = Env::builder(rpc_url, vec![Address::ZERO.to_string()])
+            .with_orderbookv4_deployment_block(0)
+            .build()
+            .unwrap();
+        let chain = RealChain::new(env.connect_provider().unwrap(), vec![])
+            .with_rpc_batch(rpc_batch);
+
+        let started = Instant::now();
+        let bodies = chain.fetch_block_bodies(100..120).await.unwrap();
+        let elapsed = started.elapsed();
+
+        assert_eq!(
+            bodies.keys().copied().collect_vec(),
+            (100..120).collect_vec()
+        );
+        assert!(bodies.iter().all(|(&number, body)| body.timestamp == number));
+        assert_eq!(chain.rpc_calls(), 20);
+        elapsed
+    }
+
+    #[tokio::test]
+    async fn test_rpc_batch_cuts_round_trips() {
+        let latency = Duration::from_millis(20);
+
+        let (rpc_url, requests) = serve_blocks(latency, true).await;
+        let individually = fetch_blocks(&rpc_url, None).await;
+        assert_eq!(requests.load(Ordering::SeqCst), 20);
+
+        let (rpc_url, requests) = serve_blocks(latency, true).await;
+        let batched = fetch_blocks(&rpc_url, Some(10)).await;
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // Two round-trips instead of 20, with room for scheduling noise.
+        assert!(batched * 4 < individually);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_rpc_batch_falls_back_to_individual_calls() {
+        let (rpc_url, requests) = serve_blocks(Duration::ZERO, false).await;
+        let env = Env::builder(rpc_url, vec![Address::ZERO.to_string()])
+            .with_orderbookv4_deployment_block(0)
+            .build()
+            .unwrap();
+        let chain = RealChain::new(env.connect_provider().unwrap(), vec![])
+            .with_rpc_batch(Some(10));
+
+        let bodies = chain.fetch_block_bodies(100..120).await.unwrap();
+        assert_eq!(bodies.len(), 20);
+        // The first batch is rejected, after which no other one is sent.
+        assert_eq!(requests.load(Ordering::SeqCst), 1 + 20);
+
+        let bodies = chain.fetch_block_bodies(120..125).await.unwrap();
+        assert_eq!(bodies.len(), 5);
+        assert_eq!(requests.load(Ordering::SeqCst), 1 + 20 + 5);
+    }
+
+    #[test]
+    fn test_raw_block_metadata_ignores_missing_and_unknown_fields() {
+        let tx_hash = B256::repeat_byte(1);
+        let origin = Address::repeat_byte(2);
+        // A block without uncles or withdrawals and with extra fields, in a
+        // JSON-RPC response.
+        let response = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "result": {
+                "number": "0x64",
+                "hash": B256::repeat_byte(3),
+                "timestamp": "0x6553f100",
+                "blockExtraData": "0x",
+                "transactions": [
+                    { "hash": tx_hash, "from": origin, "type": "0x2" },
+                    { "hash": tx_hash, "from": origin },
+                    { "hash": tx_hash, "from": origin, "type": "0x7e" },
+                ],
+            },
+        });
+
+        let block = raw_block(&response.to_string()).unwrap().unwrap();
+        let metadata = raw_block_metadata(&block).unwrap();
+
+        assert_eq!(metadata.timestamp, 1_700_000_000);
+        assert_eq!(
+            metadata
+                .transactions
+                .iter()
+                .map(|tx| (tx.hash, tx.origin, tx.tx_type.as_str()))
+                .collect_vec(),
+            vec![
+                (tx_hash, origin, "eip1559"),
+                (tx_hash, origin, "legacy"),
+                (tx_hash, origin, "0x7e"),
+            ]
+        );
+        assert_eq!(
+            raw_field::<B256>(&block, "hash").unwrap(),
+            B256::repeat_byte(3)
+        );
+        assert_eq!(raw_block("null").unwrap(), None);
     }
 }
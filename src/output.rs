@@ -0,0 +1,247 @@
+//! A module for opening the CSV files trades are written to and read from,
+//! transparently handling compression based on the file extension.
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use tracing::*;
+
+use crate::Error;
+
+/// The CSV path that stands for writing to stdout.
+pub(crate) const STDOUT_PATH: &str = "-";
+
+/// Whether the given CSV path stands for stdout rather than a file.
+pub(crate) fn is_stdout(path: &str) -> bool {
+    path == STDOUT_PATH
+}
+
+/// The directories of the `--output-dir` layout: the CSV files, the files
+/// kept alongside them, and the logs.
+pub(crate) const DATA_DIR: &str = "data";
+pub(crate) const META_DIR: &str = "meta";
+pub(crate) const LOGS_DIR: &str = "logs";
+
+/// Create the directories of the `--output-dir` layout in the given
+/// directory, if they don't exist yet.
+pub(crate) fn create_layout(output_dir: &str) -> Result<(), Error> {
+    for dir in [DATA_DIR, META_DIR, LOGS_DIR] {
+        std::fs::create_dir_all(Path::new(output_dir).join(dir))?;
+    }
+
+    Ok(())
+}
+
+/// The path of a file kept alongside the CSV file at the given path, e.g.
+/// its metadata, named after it with the given suffix. A CSV file in the
+/// `data` directory of the `--output-dir` layout, i.e. with a `meta`
+/// directory next to it, keeps them in the `meta` directory.
+pub(crate) fn sidecar_path(csv_path: &str, suffix: &str) -> String {
+    let path = Path::new(csv_path);
+    if let (Some(data_dir), Some(file_name)) = (path.parent(), path.file_name())
+    {
+        let meta_dir = data_dir.with_file_name(META_DIR);
+        if data_dir.file_name().is_some_and(|name| name == DATA_DIR)
+            && meta_dir.is_dir()
+        {
+            let file_name = format!("{}{suffix}", file_name.to_string_lossy());
+            return meta_dir.join(file_name).to_string_lossy().into_owned();
+        }
+    }
+
+    format!("{csv_path}{suffix}")
+}
+
+/// Whether the file at the given path is gzip-compressed, judging by its
+/// extension.
+pub(crate) fn is_gzip(path: &str) -> bool {
+    path.ends_with(".gz")
+}
+
+/// The given path with the given infix inserted before the extension of its
+/// file name, e.g. `trades.001.csv` for `trades.csv` and `001`, or appended
+/// if the file name has none.
+pub(crate) fn insert_infix(path: &str, infix: &str) -> String {
+    let dir_len = path.rfind('/').map_or(0, |slash| slash + 1);
+    let (dir, file_name) = path.split_at(dir_len);
+
+    match file_name.split_once('.') {
+        Some((stem, extension)) => format!("{dir}{stem}.{infix}.{extension}"),
+        None => format!("{dir}{file_name}.{infix}"),
+    }
+}
+
+/// Open the file at the given path for appending, creating it if it doesn't
+/// exist. Gzip files get a new gzip member appended, which readers decode as
+/// a continuation of the previous ones. [`STDOUT_PATH`] opens stdout.
+pub(crate) fn open_append(path: &str) -> Result<Box<dyn Write + Send>, Error> {
+    if is_stdout(path) {
+        return Ok(Box::new(std::io::stdout()));
+    }
+
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+    Ok(encode(path, file))
+}
+
+/// Create an empty temporary file next to the file at the given path,
+/// compressed the same way, to be renamed over it once fully written.
+/// Returns the path of the temporary file along with its writer.
+pub(crate) fn create_replacement(
+    path: &str,
+) -> Result<(String, Box<dyn Write + Send>), Error> {
+    let tmp_path = format!("{path}.tmp");
+    let file = File::create(&tmp_path)?;
+
+    Ok((tmp_path, encode(path, file)))
+}
+
+/// Wrap a file opened for writing in a gzip encoder if the given path is
+/// compressed.
+fn encode(path: &str, file: File) -> Box<dyn Write + Send> {
+    if is_gzip(path) {
+        Box::new(GzEncoder::new(file, flate2::Compression::default()))
+    } else {
+        Box::new(file)
+    }
+}
+
+/// Open the file at the given path for reading, decoding all gzip members if
+/// the file is compressed. A trailing row cut short by an interrupted write,
+/// including one in a gzip member that was never finished, is left out.
+pub(crate) fn open_read(path: &str) -> Result<Box<dyn Read + Send>, Error> {
+    let file = File::open(path)?;
+
+    if is_gzip(path) {
+        Ok(Box::new(CompleteRows::new(MultiGzDecoder::new(file))))
+    } else {
+        Ok(Box::new(CompleteRows::new(file)))
+    }
+}
+
+/// A reader of only the complete rows of another, i.e. those that end in a
+/// line break. Rows are written whole, so a trailing row without one was
+/// cut short.
+struct CompleteRows<R> {
+    reader: BufReader<R>,
+    row: Vec<u8>,
+    position: usize,
+}
+
+impl<R: Read> CompleteRows<R> {
+    fn new(reader: R) -> Self {
+        Self { reader: BufReader::new(reader), row: vec![], position: 0 }
+    }
+}
+
+impl<R: Read> Read for CompleteRows<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position == self.row.len() {
+            self.row.clear();
+            self.position = 0;
+            match self.reader.read_until(b'\n', &mut self.row) {
+                Ok(_) => {}
+                // A gzip member that was never finished ends early.
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => {}
+                Err(err) => return Err(err),
+            }
+            if !self.row.ends_with(b"\n") {
+                if !self.row.is_empty() {
+                    warn!(
+                        "Skipping a trailing row cut short by a failed write"
+                    );
+                }
+                self.row.clear();
+                return Ok(0);
+            }
+        }
+
+        let len = buf.len().min(self.row.len() - self.position);
+        buf[..len]
+            .copy_from_slice(&self.row[self.position..self.position + len]);
+        self.position += len;
+
+        Ok(len)
+    }
+}
+
+/// Drop a trailing row cut short by an interrupted write from the file at the
+/// given path, so that the rows appended to it start on a line of their own.
+/// The last gzip member of a compressed file is rewritten with its complete
+/// rows if it was never finished, as no member can be appended after it.
+pub(crate) fn drop_partial_row(path: &str) -> Result<(), Error> {
+    if is_gzip(path) {
+        return finish_gzip_member(path);
+    }
+
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let len = file.metadata()?.len();
+    let mut complete_len = 0;
+    let mut chunk = [0; 8192];
+    let mut end = len;
+    while end > 0 {
+        let start = end.saturating_sub(chunk.len() as u64);
+        let chunk = &mut chunk[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(chunk)?;
+        if let Some(line_break) = chunk.iter().rposition(|byte| *byte == b'\n')
+        {
+            complete_len = start + line_break as u64 + 1;
+            break;
+        }
+        end = start;
+    }
+
+    if complete_len < len {
+        warn!("Dropping a trailing row of {path} cut short by a failed write");
+        file.set_len(complete_len)?;
+    }
+
+    Ok(())
+}
+
+/// Find the first gzip member of the file at the given path that was never
+/// finished, if any, and replace it with a finished one of its complete rows.
+fn finish_gzip_member(path: &str) -> Result<(), Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let member_start = loop {
+        let member_start = reader.stream_position()?;
+        if reader.fill_buf()?.is_empty() {
+            return Ok(());
+        }
+
+        let mut member = flate2::bufread::GzDecoder::new(&mut reader);
+        match std::io::copy(&mut member, &mut std::io::sink()) {
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                break member_start;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    };
+
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(member_start))?;
+    let mut rows = vec![];
+    match flate2::read::GzDecoder::new(file).read_to_end(&mut rows) {
+        Ok(_) => {}
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => {}
+        Err(err) => return Err(err.into()),
+    }
+    let complete_len =
+        rows.iter().rposition(|byte| *byte == b'\n').map_or(0, |end| end + 1);
+
+    warn!("Rewriting the last gzip member of {path}, which was never finished");
+    OpenOptions::new().write(true).open(path)?.set_len(member_start)?;
+    let mut member = GzEncoder::new(
+        OpenOptions::new().append(true).open(path)?,
+        flate2::Compression::default(),
+    );
+    member.write_all(&rows[..complete_len])?;
+    member.finish()?;
+
+    Ok(())
+}
@@ -0,0 +1,69 @@
+//! The numbered parts a CSV file is split into with `--max-file-size`, e.g.
+//! `trades.001.csv` and `trades.002.csv` after `trades.csv`, each starting
+//! with its own header row, for downstream systems that can't handle a
+//! single enormous file.
+
+use std::path::Path;
+
+use crate::{output, Error};
+
+/// The path of the part with the given number of the CSV file at the given
+/// path, the number being inserted before the extension. Part 0 is the CSV
+/// file itself.
+pub(crate) fn part_path(csv_path: &str, part: u32) -> String {
+    match part {
+        0 => csv_path.to_string(),
+        _ => output::insert_infix(csv_path, &format!("{part:03}")),
+    }
+}
+
+/// The number of the last part of the CSV file at the given path that
+/// exists, which writing resumes in, or 0 if it isn't split.
+pub(crate) fn last_part(csv_path: &str) -> u32 {
+    let mut part = 0;
+    while Path::new(&part_path(csv_path, part + 1)).exists() {
+        part += 1;
+    }
+    part
+}
+
+/// The paths of all parts of the CSV file at the given path, in order.
+pub(crate) fn part_paths(csv_path: &str) -> Vec<String> {
+    (0..=last_part(csv_path)).map(|part| part_path(csv_path, part)).collect()
+}
+
+/// Fail if the CSV file at the given path is split into parts, for commands
+/// that only handle a single file.
+pub(crate) fn ensure_unsplit(csv_path: &str) -> Result<(), Error> {
+    match last_part(csv_path) {
+        0 => Ok(()),
+        last_part => Err(Error::Config(format!(
+            "{csv_path} is split into {} parts, which can only be appended to",
+            last_part + 1
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_part_paths_number_before_extension() {
+        assert_eq!(part_path("trades.csv", 0), "trades.csv");
+        assert_eq!(part_path("trades.csv", 1), "trades.001.csv");
+        assert_eq!(part_path("out/trades.csv.gz", 12), "out/trades.012.csv.gz");
+        assert_eq!(part_path("./trades", 2), "./trades.002");
+
+        let dir = tempfile::tempdir().unwrap();
+        let csv_path = dir.path().join("trades.csv");
+        let csv_path = csv_path.to_str().unwrap();
+        assert_eq!(part_paths(csv_path), [csv_path]);
+
+        for part in 0..3 {
+            std::fs::write(part_path(csv_path, part), "").unwrap();
+        }
+        assert_eq!(last_part(csv_path), 2);
+        assert!(ensure_unsplit(csv_path).is_err());
+    }
+}
@@ -0,0 +1,299 @@
+//! An output backend writing trades to a Postgres table instead of the CSV
+//! file, which deduplicates them by their transaction hash and log index.
+
+use alloy::primitives::BlockNumber;
+use tokio_postgres::{Client, NoTls};
+use tracing::*;
+
+use crate::{Error, Trade};
+
+/// The columns holding addresses, which are stored as 20-byte `BYTEA` with
+/// `--compact-addresses` instead of hex strings.
+const ADDRESS_COLUMNS: [&str; 6] = [
+    "tx_origin",
+    "contract_address",
+    "input_token",
+    "output_token",
+    "order_owner",
+    "counterparty_owner",
+];
+
+/// The table trades are written to, with the columns of the CSV file.
+/// Amounts are stored as numerics since they can exceed 64 bits, and
+/// addresses as hex strings or, if compact, as bytes. Columns added later are
+/// appended to tables created before them.
+fn create_table(compact_addresses: bool) -> String {
+    let address = if compact_addresses { "BYTEA" } else { "TEXT" };
+
+    format!(
+        "
+CREATE TABLE IF NOT EXISTS trades (
+    timestamp BIGINT NOT NULL,
+    tx_origin {address} NOT NULL,
+    tx_hash TEXT NOT NULL,
+    event TEXT NOT NULL,
+    contract_address {address} NOT NULL,
+    tx_type TEXT NOT NULL,
+    input_token {address},
+    output_token {address},
+    input_amount NUMERIC(78, 0),
+    output_amount NUMERIC(78, 0),
+    input_symbol TEXT,
+    output_symbol TEXT,
+    input_amount_adjusted NUMERIC,
+    output_amount_adjusted NUMERIC,
+    block_number BIGINT NOT NULL,
+    log_index BIGINT NOT NULL,
+    order_owner {address},
+    side TEXT,
+    event_json JSONB,
+    block_hash TEXT,
+    log_index_in_tx BIGINT,
+    first_seen BIGINT,
+    counterparty_owner {address},
+    UNIQUE (tx_hash, log_index)
+);
+ALTER TABLE trades ADD COLUMN IF NOT EXISTS log_index_in_tx BIGINT;
+ALTER TABLE trades ADD COLUMN IF NOT EXISTS first_seen BIGINT;
+ALTER TABLE trades ADD COLUMN IF NOT EXISTS counterparty_owner {address}"
+    )
+}
+
+/// A view of the trades table rendering compact addresses back to lowercase
+/// `0x`-prefixed hex, for exporting the table in the layout of the CSV file.
+fn create_hex_view() -> String {
+    let columns = [
+        "timestamp",
+        "tx_origin",
+        "tx_hash",
+        "event",
+        "contract_address",
+        "tx_type",
+        "input_token",
+        "output_token",
+        "input_amount",
+        "output_amount",
+        "input_symbol",
+        "output_symbol",
+        "input_amount_adjusted",
+        "output_amount_adjusted",
+        "block_number",
+        "log_index",
+        "order_owner",
+        "side",
+        "event_json",
+        "block_hash",
+        "log_index_in_tx",
+        "first_seen",
+        "counterparty_owner",
+    ]
+    .map(|column| match ADDRESS_COLUMNS.contains(&column) {
+        true => format!("'0x' || encode({column}, 'hex') AS {column}"),
+        false => column.to_string(),
+    });
+
+    format!(
+        "CREATE OR REPLACE VIEW trades_hex AS SELECT {} FROM trades",
+        columns.join(", ")
+    )
+}
+
+/// Parameters are passed as text and cast, so that amounts don't need a
+/// numeric type on the Rust side. Compact addresses are decoded from their
+/// hex strings by the database in the same way.
+fn insert_trade(compact_addresses: bool) -> String {
+    let address = |param: u8| match compact_addresses {
+        true => format!("decode(substr(${param}, 3), 'hex')"),
+        false => format!("${param}"),
+    };
+
+    format!(
+        "
+INSERT INTO trades VALUES (
+    $1, {}, $3, $4, {}, $6, {}, {}, $9::TEXT::NUMERIC, $10::TEXT::NUMERIC,
+    $11, $12, $13::TEXT::NUMERIC, $14::TEXT::NUMERIC, $15, $16, {}, $18,
+    $19::TEXT::JSONB, $20, $21, $22, {}
+)
+ON CONFLICT (tx_hash, log_index) DO NOTHING",
+        address(2),
+        address(5),
+        address(7),
+        address(8),
+        address(17),
+        address(23),
+    )
+}
+
+/// Connect to the database at the given connection string and make sure the
+/// trades table exists, with compact addresses and the `trades_hex` view if
+/// `compact_addresses` is set. An existing table must store addresses the
+/// same way. The connection is driven by a background task.
+pub(crate) async fn connect(
+    postgres_url: &str,
+    compact_addresses: bool,
+) -> Result<Client, Error> {
+    let (client, connection) =
+        tokio_postgres::connect(postgres_url, NoTls).await?;
+    tokio::spawn(async move {
+        if let Err(err) = connection.await {
+            error!("Postgres connection failed: {err}");
+        }
+    });
+
+    client.batch_execute(&create_table(compact_addresses)).await?;
+    debug!("Ensured the trades table exists");
+
+    let address_type: String = client
+        .query_one(
+            "SELECT data_type::TEXT FROM information_schema.columns \
+            WHERE table_schema = current_schema() \
+            AND table_name = 'trades' AND column_name = 'tx_origin'",
+            &[],
+        )
+        .await?
+        .get(0);
+    if (address_type == "bytea") != compact_addresses {
+        return Err(Error::Config(format!(
+            "The trades table stores addresses as {address_type}, which \
+            --compact-addresses must match"
+        )));
+    }
+
+    if compact_addresses {
+        client.batch_execute(&create_hex_view()).await?;
+        debug!("Ensured the trades_hex view exists");
+    }
+
+    Ok(client)
+}
+
+/// The block of the latest saved trade, if any.
+pub(crate) async fn latest_block(
+    client: &Client,
+) -> Result<Option<BlockNumber>, Error> {
+    let row =
+        client.query_one("SELECT MAX(block_number) FROM trades", &[]).await?;
+    let latest_block: Option<i64> = row.get(0);
+
+    Ok(latest_block.map(|block_number| block_number as BlockNumber))
+}
+
+/// Insert the given trades in one transaction, skipping those already saved,
+/// and return the number of trades inserted. `compact_addresses` must match
+/// the table, see [`connect`].
+pub(crate) async fn insert(
+    client: &mut Client,
+    trades: &[Trade],
+    compact_addresses: bool,
+) -> Result<u64, Error> {
+    let transaction = client.transaction().await?;
+    let statement =
+        transaction.prepare(&insert_trade(compact_addresses)).await?;
+
+    let mut inserted = 0;
+    for trade in trades {
+        inserted += transaction
+            .execute(
+                &statement,
+                &[
+                    &(trade.timestamp as i64),
+                    &trade.tx_origin.to_string(),
+                    &trade.tx_hash.to_string(),
+                    &trade.event.name(),
+                    &trade.contract_address.to_string(),
+                    &trade.tx_type,
+                    &trade.input_token.map(|token| token.to_string()),
+                    &trade.output_token.map(|token| token.to_string()),
+                    &trade.input_amount.map(|amount| amount.to_string()),
+                    &trade.output_amount.map(|amount| amount.to_string()),
+                    &trade.input_symbol,
+                    &trade.output_symbol,
+                    &trade.input_amount_adjusted,
+                    &trade.output_amount_adjusted,
+                    &(trade.block_number as i64),
+                    &(trade.log_index as i64),
+                    &trade.order_owner.map(|owner| owner.to_string()),
+                    &trade.side.map(|side| format!("{side:?}").to_lowercase()),
+                    &trade.event_json,
+                    &trade.block_hash.map(|block_hash| block_hash.to_string()),
+                    &trade.log_index_in_tx.map(|position| position as i64),
+                    &trade.first_seen.map(|first_seen| first_seen as i64),
+                    &trade.counterparty_owner.map(|owner| owner.to_string()),
+                ],
+            )
+            .await?;
+    }
+    transaction.commit().await?;
+
+    Ok(inserted)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::Address;
+
+    use super::*;
+
+    /// Serializes the tests, which share the trades table.
+    static TABLE: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    /// Connect to the test database with the trades table dropped first, so
+    /// that it's recreated with the given address storage.
+    async fn connect_fresh(compact_addresses: bool) -> Client {
+        let postgres_url = std::env::var("POSTGRES_TEST_URL").unwrap();
+        let (client, connection) =
+            tokio_postgres::connect(&postgres_url, NoTls).await.unwrap();
+        tokio::spawn(connection);
+        client
+            .batch_execute("DROP TABLE IF EXISTS trades CASCADE")
+            .await
+            .unwrap();
+
+        connect(&postgres_url, compact_addresses).await.unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database at POSTGRES_TEST_URL"]
+    async fn test_insert_skips_saved_trades() {
+        let _table = TABLE.lock().await;
+        let mut client = connect_fresh(false).await;
+        let trades = [0, 1].map(|log_index| Trade {
+            block_number: 100,
+            log_index,
+            ..crate::testing::trade()
+        });
+
+        assert_eq!(insert(&mut client, &trades, false).await.unwrap(), 2);
+        assert_eq!(insert(&mut client, &trades, false).await.unwrap(), 0);
+        assert_eq!(latest_block(&client).await.unwrap(), Some(100));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database at POSTGRES_TEST_URL"]
+    async fn test_compact_addresses_read_back_as_hex() {
+        let _table = TABLE.lock().await;
+        let mut client = connect_fresh(true).await;
+        let trade = Trade {
+            tx_origin: Address::repeat_byte(0xab),
+            ..crate::testing::trade()
+        };
+
+        assert_eq!(insert(&mut client, &[trade], true).await.unwrap(), 1);
+        let row = client
+            .query_one(
+                "SELECT octet_length(tx_origin), \
+                (SELECT tx_origin FROM trades_hex) FROM trades",
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(row.get::<_, i32>(0), 20);
+        assert_eq!(row.get::<_, String>(1), format!("0x{}", "ab".repeat(20)));
+        // A table with compact addresses can't be written with hex ones.
+        let postgres_url = std::env::var("POSTGRES_TEST_URL").unwrap();
+        assert!(matches!(
+            connect(&postgres_url, false).await,
+            Err(Error::Config(_))
+        ));
+    }
+}
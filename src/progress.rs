@@ -0,0 +1,181 @@
+//! The progress file stored next to the CSV file, logging every block range
+//! whose trades were fully written, so that the resume point follows from
+//! the blocks actually scanned rather than from the trades found in them.
+
+use alloy::primitives::BlockNumber;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+
+use crate::{output, Error};
+
+/// The path of the progress file of the CSV file at the given path.
+pub(crate) fn progress_path(csv_path: &str) -> String {
+    output::sidecar_path(csv_path, ".progress")
+}
+
+/// Append an inclusive block range whose trades are all written.
+pub(crate) fn record(
+    csv_path: &str,
+    start_block: BlockNumber,
+    end_block: BlockNumber,
+) -> Result<(), Error> {
+    let mut progress_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(progress_path(csv_path))?;
+    writeln!(progress_file, "{start_block},{end_block}")?;
+
+    Ok(())
+}
+
+/// Read the recorded block ranges, in the order they were recorded. CSV files
+/// without a progress file have none. A trailing line cut short by an
+/// interrupted write either doesn't parse or has a smaller end block, so at
+/// worst it claims part of a range that was scanned.
+pub(crate) fn read(
+    csv_path: &str,
+) -> Result<Vec<(BlockNumber, BlockNumber)>, Error> {
+    let progress = match std::fs::read_to_string(progress_path(csv_path)) {
+        Ok(progress) => progress,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+
+    Ok(progress
+        .lines()
+        .filter_map(|line| {
+            let (start_block, end_block) = line.split_once(',')?;
+            Some((start_block.parse().ok()?, end_block.parse().ok()?))
+        })
+        .filter(|(start_block, end_block)| start_block <= end_block)
+        .collect())
+}
+
+/// Discard the recorded block ranges, e.g. when starting a fresh CSV file.
+pub(crate) fn reset(csv_path: &str) -> Result<(), Error> {
+    match std::fs::remove_file(progress_path(csv_path)) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Forget the recorded blocks from the given one on, e.g. after trades saved
+/// from it were dropped because it was reorged. The ranges are rewritten
+/// merged.
+pub(crate) fn rewind(
+    csv_path: &str,
+    from_block: BlockNumber,
+) -> Result<(), Error> {
+    let ranges = merge(read(csv_path)?)
+        .into_iter()
+        .filter(|&(start_block, _)| start_block < from_block)
+        .map(|(start_block, end_block)| {
+            (start_block, end_block.min(from_block - 1))
+        });
+
+    let mut progress = String::new();
+    for (start_block, end_block) in ranges {
+        progress.push_str(&format!("{start_block},{end_block}\n"));
+    }
+    std::fs::write(progress_path(csv_path), progress)?;
+
+    Ok(())
+}
+
+/// Merge block ranges into sorted, disjoint ranges, joining those that
+/// overlap or are adjacent.
+pub(crate) fn merge(
+    mut ranges: Vec<(BlockNumber, BlockNumber)>,
+) -> Vec<(BlockNumber, BlockNumber)> {
+    ranges.sort_unstable();
+
+    let mut merged: Vec<(BlockNumber, BlockNumber)> = vec![];
+    for (start_block, end_block) in ranges {
+        match merged.last_mut() {
+            Some((_, last_end))
+                if start_block <= last_end.saturating_add(1) =>
+            {
+                *last_end = (*last_end).max(end_block);
+            }
+            _ => merged.push((start_block, end_block)),
+        }
+    }
+
+    merged
+}
+
+/// The last block of the range of blocks scanned without gaps from the given
+/// block, if the given block was scanned at all.
+pub(crate) fn covered_until(
+    ranges: Vec<(BlockNumber, BlockNumber)>,
+    from_block: BlockNumber,
+) -> Option<BlockNumber> {
+    merge(ranges)
+        .into_iter()
+        .find(|&(start_block, end_block)| {
+            (start_block..=end_block).contains(&from_block)
+        })
+        .map(|(_, end_block)| end_block)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_out_of_order_and_overlapping_ranges() {
+        let ranges =
+            vec![(300, 399), (100, 199), (150, 250), (251, 260), (500, 600)];
+
+        assert_eq!(merge(ranges), vec![(100, 260), (300, 399), (500, 600)]);
+    }
+
+    #[test]
+    fn test_covered_until_stops_at_first_gap() {
+        let ranges = vec![(200, 299), (100, 199), (400, 499), (150, 180)];
+
+        assert_eq!(covered_until(ranges.clone(), 100), Some(299));
+        assert_eq!(covered_until(ranges.clone(), 420), Some(499));
+        assert_eq!(covered_until(ranges.clone(), 300), None);
+        assert_eq!(covered_until(vec![], 100), None);
+    }
+
+    #[test]
+    fn test_read_skips_truncated_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv_path = dir.path().join("trades.csv");
+        let csv_path = csv_path.to_str().unwrap();
+
+        assert!(read(csv_path).unwrap().is_empty());
+
+        record(csv_path, 100, 199).unwrap();
+        record(csv_path, 200, 299).unwrap();
+        let mut progress_file = OpenOptions::new()
+            .append(true)
+            .open(progress_path(csv_path))
+            .unwrap();
+        // A line cut short within the end block claims fewer blocks, here
+        // none at all.
+        write!(progress_file, "300,3").unwrap();
+
+        assert_eq!(read(csv_path).unwrap(), vec![(100, 199), (200, 299)]);
+
+        reset(csv_path).unwrap();
+        assert!(read(csv_path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_rewind_forgets_later_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv_path = dir.path().join("trades.csv");
+        let csv_path = csv_path.to_str().unwrap();
+
+        record(csv_path, 100, 199).unwrap();
+        record(csv_path, 300, 399).unwrap();
+        record(csv_path, 200, 250).unwrap();
+        rewind(csv_path, 220).unwrap();
+
+        assert_eq!(read(csv_path).unwrap(), vec![(100, 219)]);
+        assert_eq!(covered_until(read(csv_path).unwrap(), 100), Some(219));
+    }
+}
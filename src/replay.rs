@@ -0,0 +1,55 @@
+//! Pacing for replaying saved trades as a synthetic live feed, so that
+//! consumers of the data can be tested against realistic arrival times.
+
+use std::time::Duration;
+
+use crate::env::TimestampUnit;
+
+/// The delay before each of the trades with the given timestamps, i.e. the
+/// gap between its timestamp and the previous one, divided by the speed. The
+/// first trade is due right away, and timestamps going backwards, e.g. in
+/// unsorted files, aren't waited for.
+pub(crate) fn delays(
+    timestamps: impl IntoIterator<Item = u64>,
+    timestamp_unit: TimestampUnit,
+    speed: f64,
+) -> impl Iterator<Item = Duration> {
+    let mut previous = None;
+
+    timestamps.into_iter().map(move |timestamp| {
+        let gap =
+            previous.map_or(0, |previous| timestamp.saturating_sub(previous));
+        previous = Some(timestamp);
+
+        let gap = match timestamp_unit {
+            TimestampUnit::Seconds => Duration::from_secs(gap),
+            TimestampUnit::Millis => Duration::from_millis(gap),
+        };
+        Duration::try_from_secs_f64(gap.as_secs_f64() / speed)
+            .unwrap_or(Duration::MAX)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delays_follow_timestamp_gaps() {
+        let timestamps = [100, 100, 160, 130, 190];
+
+        assert_eq!(
+            delays(timestamps, TimestampUnit::Seconds, 1.0).collect::<Vec<_>>(),
+            [0, 0, 60, 0, 60].map(Duration::from_secs)
+        );
+        assert_eq!(
+            delays(timestamps, TimestampUnit::Seconds, 60.0)
+                .collect::<Vec<_>>(),
+            [0, 0, 1, 0, 1].map(Duration::from_secs)
+        );
+        assert_eq!(
+            delays(timestamps, TimestampUnit::Millis, 2.0).collect::<Vec<_>>(),
+            [0, 0, 30, 0, 30].map(Duration::from_millis)
+        );
+    }
+}
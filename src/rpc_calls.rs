@@ -0,0 +1,63 @@
+//! A counter of the JSON-RPC requests made, which also throttles them to a
+//! maximum rate so that rate-limited providers never have to reject them.
+
+use governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counts JSON-RPC requests, including retries, and delays them to stay
+/// within the configured requests per second. Shared by all concurrent
+/// requests of a chain, so the limit holds for all of them together.
+#[derive(Debug, Default)]
+pub(crate) struct RpcCalls {
+    count: AtomicU64,
+    rate_limiter: Option<DefaultDirectRateLimiter>,
+}
+
+impl RpcCalls {
+    /// Allow at most `max_rps` requests per second, or any number if unset.
+    pub(crate) fn new(max_rps: Option<NonZeroU32>) -> Self {
+        Self {
+            count: AtomicU64::new(0),
+            rate_limiter: max_rps
+                .map(|max_rps| RateLimiter::direct(Quota::per_second(max_rps))),
+        }
+    }
+
+    /// Wait until a request may be made and count it. Must be called right
+    /// before each request.
+    pub(crate) async fn start(&self) {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.until_ready().await;
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The number of requests made so far.
+    pub(crate) fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_requests_are_throttled() {
+        let rpc_calls = RpcCalls::new(NonZeroU32::new(10));
+        let started = Instant::now();
+
+        // The quota allows a burst of 10, after which each request waits for
+        // its share of the second.
+        for _ in 0..15 {
+            rpc_calls.start().await;
+        }
+
+        assert_eq!(rpc_calls.count(), 15);
+        assert!(started.elapsed() >= Duration::from_millis(400));
+    }
+}
@@ -0,0 +1,86 @@
+//! The seen-set file stored next to the CSV file with `--seen-set`, listing
+//! the key of every trade written to it, so that rescanning blocks whose
+//! trades are already saved never writes them twice, however runs overlap.
+
+use alloy::primitives::FixedBytes;
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+
+use crate::{output, Error, TradeKey};
+
+/// The path of the seen-set file of the CSV file at the given path.
+pub(crate) fn seen_path(csv_path: &str) -> String {
+    output::sidecar_path(csv_path, ".seen")
+}
+
+/// Append the keys of newly written trades.
+pub(crate) fn record(csv_path: &str, keys: &[TradeKey]) -> Result<(), Error> {
+    let mut lines = String::new();
+    for (tx_hash, log_index) in keys {
+        lines.push_str(&format!("{tx_hash},{log_index}\n"));
+    }
+
+    let mut seen_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(seen_path(csv_path))?;
+    seen_file.write_all(lines.as_bytes())?;
+
+    Ok(())
+}
+
+/// Read the recorded keys, or `None` if the CSV file has no seen-set file
+/// yet. A trailing line cut short by an interrupted write doesn't parse and
+/// is skipped, so at worst its trade is written again.
+pub(crate) fn read(csv_path: &str) -> Result<Option<HashSet<TradeKey>>, Error> {
+    let seen = match std::fs::read_to_string(seen_path(csv_path)) {
+        Ok(seen) => seen,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    Ok(Some(
+        seen.lines()
+            .filter_map(|line| {
+                let (tx_hash, log_index) = line.split_once(',')?;
+                let tx_hash: FixedBytes<32> = tx_hash.parse().ok()?;
+                Some((tx_hash, log_index.parse().ok()?))
+            })
+            .collect(),
+    ))
+}
+
+/// Discard the recorded keys, e.g. when starting a fresh CSV file or after
+/// saved trades were dropped, so that they can be written again.
+pub(crate) fn reset(csv_path: &str) -> Result<(), Error> {
+    match std::fs::remove_file(seen_path(csv_path)) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_skips_truncated_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv_path = dir.path().join("trades.csv");
+        let csv_path = csv_path.to_str().unwrap();
+        let keys = [(FixedBytes::repeat_byte(1), 0), (FixedBytes::ZERO, 7)];
+
+        assert_eq!(read(csv_path).unwrap(), None);
+
+        record(csv_path, &keys).unwrap();
+        let mut seen_file =
+            OpenOptions::new().append(true).open(seen_path(csv_path)).unwrap();
+        write!(seen_file, "0x01").unwrap();
+
+        assert_eq!(read(csv_path).unwrap(), Some(HashSet::from(keys)));
+
+        reset(csv_path).unwrap();
+        assert_eq!(read(csv_path).unwrap(), None);
+    }
+}
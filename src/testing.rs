@@ -0,0 +1,363 @@
+//! Proptest strategies generating synthetic trade logs and block bodies,
+//! shared by the unit tests and, via the `testing` feature, the benchmarks.
+
+use alloy::hex::FromHex;
+use alloy::primitives::{Address, BlockNumber, FixedBytes, TxHash, B256};
+use itertools::Itertools;
+use proptest::prelude::*;
+use proptest::strategy::ValueTree;
+use proptest::test_runner::{Config, RngAlgorithm, TestRng, TestRunner};
+use std::collections::BTreeMap;
+
+use crate::compose::enrich_and_merge;
+use crate::logs::{TradeEvent, TradeFill, TradeLog};
+use crate::onchain::{BlockMetadata, TxMetadata};
+use crate::Trade;
+
+/// Bounds on the size of generated data.
+#[derive(Debug, Clone, Copy)]
+pub struct ArbSizes {
+    /// The maximum number of trade logs generated per event type.
+    pub max_trades_per_event: usize,
+    /// The maximum number of transactions without trades added to each
+    /// block.
+    pub max_extra_txs_per_block: usize,
+}
+
+impl Default for ArbSizes {
+    fn default() -> Self {
+        Self { max_trades_per_event: 10, max_extra_txs_per_block: 10 }
+    }
+}
+
+/// Opaque [`enrich_and_merge`] arguments for use outside of the crate.
+#[derive(Debug, Clone)]
+pub struct EnrichAndMergeInput(EnrichAndMergeArgs);
+
+impl EnrichAndMergeInput {
+    /// The total number of trades in the input.
+    pub fn trade_count(&self) -> usize {
+        let (clearv2_trades, takeorderv2_trades, _) = &self.0;
+        clearv2_trades
+            .values()
+            .chain(takeorderv2_trades.values())
+            .map(|trades| trades.len())
+            .sum()
+    }
+
+    /// Run [`enrich_and_merge`] on the input.
+    pub fn enrich_and_merge(self) -> Vec<Trade> {
+        let (clearv2_trades, takeorderv2_trades, block_bodies) = self.0;
+        enrich_and_merge(clearv2_trades, takeorderv2_trades, block_bodies)
+    }
+}
+
+/// Generate [`EnrichAndMergeInput`]s of the given size.
+pub fn arb_enrich_and_merge_input(
+    sizes: ArbSizes,
+) -> impl Strategy<Value = EnrichAndMergeInput> {
+    arb_enrich_and_merge_args(sizes).prop_map(EnrichAndMergeInput)
+}
+
+/// The arguments of [`enrich_and_merge`]: ClearV2 trade logs, TakeOrderV2
+/// trade logs and the bodies of all blocks with trades.
+pub(crate) type EnrichAndMergeArgs = (
+    BTreeMap<BlockNumber, Vec<TradeLog>>,
+    BTreeMap<BlockNumber, Vec<TradeLog>>,
+    BTreeMap<BlockNumber, BlockMetadata>,
+);
+
+/// Generate consistent [`enrich_and_merge`] arguments where every trade's
+/// transaction is included in its block's body.
+pub(crate) fn arb_enrich_and_merge_args(
+    sizes: ArbSizes,
+) -> impl Strategy<Value = EnrichAndMergeArgs> {
+    arb_enrich_and_merge_args_in(sizes, 0, 999)
+}
+
+/// Generate trade logs from blocks within the given inclusive range and the
+/// bodies of those blocks, the same for the same seed, to set up an
+/// [`InMemoryChain`](crate::onchain::in_memory::InMemoryChain) with
+/// reproducible data.
+pub fn seeded_chain_data(
+    seed: u64,
+    start_block: BlockNumber,
+    end_block: BlockNumber,
+    sizes: ArbSizes,
+) -> (Vec<TradeLog>, BTreeMap<BlockNumber, BlockMetadata>) {
+    let mut seed_bytes = [0; 32];
+    seed_bytes[..8].copy_from_slice(&seed.to_le_bytes());
+    let rng = TestRng::from_seed(RngAlgorithm::ChaCha, &seed_bytes);
+    let mut runner = TestRunner::new_with_rng(Config::default(), rng);
+
+    let (clearv2_trades, takeorderv2_trades, blocks) =
+        arb_enrich_and_merge_args_in(sizes, start_block, end_block)
+            .new_tree(&mut runner)
+            .expect("generating chain data never fails")
+            .current();
+    let trades = clearv2_trades
+        .into_values()
+        .chain(takeorderv2_trades.into_values())
+        .flatten()
+        .collect();
+
+    (trades, blocks)
+}
+
+fn arb_enrich_and_merge_args_in(
+    sizes: ArbSizes,
+    start_block: BlockNumber,
+    end_block: BlockNumber,
+) -> impl Strategy<Value = EnrichAndMergeArgs> {
+    arb_trade_logs_and_hashes(
+        sizes.max_trades_per_event,
+        start_block,
+        end_block,
+    )
+    .prop_flat_map(
+        move |(clearv2_trades, takeorderv2_trades, block_num_to_tx_hashes)| {
+            let block_metadata_strategy = arb_blocks(
+                block_num_to_tx_hashes,
+                sizes.max_extra_txs_per_block,
+            );
+
+            (
+                Just(clearv2_trades),
+                Just(takeorderv2_trades),
+                block_metadata_strategy,
+            )
+                .prop_map(|(clearv2, takeorderv2, blocks)| {
+                    (clearv2, takeorderv2, blocks)
+                })
+        },
+    )
+}
+
+fn arb_blocks(
+    block_num_to_tx_hashes: BTreeMap<BlockNumber, Vec<TxHash>>,
+    max_extra_txs: usize,
+) -> impl Strategy<Value = BTreeMap<BlockNumber, BlockMetadata>> {
+    let block_strategies: Vec<_> = block_num_to_tx_hashes
+        .into_iter()
+        .map(|(block_number, tx_hashes)| {
+            (Just(block_number), arb_block_metadata(tx_hashes, max_extra_txs))
+        })
+        .collect();
+
+    prop_oneof![block_strategies]
+        .prop_map(|blocks| blocks.into_iter().collect())
+}
+
+prop_compose! {
+    fn arb_trade_logs_and_hashes(
+        max_trades: usize,
+        start_block: BlockNumber,
+        end_block: BlockNumber,
+    )(
+        clearv2_logs in arb_trade_logs(
+            TradeEvent::ClearV2, max_trades, start_block, end_block
+        ),
+        takeorderv2_logs in arb_trade_logs(
+            TradeEvent::TakeOrderV2, max_trades, start_block, end_block
+        )
+    ) -> (
+        BTreeMap<BlockNumber, Vec<TradeLog>>,
+        BTreeMap<BlockNumber, Vec<TradeLog>>,
+        BTreeMap<BlockNumber, Vec<TxHash>>,
+    ) {
+        let mut block_num_to_tx_hashes =
+            BTreeMap::<BlockNumber, Vec<(u64, TxHash)>>::new();
+
+        // First collect all takeorderv2 trades
+        for log in takeorderv2_logs.clone() {
+            block_num_to_tx_hashes
+                .entry(log.block_number)
+                .and_modify(|hashes| hashes.push((log.log_index, log.tx_hash)))
+                .or_insert(vec![(log.log_index, log.tx_hash)]);
+        }
+
+        // Then add clearv2 trades, dropping any that have the same block and
+        // log index as a takeorderv2 trade, as logs never share both
+        let clearv2_logs = clearv2_logs
+            .into_iter()
+            .filter(|log| {
+                !takeorderv2_logs.iter().any(|other| {
+                    other.block_number == log.block_number
+                        && other.log_index == log.log_index
+                })
+            })
+            .collect_vec();
+        for log in clearv2_logs.clone() {
+            block_num_to_tx_hashes
+                .entry(log.block_number)
+                .and_modify(|hashes| hashes.push((log.log_index, log.tx_hash)))
+                .or_insert(vec![(log.log_index, log.tx_hash)]);
+        }
+
+        let clearv2_trades: BTreeMap<BlockNumber, Vec<TradeLog>> = clearv2_logs
+            .into_iter()
+            .map(|log| (log.block_number, vec![log]))
+            .collect();
+
+        let takeorderv2_trades: BTreeMap<BlockNumber, Vec<TradeLog>> =
+            takeorderv2_logs
+                .into_iter()
+                .map(|log| (log.block_number, vec![log]))
+                .collect();
+
+        let block_num_to_tx_hashes = block_num_to_tx_hashes
+            .into_iter()
+            .map(|(block_number, hashes_with_indices)| {
+                let sorted_hashes = hashes_with_indices
+                    .into_iter()
+                    .sorted_by_key(|(index, _)| *index)
+                    .map(|(_, hash)| hash)
+                    .collect_vec();
+                (block_number, sorted_hashes)
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        (clearv2_trades, takeorderv2_trades, block_num_to_tx_hashes)
+    }
+}
+
+prop_compose! {
+    fn arb_block_metadata(
+        required_tx_hashes: Vec<TxHash>,
+        max_extra_txs: usize,
+    )(
+        timestamp in 0u64..10000,
+        transactions in arb_transactions(required_tx_hashes, max_extra_txs)
+    ) -> BlockMetadata {
+        BlockMetadata { timestamp, transactions }
+    }
+}
+
+fn arb_transactions(
+    required_tx_hashes: Vec<TxHash>,
+    max_extra_txs: usize,
+) -> impl Strategy<Value = Vec<TxMetadata>> {
+    // First generate the required transactions using arb_tx_metadata_from_hash
+    let required_txs = required_tx_hashes
+        .into_iter()
+        .map(arb_tx_metadata_from_hash)
+        .collect::<Vec<_>>();
+
+    // Then generate some arbitrary transactions
+    let arbitrary_txs =
+        prop::collection::vec(arb_tx_metadata(), 0..max_extra_txs);
+
+    // Combine them using prop_flat_map
+    (required_txs, arbitrary_txs).prop_flat_map(|(required, arbitrary)| {
+        // Combine the required and arbitrary transactions
+        let mut all_txs = required;
+        all_txs.extend(arbitrary);
+        Just(all_txs)
+    })
+}
+
+fn arb_trade_logs(
+    event: TradeEvent,
+    max_trades: usize,
+    start_block: BlockNumber,
+    end_block: BlockNumber,
+) -> impl Strategy<Value = Vec<TradeLog>> {
+    prop::collection::vec(
+        arb_trade_log(event, start_block, end_block),
+        1..=max_trades,
+    )
+}
+
+prop_compose! {
+    fn arb_trade_log(
+        event: TradeEvent,
+        start_block: BlockNumber,
+        end_block: BlockNumber,
+    )(
+        log_index in 0u64..1000,
+        block_number in start_block..=end_block,
+        tx_hash in arb_tx_hash(),
+        contract_address in arb_address(),
+    ) -> TradeLog {
+        TradeLog {
+            log_index,
+            block_number,
+            block_hash: B256::ZERO,
+            tx_hash,
+            event: event.clone(),
+            contract_address,
+            fill: TradeFill::default(),
+            event_json: None,
+        }
+    }
+}
+
+prop_compose! {
+    fn arb_tx_metadata_from_hash(hash: TxHash)(
+        origin in arb_address(),
+        tx_type in arb_tx_type()
+    ) -> TxMetadata {
+        TxMetadata { hash, origin, tx_type }
+    }
+}
+
+prop_compose! {
+    fn arb_tx_metadata()(
+        hash in arb_tx_hash(),
+        origin in arb_address(),
+        tx_type in arb_tx_type()
+    ) -> TxMetadata {
+        TxMetadata { hash, origin, tx_type }
+    }
+}
+
+fn arb_tx_type() -> impl Strategy<Value = String> {
+    prop_oneof![
+        Just("legacy".to_string()),
+        Just("eip1559".to_string()),
+        Just("eip4844".to_string()),
+    ]
+}
+
+prop_compose! {
+    pub(crate) fn arb_tx_hash()(hash in "0x[a-f0-9]{64}") -> FixedBytes<32> {
+        FixedBytes::from_hex(hash).unwrap()
+    }
+}
+
+prop_compose! {
+    pub(crate) fn arb_address()(address in "0x[a-f0-9]{40}") -> Address {
+        Address::from_hex(address).unwrap()
+    }
+}
+
+/// A trade with placeholder values, for tests that only care about a few
+/// fields.
+#[cfg(test)]
+pub(crate) fn trade() -> Trade {
+    Trade {
+        timestamp: 0,
+        tx_origin: Address::ZERO,
+        tx_hash: FixedBytes::ZERO,
+        event: TradeEvent::TakeOrderV2,
+        contract_address: Address::ZERO,
+        tx_type: "eip1559".to_string(),
+        input_token: None,
+        output_token: None,
+        input_amount: None,
+        output_amount: None,
+        input_symbol: None,
+        output_symbol: None,
+        input_amount_adjusted: None,
+        output_amount_adjusted: None,
+        block_number: 0,
+        log_index: 0,
+        order_owner: None,
+        side: None,
+        event_json: None,
+        block_hash: None,
+        log_index_in_tx: None,
+        first_seen: None,
+        counterparty_owner: None,
+    }
+}
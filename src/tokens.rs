@@ -0,0 +1,206 @@
+//! Resolution of ERC-20 token metadata for the optional `--resolve-tokens`
+//! enrichment step.
+
+use alloy::primitives::Address;
+use alloy::sol;
+use std::collections::HashMap;
+use tracing::*;
+
+use crate::env::Env;
+use crate::onchain::OnChain;
+use crate::units::adjust_amount;
+use crate::{enrich_cache, output, Error, Trade};
+
+sol! {
+    #[sol(rpc)]
+    interface IERC20Metadata {
+        function symbol() external view returns (string);
+        function decimals() external view returns (uint8);
+    }
+}
+
+/// Token metadata from the ERC-20 `symbol` and `decimals` getters. Both are
+/// optional in the standard, so either may be missing for non-standard
+/// tokens.
+#[derive(
+    Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize,
+)]
+pub struct TokenMetadata {
+    pub(crate) symbol: Option<String>,
+    pub(crate) decimals: Option<u8>,
+}
+
+/// A cache of resolved token metadata so that each token is queried at most
+/// once per run, or at most once across runs when persisted next to the CSV
+/// file.
+#[derive(Debug, Default)]
+pub(crate) struct TokenCache {
+    tokens: HashMap<Address, TokenMetadata>,
+    /// The metadata returned by the chain to earlier runs, before applying
+    /// the decimals overrides and fallback, which may have changed since.
+    persisted: HashMap<Address, TokenMetadata>,
+    /// The CSV file whose token file newly resolved tokens are recorded in.
+    csv_path: Option<String>,
+    /// Decimals that take precedence over those resolved from the chain.
+    decimals_overrides: HashMap<Address, u8>,
+    /// Decimals to fall back to for tokens whose `decimals` can't be
+    /// resolved.
+    default_decimals: Option<u8>,
+}
+
+impl TokenCache {
+    /// Create an empty cache applying the configured decimals overrides and
+    /// fallback.
+    pub(crate) fn from_env(env: &Env) -> Self {
+        Self {
+            tokens: HashMap::new(),
+            persisted: HashMap::new(),
+            csv_path: None,
+            decimals_overrides: env.decimals_override.iter().copied().collect(),
+            default_decimals: env.default_decimals,
+        }
+    }
+
+    /// Reuse the tokens resolved by earlier runs for the CSV file at the
+    /// given path, and record the ones this run resolves for later runs.
+    /// Nothing is persisted for trades written to stdout.
+    pub(crate) fn persisted(mut self, csv_path: &str) -> Result<Self, Error> {
+        if output::is_stdout(csv_path) {
+            return Ok(self);
+        }
+
+        self.persisted = enrich_cache::read_tokens(csv_path)?;
+        self.csv_path = Some(csv_path.to_string());
+        Ok(self)
+    }
+
+    /// Get the metadata of the given token, querying the chain on a cache
+    /// miss unless an earlier run resolved it. Overridden decimals replace
+    /// the resolved ones, and the default decimals fill in for those that
+    /// couldn't be resolved.
+    pub(crate) async fn resolve(
+        &mut self,
+        onchain: &impl OnChain,
+        token: Address,
+    ) -> Result<TokenMetadata, Error> {
+        if let Some(metadata) = self.tokens.get(&token) {
+            return Ok(metadata.clone());
+        }
+
+        let mut metadata = match self.persisted.get(&token) {
+            Some(metadata) => metadata.clone(),
+            None => {
+                let metadata = onchain.fetch_token_metadata(token).await?;
+                // A getter may have failed transiently, so tokens missing
+                // either are resolved again by the next run.
+                let resolved =
+                    metadata.symbol.is_some() && metadata.decimals.is_some();
+                if let Some(csv_path) =
+                    self.csv_path.as_ref().filter(|_| resolved)
+                {
+                    enrich_cache::record_token(csv_path, token, &metadata)?;
+                }
+                metadata
+            }
+        };
+        metadata.decimals = self
+            .decimals_overrides
+            .get(&token)
+            .copied()
+            .or(metadata.decimals)
+            .or(self.default_decimals);
+        debug!("Resolved token {token}: {metadata:?}");
+        self.tokens.insert(token, metadata.clone());
+
+        Ok(metadata)
+    }
+
+    /// Fill in the token symbols and decimal-adjusted amounts of a trade.
+    pub(crate) async fn enrich(
+        &mut self,
+        onchain: &impl OnChain,
+        trade: &mut Trade,
+    ) -> Result<(), Error> {
+        if let Some(input_token) = trade.input_token {
+            let metadata = self.resolve(onchain, input_token).await?;
+            trade.input_amount_adjusted =
+                adjust_amount(trade.input_amount, metadata.decimals);
+            trade.input_symbol = metadata.symbol;
+        }
+
+        if let Some(output_token) = trade.output_token {
+            let metadata = self.resolve(onchain, output_token).await?;
+            trade.output_amount_adjusted =
+                adjust_amount(trade.output_amount, metadata.decimals);
+            trade.output_symbol = metadata.symbol;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::onchain::in_memory::InMemoryChain;
+
+    #[tokio::test]
+    async fn test_decimals_overrides_and_default() {
+        // The in-memory chain resolves no decimals for any token.
+        let onchain = InMemoryChain::new(0, BTreeMap::new(), []);
+        let overridden = Address::repeat_byte(1);
+        let mut token_cache = TokenCache {
+            decimals_overrides: HashMap::from([(overridden, 6)]),
+            default_decimals: Some(18),
+            ..Default::default()
+        };
+
+        let metadata = token_cache.resolve(&onchain, overridden).await.unwrap();
+        assert_eq!(metadata.decimals, Some(6));
+
+        let metadata = token_cache
+            .resolve(&onchain, Address::repeat_byte(2))
+            .await
+            .unwrap();
+        assert_eq!(metadata.decimals, Some(18));
+    }
+
+    #[tokio::test]
+    async fn test_persisted_tokens_are_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv_path = dir.path().join("trades.csv");
+        let csv_path = csv_path.to_str().unwrap();
+        let onchain = InMemoryChain::new(0, BTreeMap::new(), []);
+        let (usdc, unresolved) =
+            (Address::repeat_byte(1), Address::repeat_byte(2));
+        let usdc_metadata = TokenMetadata {
+            symbol: Some("USDC".to_string()),
+            decimals: Some(6),
+        };
+        enrich_cache::record_token(csv_path, usdc, &usdc_metadata).unwrap();
+
+        let mut token_cache = TokenCache {
+            decimals_overrides: HashMap::from([(unresolved, 18)]),
+            ..Default::default()
+        }
+        .persisted(csv_path)
+        .unwrap();
+
+        // The in-memory chain resolves no metadata for any token.
+        let metadata = token_cache.resolve(&onchain, usdc).await.unwrap();
+        assert_eq!(metadata, usdc_metadata);
+        let metadata = token_cache.resolve(&onchain, unresolved).await.unwrap();
+        assert_eq!(
+            metadata,
+            TokenMetadata { symbol: None, decimals: Some(18) }
+        );
+
+        // Only fully resolved tokens are recorded.
+        assert_eq!(
+            enrich_cache::read_tokens(csv_path).unwrap(),
+            HashMap::from([(usdc, usdc_metadata)])
+        );
+    }
+}
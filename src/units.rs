@@ -0,0 +1,86 @@
+//! Helpers for presenting raw token amounts.
+
+use alloy::primitives::utils::format_units;
+use alloy::primitives::U256;
+
+/// Scale a raw token amount down by the token's decimals, e.g. `1500000`
+/// with 6 decimals becomes `1.500000`.
+pub(crate) fn adjust_amount(
+    amount: Option<U256>,
+    decimals: Option<u8>,
+) -> Option<String> {
+    format_units(amount?, decimals?).ok()
+}
+
+/// Serde helper for writing amounts per token as decimal strings, like
+/// [`decimal`] does for single amounts.
+pub(crate) mod decimal_per_token {
+    use alloy::primitives::{Address, U256};
+    use serde::ser::SerializeMap;
+    use serde::Serializer;
+    use std::collections::BTreeMap;
+
+    pub(crate) fn serialize<S: Serializer>(
+        amounts: &BTreeMap<Address, U256>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(amounts.len()))?;
+        for (token, amount) in amounts {
+            map.serialize_entry(token, &amount.to_string())?;
+        }
+        map.end()
+    }
+}
+
+/// Serde helpers for writing optional amounts as decimal strings rather than
+/// the hex strings used by the default [`U256`] encoding, as decimals are
+/// what spreadsheet and dataframe tooling expects.
+pub(crate) mod decimal {
+    use alloy::primitives::U256;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::str::FromStr;
+
+    pub(crate) fn serialize<S: Serializer>(
+        amount: &Option<U256>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match amount {
+            Some(amount) => serializer.serialize_str(&amount.to_string()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<U256>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .filter(|amount| !amount.is_empty())
+            .map(|amount| {
+                U256::from_str(&amount).map_err(serde::de::Error::custom)
+            })
+            .transpose()
+    }
+}
+
+/// Serde helper for reading timestamps written either as integers or, by
+/// the Dune dialect, as ISO-8601 UTC strings, which are read back in
+/// seconds.
+pub(crate) mod timestamp {
+    use serde::{Deserialize, Deserializer};
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<u64, D::Error> {
+        let timestamp = String::deserialize(deserializer)?;
+        if let Ok(timestamp) = timestamp.parse() {
+            return Ok(timestamp);
+        }
+
+        chrono::DateTime::parse_from_rfc3339(&timestamp)
+            .map_err(serde::de::Error::custom)
+            .and_then(|datetime| {
+                u64::try_from(datetime.timestamp())
+                    .map_err(serde::de::Error::custom)
+            })
+    }
+}
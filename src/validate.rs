@@ -0,0 +1,234 @@
+//! A check of the block hashes saved with the trades of the CSV file against
+//! the chain, with the `validate` command, for auditing a collected dataset
+//! for trades of blocks that were reorged since they were collected.
+
+use alloy::primitives::{BlockNumber, B256};
+use tracing::*;
+
+use crate::onchain::OnChain;
+use crate::{env, output, sampled, stream_trades_csv, Error, Trade};
+
+/// Why the block hash saved with a trade doesn't match the chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mismatch {
+    /// The chain has another block at the trade's block number.
+    Reorged,
+    /// The chain has no block at the trade's block number.
+    Invalid,
+}
+
+/// How many rows of the CSV file were checked against the chain and how many
+/// of them don't match it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct ValidationSummary {
+    pub checked: usize,
+    pub reorged: usize,
+    pub invalid: usize,
+    /// Rows saved without a block hash, which can't be checked.
+    pub unchecked: usize,
+}
+
+impl ValidationSummary {
+    /// Whether all checked rows match the chain.
+    pub fn is_valid(&self) -> bool {
+        self.reorged == 0 && self.invalid == 0
+    }
+}
+
+/// Compare the block hash saved with each trade of the CSV file, or with
+/// those of the transactions sampled at the given rate, to the hash of the
+/// block at its number on the chain, and hand each trade that doesn't match
+/// to `mismatched`, in file order. Each block is only fetched once as long
+/// as the file is sorted.
+pub async fn validate_trades_csv(
+    env: &env::Env,
+    onchain: &impl OnChain,
+    sample_rate: Option<f64>,
+    mut mismatched: impl FnMut(Mismatch, &Trade) -> Result<(), Error>,
+) -> Result<ValidationSummary, Error> {
+    if output::is_stdout(&env.output_path()) {
+        return Err(Error::Config(
+            "Can't validate trades written to stdout".to_string(),
+        ));
+    }
+
+    let mut summary = ValidationSummary::default();
+    let mut last_block: Option<(BlockNumber, Option<B256>)> = None;
+    for trade in stream_trades_csv(env)? {
+        let trade = trade?;
+        if sample_rate.is_some_and(|rate| !sampled(&trade.tx_hash, rate)) {
+            continue;
+        }
+        let Some(saved_hash) = trade.block_hash else {
+            summary.unchecked += 1;
+            continue;
+        };
+
+        let chain_hash = match last_block {
+            Some((block_number, chain_hash))
+                if block_number == trade.block_number =>
+            {
+                chain_hash
+            }
+            _ => {
+                let chain_hash =
+                    onchain.get_block_hash(trade.block_number).await?;
+                last_block = Some((trade.block_number, chain_hash));
+                chain_hash
+            }
+        };
+
+        summary.checked += 1;
+        let mismatch = match chain_hash {
+            Some(chain_hash) if chain_hash == saved_hash => continue,
+            Some(_) => {
+                summary.reorged += 1;
+                Mismatch::Reorged
+            }
+            None => {
+                summary.invalid += 1;
+                Mismatch::Invalid
+            }
+        };
+        mismatched(mismatch, &trade)?;
+    }
+    debug!("Validated {} trades against the chain", summary.checked);
+
+    Ok(summary)
+}
+
+/// Check the saved trades against the chain and print how many rows don't
+/// match it, preceded by those rows as JSON, marked `reorged` if the chain
+/// has another block at their block number and `invalid` if it has none.
+pub async fn print_validation(
+    env: &env::Env,
+    onchain: &impl OnChain,
+    sample_rate: Option<f64>,
+) -> Result<ValidationSummary, Error> {
+    let summary =
+        validate_trades_csv(env, onchain, sample_rate, |mismatch, trade| {
+            let marker = match mismatch {
+                Mismatch::Reorged => "reorged",
+                Mismatch::Invalid => "invalid",
+            };
+            println!("{marker} {}", serde_json::to_string(trade)?);
+            Ok(())
+        })
+        .await?;
+
+    println!("Checked: {}", summary.checked);
+    println!("Reorged: {}", summary.reorged);
+    println!("Invalid: {}", summary.invalid);
+    println!("Without a block hash: {}", summary.unchecked);
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::Address;
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::compose::{BlockMetadata, TradeEvent, TradeLog, TxMetadata};
+    use crate::env::Env;
+    use crate::logs::TradeFill;
+    use crate::onchain::in_memory::InMemoryChain;
+    use crate::update_trades_csv;
+
+    #[tokio::test]
+    async fn test_validate_reports_reorged_trades() {
+        let tx_hash = |block_number: BlockNumber| {
+            B256::with_last_byte(block_number as u8)
+        };
+        let blocks = (100..=103)
+            .map(|block_number| {
+                let block = BlockMetadata {
+                    timestamp: block_number,
+                    transactions: vec![TxMetadata {
+                        origin: Address::ZERO,
+                        hash: tx_hash(block_number),
+                        tx_type: "eip1559".to_string(),
+                    }],
+                };
+                (block_number, block)
+            })
+            .collect::<BTreeMap<_, _>>();
+        // Blocks from `forked_from` on get other hashes, and blocks from
+        // `last_block` on are left out.
+        let chain = |forked_from: BlockNumber, last_block: BlockNumber| {
+            let trades = (100..last_block).map(|block_number| TradeLog {
+                log_index: 0,
+                block_number,
+                block_hash: B256::left_padding_from(&[
+                    u8::from(block_number >= forked_from),
+                    block_number as u8,
+                ]),
+                tx_hash: tx_hash(block_number),
+                event: TradeEvent::TakeOrderV2,
+                contract_address: Address::ZERO,
+                fill: TradeFill::default(),
+                event_json: None,
+            });
+            InMemoryChain::new(110, blocks.clone(), trades)
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let env = Env::builder(
+            "http://localhost:8545",
+            vec![Address::ZERO.to_string()],
+        )
+        .with_csv_path(dir.path().join("trades.csv").to_str().unwrap())
+        .with_orderbookv4_deployment_block(100)
+        .build()
+        .unwrap();
+        assert_eq!(
+            update_trades_csv(&env, &chain(u64::MAX, 104)).await.unwrap(),
+            4
+        );
+
+        let summary =
+            validate_trades_csv(&env, &chain(u64::MAX, 104), None, |_, _| {
+                panic!("no trade is reorged")
+            })
+            .await
+            .unwrap();
+        assert_eq!(summary.checked, 4);
+        assert!(summary.is_valid());
+
+        let mut mismatches = vec![];
+        let summary = validate_trades_csv(
+            &env,
+            &chain(102, 103),
+            None,
+            |mismatch, trade| {
+                mismatches.push((mismatch, trade.block_number));
+                Ok(())
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            mismatches,
+            [(Mismatch::Reorged, 102), (Mismatch::Invalid, 103)]
+        );
+        assert_eq!(
+            summary,
+            ValidationSummary {
+                checked: 4,
+                reorged: 1,
+                invalid: 1,
+                unchecked: 0
+            }
+        );
+        assert!(!summary.is_valid());
+
+        let summary =
+            validate_trades_csv(&env, &chain(100, 104), Some(0.0), |_, _| {
+                panic!("no trade is sampled")
+            })
+            .await
+            .unwrap();
+        assert_eq!(summary.checked, 0);
+    }
+}
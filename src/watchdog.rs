@@ -0,0 +1,92 @@
+//! A watchdog that aborts trade collection if it stops making progress, e.g.
+//! because a connection hangs in a way the request timeout doesn't catch.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::*;
+
+/// Tracks when the last batch was completed and calls the stall handler if
+/// no batch is completed within the stall timeout. Stops watching when
+/// dropped.
+pub(crate) struct Watchdog {
+    last_progress: Arc<Mutex<Instant>>,
+    task: JoinHandle<()>,
+}
+
+impl Watchdog {
+    /// Start watching for stalls, counting from now.
+    pub(crate) fn spawn(
+        stall_timeout: Duration,
+        on_stall: impl FnOnce() + Send + 'static,
+    ) -> Self {
+        let last_progress = Arc::new(Mutex::new(Instant::now()));
+
+        let task = tokio::spawn({
+            let last_progress = last_progress.clone();
+            async move {
+                loop {
+                    let deadline =
+                        *last_progress.lock().unwrap() + stall_timeout;
+                    tokio::time::sleep_until(deadline).await;
+
+                    let elapsed = last_progress.lock().unwrap().elapsed();
+                    if elapsed >= stall_timeout {
+                        error!("No batch completed in the last {elapsed:?}");
+                        on_stall();
+                        return;
+                    }
+                }
+            }
+        });
+
+        Self { last_progress, task }
+    }
+
+    /// Record that a batch was completed.
+    pub(crate) fn record_progress(&self) {
+        *self.last_progress.lock().unwrap() = Instant::now();
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::oneshot;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_watchdog_fires_without_progress() {
+        let (stalled, on_stall) = oneshot::channel();
+        let _watchdog = Watchdog::spawn(Duration::from_millis(50), move || {
+            stalled.send(()).unwrap();
+        });
+
+        tokio::time::timeout(Duration::from_secs(1), on_stall)
+            .await
+            .expect("watchdog didn't fire")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_watchdog_holds_off_while_progressing() {
+        let (stalled, mut on_stall) = oneshot::channel();
+        let watchdog = Watchdog::spawn(Duration::from_millis(200), move || {
+            stalled.send(()).unwrap();
+        });
+
+        for _ in 0..10 {
+            tokio::time::sleep(Duration::from_millis(40)).await;
+            watchdog.record_progress();
+        }
+
+        assert!(on_stall.try_recv().is_err());
+    }
+}
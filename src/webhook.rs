@@ -0,0 +1,118 @@
+//! Delivery of newly collected trades to a webhook, for feeding downstream
+//! services in real time.
+
+use alloy::transports::http::reqwest::{self, header, Url};
+use backon::{ExponentialBuilder, Retryable};
+use tracing::*;
+
+use crate::{Error, Trade};
+
+/// A webhook that receives each batch of new trades as a JSON array in a
+/// single POST request.
+#[derive(Debug, Clone)]
+pub(crate) struct Webhook {
+    client: reqwest::Client,
+    url: Url,
+}
+
+impl Webhook {
+    /// Create a webhook posting to the given URL.
+    pub(crate) fn new(url: &str) -> Result<Self, Error> {
+        let url = url.parse::<Url>().map_err(|err| {
+            Error::Config(format!("Invalid webhook URL: {err}"))
+        })?;
+
+        Ok(Self { client: reqwest::Client::new(), url })
+    }
+
+    /// Post the given trades, retrying failed requests and non-2xx responses
+    /// with exponential backoff. Empty batches aren't posted.
+    pub(crate) async fn send(&self, trades: &[Trade]) -> Result<(), Error> {
+        if trades.is_empty() {
+            return Ok(());
+        }
+
+        let body = serde_json::to_vec(trades)?;
+        let post = || async {
+            self.client
+                .post(self.url.clone())
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(body.clone())
+                .send()
+                .await?
+                .error_for_status()
+        };
+
+        post.retry(ExponentialBuilder::default())
+            .notify(|err, dur| {
+                warn!(
+                    "Retrying posting {} trades to the webhook in {dur:?} due \
+                    to {err:?}",
+                    trades.len()
+                );
+            })
+            .await?;
+        debug!("Posted {} trades to the webhook", trades.len());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::testing::trade;
+
+    /// Serve an endpoint that fails the first request and accepts the rest,
+    /// returning its URL and the number of requests received.
+    async fn flaky_endpoint() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let received = requests.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = [0; 4096];
+                assert!(socket.read(&mut request).await.unwrap() > 0);
+                let status = match received.fetch_add(1, Ordering::SeqCst) {
+                    0 => "500 Internal Server Error",
+                    _ => "200 OK",
+                };
+                let response = format!(
+                    "HTTP/1.1 {status}\r\nContent-Length: 0\r\n\
+                    Connection: close\r\n\r\n"
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        (url, requests)
+    }
+
+    #[tokio::test]
+    async fn test_send_retries_non_2xx_responses() {
+        let (url, requests) = flaky_endpoint().await;
+        let webhook = Webhook::new(&url).unwrap();
+
+        webhook.send(&[trade(), trade()]).await.unwrap();
+
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_send_skips_empty_batches() {
+        let (url, requests) = flaky_endpoint().await;
+        let webhook = Webhook::new(&url).unwrap();
+
+        webhook.send(&[]).await.unwrap();
+
+        assert_eq!(requests.load(Ordering::SeqCst), 0);
+    }
+}